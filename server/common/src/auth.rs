@@ -0,0 +1,287 @@
+use crate::settings::Settings;
+use crate::utils::LocalError;
+use hmac::{Hmac, Mac};
+use http::header::AUTHORIZATION;
+use http::request::Parts;
+use rand::RngCore;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const TOKEN_TTL_SECS: u64 = 24 * 60 * 60;
+// Tokens carrying permissions can't be revoked either, so a role change or a withdrawn grant
+// has to take effect by their expiry.
+pub const PRIVILEGED_TOKEN_TTL_SECS: u64 = 15 * 60;
+
+pub const PRODUCTS_WRITE: &str = "products:write";
+pub const PRODUCTS_DELETE: &str = "products:delete";
+pub const ACCOUNTS_MANAGE: &str = "accounts:manage";
+pub const ROLES_MANAGE: &str = "roles:manage";
+pub const ORDERS_READ: &str = "orders:read";
+pub const ORDERS_MANAGE: &str = "orders:manage";
+// Held by the services only: no role has it and it can't be granted to an account.
+pub const ORDERS_CREATE: &str = "orders:create";
+
+// Secrets shorter than this, or still set to one of the `change-me-` placeholders the shipped
+// configs carry, keep a service from starting.
+pub const MIN_SECRET_LEN: usize = 32;
+const PLACEHOLDER_SECRET_PREFIX: &str = "change-me";
+
+// The identity services call each other with.
+pub const SERVICE_USER_ID: i32 = 0;
+
+pub const ALL_PERMISSIONS: [&str; 6] = [
+    PRODUCTS_WRITE,
+    PRODUCTS_DELETE,
+    ACCOUNTS_MANAGE,
+    ROLES_MANAGE,
+    ORDERS_READ,
+    ORDERS_MANAGE,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Customer,
+    Staff,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "customer" => Some(Role::Customer),
+            "staff" => Some(Role::Staff),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Customer => "customer",
+            Role::Staff => "staff",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn permissions(&self) -> Vec<&'static str> {
+        match self {
+            Role::Customer => vec![],
+            Role::Staff => vec![PRODUCTS_WRITE, ORDERS_READ],
+            Role::Admin => ALL_PERMISSIONS.to_vec(),
+        }
+    }
+}
+
+// Authenticated caller, extracted from a signed bearer token issued by user_manager.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthContext {
+    pub user_id: i32,
+    pub role: Role,
+    pub permissions: Vec<String>,
+}
+
+impl AuthContext {
+    // Effective permissions are the role's ones plus the account's extra grants.
+    pub fn new(user_id: i32, role: Role, extra_permissions: &[String]) -> AuthContext {
+        let mut permissions: Vec<String> =
+            role.permissions().iter().map(|p| p.to_string()).collect();
+        for permission in extra_permissions {
+            if !permissions.contains(permission) {
+                permissions.push(permission.clone());
+            }
+        }
+
+        AuthContext {
+            user_id,
+            role,
+            permissions,
+        }
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn sign(payload: &str, secret: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+pub fn token_ttl_secs(auth: &AuthContext) -> u64 {
+    if auth.permissions.is_empty() {
+        TOKEN_TTL_SECS
+    } else {
+        PRIVILEGED_TOKEN_TTL_SECS
+    }
+}
+
+// Token format: base64url(json claims) "." base64url(hmac-sha256 of the first part)
+pub fn issue_token(auth: &AuthContext, secret: &str) -> String {
+    let claims = json!({
+        "user_id": auth.user_id,
+        "role": auth.role.as_str(),
+        "permissions": auth.permissions,
+        "exp": now_secs() + token_ttl_secs(auth),
+    });
+    let payload = base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD);
+    let signature = sign(&payload, secret);
+
+    format!("{}.{}", payload, signature)
+}
+
+// Token of a service calling another one, with the service-only `permissions` it needs.
+pub fn service_token(secret: &str, permissions: &[&str]) -> String {
+    let permissions: Vec<String> = permissions.iter().map(|p| p.to_string()).collect();
+    issue_token(
+        &AuthContext::new(SERVICE_USER_ID, Role::Staff, &permissions),
+        secret,
+    )
+}
+
+pub fn verify_token(token: &str, secret: &str) -> Result<AuthContext, LocalError> {
+    let (payload, signature) = token
+        .split_once('.')
+        .ok_or(LocalError::UnauthenticatedUser)?;
+
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| LocalError::UnauthenticatedUser)?;
+    mac.verify_slice(&signature)
+        .map_err(|_| LocalError::UnauthenticatedUser)?;
+
+    let claims: Value = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .ok_or(LocalError::UnauthenticatedUser)?;
+
+    let exp = claims.get("exp").and_then(|v| v.as_u64()).unwrap_or(0);
+    if exp < now_secs() {
+        return Err(LocalError::UnauthenticatedUser);
+    }
+
+    let user_id = claims
+        .get("user_id")
+        .and_then(|v| v.as_i64())
+        .ok_or(LocalError::UnauthenticatedUser)?;
+
+    let role = claims
+        .get("role")
+        .and_then(|v| v.as_str())
+        .and_then(Role::parse)
+        .ok_or(LocalError::UnauthenticatedUser)?;
+
+    let permissions: Vec<String> = claims
+        .get("permissions")
+        .and_then(|v| v.as_array())
+        .map(|permissions| {
+            permissions
+                .iter()
+                .filter_map(|p| p.as_str())
+                .map(|p| p.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(AuthContext {
+        user_id: user_id as i32,
+        role,
+        permissions,
+    })
+}
+
+// Reads `Authorization: Bearer <token>` from the request.
+pub fn authenticate(parts: &Parts, secret: &str) -> Result<AuthContext, LocalError> {
+    let token = parts
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(LocalError::UnauthenticatedUser)?;
+
+    verify_token(token.trim(), secret)
+}
+
+// Like `authenticate`, but also requires the caller to hold `permission`.
+pub fn authorize(parts: &Parts, secret: &str, permission: &str) -> Result<AuthContext, LocalError> {
+    let auth = authenticate(parts, secret)?;
+    if !auth.has_permission(permission) {
+        return Err(LocalError::Forbidden);
+    }
+
+    Ok(auth)
+}
+
+// Random url-safe token for links sent to users (email verification, password reset).
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// Only the hash of a random token is stored, so a leaked table can't be used to act as users.
+pub fn hash_token(token: &str) -> String {
+    base64::encode_config(Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+pub fn check_secret(secret: &str) -> Result<(), LocalError> {
+    if secret.len() < MIN_SECRET_LEN || secret.starts_with(PLACEHOLDER_SECRET_PREFIX) {
+        return Err(LocalError::WrongParameters);
+    }
+
+    Ok(())
+}
+
+// Startup check of the secrets a service reads: `auth.secret`, the API keys it sends to the
+// other services and the ones it accepts in `rate_limit_api_keys`. Names the first setting
+// that fails `check_secret`.
+pub fn check_secrets(settings: &Settings) -> Result<(), String> {
+    let secret = settings.try_get("auth", "secret").unwrap_or_default();
+    if check_secret(&secret).is_err() {
+        return Err("auth.secret".to_string());
+    }
+
+    for service in ["product_manager", "user_manager", "order_manager"] {
+        let api_key = settings.try_get(service, "api_key").unwrap_or_default();
+        if !api_key.is_empty() && check_secret(&api_key).is_err() {
+            return Err(format!("{}.api_key", service));
+        }
+    }
+
+    let accepted = settings.section("rate_limit_api_keys");
+    for (api_key, client) in accepted.into_iter().flatten() {
+        if check_secret(api_key).is_err() {
+            return Err(format!("the rate_limit_api_keys key of {}", client));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_secret_refuses_placeholders_and_short_secrets() {
+        assert!(check_secret("change-me-shared-auth-secret").is_err());
+        assert!(check_secret("change-me-but-long-enough-to-pass-the-length-check").is_err());
+        assert!(check_secret("0123456789abcdef0123456789abcde").is_err());
+        assert!(check_secret("").is_err());
+        assert!(check_secret("0123456789abcdef0123456789abcdef").is_ok());
+    }
+}
@@ -33,3 +33,14 @@ impl<T> ToError<T> for Result<T, DbErr> {
         }
     }
 }
+
+// Name of the violated unique constraint (e.g. `account_email_key`), if `err` is one.
+pub fn unique_violation(err: &DbErr) -> Option<&str> {
+    match err {
+        DbErr::Exec(e) | DbErr::Query(e) => {
+            let (_, rest) = e.split_once("violates unique constraint \"")?;
+            rest.split('"').next()
+        }
+        _ => None,
+    }
+}
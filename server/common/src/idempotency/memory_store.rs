@@ -0,0 +1,57 @@
+use crate::idempotency::{IdempotencyStore, Record};
+use crate::utils::LocalError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MAX_RECORDS: usize = 100_000;
+
+// Records local to this process.
+#[derive(Default)]
+pub struct MemoryIdempotencyStore {
+    // key -> (record, expires at)
+    records: Mutex<HashMap<String, (Record, Instant)>>,
+}
+
+impl IdempotencyStore for MemoryIdempotencyStore {
+    fn claim(
+        &self,
+        key: &str,
+        record: &Record,
+        ttl: Duration,
+    ) -> Result<Option<Record>, LocalError> {
+        let now = Instant::now();
+        let mut records = self
+            .records
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        if records.len() >= MAX_RECORDS {
+            records.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+
+        match records.get(key) {
+            Some((existing, expires_at)) if *expires_at > now => Ok(Some(existing.clone())),
+            _ => {
+                records.insert(key.to_string(), (record.clone(), now + ttl));
+                Ok(None)
+            }
+        }
+    }
+
+    fn save(&self, key: &str, record: &Record, ttl: Duration) -> Result<(), LocalError> {
+        self.records
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?
+            .insert(key.to_string(), (record.clone(), Instant::now() + ttl));
+        Ok(())
+    }
+
+    fn release(&self, key: &str) -> Result<(), LocalError> {
+        self.records
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?
+            .remove(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use crate::auth::verify_token;
+use crate::idempotency::memory_store::MemoryIdempotencyStore;
+use crate::idempotency::redis_store::RedisIdempotencyStore;
+use crate::request_response_utils::create_response;
+use crate::settings::Settings;
+use crate::utils::LocalError;
+use http::header::{HeaderName, AUTHORIZATION};
+use http::request::Parts;
+use http::{HeaderValue, Method, StatusCode};
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+pub mod memory_store;
+pub mod redis_store;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    // base64, bodies aren't necessarily utf-8
+    pub body: String,
+}
+
+// `response` is None while the first request is still being handled.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub fingerprint: String,
+    pub response: Option<StoredResponse>,
+}
+
+pub trait IdempotencyStore: Send + Sync {
+    // Stores `record` under `key` for `ttl` unless there already is one, which is returned instead.
+    fn claim(
+        &self,
+        key: &str,
+        record: &Record,
+        ttl: Duration,
+    ) -> Result<Option<Record>, LocalError>;
+    fn save(&self, key: &str, record: &Record, ttl: Duration) -> Result<(), LocalError>;
+    fn release(&self, key: &str) -> Result<(), LocalError>;
+}
+
+// A request that holds its idempotency key until its response is saved.
+pub struct Claim {
+    key: String,
+    fingerprint: String,
+}
+
+pub enum Begin {
+    // Handle the request; with a claim, its response must be passed to `finish`.
+    Handle(Option<Claim>),
+    // Send this response right away: a replay or a conflict.
+    Respond(Result<Response<Body>, hyper::Error>),
+}
+
+// Replays responses of mutating requests sent again with the same `Idempotency-Key`.
+pub struct Idempotency {
+    store: Box<dyn IdempotencyStore>,
+    ttl: Duration,
+    lock_ttl: Duration,
+    auth_secret: Option<String>,
+}
+
+fn is_mutating(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    )
+}
+
+fn fingerprint(parts: &Parts, body: &Option<Value>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(" ");
+    hasher.update(parts.uri.to_string());
+    hasher.update("\n");
+    if let Some(body) = body {
+        hasher.update(body.to_string());
+    }
+    base64::encode_config(hasher.finalize(), base64::URL_SAFE_NO_PAD)
+}
+
+fn conflict(error: LocalError) -> Result<Response<Body>, hyper::Error> {
+    create_response(StatusCode::CONFLICT, error.to_string())
+}
+
+fn replay(stored: &StoredResponse) -> Result<Response<Body>, hyper::Error> {
+    let status = StatusCode::from_u16(stored.status);
+    let body = base64::decode(&stored.body);
+    if status.is_err() || body.is_err() {
+        return create_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            LocalError::OperationFailed.to_string(),
+        );
+    }
+
+    let mut response = Response::new(Body::from(body.ok().unwrap()));
+    *response.status_mut() = status.ok().unwrap();
+    let headers = response.headers_mut();
+    for (name, value) in &stored.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    headers.insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+
+    Ok(response)
+}
+
+impl Idempotency {
+    // Reads the `idempotency` section: `store` ("memory" or "redis"), `redis_uri`,
+    // `ttl_secs` for saved responses and `lock_ttl_secs` for requests in progress.
+    pub fn init(settings: &Settings) -> Result<Idempotency, LocalError> {
+        let store: Box<dyn IdempotencyStore> = match settings.get("idempotency", "store").as_str() {
+            "memory" => Box::new(MemoryIdempotencyStore::default()),
+            "redis" => Box::new(RedisIdempotencyStore::init(
+                &settings.get("idempotency", "redis_uri"),
+            )?),
+            _ => return Err(LocalError::WrongParameters),
+        };
+
+        let secs = |key: &str| -> Result<Duration, LocalError> {
+            settings
+                .get("idempotency", key)
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| LocalError::WrongParameters)
+        };
+
+        Ok(Idempotency {
+            store,
+            ttl: secs("ttl_secs")?,
+            lock_ttl: secs("lock_ttl_secs")?,
+            auth_secret: settings.try_get("auth", "secret"),
+        })
+    }
+
+    // Keys are scoped to the route and the authenticated user, so clients can't
+    // replay each other's responses.
+    fn store_key(&self, parts: &Parts, key: &str) -> String {
+        let user_id = self.auth_secret.as_ref().and_then(|secret| {
+            let token = parts
+                .headers
+                .get(AUTHORIZATION)?
+                .to_str()
+                .ok()?
+                .strip_prefix("Bearer ")?;
+            verify_token(token.trim(), secret).ok()
+        });
+        let caller = user_id
+            .map(|auth| format!("user:{}", auth.user_id))
+            .unwrap_or_else(|| "anonymous".to_string());
+
+        format!(
+            "idempotency:{} {}:{}:{}",
+            parts.method,
+            parts.uri.path(),
+            caller,
+            key
+        )
+    }
+
+    // Called before routing, requests without a key are handled as usual.
+    pub fn begin(&self, parts: &Parts, body: &Option<Value>) -> Begin {
+        if !is_mutating(&parts.method) {
+            return Begin::Handle(None);
+        }
+
+        let key = match parts.headers.get(IDEMPOTENCY_KEY_HEADER) {
+            None => return Begin::Handle(None),
+            Some(key) => key.to_str().unwrap_or_default().trim(),
+        };
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return Begin::Respond(create_response(
+                StatusCode::BAD_REQUEST,
+                LocalError::WrongParameters.to_string(),
+            ));
+        }
+
+        let claim = Claim {
+            key: self.store_key(parts, key),
+            fingerprint: fingerprint(parts, body),
+        };
+        let record = Record {
+            fingerprint: claim.fingerprint.clone(),
+            response: None,
+        };
+
+        // without the store there's no way to tell a retry apart, so don't risk running it twice
+        let existing = match self.store.claim(&claim.key, &record, self.lock_ttl) {
+            Ok(existing) => existing,
+            Err(e) => {
+                println!("Error when claiming idempotency key {}", claim.key);
+                return Begin::Respond(create_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    e.to_string(),
+                ));
+            }
+        };
+
+        match existing {
+            None => Begin::Handle(Some(claim)),
+            Some(existing) if existing.fingerprint != claim.fingerprint => {
+                Begin::Respond(conflict(LocalError::IdempotencyKeyReused))
+            }
+            Some(Record { response: None, .. }) => {
+                Begin::Respond(conflict(LocalError::RequestInProgress))
+            }
+            Some(Record {
+                response: Some(stored),
+                ..
+            }) => Begin::Respond(replay(&stored)),
+        }
+    }
+
+    // Saves the response for replays. Server errors release the key instead, so the
+    // request can be retried.
+    pub async fn finish(
+        &self,
+        claim: Option<Claim>,
+        response: Result<Response<Body>, hyper::Error>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let claim = match claim {
+            None => return response,
+            Some(claim) => claim,
+        };
+
+        let response = match response {
+            Ok(response) if !response.status().is_server_error() => response,
+            response => {
+                let _ = self.store.release(&claim.key);
+                return response;
+            }
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                let _ = self.store.release(&claim.key);
+                return Err(e);
+            }
+        };
+
+        let record = Record {
+            fingerprint: claim.fingerprint,
+            response: Some(StoredResponse {
+                status: parts.status.as_u16(),
+                headers: parts
+                    .headers
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        Some((name.to_string(), value.to_str().ok()?.to_string()))
+                    })
+                    .collect(),
+                body: base64::encode(&body),
+            }),
+        };
+        if self.store.save(&claim.key, &record, self.ttl).is_err() {
+            println!(
+                "Error when saving response for idempotency key {}",
+                claim.key
+            );
+            let _ = self.store.release(&claim.key);
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
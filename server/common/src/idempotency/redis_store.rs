@@ -0,0 +1,79 @@
+use crate::idempotency::{IdempotencyStore, Record};
+use crate::utils::LocalError;
+use redis::{Client, Commands, Connection};
+use std::time::Duration;
+
+// Records shared between replicas.
+pub struct RedisIdempotencyStore {
+    redis_client: Client,
+}
+
+impl RedisIdempotencyStore {
+    pub fn init(redis_uri: &str) -> Result<RedisIdempotencyStore, LocalError> {
+        let client = Client::open(redis_uri).map_err(|_| LocalError::OperationFailed)?;
+
+        Ok(RedisIdempotencyStore {
+            redis_client: client,
+        })
+    }
+
+    fn connection(&self) -> Result<Connection, LocalError> {
+        self.redis_client
+            .get_connection()
+            .map_err(|_| LocalError::OperationFailed)
+    }
+}
+
+impl IdempotencyStore for RedisIdempotencyStore {
+    fn claim(
+        &self,
+        key: &str,
+        record: &Record,
+        ttl: Duration,
+    ) -> Result<Option<Record>, LocalError> {
+        let mut conn = self.connection()?;
+        let value = serde_json::to_string(record).map_err(|_| LocalError::OperationFailed)?;
+
+        // the existing record may expire between SET NX and GET, so try again once
+        for _ in 0..2 {
+            let claimed: Option<String> = redis::cmd("SET")
+                .arg(key)
+                .arg(&value)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .query(&mut conn)
+                .map_err(|_| LocalError::OperationFailed)?;
+            if claimed.is_some() {
+                return Ok(None);
+            }
+
+            let existing: Option<String> =
+                conn.get(key).map_err(|_| LocalError::OperationFailed)?;
+            if let Some(existing) = existing {
+                return serde_json::from_str(&existing)
+                    .map(Some)
+                    .map_err(|_| LocalError::OperationFailed);
+            }
+        }
+
+        Err(LocalError::OperationFailed)
+    }
+
+    fn save(&self, key: &str, record: &Record, ttl: Duration) -> Result<(), LocalError> {
+        let value = serde_json::to_string(record).map_err(|_| LocalError::OperationFailed)?;
+        redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query(&mut self.connection()?)
+            .map_err(|_| LocalError::OperationFailed)
+    }
+
+    fn release(&self, key: &str) -> Result<(), LocalError> {
+        self.connection()?
+            .del(key)
+            .map_err(|_| LocalError::OperationFailed)
+    }
+}
@@ -1,4 +1,9 @@
+pub mod auth;
 pub mod db_utils;
+pub mod idempotency;
+pub mod mailer;
+pub mod money;
+pub mod rate_limit;
 pub mod request_response_utils;
 pub mod settings;
 pub mod utils;
@@ -0,0 +1,39 @@
+use crate::mailer::{Mail, Mailer};
+use crate::utils::LocalError;
+use chrono::Utc;
+use std::fs::OpenOptions;
+use std::io::Write;
+
+// Appends mails to `path`, or prints them when `path` is empty.
+pub struct FileMailer {
+    pub from: String,
+    pub path: String,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, mail: &Mail) -> Result<(), LocalError> {
+        let record = format!(
+            "Date: {}\nFrom: {}\nTo: {}\nSubject: {}\n\n{}\n\n",
+            Utc::now(),
+            self.from,
+            mail.to,
+            mail.subject,
+            mail.body
+        );
+
+        if self.path.is_empty() {
+            println!("{}", record);
+            return Ok(());
+        }
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(record.as_bytes()))
+            .map_err(|e| {
+                println!("Error when writing mail: {}", e);
+                LocalError::OperationFailed
+            })
+    }
+}
@@ -0,0 +1,45 @@
+use crate::mailer::file_mailer::FileMailer;
+use crate::mailer::smtp_mailer::SmtpMailer;
+use crate::settings::Settings;
+use crate::utils::LocalError;
+
+pub mod file_mailer;
+pub mod smtp_mailer;
+
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Sending is blocking; callers run it off the async workers.
+pub trait Mailer: Send + Sync {
+    fn send(&self, mail: &Mail) -> Result<(), LocalError>;
+}
+
+// `mailer.kind` selects the implementation: "smtp", or "file" for local and test use.
+pub fn init(settings: &Settings) -> Result<Box<dyn Mailer>, LocalError> {
+    let from = settings.get("mailer", "from");
+
+    match settings.get("mailer", "kind").as_str() {
+        "smtp" => {
+            let port = settings
+                .get("mailer", "smtp_port")
+                .parse()
+                .map_err(|_| LocalError::WrongParameters)?;
+            let mailer = SmtpMailer::init(
+                from,
+                &settings.get("mailer", "smtp_host"),
+                port,
+                settings.get("mailer", "smtp_username"),
+                settings.get("mailer", "smtp_password"),
+            )?;
+            Ok(Box::new(mailer))
+        }
+        "file" => Ok(Box::new(FileMailer {
+            from,
+            path: settings.get("mailer", "file_path"),
+        })),
+        _ => Err(LocalError::WrongParameters),
+    }
+}
@@ -0,0 +1,49 @@
+use crate::mailer::{Mail, Mailer};
+use crate::utils::LocalError;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+pub struct SmtpMailer {
+    from: String,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    pub fn init(
+        from: String,
+        host: &str,
+        port: u16,
+        username: String,
+        password: String,
+    ) -> Result<SmtpMailer, LocalError> {
+        let transport = SmtpTransport::starttls_relay(host)
+            .map_err(|e| {
+                println!("Error when initializing SMTP transport: {}", e);
+                LocalError::OperationFailed
+            })?
+            .port(port)
+            .credentials(Credentials::new(username, password))
+            .build();
+
+        Ok(SmtpMailer { from, transport })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, mail: &Mail) -> Result<(), LocalError> {
+        let from = self.from.parse().map_err(|_| LocalError::WrongParameters)?;
+        let to = mail.to.parse().map_err(|_| LocalError::InvalidEmail)?;
+
+        let message = Message::builder()
+            .from(from)
+            .to(to)
+            .subject(mail.subject.clone())
+            .body(mail.body.clone())
+            .map_err(|_| LocalError::WrongParameters)?;
+
+        self.transport.send(&message).map(|_| ()).map_err(|e| {
+            println!("Error when sending mail: {}", e);
+            LocalError::OperationFailed
+        })
+    }
+}
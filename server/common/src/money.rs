@@ -0,0 +1,204 @@
+use crate::settings::Settings;
+use crate::utils::LocalError;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// Half a cent rounds up, as on receipts.
+pub fn round(amount: Decimal) -> Decimal {
+    let mut amount = amount.round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero);
+    amount.rescale(2);
+    amount
+}
+
+// An amount given as a JSON number or string, e.g. 19.99 or "19.99", rounded to cents.
+// Numbers are read from their text so that they don't pick up binary fractions.
+pub fn parse_amount(val: &Value) -> Option<Decimal> {
+    let text = match val {
+        Value::String(text) => text.trim().to_string(),
+        Value::Number(number) => number.to_string(),
+        _ => return None,
+    };
+
+    Decimal::from_str(&text)
+        .or_else(|_| Decimal::from_scientific(&text))
+        .ok()
+        .map(round)
+}
+
+// Splits `amount` in proportion to `weights`, in cents. Every share is rounded down and the
+// cents left over go one at a time to the shares that lost the most, so that the shares add
+// up to `amount`. While `amount` is within the total of the weights no share is more than
+// its weight. The first share takes everything when the weights are all zero.
+pub fn allocate(amount: Decimal, weights: &[Decimal]) -> Vec<Decimal> {
+    let mut shares = vec![round(Decimal::ZERO); weights.len()];
+    let base: Decimal = weights.iter().sum();
+    let amount = round(amount);
+    if shares.is_empty() {
+        return shares;
+    }
+    if base.is_zero() {
+        shares[0] = amount;
+        return shares;
+    }
+
+    let mut capped = amount.abs() <= base.abs();
+    let sign = if amount.is_sign_negative() {
+        -Decimal::ONE
+    } else {
+        Decimal::ONE
+    };
+    let cent = sign * Decimal::new(1, 2);
+    let mut exact = Vec::with_capacity(weights.len());
+    for (share, weight) in shares.iter_mut().zip(weights.iter()) {
+        let quota = amount * weight / base;
+        *share = quota.round_dp_with_strategy(2, RoundingStrategy::ToZero);
+        if capped && share.abs() > weight.abs() {
+            *share = weight.abs() * sign;
+        }
+        share.rescale(2);
+        exact.push(quota);
+    }
+
+    // largest remainders first, then in order
+    let mut order: Vec<usize> = (0..shares.len()).collect();
+    order.sort_by(|&a, &b| {
+        let left = |i: usize| (exact[i] - shares[i]).abs();
+        left(b).cmp(&left(a)).then(a.cmp(&b))
+    });
+    let mut left = amount - shares.iter().sum::<Decimal>();
+    while !left.is_zero() && left.is_sign_negative() == amount.is_sign_negative() {
+        let before = left;
+        for &i in order.iter() {
+            if left.is_zero() {
+                break;
+            }
+            if capped && (shares[i] + cent).abs() > weights[i].abs() {
+                continue;
+            }
+            shares[i] += cent;
+            left -= cent;
+        }
+        // weights of less than a cent can't take what is left
+        if left == before {
+            capped = false;
+        }
+    }
+    shares
+}
+
+// ISO 4217 codes are three uppercase letters.
+pub fn is_currency_code(code: &str) -> bool {
+    code.len() == 3 && code.chars().all(|c| c.is_ascii_uppercase())
+}
+
+// Rates from `money.currency`, the currency prices are kept in, to the currencies amounts
+// can be shown in. Read from the `exchange_rates` section, e.g. `USD: "1.08"` for 1.08 US
+// dollars to one unit of the base currency.
+pub struct ExchangeRates {
+    pub base: String,
+    rates: HashMap<String, Decimal>,
+}
+
+impl ExchangeRates {
+    pub fn init(settings: &Settings) -> Result<ExchangeRates, LocalError> {
+        let base = settings.get("money", "currency");
+        if !is_currency_code(&base) {
+            return Err(LocalError::UnknownCurrency);
+        }
+
+        let mut rates = HashMap::new();
+        for (code, rate) in settings.section("exchange_rates").into_iter().flatten() {
+            let rate = Decimal::from_str(rate.trim())
+                .ok()
+                .filter(|r| *r > Decimal::ZERO);
+            if !is_currency_code(code) || rate.is_none() {
+                return Err(LocalError::UnknownCurrency);
+            }
+            rates.insert(code.clone(), rate.unwrap());
+        }
+        rates.insert(base.clone(), Decimal::ONE);
+
+        Ok(ExchangeRates { base, rates })
+    }
+
+    pub fn rate(&self, currency: &str) -> Result<Decimal, LocalError> {
+        self.rates
+            .get(currency)
+            .copied()
+            .ok_or(LocalError::UnknownCurrency)
+    }
+
+    // An amount of the base currency in `currency`.
+    pub fn convert(&self, amount: Decimal, currency: &str) -> Result<Decimal, LocalError> {
+        Ok(round(amount * self.rate(currency)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(amount: &str) -> Decimal {
+        Decimal::from_str(amount).unwrap()
+    }
+
+    fn decs(amounts: &[&str]) -> Vec<Decimal> {
+        amounts.iter().map(|a| dec(a)).collect()
+    }
+
+    #[test]
+    fn allocate_gives_the_cents_left_to_the_first_equal_shares() {
+        let shares = allocate(dec("10.00"), &decs(&["1", "1", "1"]));
+        assert_eq!(shares, decs(&["3.34", "3.33", "3.33"]));
+
+        let shares = allocate(dec("0.05"), &decs(&["1", "1"]));
+        assert_eq!(shares, decs(&["0.03", "0.02"]));
+    }
+
+    #[test]
+    fn allocate_follows_the_weights() {
+        let shares = allocate(dec("4.99"), &decs(&["30.00", "10.00", "0"]));
+        assert_eq!(shares, decs(&["3.74", "1.25", "0.00"]));
+        assert_eq!(shares.iter().sum::<Decimal>(), dec("4.99"));
+    }
+
+    #[test]
+    fn allocate_keeps_shares_within_their_weights() {
+        // every share is 0.095 exactly, which rounds up to 0.10 on its own
+        let weights = decs(&["0.10", "0.10", "0.10", "0.10", "0.10", "0.10"]);
+        let shares = allocate(dec("0.57"), &weights);
+        assert_eq!(
+            shares,
+            decs(&["0.10", "0.10", "0.10", "0.09", "0.09", "0.09"])
+        );
+        assert_eq!(shares.iter().sum::<Decimal>(), dec("0.57"));
+
+        // each share is 0.025, rounding those up would leave the last one at -0.02
+        let weights = vec![dec("0.03"); 10];
+        let shares = allocate(dec("0.25"), &weights);
+        assert_eq!(shares.iter().sum::<Decimal>(), dec("0.25"));
+        for (share, weight) in shares.iter().zip(weights.iter()) {
+            assert!(!share.is_sign_negative() && share <= weight);
+        }
+    }
+
+    #[test]
+    fn allocate_gives_the_cents_left_to_the_largest_remainders() {
+        let shares = allocate(dec("1.00"), &decs(&["1", "2", "3", "4", "5"]));
+        // 0.0666.., 0.1333.., 0.2, 0.2666.., 0.3333..
+        assert_eq!(shares, decs(&["0.07", "0.13", "0.20", "0.27", "0.33"]));
+    }
+
+    #[test]
+    fn allocate_with_zero_weights_gives_all_to_the_first() {
+        let shares = allocate(dec("7.5"), &decs(&["0", "0"]));
+        assert_eq!(shares, decs(&["7.50", "0"]));
+    }
+
+    #[test]
+    fn allocate_without_weights_gives_nothing() {
+        assert!(allocate(dec("7.50"), &[]).is_empty());
+    }
+}
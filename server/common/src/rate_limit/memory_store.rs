@@ -0,0 +1,48 @@
+use crate::rate_limit::BucketStore;
+use crate::utils::LocalError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const MAX_BUCKETS: usize = 100_000;
+
+// Buckets local to this process.
+#[derive(Default)]
+pub struct MemoryBucketStore {
+    // key -> (tokens, last refill)
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl BucketStore for MemoryBucketStore {
+    fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        refill_per_sec: f64,
+    ) -> Result<(bool, f64), LocalError> {
+        let now = Instant::now();
+        let mut buckets = self
+            .buckets
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        // buckets that would be full again carry no state worth keeping
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, (tokens, last)| {
+                *tokens + now.duration_since(*last).as_secs_f64() * refill_per_sec < capacity
+            });
+        }
+
+        let (tokens, last) = buckets.entry(key.to_string()).or_insert((capacity, now));
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * refill_per_sec).min(capacity);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok((true, *tokens))
+        } else {
+            Ok((false, *tokens))
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::auth::verify_token;
+use crate::rate_limit::memory_store::MemoryBucketStore;
+use crate::rate_limit::redis_store::RedisBucketStore;
+use crate::request_response_utils::{get_client_ip, response_too_many_requests, trusted_proxies};
+use crate::settings::Settings;
+use crate::utils::LocalError;
+use http::header::AUTHORIZATION;
+use http::request::Parts;
+use http::{HeaderValue, Method};
+use hyper::{Body, Response};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+pub mod memory_store;
+pub mod redis_store;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+pub trait BucketStore: Send + Sync {
+    // Refills the bucket for the time elapsed since its last use, then takes one token
+    // if there is one. Returns whether a token was taken and the tokens left.
+    fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        refill_per_sec: f64,
+    ) -> Result<(bool, f64), LocalError>;
+}
+
+// `capacity` requests per `period_secs`, refilled continuously.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rule {
+    pub capacity: u64,
+    pub period_secs: u64,
+}
+
+impl Rule {
+    // "30/60" is 30 requests per 60 seconds
+    pub fn parse(rule: &str) -> Option<Rule> {
+        let (capacity, period_secs) = rule.split_once('/')?;
+        let rule = Rule {
+            capacity: capacity.trim().parse().ok()?,
+            period_secs: period_secs.trim().parse().ok()?,
+        };
+
+        if rule.capacity == 0 || rule.period_secs == 0 {
+            return None;
+        }
+        Some(rule)
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.period_secs as f64
+    }
+}
+
+pub struct RateLimit {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset_secs: u64,
+    pub retry_after_secs: u64,
+}
+
+impl RateLimit {
+    fn unlimited() -> RateLimit {
+        RateLimit {
+            allowed: true,
+            limit: 0,
+            remaining: 0,
+            reset_secs: 0,
+            retry_after_secs: 0,
+        }
+    }
+
+    // RateLimit-* headers, as in the IETF RateLimit header fields draft.
+    pub fn add_headers(&self, response: &mut Response<Body>) {
+        if self.limit == 0 {
+            return;
+        }
+
+        let headers = response.headers_mut();
+        headers.insert("RateLimit-Limit", HeaderValue::from(self.limit));
+        headers.insert("RateLimit-Remaining", HeaderValue::from(self.remaining));
+        headers.insert("RateLimit-Reset", HeaderValue::from(self.reset_secs));
+    }
+
+    pub fn response(&self) -> Result<Response<Body>, hyper::Error> {
+        let mut response =
+            response_too_many_requests(LocalError::TooManyRequests, self.retry_after_secs)?;
+        self.add_headers(&mut response);
+        Ok(response)
+    }
+}
+
+// Token-bucket limiter keyed by route and caller. Callers are identified by a configured
+// API key, else by the user of a valid bearer token, else by client address. Clients with
+// their own rule (other services, mostly) get one bucket for all routes instead.
+pub struct RateLimiter {
+    store: Box<dyn BucketStore>,
+    default_rule: Option<Rule>,
+    // "METHOD /path" -> rule
+    routes: HashMap<String, Rule>,
+    // API key -> client name
+    api_keys: HashMap<String, String>,
+    // client name -> rule, None when the client isn't limited
+    clients: HashMap<String, Option<Rule>>,
+    auth_secret: Option<String>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimiter {
+    // Reads the `rate_limit` section (`store`, `redis_uri`, `default`), per-route rules from
+    // `rate_limit_routes`, known API keys from `rate_limit_api_keys` and per-client rules
+    // ("none" for no limit) from `rate_limit_clients`.
+    pub fn init(settings: &Settings) -> Result<RateLimiter, LocalError> {
+        let store: Box<dyn BucketStore> = match settings.get("rate_limit", "store").as_str() {
+            "memory" => Box::new(MemoryBucketStore::default()),
+            "redis" => Box::new(RedisBucketStore::init(
+                &settings.get("rate_limit", "redis_uri"),
+            )?),
+            _ => return Err(LocalError::WrongParameters),
+        };
+
+        let default_rule = match settings.get("rate_limit", "default").as_str() {
+            "" | "none" => None,
+            rule => Some(Rule::parse(rule).ok_or(LocalError::WrongParameters)?),
+        };
+
+        let mut routes = HashMap::new();
+        for (route, rule) in settings.section("rate_limit_routes").into_iter().flatten() {
+            let rule = Rule::parse(rule).ok_or(LocalError::WrongParameters)?;
+            routes.insert(route.to_string(), rule);
+        }
+
+        let api_keys = settings
+            .section("rate_limit_api_keys")
+            .map(|keys| keys.clone().into_iter().collect())
+            .unwrap_or_default();
+
+        let mut clients = HashMap::new();
+        for (client, rule) in settings.section("rate_limit_clients").into_iter().flatten() {
+            let rule = match rule.as_str() {
+                "none" => None,
+                rule => Some(Rule::parse(rule).ok_or(LocalError::WrongParameters)?),
+            };
+            clients.insert(client.to_string(), rule);
+        }
+
+        Ok(RateLimiter {
+            store,
+            default_rule,
+            routes,
+            api_keys,
+            clients,
+            auth_secret: settings.try_get("auth", "secret"),
+            trusted_proxies: trusted_proxies(settings)?,
+        })
+    }
+
+    // Name of the client whose API key the request carries.
+    fn client(&self, parts: &Parts) -> Option<&String> {
+        parts
+            .headers
+            .get(API_KEY_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|key| self.api_keys.get(key))
+    }
+
+    fn caller(&self, parts: &Parts, remote_addr: SocketAddr) -> String {
+        if let Some(client) = self.client(parts) {
+            return format!("key:{}", client);
+        }
+
+        let user_id = self.auth_secret.as_ref().and_then(|secret| {
+            let token = parts
+                .headers
+                .get(AUTHORIZATION)?
+                .to_str()
+                .ok()?
+                .strip_prefix("Bearer ")?;
+            verify_token(token.trim(), secret).ok()
+        });
+        if let Some(auth) = user_id {
+            return format!("user:{}", auth.user_id);
+        }
+
+        format!(
+            "ip:{}",
+            get_client_ip(parts, remote_addr, &self.trusted_proxies)
+        )
+    }
+
+    pub fn check(&self, parts: &Parts, remote_addr: SocketAddr) -> RateLimit {
+        // CORS preflights are sent by browsers on their own and shouldn't use up tokens
+        if parts.method == Method::OPTIONS {
+            return RateLimit::unlimited();
+        }
+
+        let route = format!("{} {}", parts.method, parts.uri.path());
+        let client_rule = self
+            .client(parts)
+            .and_then(|client| self.clients.get(client));
+        let (bucket, rule) = match (client_rule, self.routes.get(&route)) {
+            (Some(None), _) => return RateLimit::unlimited(),
+            (Some(Some(rule)), _) => ("client".to_string(), *rule),
+            (None, Some(rule)) => (route, *rule),
+            (None, None) => match self.default_rule {
+                Some(rule) => ("default".to_string(), rule),
+                None => return RateLimit::unlimited(),
+            },
+        };
+
+        let key = format!("ratelimit:{}:{}", bucket, self.caller(parts, remote_addr));
+        let refill_per_sec = rule.refill_per_sec();
+
+        // a broken store shouldn't take the service down with it
+        let (allowed, tokens) = match self.store.take(&key, rule.capacity as f64, refill_per_sec) {
+            Ok(res) => res,
+            Err(_) => {
+                println!("Error when checking rate limit for {}", key);
+                return RateLimit::unlimited();
+            }
+        };
+
+        RateLimit {
+            allowed,
+            limit: rule.capacity,
+            remaining: tokens.floor().max(0.0) as u64,
+            reset_secs: ((rule.capacity as f64 - tokens) / refill_per_sec).ceil() as u64,
+            retry_after_secs: if allowed {
+                0
+            } else {
+                ((1.0 - tokens) / refill_per_sec).ceil().max(1.0) as u64
+            },
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::rate_limit::BucketStore;
+use crate::utils::LocalError;
+use redis::{Client, Script};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Refill and take run in one script so replicas sharing a bucket can't race.
+const TAKE_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or capacity
+local ts = tonumber(state[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) / 1000 * rate)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity / rate * 1000))
+return {allowed, tostring(tokens)}
+"#;
+
+// Buckets shared between replicas.
+pub struct RedisBucketStore {
+    redis_client: Client,
+    script: Script,
+}
+
+impl RedisBucketStore {
+    pub fn init(redis_uri: &str) -> Result<RedisBucketStore, LocalError> {
+        let client = Client::open(redis_uri).map_err(|_| LocalError::OperationFailed)?;
+
+        Ok(RedisBucketStore {
+            redis_client: client,
+            script: Script::new(TAKE_SCRIPT),
+        })
+    }
+}
+
+impl BucketStore for RedisBucketStore {
+    fn take(
+        &self,
+        key: &str,
+        capacity: f64,
+        refill_per_sec: f64,
+    ) -> Result<(bool, f64), LocalError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| LocalError::OperationFailed)?
+            .as_millis() as u64;
+
+        let mut conn = self
+            .redis_client
+            .get_connection()
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        let (allowed, tokens): (i32, String) = self
+            .script
+            .key(key)
+            .arg(capacity)
+            .arg(refill_per_sec)
+            .arg(now)
+            .invoke(&mut conn)
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        Ok((allowed == 1, tokens.parse().unwrap_or(0.0)))
+    }
+}
@@ -1,9 +1,13 @@
+use crate::settings::Settings;
 use crate::utils::LocalError;
-use http::header::{CONTENT_TYPE, LOCATION};
+use http::header::{CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use http::request::Parts;
 use http::{HeaderValue, StatusCode, Uri};
+use hyper::body::HttpBody;
 use hyper::{Body, Response};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 
 pub fn create_response(
     status_code: StatusCode,
@@ -13,7 +17,10 @@ pub fn create_response(
         .header(CONTENT_TYPE, "application/json")
         .header("Access-Control-Allow-Origin", "*")
         .header("Access-Control-Allow-Headers", "*")
-        .header("Access-Control-Allow-Methods", "PUT, GET, OPTIONS")
+        .header(
+            "Access-Control-Allow-Methods",
+            "PUT, GET, POST, DELETE, OPTIONS",
+        )
         .status(status_code)
         .body(Body::from(body))
         .unwrap())
@@ -69,11 +76,83 @@ pub fn response_redirect(addr: String) -> Result<Response<Body>, hyper::Error> {
     create_redirect_response(StatusCode::PERMANENT_REDIRECT, addr)
 }
 
+// 401 for a missing or invalid token, 403 for a missing permission
+pub fn response_auth_error(error: LocalError) -> Result<Response<Body>, hyper::Error> {
+    let status_code = if error == LocalError::Forbidden {
+        StatusCode::FORBIDDEN
+    } else {
+        StatusCode::UNAUTHORIZED
+    };
+    create_response(status_code, error.to_string())
+}
+
+pub fn response_too_many_requests(
+    error: LocalError,
+    retry_after_secs: u64,
+) -> Result<Response<Body>, hyper::Error> {
+    let mut response = create_response(StatusCode::TOO_MANY_REQUESTS, error.to_string())?;
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after_secs));
+    Ok(response)
+}
+
+// Client address. Behind our nginx, which appends the peer address to `X-Forwarded-For`,
+// it is the last entry not added by one of `trusted_proxies`. The header is ignored on
+// connections that don't come from a trusted proxy, since anyone can send it.
+pub fn get_client_ip(parts: &Parts, remote_addr: SocketAddr, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client_ip = remote_addr.ip();
+    if !trusted_proxies.contains(&client_ip) {
+        return client_ip;
+    }
+
+    let forwarded_for = parts
+        .headers
+        .get("X-Forwarded-For")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    for ip in forwarded_for.rsplit(',') {
+        match ip.trim().parse() {
+            Ok(ip) => client_ip = ip,
+            Err(_) => break,
+        }
+        if !trusted_proxies.contains(&client_ip) {
+            break;
+        }
+    }
+    client_ip
+}
+
+// `network.trusted_proxies`: comma-separated addresses of the proxies in front of the service.
+pub fn trusted_proxies(settings: &Settings) -> Result<Vec<IpAddr>, LocalError> {
+    settings
+        .try_get("network", "trusted_proxies")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(|ip| ip.parse().map_err(|_| LocalError::WrongParameters))
+        .collect()
+}
+
 //
 pub fn response_ok() -> Result<Response<Body>, hyper::Error> {
     create_response(StatusCode::OK, String::new())
 }
 
+// The whole body, unless it is longer than `limit` bytes.
+pub async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, LocalError> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| LocalError::WrongParameters)?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(LocalError::PayloadTooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
 pub async fn get_json_from_body(body: Body) -> Option<Value> {
     let body_bytes = hyper::body::to_bytes(body).await.ok()?;
 
@@ -14,4 +14,12 @@ impl Settings {
     pub fn get(&self, section: &str, key: &str) -> String {
         self.btree[section][key].to_string()
     }
+
+    pub fn try_get(&self, section: &str, key: &str) -> Option<String> {
+        self.btree.get(section)?.get(key).cloned()
+    }
+
+    pub fn section(&self, section: &str) -> Option<&BTreeMap<String, String>> {
+        self.btree.get(section)
+    }
 }
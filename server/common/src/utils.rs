@@ -7,6 +7,42 @@ pub enum LocalError {
     OperationFailed,
     UnauthenticatedUser,
     WrongUserOrPassword,
+    InvalidEmail,
+    InvalidPhone,
+    UsernameTaken,
+    EmailTaken,
+    PhoneTaken,
+    InvalidToken,
+    EmailNotVerified,
+    Forbidden,
+    AccountLocked,
+    TooManyRequests,
+    IdempotencyKeyReused,
+    RequestInProgress,
+    ReservationExpired,
+    WarehouseNotEmpty,
+    ItemInStock,
+    UnknownCategory,
+    CategoryExists,
+    CategoryNotEmpty,
+    VariantRequired,
+    VariantExists,
+    SkuTaken,
+    PriceScheduleOverlaps,
+    InvalidCoupon,
+    CouponLimitReached,
+    CouponCodeTaken,
+    UnknownRegion,
+    UnknownCurrency,
+    PaymentDeclined,
+    WrongPaymentStatus,
+    InvalidSignature,
+    WrongOrderStatus,
+    ReturnLimitExceeded,
+    InvalidAddress,
+    ShipmentLimitExceeded,
+    OrderRefTaken,
+    PayloadTooLarge,
 }
 
 impl LocalError {
@@ -19,10 +55,57 @@ impl LocalError {
             LocalError::OperationFailed => "Operation has not been executed".to_string(),
             LocalError::UnauthenticatedUser => "User is not authenticated".to_string(),
             LocalError::WrongUserOrPassword => "Wrong user or password".to_string(),
+            LocalError::InvalidEmail => "Invalid email".to_string(),
+            LocalError::InvalidPhone => "Invalid phone".to_string(),
+            LocalError::UsernameTaken => "Username is already taken".to_string(),
+            LocalError::EmailTaken => "Email is already in use".to_string(),
+            LocalError::PhoneTaken => "Phone is already in use".to_string(),
+            LocalError::InvalidToken => "Invalid or expired token".to_string(),
+            LocalError::EmailNotVerified => "Email is not verified".to_string(),
+            LocalError::Forbidden => "Permission denied".to_string(),
+            LocalError::AccountLocked => "Too many failed attempts, try again later".to_string(),
+            LocalError::TooManyRequests => "Too many requests".to_string(),
+            LocalError::IdempotencyKeyReused => {
+                "Idempotency key was already used for a different request".to_string()
+            }
+            LocalError::RequestInProgress => {
+                "A request with this idempotency key is still in progress".to_string()
+            }
+            LocalError::ReservationExpired => "Reservation has expired".to_string(),
+            LocalError::WarehouseNotEmpty => "Warehouse still holds stock".to_string(),
+            LocalError::ItemInStock => "Item is in stock".to_string(),
+            LocalError::UnknownCategory => "Unknown category".to_string(),
+            LocalError::CategoryExists => "Category already exists".to_string(),
+            LocalError::CategoryNotEmpty => {
+                "Category still has products or subcategories".to_string()
+            }
+            LocalError::VariantRequired => "A variant of the product must be given".to_string(),
+            LocalError::VariantExists => "Variant already exists".to_string(),
+            LocalError::SkuTaken => "SKU is already in use".to_string(),
+            LocalError::PriceScheduleOverlaps => {
+                "Another price change is scheduled for that time".to_string()
+            }
+            LocalError::InvalidCoupon => "Coupon is not valid".to_string(),
+            LocalError::CouponLimitReached => "Coupon can't be used any more".to_string(),
+            LocalError::CouponCodeTaken => "Coupon code is already in use".to_string(),
+            LocalError::UnknownRegion => "No tax rate is set for that region".to_string(),
+            LocalError::UnknownCurrency => "Unknown currency".to_string(),
+            LocalError::PaymentDeclined => "Payment was declined".to_string(),
+            LocalError::WrongPaymentStatus => {
+                "Not possible in the current state of the payment".to_string()
+            }
+            LocalError::InvalidSignature => "Invalid signature".to_string(),
+            LocalError::WrongOrderStatus => "Not possible in the current state of the order".to_string(),
+            LocalError::ReturnLimitExceeded => "More items than can be returned".to_string(),
+            LocalError::InvalidAddress => "Invalid address".to_string(),
+            LocalError::ShipmentLimitExceeded => "More items than are left to ship".to_string(),
+            LocalError::OrderRefTaken => "Order reference is already in use".to_string(),
+            LocalError::PayloadTooLarge => "Request body is too large".to_string(),
         }
     }
 }
 
+// For scores and ratios; amounts of money are rounded with `money::round`.
 pub fn round(x: f64, decimals: u32) -> f64 {
     let y = 10i32.pow(decimals) as f64;
     (x * y).round() / y
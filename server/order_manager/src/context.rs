@@ -1,5 +1,34 @@
 use crate::db::DB;
+use crate::invoices::Invoices;
+use crate::payments::{PaymentProvider, Webhooks};
+use common::idempotency::Idempotency;
+use common::rate_limit::RateLimiter;
+use std::sync::Arc;
 
 pub struct Context {
     pub db: DB,
+    pub product_manager: ProductManagerContext,
+    pub user_manager: UserManagerContext,
+    pub rate_limiter: RateLimiter,
+    pub idempotency: Idempotency,
+    // currency of the orders that don't give one
+    pub currency: String,
+    pub auth_secret: String,
+    pub payment_provider: Arc<dyn PaymentProvider>,
+    pub webhooks: Webhooks,
+    // capture payments right after authorizing them
+    pub auto_capture: bool,
+    pub invoices: Invoices,
+}
+
+pub struct ProductManagerContext {
+    pub uri: String,
+    pub stock_movement_endpoint: String,
+    pub products_endpoint: String,
+}
+
+pub struct UserManagerContext {
+    pub uri: String,
+    pub profile_endpoint: String,
+    pub addresses_endpoint: String,
 }
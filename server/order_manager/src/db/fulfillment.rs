@@ -0,0 +1,198 @@
+use crate::db::postgres::PostgresDB;
+use crate::db::returns::lock_orders;
+use crate::entities::{fulfillment, fulfillment_item, orders};
+use chrono::Utc;
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::sea_query::Expr;
+use sea_orm::{entity::*, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, TransactionTrait};
+use std::collections::HashMap;
+
+pub struct NewFulfillmentItem {
+    pub order_id: i32,
+    // None for all the units of the order left to ship
+    pub quantity: Option<i32>,
+}
+
+impl PostgresDB {
+    // Records a parcel sent for the checkout `order_ref`, with the given units of its orders,
+    // or with everything left to ship when `items` is empty. Orders are shipped once all
+    // their units are.
+    pub async fn add_fulfillment(
+        &self,
+        order_ref: &str,
+        carrier: Option<String>,
+        tracking_number: Option<String>,
+        items: Vec<NewFulfillmentItem>,
+    ) -> Result<(fulfillment::Model, Vec<fulfillment_item::Model>), LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Order)?;
+
+        let order_ids: Vec<i32> = orders::Entity::find()
+            .filter(orders::Column::OrderRef.eq(order_ref))
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Order)?
+            .iter()
+            .map(|o| o.order_id)
+            .collect();
+        if order_ids.is_empty() {
+            return Err(LocalError::IdNotFound);
+        }
+        let orders = lock_orders(&txn, &order_ids).await?;
+        let mut left: HashMap<i32, i32> = orders
+            .iter()
+            .filter(|o| !o.is_cancelled())
+            .map(|o| (o.order_id, o.quantity - o.shipped_quantity))
+            .collect();
+
+        let items = if items.is_empty() {
+            let mut order_ids: Vec<i32> = left.keys().copied().collect();
+            order_ids.sort_unstable();
+            order_ids
+                .into_iter()
+                .map(|order_id| NewFulfillmentItem {
+                    order_id,
+                    quantity: None,
+                })
+                .collect()
+        } else {
+            items
+        };
+        let mut shipped: Vec<(i32, i32)> = Vec::new();
+        for item in items {
+            if !order_ids.contains(&item.order_id) {
+                return Err(LocalError::WrongParameters);
+            }
+            let left = left
+                .get_mut(&item.order_id)
+                .ok_or(LocalError::WrongOrderStatus)?;
+            let quantity = item.quantity.unwrap_or(*left);
+            if item.quantity.is_some() && quantity <= 0 {
+                return Err(LocalError::WrongParameters);
+            }
+            if quantity > *left {
+                return Err(LocalError::ShipmentLimitExceeded);
+            }
+            *left -= quantity;
+            if quantity > 0 {
+                shipped.push((item.order_id, quantity));
+            }
+        }
+        if shipped.is_empty() {
+            return Err(LocalError::WrongOrderStatus);
+        }
+
+        let now = Utc::now().naive_utc();
+        for order in orders {
+            let quantity: i32 = shipped
+                .iter()
+                .filter(|(order_id, _)| *order_id == order.order_id)
+                .map(|(_, quantity)| quantity)
+                .sum();
+            if quantity == 0 {
+                continue;
+            }
+            let shipped_quantity = order.shipped_quantity + quantity;
+            let done = shipped_quantity == order.quantity;
+            let mut order: orders::ActiveModel = order.into();
+            order.shipped_quantity = Set(shipped_quantity);
+            if done {
+                order.status = Set(orders::SHIPPED.to_string());
+                order.shipped_at = Set(Some(now));
+            } else {
+                order.status = Set(orders::PARTIALLY_SHIPPED.to_string());
+            }
+            order.update(&txn).await.to_local_error(RecordType::Order)?;
+        }
+
+        let fulfillment = fulfillment::ActiveModel {
+            fulfillment_id: NotSet,
+            order_ref: Set(order_ref.to_string()),
+            carrier: Set(carrier),
+            tracking_number: Set(tracking_number),
+            status: Set(fulfillment::IN_TRANSIT.to_string()),
+            shipped_at: Set(now),
+            delivered_at: Set(None),
+        }
+        .insert(&txn)
+        .await
+        .to_local_error(RecordType::Order)?;
+
+        let mut added = Vec::new();
+        for (order_id, quantity) in shipped {
+            let item = fulfillment_item::ActiveModel {
+                fulfillment_item_id: NotSet,
+                fulfillment_id: Set(fulfillment.fulfillment_id),
+                order_id: Set(order_id),
+                quantity: Set(quantity),
+            }
+            .insert(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+            added.push(item);
+        }
+
+        txn.commit().await.to_local_error(RecordType::Order)?;
+        Ok((fulfillment, added))
+    }
+
+    pub async fn get_fulfillment(
+        &self,
+        fulfillment_id: i32,
+    ) -> Result<(fulfillment::Model, Vec<fulfillment_item::Model>), LocalError> {
+        let fulfillment = fulfillment::Entity::find_by_id(fulfillment_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?
+            .ok_or(LocalError::IdNotFound)?;
+
+        let items = fulfillment_item::Entity::find()
+            .filter(fulfillment_item::Column::FulfillmentId.eq(fulfillment_id))
+            .order_by_asc(fulfillment_item::Column::FulfillmentItemId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+        Ok((fulfillment, items))
+    }
+
+    // The checkout's parcels, oldest first.
+    pub async fn get_fulfillments(
+        &self,
+        order_ref: &str,
+    ) -> Result<Vec<(fulfillment::Model, Vec<fulfillment_item::Model>)>, LocalError> {
+        fulfillment::Entity::find()
+            .filter(fulfillment::Column::OrderRef.eq(order_ref))
+            .order_by_asc(fulfillment::Column::FulfillmentId)
+            .find_with_related(fulfillment_item::Entity)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+
+    // Marks the parcel delivered, only the first time.
+    pub async fn deliver_fulfillment(
+        &self,
+        fulfillment_id: i32,
+    ) -> Result<fulfillment::Model, LocalError> {
+        let res = fulfillment::Entity::update_many()
+            .col_expr(
+                fulfillment::Column::Status,
+                Expr::value(fulfillment::DELIVERED),
+            )
+            .col_expr(
+                fulfillment::Column::DeliveredAt,
+                Expr::value(Utc::now().naive_utc()),
+            )
+            .filter(fulfillment::Column::FulfillmentId.eq(fulfillment_id))
+            .filter(fulfillment::Column::Status.eq(fulfillment::IN_TRANSIT))
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+
+        let fulfillment = self.get_fulfillment(fulfillment_id).await?.0;
+        if res.rows_affected == 0 {
+            return Err(LocalError::WrongOrderStatus);
+        }
+        Ok(fulfillment)
+    }
+}
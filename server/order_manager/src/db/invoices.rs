@@ -0,0 +1,93 @@
+use crate::db::postgres::PostgresDB;
+use crate::entities::{invoice, invoice_sequence};
+use chrono::{NaiveDateTime, Utc};
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::{entity::*, ColumnTrait, EntityTrait, QueryFilter, QuerySelect, TransactionTrait};
+use serde_json::Value;
+
+const INVOICE_SEQUENCE: &str = "invoice";
+
+// An invoice rendered with its number and date.
+pub struct NewInvoice {
+    pub user_id: i32,
+    pub currency: String,
+    pub total: Decimal,
+    pub data: Value,
+    pub html: String,
+    pub pdf: Vec<u8>,
+}
+
+impl PostgresDB {
+    pub async fn get_invoice(&self, order_ref: &str) -> Result<Option<invoice::Model>, LocalError> {
+        invoice::Entity::find()
+            .filter(invoice::Column::OrderRef.eq(order_ref))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+
+    // Issues the invoice of the checkout, numbered `number_prefix` and the next number of the
+    // sequence, unless it already has one. Invoices are issued one at a time, so numbers are
+    // given in order and only to invoices that are stored.
+    pub async fn add_invoice<F>(
+        &self,
+        order_ref: &str,
+        number_prefix: &str,
+        render: F,
+    ) -> Result<invoice::Model, LocalError>
+    where
+        F: FnOnce(&str, NaiveDateTime) -> NewInvoice,
+    {
+        let txn = self.db.begin().await.to_local_error(RecordType::Order)?;
+
+        let sequence = invoice_sequence::Entity::find_by_id(INVOICE_SEQUENCE.to_string())
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Order)?
+            .ok_or(LocalError::OperationFailed)?;
+
+        let issued = invoice::Entity::find()
+            .filter(invoice::Column::OrderRef.eq(order_ref))
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+        if let Some(issued) = issued {
+            return Ok(issued);
+        }
+
+        let number = sequence.last_value + 1;
+        let invoice_number = format!("{}{:06}", number_prefix, number);
+        let issued_at = Utc::now().naive_utc();
+        let new_invoice = render(&invoice_number, issued_at);
+
+        let mut sequence: invoice_sequence::ActiveModel = sequence.into();
+        sequence.last_value = Set(number);
+        sequence
+            .update(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+
+        let invoice = invoice::ActiveModel {
+            invoice_id: NotSet,
+            invoice_number: Set(invoice_number),
+            sequence: Set(number),
+            order_ref: Set(order_ref.to_string()),
+            user_id: Set(new_invoice.user_id),
+            currency: Set(new_invoice.currency),
+            total: Set(new_invoice.total),
+            data: Set(new_invoice.data),
+            html: Set(new_invoice.html),
+            pdf: Set(new_invoice.pdf),
+            issued_at: Set(issued_at),
+        }
+        .insert(&txn)
+        .await
+        .to_local_error(RecordType::Order)?;
+
+        txn.commit().await.to_local_error(RecordType::Order)?;
+        Ok(invoice)
+    }
+}
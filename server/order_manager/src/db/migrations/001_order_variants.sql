@@ -0,0 +1 @@
+ALTER TABLE orders ADD COLUMN variant_id INT;
@@ -0,0 +1,4 @@
+ALTER TABLE orders ADD COLUMN quantity INT NOT NULL DEFAULT 1;
+ALTER TABLE orders ADD COLUMN unit_price DECIMAL;
+UPDATE orders SET unit_price = total_price / quantity;
+ALTER TABLE orders ALTER COLUMN unit_price SET NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE orders ADD COLUMN discount DECIMAL NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN discounts JSONB NOT NULL DEFAULT '[]';
@@ -0,0 +1,9 @@
+ALTER TABLE orders ADD COLUMN order_ref VARCHAR(100);
+ALTER TABLE orders ADD COLUMN subtotal DECIMAL;
+UPDATE orders SET subtotal = unit_price * quantity;
+ALTER TABLE orders ALTER COLUMN subtotal SET NOT NULL;
+ALTER TABLE orders ADD COLUMN tax_rate DECIMAL NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN tax DECIMAL NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN shipping DECIMAL NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN region VARCHAR(20);
+ALTER TABLE orders ADD COLUMN currency CHAR(3) NOT NULL DEFAULT 'EUR';
@@ -0,0 +1,17 @@
+ALTER TABLE orders ADD COLUMN payment_status VARCHAR(20) NOT NULL DEFAULT 'unpaid';
+CREATE INDEX orders_order_ref ON orders ( order_ref );
+
+CREATE TABLE payment_attempt (
+     attempt_id serial PRIMARY KEY,
+     order_ref VARCHAR ( 100 ) NOT NULL,
+     provider VARCHAR ( 50 ) NOT NULL,
+     operation VARCHAR ( 20 ) NOT NULL,
+     reference VARCHAR ( 100 ),
+     amount DECIMAL NOT NULL,
+     currency CHAR ( 3 ) NOT NULL,
+     status VARCHAR ( 20 ) NOT NULL,
+     error VARCHAR ( 255 ),
+     event_id VARCHAR ( 100 ) UNIQUE,
+     created_at TIMESTAMP NOT NULL
+);
+CREATE INDEX payment_attempt_order_ref ON payment_attempt ( order_ref );
@@ -0,0 +1,29 @@
+ALTER TABLE orders ADD COLUMN status VARCHAR(20) NOT NULL DEFAULT 'placed';
+ALTER TABLE orders ADD COLUMN refunded DECIMAL NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN shipped_at TIMESTAMP;
+ALTER TABLE orders ADD COLUMN cancelled_at TIMESTAMP;
+
+CREATE TABLE order_return (
+     return_id serial PRIMARY KEY,
+     order_ref VARCHAR ( 100 ) NOT NULL,
+     user_id INT NOT NULL,
+     status VARCHAR ( 20 ) NOT NULL,
+     note VARCHAR ( 1000 ),
+     restocked BOOLEAN NOT NULL DEFAULT FALSE,
+     refund_amount DECIMAL NOT NULL DEFAULT 0,
+     created_at TIMESTAMP NOT NULL,
+     decided_at TIMESTAMP,
+     received_at TIMESTAMP,
+     refunded_at TIMESTAMP
+);
+CREATE INDEX order_return_user_id ON order_return ( user_id );
+
+CREATE TABLE order_return_item (
+     return_item_id serial PRIMARY KEY,
+     return_id INT NOT NULL REFERENCES order_return ( return_id ) ON DELETE CASCADE,
+     order_id INT NOT NULL REFERENCES orders ( order_id ),
+     quantity INT NOT NULL CHECK ( quantity > 0 ),
+     reason VARCHAR ( 255 ) NOT NULL,
+     refund_amount DECIMAL NOT NULL DEFAULT 0
+);
+CREATE INDEX order_return_item_order_id ON order_return_item ( order_id );
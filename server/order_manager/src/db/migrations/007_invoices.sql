@@ -0,0 +1,30 @@
+CREATE TABLE invoice_sequence (
+     name VARCHAR ( 20 ) PRIMARY KEY,
+     last_value INT NOT NULL
+);
+INSERT INTO invoice_sequence ( name, last_value ) VALUES ( 'invoice', 0 );
+
+CREATE TABLE invoice (
+     invoice_id serial PRIMARY KEY,
+     invoice_number VARCHAR ( 50 ) UNIQUE NOT NULL,
+     sequence INT UNIQUE NOT NULL,
+     order_ref VARCHAR ( 100 ) UNIQUE NOT NULL,
+     user_id INT NOT NULL,
+     currency CHAR ( 3 ) NOT NULL,
+     total DECIMAL NOT NULL,
+     data JSONB NOT NULL,
+     html TEXT NOT NULL,
+     pdf BYTEA NOT NULL,
+     issued_at TIMESTAMP NOT NULL
+);
+CREATE INDEX invoice_user_id ON invoice ( user_id );
+
+-- an invoice is never changed once issued
+CREATE FUNCTION invoice_immutable ( ) RETURNS TRIGGER AS $$
+BEGIN
+     RAISE EXCEPTION 'invoices can not be changed';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER invoice_immutable BEFORE UPDATE OR DELETE ON invoice
+     FOR EACH ROW EXECUTE FUNCTION invoice_immutable ( );
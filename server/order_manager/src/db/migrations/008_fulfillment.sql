@@ -0,0 +1,23 @@
+ALTER TABLE orders ADD COLUMN shipped_quantity INT NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN shipping_address JSONB;
+ALTER TABLE orders ADD COLUMN billing_address JSONB;
+UPDATE orders SET shipped_quantity = quantity WHERE status = 'shipped';
+
+CREATE TABLE fulfillment (
+     fulfillment_id serial PRIMARY KEY,
+     order_ref VARCHAR ( 100 ) NOT NULL,
+     carrier VARCHAR ( 100 ),
+     tracking_number VARCHAR ( 100 ),
+     status VARCHAR ( 20 ) NOT NULL,
+     shipped_at TIMESTAMP NOT NULL,
+     delivered_at TIMESTAMP
+);
+CREATE INDEX fulfillment_order_ref ON fulfillment ( order_ref );
+
+CREATE TABLE fulfillment_item (
+     fulfillment_item_id serial PRIMARY KEY,
+     fulfillment_id INT NOT NULL REFERENCES fulfillment ( fulfillment_id ) ON DELETE CASCADE,
+     order_id INT NOT NULL REFERENCES orders ( order_id ),
+     quantity INT NOT NULL CHECK ( quantity > 0 )
+);
+CREATE INDEX fulfillment_item_order_id ON fulfillment_item ( order_id );
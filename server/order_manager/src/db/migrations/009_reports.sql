@@ -0,0 +1,2 @@
+CREATE INDEX orders_date_time ON orders ( date_time );
+CREATE INDEX orders_user_id ON orders ( user_id );
@@ -0,0 +1,7 @@
+ALTER TABLE orders ADD COLUMN restocked BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE order_return_item ADD COLUMN restocked BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- stock was sent back when these were cancelled or received
+UPDATE orders SET restocked = TRUE WHERE status IN ( 'cancelled', 'cancelling' );
+UPDATE order_return_item SET restocked = TRUE
+    WHERE return_id IN ( SELECT return_id FROM order_return WHERE restocked );
@@ -1,7 +1,12 @@
 use crate::db::postgres::PostgresDB;
 use sea_orm::{Database, DatabaseConnection, DbErr};
 
+pub mod fulfillment;
+pub mod invoices;
+pub mod payments;
 pub mod postgres;
+pub mod reports;
+pub mod returns;
 
 pub struct DB {
     pub postgres_db: PostgresDB,
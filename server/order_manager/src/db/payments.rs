@@ -0,0 +1,166 @@
+use crate::db::postgres::PostgresDB;
+use crate::entities::{orders, payment_attempt};
+use chrono::Utc;
+use common::db_utils::{unique_violation, RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ColumnTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect, TransactionTrait,
+};
+
+// A payment attempt to record, the rest is filled in by `add_payment_attempt`.
+pub struct Attempt<'a> {
+    pub order_ref: &'a str,
+    pub provider: &'a str,
+    pub operation: &'a str,
+    pub reference: Option<String>,
+    pub amount: Decimal,
+    pub currency: &'a str,
+    pub status: &'a str,
+    pub error: Option<String>,
+    pub event_id: Option<String>,
+}
+
+impl PostgresDB {
+    // The orders of a checkout, all paid at once.
+    pub async fn get_checkout(&self, order_ref: &str) -> Result<Vec<orders::Model>, LocalError> {
+        let orders = orders::Entity::find()
+            .filter(orders::Column::OrderRef.eq(order_ref))
+            .order_by_asc(orders::Column::OrderId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+
+        if orders.is_empty() {
+            return Err(LocalError::IdNotFound);
+        }
+        Ok(orders)
+    }
+
+    // Moves the checkout to `to` if it is in one of the `from` states, and tells whether it
+    // was; of two concurrent requests, only one gets to move it.
+    pub async fn set_payment_status(
+        &self,
+        order_ref: &str,
+        from: &[&str],
+        to: &str,
+    ) -> Result<bool, LocalError> {
+        let res = orders::Entity::update_many()
+            .col_expr(orders::Column::PaymentStatus, Expr::value(to))
+            .filter(orders::Column::OrderRef.eq(order_ref))
+            .filter(orders::Column::PaymentStatus.is_in(from.to_vec()))
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+
+        Ok(res.rows_affected > 0)
+    }
+
+    // Moves the checkout from one of the `from` states to `to` like `set_payment_status`,
+    // and returns its orders as they were moved. They are locked meanwhile, so that an order
+    // cancelled at the same time is either left out of the amount or waits for the payment.
+    // Fails when the checkout isn't in a `from` state, or nothing of it is left to pay.
+    pub async fn claim_payment(
+        &self,
+        order_ref: &str,
+        from: &[&str],
+        to: &str,
+    ) -> Result<Vec<orders::Model>, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Order)?;
+
+        let mut orders: Vec<orders::Model> = orders::Entity::find()
+            .filter(orders::Column::OrderRef.eq(order_ref))
+            .filter(orders::Column::PaymentStatus.is_in(from.to_vec()))
+            .order_by_asc(orders::Column::OrderId)
+            .lock_exclusive()
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+        if orders.is_empty() {
+            return Err(LocalError::WrongPaymentStatus);
+        }
+        let due: Decimal = orders
+            .iter()
+            .filter(|o| !o.is_cancelled())
+            .map(|o| o.total_price)
+            .sum();
+        if due.is_zero() {
+            return Err(LocalError::WrongOrderStatus);
+        }
+
+        let order_ids: Vec<i32> = orders.iter().map(|o| o.order_id).collect();
+        orders::Entity::update_many()
+            .col_expr(orders::Column::PaymentStatus, Expr::value(to))
+            .filter(orders::Column::OrderId.is_in(order_ids))
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+        txn.commit().await.to_local_error(RecordType::Order)?;
+
+        for order in orders.iter_mut() {
+            order.payment_status = to.to_string();
+        }
+        Ok(orders)
+    }
+
+    // None when the webhook event of the attempt was already recorded.
+    pub async fn add_payment_attempt(
+        &self,
+        attempt: Attempt<'_>,
+    ) -> Result<Option<payment_attempt::Model>, LocalError> {
+        let res = payment_attempt::ActiveModel {
+            attempt_id: NotSet,
+            order_ref: Set(attempt.order_ref.to_string()),
+            provider: Set(attempt.provider.to_string()),
+            operation: Set(attempt.operation.to_string()),
+            reference: Set(attempt.reference),
+            amount: Set(attempt.amount),
+            currency: Set(attempt.currency.to_string()),
+            status: Set(attempt.status.to_string()),
+            error: Set(attempt.error),
+            event_id: Set(attempt.event_id),
+            created_at: Set(Utc::now().naive_utc()),
+        }
+        .insert(&self.db)
+        .await;
+
+        if let Err(ref e) = res {
+            if unique_violation(e) == Some("payment_attempt_event_id_key") {
+                return Ok(None);
+            }
+        }
+        res.map(Some).to_local_error(RecordType::Order)
+    }
+
+    // Oldest first, for reconciling with the provider's records.
+    pub async fn get_payment_attempts(
+        &self,
+        order_ref: &str,
+    ) -> Result<Vec<payment_attempt::Model>, LocalError> {
+        payment_attempt::Entity::find()
+            .filter(payment_attempt::Column::OrderRef.eq(order_ref))
+            .order_by_asc(payment_attempt::Column::AttemptId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+
+    // The provider's reference of the checkout's last authorization that went through or
+    // is still pending.
+    pub async fn get_payment_reference(&self, order_ref: &str) -> Result<String, LocalError> {
+        payment_attempt::Entity::find()
+            .filter(payment_attempt::Column::OrderRef.eq(order_ref))
+            .filter(payment_attempt::Column::Operation.eq(payment_attempt::AUTHORIZE))
+            .filter(
+                payment_attempt::Column::Status
+                    .is_in([payment_attempt::SUCCEEDED, payment_attempt::PENDING]),
+            )
+            .order_by_desc(payment_attempt::Column::AttemptId)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?
+            .and_then(|a| a.reference)
+            .ok_or(LocalError::WrongPaymentStatus)
+    }
+}
@@ -1,5 +1,8 @@
-use sea_orm::{ActiveModelTrait, DatabaseConnection, EntityTrait};
-use serde_json::Value;
+use sea_orm::{
+    entity::*, ActiveModelTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect,
+};
+use serde_json::{json, Value};
 
 use crate::entities::orders;
 
@@ -11,8 +14,14 @@ pub struct PostgresDB {
 }
 
 impl PostgresDB {
-    pub async fn add_order(&self, order_json: Value) -> Result<i32, LocalError> {
-        let order = orders::ActiveModel::from_json(order_json).to_local_error(RecordType::Order)?;
+    pub async fn add_order(&self, mut order_json: Value) -> Result<i32, LocalError> {
+        // `from_json` deserializes a whole `Model`, so it needs a placeholder primary key
+        if let Some(json_map) = order_json.as_object_mut() {
+            json_map.insert("order_id".to_string(), json!(0));
+        }
+        let mut order =
+            orders::ActiveModel::from_json(order_json).to_local_error(RecordType::Order)?;
+        order.order_id = NotSet;
         let res = orders::Entity::insert(order)
             .exec(&self.db)
             .await
@@ -20,4 +29,32 @@ impl PostgresDB {
 
         Ok(res.last_insert_id)
     }
+
+    pub async fn get_order(&self, order_id: i32) -> Result<orders::Model, LocalError> {
+        orders::Entity::find_by_id(order_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?
+            .ok_or(LocalError::IdNotFound)
+    }
+
+    // Newest first, optionally only the orders of one user.
+    pub async fn get_orders(
+        &self,
+        user_id: Option<i32>,
+        limit: u64,
+    ) -> Result<Vec<orders::Model>, LocalError> {
+        let mut query = orders::Entity::find();
+        if let Some(user_id) = user_id {
+            query = query.filter(orders::Column::UserId.eq(user_id));
+        }
+
+        query
+            .order_by_desc(orders::Column::DateTime)
+            .order_by_desc(orders::Column::OrderId)
+            .limit(limit)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
 }
@@ -0,0 +1,213 @@
+use crate::db::postgres::PostgresDB;
+use crate::entities::orders;
+use chrono::{NaiveDate, NaiveDateTime};
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::sea_query::{Expr, SimpleExpr};
+use sea_orm::{
+    ColumnTrait, EntityTrait, FromQueryResult, Order, QueryFilter, QueryOrder, QuerySelect, Select,
+};
+use serde::Serialize;
+
+// Checkouts are counted once, whatever number of products they are for; orders placed
+// without a reference count on their own.
+const CHECKOUT_COUNT: &str = "COUNT(DISTINCT COALESCE(order_ref, order_id::text))";
+const UNITS: &str = "SUM(quantity)";
+// Net sales: without tax and shipping, which are passed on, and less refunds. Refunds give
+// back tax and shipping too, so only their net share is taken off.
+const REVENUE: &str = "SUM(CASE WHEN total_price = 0 THEN 0 \
+     ELSE (total_price - tax - shipping) * (total_price - refunded) / total_price END)";
+const REFUNDED: &str = "SUM(refunded)";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    pub fn parse(period: &str) -> Option<Period> {
+        match period {
+            "day" => Some(Period::Day),
+            "week" => Some(Period::Week),
+            "month" => Some(Period::Month),
+            _ => None,
+        }
+    }
+
+    // Its first day, weeks starting on Monday.
+    fn start(&self) -> SimpleExpr {
+        let unit = match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        };
+        Expr::cust(&format!("CAST(date_trunc('{}', date_time) AS DATE)", unit))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProductOrder {
+    Revenue,
+    Units,
+}
+
+// Orders placed from `from` and before `to`.
+#[derive(Default)]
+pub struct Range {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, FromQueryResult, Serialize)]
+pub struct SalesRow {
+    pub period: NaiveDate,
+    pub currency: String,
+    pub orders: i64,
+    pub units: i64,
+    pub revenue: Decimal,
+    pub refunded: Decimal,
+}
+
+#[derive(Debug, FromQueryResult, Serialize)]
+pub struct ProductRow {
+    pub product_id: i32,
+    pub currency: String,
+    pub orders: i64,
+    pub units: i64,
+    pub revenue: Decimal,
+}
+
+#[derive(Debug, FromQueryResult, Serialize)]
+pub struct CustomerRow {
+    pub user_id: i32,
+    pub currency: String,
+    pub orders: i64,
+    pub revenue: Decimal,
+    pub first_order: NaiveDateTime,
+    pub last_order: NaiveDateTime,
+}
+
+#[derive(Debug, FromQueryResult, Serialize)]
+pub struct SummaryRow {
+    pub currency: String,
+    pub orders: i64,
+    pub units: i64,
+    pub customers: i64,
+    pub revenue: Decimal,
+    pub refunded: Decimal,
+}
+
+// Sales are the orders that were paid for and not cancelled (or being cancelled), less what
+// was refunded of them; the refunds of cancelled orders aren't counted either. Amounts are
+// in the currency of the orders, so every report is by currency.
+fn sales(range: &Range) -> Select<orders::Entity> {
+    let paid = [orders::PAID, orders::PARTIALLY_REFUNDED, orders::REFUNDED];
+    let mut query = orders::Entity::find()
+        .select_only()
+        .filter(orders::Column::Status.is_not_in([orders::CANCELLED, orders::CANCELLING]))
+        .filter(orders::Column::PaymentStatus.is_in(paid));
+    if let Some(from) = range.from {
+        query = query.filter(orders::Column::DateTime.gte(from));
+    }
+    if let Some(to) = range.to {
+        query = query.filter(orders::Column::DateTime.lt(to));
+    }
+    query
+}
+
+impl PostgresDB {
+    // Sales of each period, oldest first.
+    pub async fn get_sales_report(
+        &self,
+        period: Period,
+        range: &Range,
+    ) -> Result<Vec<SalesRow>, LocalError> {
+        sales(range)
+            .column_as(period.start(), "period")
+            .column(orders::Column::Currency)
+            .column_as(Expr::cust(CHECKOUT_COUNT), "orders")
+            .column_as(Expr::cust(UNITS), "units")
+            .column_as(Expr::cust(REVENUE), "revenue")
+            .column_as(Expr::cust(REFUNDED), "refunded")
+            .group_by(period.start())
+            .group_by(orders::Column::Currency)
+            .order_by_asc(period.start())
+            .order_by_asc(orders::Column::Currency)
+            .into_model::<SalesRow>()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+
+    // The best selling products first.
+    pub async fn get_products_report(
+        &self,
+        order: ProductOrder,
+        range: &Range,
+        limit: u64,
+    ) -> Result<Vec<ProductRow>, LocalError> {
+        let (first, second) = match order {
+            ProductOrder::Revenue => ("revenue", "units"),
+            ProductOrder::Units => ("units", "revenue"),
+        };
+        sales(range)
+            .column(orders::Column::ProductId)
+            .column(orders::Column::Currency)
+            .column_as(Expr::cust(CHECKOUT_COUNT), "orders")
+            .column_as(Expr::cust(UNITS), "units")
+            .column_as(Expr::cust(REVENUE), "revenue")
+            .group_by(orders::Column::ProductId)
+            .group_by(orders::Column::Currency)
+            .order_by(Expr::cust(first), Order::Desc)
+            .order_by(Expr::cust(second), Order::Desc)
+            .order_by_asc(orders::Column::ProductId)
+            .limit(limit)
+            .into_model::<ProductRow>()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+
+    // What each customer spent, the most first. Without a range, it is their lifetime value.
+    pub async fn get_customers_report(
+        &self,
+        range: &Range,
+        limit: u64,
+    ) -> Result<Vec<CustomerRow>, LocalError> {
+        sales(range)
+            .column(orders::Column::UserId)
+            .column(orders::Column::Currency)
+            .column_as(Expr::cust(CHECKOUT_COUNT), "orders")
+            .column_as(Expr::cust(REVENUE), "revenue")
+            .column_as(Expr::col(orders::Column::DateTime).min(), "first_order")
+            .column_as(Expr::col(orders::Column::DateTime).max(), "last_order")
+            .group_by(orders::Column::UserId)
+            .group_by(orders::Column::Currency)
+            .order_by(Expr::cust("revenue"), Order::Desc)
+            .order_by_asc(orders::Column::UserId)
+            .limit(limit)
+            .into_model::<CustomerRow>()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+
+    pub async fn get_sales_summary(&self, range: &Range) -> Result<Vec<SummaryRow>, LocalError> {
+        sales(range)
+            .column(orders::Column::Currency)
+            .column_as(Expr::cust(CHECKOUT_COUNT), "orders")
+            .column_as(Expr::cust(UNITS), "units")
+            .column_as(Expr::cust("COUNT(DISTINCT user_id)"), "customers")
+            .column_as(Expr::cust(REVENUE), "revenue")
+            .column_as(Expr::cust(REFUNDED), "refunded")
+            .group_by(orders::Column::Currency)
+            .order_by_asc(orders::Column::Currency)
+            .into_model::<SummaryRow>()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+}
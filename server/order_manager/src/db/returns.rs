@@ -0,0 +1,323 @@
+use crate::db::postgres::PostgresDB;
+use crate::entities::{order_return, order_return_item, orders};
+use chrono::Utc;
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect,
+    TransactionTrait,
+};
+use std::collections::HashMap;
+
+pub struct NewReturnItem {
+    pub order_id: i32,
+    pub quantity: i32,
+    pub reason: String,
+}
+
+pub(crate) async fn lock_orders<C: ConnectionTrait>(
+    db: &C,
+    order_ids: &[i32],
+) -> Result<Vec<orders::Model>, LocalError> {
+    let orders = orders::Entity::find()
+        .filter(orders::Column::OrderId.is_in(order_ids.to_vec()))
+        .order_by_asc(orders::Column::OrderId)
+        .lock_exclusive()
+        .all(db)
+        .await
+        .to_local_error(RecordType::Order)?;
+
+    let mut unique = order_ids.to_vec();
+    unique.sort_unstable();
+    unique.dedup();
+    if orders.len() != unique.len() {
+        return Err(LocalError::IdNotFound);
+    }
+    Ok(orders)
+}
+
+impl PostgresDB {
+    // Cancels the orders if none of them has shipped yet, with `status` CANCELLED or, until
+    // their units are back in stock and their refund went through, CANCELLING. Orders being
+    // cancelled can be cancelled again.
+    pub async fn cancel_orders(
+        &self,
+        order_ids: &[i32],
+        status: &str,
+    ) -> Result<Vec<orders::Model>, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Order)?;
+
+        let orders = lock_orders(&txn, order_ids).await?;
+        let cancellable = [orders::PLACED, orders::CANCELLING];
+        if orders
+            .iter()
+            .any(|o| !cancellable.contains(&o.status.as_str()))
+        {
+            return Err(LocalError::WrongOrderStatus);
+        }
+        // the amount of a payment being made is set; see `claim_payment`
+        if orders.iter().any(|o| o.payment_status == orders::PENDING) {
+            return Err(LocalError::WrongPaymentStatus);
+        }
+
+        let now = Utc::now().naive_utc();
+        let mut cancelled = Vec::new();
+        for order in orders {
+            let cancelled_at = order.cancelled_at.unwrap_or(now);
+            let mut order: orders::ActiveModel = order.into();
+            order.status = Set(status.to_string());
+            order.cancelled_at = Set(Some(cancelled_at));
+            cancelled.push(order.update(&txn).await.to_local_error(RecordType::Order)?);
+        }
+
+        txn.commit().await.to_local_error(RecordType::Order)?;
+        Ok(cancelled)
+    }
+
+    // Adds what was given back to each order, by order id.
+    pub async fn add_refunds(&self, amounts: &[(i32, Decimal)]) -> Result<(), LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Order)?;
+
+        for (order_id, amount) in amounts.iter() {
+            orders::Entity::update_many()
+                .col_expr(
+                    orders::Column::Refunded,
+                    Expr::col(orders::Column::Refunded).add(*amount),
+                )
+                .filter(orders::Column::OrderId.eq(*order_id))
+                .exec(&txn)
+                .await
+                .to_local_error(RecordType::Order)?;
+        }
+
+        txn.commit().await.to_local_error(RecordType::Order)
+    }
+
+    // A return of shipped items of one checkout. Units that haven't shipped yet, or are
+    // already in a return that wasn't rejected, can't be returned.
+    pub async fn add_return(
+        &self,
+        user_id: i32,
+        note: Option<String>,
+        items: Vec<NewReturnItem>,
+    ) -> Result<(order_return::Model, Vec<order_return_item::Model>), LocalError> {
+        if items.is_empty() {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let txn = self.db.begin().await.to_local_error(RecordType::Order)?;
+
+        let order_ids: Vec<i32> = items.iter().map(|i| i.order_id).collect();
+        let orders = lock_orders(&txn, &order_ids).await?;
+        let order_ref = orders[0]
+            .order_ref
+            .clone()
+            .ok_or(LocalError::WrongParameters)?;
+        if orders
+            .iter()
+            .any(|o| o.user_id != user_id || o.order_ref.as_ref() != Some(&order_ref))
+        {
+            return Err(LocalError::WrongParameters);
+        }
+        let shipped = [orders::SHIPPED, orders::PARTIALLY_SHIPPED];
+        if orders.iter().any(|o| !shipped.contains(&o.status.as_str())) {
+            return Err(LocalError::WrongOrderStatus);
+        }
+
+        let returned = order_return_item::Entity::find()
+            .inner_join(order_return::Entity)
+            .filter(order_return_item::Column::OrderId.is_in(order_ids))
+            .filter(order_return::Column::Status.ne(order_return::REJECTED))
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+        let mut left: HashMap<i32, i32> = orders
+            .iter()
+            .map(|o| (o.order_id, o.shipped_quantity))
+            .collect();
+        for item in returned.iter() {
+            *left.entry(item.order_id).or_default() -= item.quantity;
+        }
+        for item in items.iter() {
+            if item.quantity <= 0 || item.reason.trim().is_empty() {
+                return Err(LocalError::WrongParameters);
+            }
+            let left = left.entry(item.order_id).or_default();
+            *left -= item.quantity;
+            if *left < 0 {
+                return Err(LocalError::ReturnLimitExceeded);
+            }
+        }
+
+        let order_return = order_return::ActiveModel {
+            return_id: NotSet,
+            order_ref: Set(order_ref),
+            user_id: Set(user_id),
+            status: Set(order_return::REQUESTED.to_string()),
+            note: Set(note),
+            restocked: Set(false),
+            refund_amount: Set(Decimal::ZERO),
+            created_at: Set(Utc::now().naive_utc()),
+            decided_at: Set(None),
+            received_at: Set(None),
+            refunded_at: Set(None),
+        }
+        .insert(&txn)
+        .await
+        .to_local_error(RecordType::Order)?;
+
+        let mut added = Vec::new();
+        for item in items {
+            let item = order_return_item::ActiveModel {
+                return_item_id: NotSet,
+                return_id: Set(order_return.return_id),
+                order_id: Set(item.order_id),
+                quantity: Set(item.quantity),
+                reason: Set(item.reason.trim().to_string()),
+                refund_amount: Set(Decimal::ZERO),
+                restocked: Set(false),
+            }
+            .insert(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+            added.push(item);
+        }
+
+        txn.commit().await.to_local_error(RecordType::Order)?;
+        Ok((order_return, added))
+    }
+
+    pub async fn get_return(
+        &self,
+        return_id: i32,
+    ) -> Result<(order_return::Model, Vec<order_return_item::Model>), LocalError> {
+        let order_return = order_return::Entity::find_by_id(return_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?
+            .ok_or(LocalError::IdNotFound)?;
+
+        let items = order_return_item::Entity::find()
+            .filter(order_return_item::Column::ReturnId.eq(return_id))
+            .order_by_asc(order_return_item::Column::ReturnItemId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+
+        Ok((order_return, items))
+    }
+
+    // Newest first.
+    pub async fn get_returns(
+        &self,
+        user_id: Option<i32>,
+        status: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<order_return::Model>, LocalError> {
+        let mut query = order_return::Entity::find();
+        if let Some(user_id) = user_id {
+            query = query.filter(order_return::Column::UserId.eq(user_id));
+        }
+        if let Some(status) = status {
+            query = query.filter(order_return::Column::Status.eq(status));
+        }
+
+        query
+            .order_by_desc(order_return::Column::ReturnId)
+            .limit(limit)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Order)
+    }
+
+    // Moves the return from `from` to `to`, stamping the step; only one of two concurrent
+    // requests gets to move it.
+    pub async fn set_return_status(
+        &self,
+        return_id: i32,
+        from: &str,
+        to: &str,
+    ) -> Result<bool, LocalError> {
+        let now = Utc::now().naive_utc();
+        let stamp = match to {
+            order_return::APPROVED | order_return::REJECTED => order_return::Column::DecidedAt,
+            order_return::RECEIVING | order_return::RECEIVED => order_return::Column::ReceivedAt,
+            _ => order_return::Column::RefundedAt,
+        };
+
+        let res = order_return::Entity::update_many()
+            .col_expr(order_return::Column::Status, Expr::value(to))
+            .col_expr(stamp, Expr::value(now))
+            .filter(order_return::Column::ReturnId.eq(return_id))
+            .filter(order_return::Column::Status.eq(from))
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+
+        Ok(res.rows_affected > 0)
+    }
+
+    // The order's units went back in stock.
+    pub async fn set_order_restocked(&self, order_id: i32) -> Result<(), LocalError> {
+        orders::Entity::update_many()
+            .col_expr(orders::Column::Restocked, Expr::value(true))
+            .filter(orders::Column::OrderId.eq(order_id))
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+        Ok(())
+    }
+
+    pub async fn set_return_item_restocked(&self, return_item_id: i32) -> Result<(), LocalError> {
+        order_return_item::Entity::update_many()
+            .col_expr(order_return_item::Column::Restocked, Expr::value(true))
+            .filter(order_return_item::Column::ReturnItemId.eq(return_item_id))
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+        Ok(())
+    }
+
+    pub async fn set_return_restocked(&self, return_id: i32) -> Result<(), LocalError> {
+        order_return::Entity::update_many()
+            .col_expr(order_return::Column::Restocked, Expr::value(true))
+            .filter(order_return::Column::ReturnId.eq(return_id))
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Order)?;
+        Ok(())
+    }
+
+    // What each item of the return was refunded, by item id.
+    pub async fn set_return_refunds(
+        &self,
+        return_id: i32,
+        amounts: &[(i32, Decimal)],
+    ) -> Result<(), LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Order)?;
+
+        for (return_item_id, amount) in amounts.iter() {
+            order_return_item::Entity::update_many()
+                .col_expr(
+                    order_return_item::Column::RefundAmount,
+                    Expr::value(*amount),
+                )
+                .filter(order_return_item::Column::ReturnItemId.eq(*return_item_id))
+                .exec(&txn)
+                .await
+                .to_local_error(RecordType::Order)?;
+        }
+
+        let total: Decimal = amounts.iter().map(|a| a.1).sum();
+        order_return::Entity::update_many()
+            .col_expr(order_return::Column::RefundAmount, Expr::value(total))
+            .filter(order_return::Column::ReturnId.eq(return_id))
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::Order)?;
+
+        txn.commit().await.to_local_error(RecordType::Order)
+    }
+}
@@ -5,6 +5,121 @@ CREATE TABLE orders (
     order_id serial PRIMARY KEY,
     user_id INT NOT NULL,
     product_id INT NOT NULL,
+    variant_id INT,
+    order_ref VARCHAR(100),
     date_time TIMESTAMP NOT NULL,
-    total_price DECIMAL NOT NULL
+    quantity INT NOT NULL DEFAULT 1,
+    unit_price DECIMAL NOT NULL,
+    subtotal DECIMAL NOT NULL,
+    discount DECIMAL NOT NULL DEFAULT 0,
+    discounts JSONB NOT NULL DEFAULT '[]',
+    tax_rate DECIMAL NOT NULL DEFAULT 0,
+    tax DECIMAL NOT NULL DEFAULT 0,
+    shipping DECIMAL NOT NULL DEFAULT 0,
+    region VARCHAR(20),
+    currency CHAR(3) NOT NULL DEFAULT 'EUR',
+    total_price DECIMAL NOT NULL,
+    payment_status VARCHAR(20) NOT NULL DEFAULT 'unpaid',
+    status VARCHAR(20) NOT NULL DEFAULT 'placed',
+    refunded DECIMAL NOT NULL DEFAULT 0,
+    shipped_at TIMESTAMP,
+    cancelled_at TIMESTAMP,
+    restocked BOOLEAN NOT NULL DEFAULT FALSE,
+    shipped_quantity INT NOT NULL DEFAULT 0,
+    shipping_address JSONB,
+    billing_address JSONB
 );
+CREATE INDEX orders_order_ref ON orders ( order_ref );
+CREATE INDEX orders_date_time ON orders ( date_time );
+CREATE INDEX orders_user_id ON orders ( user_id );
+
+CREATE TABLE payment_attempt (
+    attempt_id serial PRIMARY KEY,
+    order_ref VARCHAR ( 100 ) NOT NULL,
+    provider VARCHAR ( 50 ) NOT NULL,
+    operation VARCHAR ( 20 ) NOT NULL,
+    reference VARCHAR ( 100 ),
+    amount DECIMAL NOT NULL,
+    currency CHAR ( 3 ) NOT NULL,
+    status VARCHAR ( 20 ) NOT NULL,
+    error VARCHAR ( 255 ),
+    event_id VARCHAR ( 100 ) UNIQUE,
+    created_at TIMESTAMP NOT NULL
+);
+CREATE INDEX payment_attempt_order_ref ON payment_attempt ( order_ref );
+
+CREATE TABLE order_return (
+    return_id serial PRIMARY KEY,
+    order_ref VARCHAR ( 100 ) NOT NULL,
+    user_id INT NOT NULL,
+    status VARCHAR ( 20 ) NOT NULL,
+    note VARCHAR ( 1000 ),
+    restocked BOOLEAN NOT NULL DEFAULT FALSE,
+    refund_amount DECIMAL NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL,
+    decided_at TIMESTAMP,
+    received_at TIMESTAMP,
+    refunded_at TIMESTAMP
+);
+CREATE INDEX order_return_user_id ON order_return ( user_id );
+
+CREATE TABLE order_return_item (
+    return_item_id serial PRIMARY KEY,
+    return_id INT NOT NULL REFERENCES order_return ( return_id ) ON DELETE CASCADE,
+    order_id INT NOT NULL REFERENCES orders ( order_id ),
+    quantity INT NOT NULL CHECK ( quantity > 0 ),
+    reason VARCHAR ( 255 ) NOT NULL,
+    refund_amount DECIMAL NOT NULL DEFAULT 0,
+    restocked BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX order_return_item_order_id ON order_return_item ( order_id );
+
+CREATE TABLE invoice_sequence (
+    name VARCHAR ( 20 ) PRIMARY KEY,
+    last_value INT NOT NULL
+);
+INSERT INTO invoice_sequence ( name, last_value ) VALUES ( 'invoice', 0 );
+
+CREATE TABLE invoice (
+    invoice_id serial PRIMARY KEY,
+    invoice_number VARCHAR ( 50 ) UNIQUE NOT NULL,
+    sequence INT UNIQUE NOT NULL,
+    order_ref VARCHAR ( 100 ) UNIQUE NOT NULL,
+    user_id INT NOT NULL,
+    currency CHAR ( 3 ) NOT NULL,
+    total DECIMAL NOT NULL,
+    data JSONB NOT NULL,
+    html TEXT NOT NULL,
+    pdf BYTEA NOT NULL,
+    issued_at TIMESTAMP NOT NULL
+);
+CREATE INDEX invoice_user_id ON invoice ( user_id );
+
+-- an invoice is never changed once issued
+CREATE FUNCTION invoice_immutable ( ) RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'invoices can not be changed';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER invoice_immutable BEFORE UPDATE OR DELETE ON invoice
+    FOR EACH ROW EXECUTE FUNCTION invoice_immutable ( );
+
+CREATE TABLE fulfillment (
+    fulfillment_id serial PRIMARY KEY,
+    order_ref VARCHAR ( 100 ) NOT NULL,
+    carrier VARCHAR ( 100 ),
+    tracking_number VARCHAR ( 100 ),
+    status VARCHAR ( 20 ) NOT NULL,
+    shipped_at TIMESTAMP NOT NULL,
+    delivered_at TIMESTAMP
+);
+CREATE INDEX fulfillment_order_ref ON fulfillment ( order_ref );
+
+CREATE TABLE fulfillment_item (
+    fulfillment_item_id serial PRIMARY KEY,
+    fulfillment_id INT NOT NULL REFERENCES fulfillment ( fulfillment_id ) ON DELETE CASCADE,
+    order_id INT NOT NULL REFERENCES orders ( order_id ),
+    quantity INT NOT NULL CHECK ( quantity > 0 )
+);
+CREATE INDEX fulfillment_item_order_id ON fulfillment_item ( order_id );
@@ -0,0 +1,34 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// status
+pub const IN_TRANSIT: &str = "in_transit";
+pub const DELIVERED: &str = "delivered";
+
+// A parcel sent for items of the checkout `order_ref`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "fulfillment")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub fulfillment_id: i32,
+    pub order_ref: String,
+    pub carrier: Option<String>,
+    pub tracking_number: Option<String>,
+    pub status: String,
+    pub shipped_at: DateTime,
+    pub delivered_at: Option<DateTime>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(has_many = "super::fulfillment_item::Entity")]
+    FulfillmentItem,
+}
+
+impl Related<super::fulfillment_item::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::FulfillmentItem.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
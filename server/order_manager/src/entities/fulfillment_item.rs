@@ -0,0 +1,33 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Units of an order sent with a fulfillment.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "fulfillment_item")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub fulfillment_item_id: i32,
+    pub fulfillment_id: i32,
+    pub order_id: i32,
+    pub quantity: i32,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::fulfillment::Entity",
+        from = "Column::FulfillmentId",
+        to = "super::fulfillment::Column::FulfillmentId",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    Fulfillment,
+}
+
+impl Related<super::fulfillment::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Fulfillment.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,33 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// The invoice of a paid checkout, rendered when it was issued and never changed after.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "invoice")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub invoice_id: i32,
+    // `invoices.number_prefix` and `sequence`, e.g. "INV-000042"
+    #[sea_orm(unique)]
+    pub invoice_number: String,
+    // numbers follow each other without gaps
+    #[sea_orm(unique)]
+    pub sequence: i32,
+    #[sea_orm(unique)]
+    pub order_ref: String,
+    pub user_id: i32,
+    pub currency: String,
+    pub total: Decimal,
+    // seller, buyer, lines and totals as they were when issued
+    pub data: Json,
+    #[serde(skip_serializing)]
+    pub html: String,
+    #[serde(skip_serializing)]
+    pub pdf: Vec<u8>,
+    pub issued_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
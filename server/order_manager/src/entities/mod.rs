@@ -2,4 +2,11 @@
 
 pub mod prelude;
 
+pub mod fulfillment;
+pub mod fulfillment_item;
+pub mod invoice;
+pub mod invoice_sequence;
+pub mod order_return;
+pub mod order_return_item;
 pub mod orders;
+pub mod payment_attempt;
@@ -0,0 +1,45 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// status, in the order a return goes through them
+pub const REQUESTED: &str = "requested";
+pub const APPROVED: &str = "approved";
+pub const REJECTED: &str = "rejected";
+// arrived, waiting for its items to go back in stock
+pub const RECEIVING: &str = "receiving";
+pub const RECEIVED: &str = "received";
+pub const REFUNDED: &str = "refunded";
+
+// A return request (RMA) for items of the checkout `order_ref`.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "order_return")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub return_id: i32,
+    pub order_ref: String,
+    pub user_id: i32,
+    pub status: String,
+    pub note: Option<String>,
+    // the items were put back in stock when received
+    pub restocked: bool,
+    pub refund_amount: Decimal,
+    pub created_at: DateTime,
+    // approved or rejected
+    pub decided_at: Option<DateTime>,
+    pub received_at: Option<DateTime>,
+    pub refunded_at: Option<DateTime>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(has_many = "super::order_return_item::Entity")]
+    OrderReturnItem,
+}
+
+impl Related<super::order_return_item::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::OrderReturnItem.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,37 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Units of an order sent back with a return.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "order_return_item")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub return_item_id: i32,
+    pub return_id: i32,
+    pub order_id: i32,
+    pub quantity: i32,
+    pub reason: String,
+    pub refund_amount: Decimal,
+    // the units went back in stock when the return was received
+    pub restocked: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::order_return::Entity",
+        from = "Column::ReturnId",
+        to = "super::order_return::Column::ReturnId",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    OrderReturn,
+}
+
+impl Related<super::order_return::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::OrderReturn.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -3,6 +3,24 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
+// status
+pub const PLACED: &str = "placed";
+pub const PARTIALLY_SHIPPED: &str = "partially_shipped";
+pub const SHIPPED: &str = "shipped";
+pub const CANCELLED: &str = "cancelled";
+// cancelled, waiting for its units to go back in stock or its refund to go through
+pub const CANCELLING: &str = "cancelling";
+
+// payment_status, see the `payments` module of order_manager
+pub const UNPAID: &str = "unpaid";
+pub const PENDING: &str = "pending";
+pub const AUTHORIZED: &str = "authorized";
+pub const PAID: &str = "paid";
+pub const FAILED: &str = "failed";
+pub const VOIDED: &str = "voided";
+pub const PARTIALLY_REFUNDED: &str = "partially_refunded";
+pub const REFUNDED: &str = "refunded";
+
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
 #[sea_orm(table_name = "orders")]
 pub struct Model {
@@ -10,8 +28,48 @@ pub struct Model {
     pub order_id: i32,
     pub user_id: i32,
     pub product_id: i32,
+    pub variant_id: Option<i32>,
+    // the checkout the order was part of
+    pub order_ref: Option<String>,
     pub date_time: DateTime,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+    // quantity * unit_price
+    pub subtotal: Decimal,
+    pub discount: Decimal,
+    // promotions that make up `discount`, with their amounts
+    pub discounts: Json,
+    // percent
+    pub tax_rate: Decimal,
+    pub tax: Decimal,
+    // the order's share of the checkout's shipping fee
+    pub shipping: Decimal,
+    pub region: Option<String>,
+    pub currency: String,
+    // subtotal - discount + shipping, and + tax unless the prices include it
     pub total_price: Decimal,
+    // of the whole checkout, `order_ref` is paid at once
+    pub payment_status: String,
+    pub status: String,
+    // given back for this order, by its cancellation or returns
+    pub refunded: Decimal,
+    // when the last unit shipped
+    pub shipped_at: Option<DateTime>,
+    pub cancelled_at: Option<DateTime>,
+    // the units went back in stock when the order was cancelled
+    pub restocked: bool,
+    // units sent with fulfillments so far
+    pub shipped_quantity: i32,
+    // snapshots of the buyer's address book entries when the order was placed
+    pub shipping_address: Option<Json>,
+    pub billing_address: Option<Json>,
+}
+
+impl Model {
+    // Whether the order is cancelled, or will be once refunded.
+    pub fn is_cancelled(&self) -> bool {
+        self.status == CANCELLED || self.status == CANCELLING
+    }
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -1,3 +1,10 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.0
 
+pub use super::fulfillment::Entity as Fulfillment;
+pub use super::fulfillment_item::Entity as FulfillmentItem;
+pub use super::invoice::Entity as Invoice;
+pub use super::invoice_sequence::Entity as InvoiceSequence;
+pub use super::order_return::Entity as OrderReturn;
+pub use super::order_return_item::Entity as OrderReturnItem;
 pub use super::orders::Entity as Orders;
+pub use super::payment_attempt::Entity as PaymentAttempt;
@@ -1,17 +1,49 @@
 use crate::context::Context;
+use crate::invoices::{render_html, render_pdf, Buyer};
+use crate::payments::{
+    self, Outcome, PaymentRequest, PAYMENT_FAILED, PAYMENT_SUCCEEDED, SIGNATURE_HEADER,
+};
+use crate::reports;
 use chrono::Utc;
-use common::request_response_utils::create_response;
+use common::auth::{
+    authenticate, authorize, service_token, ORDERS_CREATE, ORDERS_MANAGE, ORDERS_READ,
+};
+use common::idempotency::IDEMPOTENCY_KEY_HEADER;
+use common::money;
+use common::request_response_utils::{
+    create_response, get_id_from_uri, get_params, response_auth_error,
+};
 use common::utils::LocalError;
-use http::StatusCode;
-use hyper::{Body, Response};
+use http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use http::request::Parts;
+use http::{HeaderValue, StatusCode};
+use hyper::body::Bytes;
+use hyper::{Body, Client, Response};
+use order_manager::db::fulfillment::NewFulfillmentItem;
+use order_manager::db::invoices::NewInvoice;
+use order_manager::db::payments::Attempt;
+use order_manager::db::reports::{Period, ProductOrder, Range};
+use order_manager::db::returns::NewReturnItem;
+use order_manager::entities::{
+    fulfillment, fulfillment_item, invoice, order_return, order_return_item, orders,
+    payment_attempt,
+};
+use sea_orm::prelude::Decimal;
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::sync::Arc;
 
 // /order/add
+// Orders are placed by product_manager, priced by it.
 pub async fn add_order(
+    parts: &Parts,
     mut body: Option<Value>,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, ORDERS_CREATE) {
+        return response_auth_error(e);
+    }
+
     let json_map: Option<&mut serde_json::Map<String, Value>> = body
         .as_mut()
         .and_then(|json: &mut Value| json.as_object_mut());
@@ -35,6 +67,56 @@ pub async fn add_order(
         );
     }
 
+    // orders from older clients are for a single unit
+    json_map.entry("quantity").or_insert(json!(1));
+    let total_price = json_map["total_price"].clone();
+    json_map.entry("unit_price").or_insert(total_price);
+    let subtotal = json_map["total_price"].clone();
+    json_map.entry("subtotal").or_insert(subtotal);
+    json_map.entry("discount").or_insert(json!(0));
+    json_map.entry("discounts").or_insert(json!([]));
+    json_map.entry("tax_rate").or_insert(json!(0));
+    json_map.entry("tax").or_insert(json!(0));
+    json_map.entry("shipping").or_insert(json!(0));
+    json_map
+        .entry("currency")
+        .or_insert(json!(context.currency));
+    if let Err(e) = check_order_amounts(json_map) {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+    if let Err(e) = check_checkout(&context, json_map).await {
+        return error_response(e);
+    }
+    json_map.insert("payment_status".to_string(), json!(orders::UNPAID));
+    json_map.insert("status".to_string(), json!(orders::PLACED));
+    json_map.insert("refunded".to_string(), json!(0));
+    json_map.insert("shipped_quantity".to_string(), json!(0));
+    let shipping_address_id = json_map
+        .remove("shipping_address_id")
+        .and_then(|id| id.as_i64());
+    let billing_address_id = json_map
+        .remove("billing_address_id")
+        .and_then(|id| id.as_i64());
+    let addresses = get_order_addresses(
+        &context,
+        json_map["user_id"].as_i64().unwrap_or_default(),
+        shipping_address_id,
+        billing_address_id,
+    )
+    .await;
+    let (shipping_address, billing_address) = match addresses {
+        // user_manager couldn't be asked
+        Err(LocalError::OperationFailed) => {
+            return create_response(
+                StatusCode::BAD_GATEWAY,
+                LocalError::OperationFailed.to_string(),
+            )
+        }
+        Err(e) => return error_response(e),
+        Ok(addresses) => addresses,
+    };
+    json_map.insert("shipping_address".to_string(), shipping_address);
+    json_map.insert("billing_address".to_string(), billing_address);
     json_map
         .entry("date_time")
         .or_insert(json!(Utc::now().naive_utc()));
@@ -47,3 +129,1900 @@ pub async fn add_order(
         Ok(id) => create_response(StatusCode::OK, id.to_string()),
     }
 }
+
+// The amounts of an order must add up: total = subtotal - discount + shipping, plus the tax
+// unless it is included in the prices.
+fn check_order_amounts(json_map: &serde_json::Map<String, Value>) -> Result<(), LocalError> {
+    let quantity = json_map["quantity"]
+        .as_i64()
+        .filter(|q| (1..=i32::MAX as i64).contains(q))
+        .ok_or(LocalError::WrongParameters)?;
+    let amount = |key: &str| {
+        money::parse_amount(&json_map[key])
+            .filter(|amount| !amount.is_sign_negative())
+            .ok_or(LocalError::WrongParameters)
+    };
+    let unit_price = amount("unit_price")?;
+    let subtotal = amount("subtotal")?;
+    let discount = amount("discount")?;
+    let tax = amount("tax")?;
+    let shipping = amount("shipping")?;
+    let total_price = amount("total_price")?;
+    amount("tax_rate")?;
+
+    let before_tax = subtotal - discount + shipping;
+    if subtotal != money::round(unit_price * Decimal::from(quantity))
+        || discount > subtotal
+        || (total_price != before_tax && total_price != before_tax + tax)
+    {
+        return Err(LocalError::WrongParameters);
+    }
+
+    let currency = json_map["currency"].as_str().unwrap_or_default();
+    if !money::is_currency_code(currency) {
+        return Err(LocalError::WrongParameters);
+    }
+    Ok(())
+}
+
+// An order joins a checkout of the same buyer and currency that isn't paid for yet.
+async fn check_checkout(
+    context: &Context,
+    json_map: &serde_json::Map<String, Value>,
+) -> Result<(), LocalError> {
+    let order_ref = match json_map.get("order_ref").and_then(|r| r.as_str()) {
+        None => return Ok(()),
+        Some(order_ref) => order_ref,
+    };
+    let orders = match context.db.postgres_db.get_checkout(order_ref).await {
+        Err(LocalError::IdNotFound) => return Ok(()),
+        res => res?,
+    };
+
+    let user_id = json_map["user_id"].as_i64();
+    let currency = json_map["currency"].as_str();
+    let joins = orders.iter().all(|o| {
+        user_id == Some(o.user_id as i64)
+            && currency == Some(o.currency.as_str())
+            && o.payment_status == orders::UNPAID
+    });
+    if !joins {
+        return Err(LocalError::WrongParameters);
+    }
+    Ok(())
+}
+
+// fields of an address book entry that aren't part of the address
+const ADDRESS_BOOK_FIELDS: [&str; 4] = [
+    "user_id",
+    "default_shipping",
+    "default_billing",
+    "created_at",
+];
+
+// Snapshots of the buyer's address book entries for an order: the chosen ones, or the
+// defaults, billing to the shipping address when there is no default billing address.
+// A chosen address must be in the buyer's book; without defaults the order has no address.
+async fn get_order_addresses(
+    context: &Context,
+    user_id: i64,
+    shipping_address_id: Option<i64>,
+    billing_address_id: Option<i64>,
+) -> Result<(Value, Value), LocalError> {
+    let addresses = service_get(
+        context,
+        &format!(
+            "{}{}?user_id={}",
+            context.user_manager.uri, context.user_manager.addresses_endpoint, user_id
+        ),
+    )
+    .await;
+    if let Err(ref e) = addresses {
+        println!(
+            "Error when getting the addresses of user {}: {}",
+            user_id,
+            e.to_string()
+        );
+    }
+    let addresses = addresses?;
+    let addresses = addresses.as_array().cloned().unwrap_or_default();
+
+    let find = |address_id: Option<i64>, default: &str| {
+        let address = match address_id {
+            Some(address_id) => Some(
+                addresses
+                    .iter()
+                    .find(|a| a.get("address_id").and_then(|id| id.as_i64()) == Some(address_id))
+                    .ok_or(LocalError::InvalidAddress)?,
+            ),
+            None => addresses
+                .iter()
+                .find(|a| a.get(default).and_then(|d| d.as_bool()) == Some(true)),
+        };
+        Ok(address.cloned().map(|mut address| {
+            if let Some(address) = address.as_object_mut() {
+                for key in ADDRESS_BOOK_FIELDS {
+                    address.remove(key);
+                }
+            }
+            address
+        }))
+    };
+    let shipping = find(shipping_address_id, "default_shipping")?;
+    let billing = find(billing_address_id, "default_billing")?.or_else(|| shipping.clone());
+    Ok((shipping.unwrap_or_default(), billing.unwrap_or_default()))
+}
+
+fn error_status(error: &LocalError) -> StatusCode {
+    match error {
+        LocalError::IdNotFound => StatusCode::NOT_FOUND,
+        LocalError::WrongPaymentStatus
+        | LocalError::WrongOrderStatus
+        | LocalError::ReturnLimitExceeded
+        | LocalError::ShipmentLimitExceeded => StatusCode::CONFLICT,
+        LocalError::PaymentDeclined => StatusCode::PAYMENT_REQUIRED,
+        LocalError::InvalidSignature => StatusCode::UNAUTHORIZED,
+        LocalError::OperationFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+fn error_response(error: LocalError) -> Result<Response<Body>, hyper::Error> {
+    match error {
+        LocalError::UnauthenticatedUser | LocalError::Forbidden => response_auth_error(error),
+        _ => create_response(error_status(&error), error.to_string()),
+    }
+}
+
+// What is due for the checkout, and in which currency. Cancelled orders aren't charged, or
+// were refunded when cancelled.
+fn checkout_total(orders: &[orders::Model]) -> (Decimal, String) {
+    let amount = orders
+        .iter()
+        .filter(|o| !o.is_cancelled())
+        .map(|o| o.total_price)
+        .sum();
+    (money::round(amount), orders[0].currency.clone())
+}
+
+// What can still be given back of each order of the checkout.
+fn refundable(orders: &[orders::Model]) -> Vec<(i32, Decimal)> {
+    orders
+        .iter()
+        .filter(|o| o.status != orders::CANCELLED)
+        .map(|o| (o.order_id, (o.total_price - o.refunded).max(Decimal::ZERO)))
+        .collect()
+}
+
+// The checkout's orders, to the user who placed them or to staff with `permission`.
+async fn get_checkout_for(
+    parts: &Parts,
+    context: &Context,
+    order_ref: &str,
+    permission: &str,
+) -> Result<Vec<orders::Model>, LocalError> {
+    let auth = authenticate(parts, &context.auth_secret)?;
+    let orders = context.db.postgres_db.get_checkout(order_ref).await?;
+    if orders[0].user_id != auth.user_id && !auth.has_permission(permission) {
+        return Err(LocalError::Forbidden);
+    }
+
+    Ok(orders)
+}
+
+// The checkout's payment status, with every attempt for reconciling.
+async fn get_payment_summary(context: &Context, order_ref: &str) -> Result<Value, LocalError> {
+    let orders = context.db.postgres_db.get_checkout(order_ref).await?;
+    let attempts = context
+        .db
+        .postgres_db
+        .get_payment_attempts(order_ref)
+        .await?;
+    let refunded: Decimal = orders.iter().map(|o| o.refunded).sum();
+    let (amount, currency) = checkout_total(&orders);
+    let payment_status = orders[0].payment_status.clone();
+    let orders: Vec<Value> = orders
+        .iter()
+        .map(|o| {
+            json!({
+                "order_id": o.order_id,
+                "status": o.status,
+                "total_price": o.total_price,
+                "refunded": o.refunded,
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "order_ref": order_ref,
+        "payment_status": payment_status,
+        "amount": amount,
+        "refunded": money::round(refunded),
+        "currency": currency,
+        "orders": orders,
+        "attempts": attempts,
+    }))
+}
+
+async fn payment_response(
+    context: &Context,
+    order_ref: &str,
+    status_code: StatusCode,
+) -> Result<Response<Body>, hyper::Error> {
+    match get_payment_summary(context, order_ref).await {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(summary) => create_response(status_code, summary.to_string()),
+    }
+}
+
+// Records the provider's answer; not reaching it is recorded as a failed attempt too.
+async fn record_attempt(
+    context: &Context,
+    order_ref: &str,
+    operation: &str,
+    amount: Decimal,
+    currency: &str,
+    res: &Result<Outcome, LocalError>,
+) {
+    let (reference, status, error) = match res {
+        Ok(outcome) => (
+            Some(outcome.reference.clone()),
+            outcome.status,
+            outcome.error.clone(),
+        ),
+        Err(_) => (
+            None,
+            payment_attempt::FAILED,
+            Some("provider_unavailable".to_string()),
+        ),
+    };
+    let attempt = Attempt {
+        order_ref,
+        provider: context.payment_provider.name(),
+        operation,
+        reference,
+        amount,
+        currency,
+        status,
+        error,
+        event_id: None,
+    };
+    if let Err(e) = context.db.postgres_db.add_payment_attempt(attempt).await {
+        println!(
+            "Error when recording the {} of {}: {}",
+            operation,
+            order_ref,
+            e.to_string()
+        );
+    }
+}
+
+fn succeeded(res: &Result<Outcome, LocalError>) -> bool {
+    matches!(res, Ok(outcome) if outcome.status == payment_attempt::SUCCEEDED)
+}
+
+async fn capture_checkout(
+    context: &Context,
+    order_ref: &str,
+    reference: &str,
+    amount: Decimal,
+    currency: &str,
+) -> Result<(), LocalError> {
+    let reference = reference.to_string();
+    let res = payments::call(&context.payment_provider, move |provider| {
+        provider.capture(&reference, amount)
+    })
+    .await;
+    record_attempt(
+        context,
+        order_ref,
+        payment_attempt::CAPTURE,
+        amount,
+        currency,
+        &res,
+    )
+    .await;
+    if !succeeded(&res) {
+        return Err(res.err().unwrap_or(LocalError::PaymentDeclined));
+    }
+
+    context
+        .db
+        .postgres_db
+        .set_payment_status(order_ref, &[orders::AUTHORIZED], orders::PAID)
+        .await?;
+
+    // GET /order/invoice issues it when this doesn't go through
+    if let Err(e) = issue_invoice(context, order_ref).await {
+        println!(
+            "Error when issuing the invoice of {}: {}",
+            order_ref,
+            e.to_string()
+        );
+    }
+    Ok(())
+}
+
+// /order/payment
+// Pays the checkout {"order_ref"} with {"payment_method"}, captured at once when
+// `payments.auto_capture` is set. A declined payment can be tried again.
+pub async fn pay(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let field = |key: &str| {
+        body.as_ref()
+            .and_then(|b| b.get(key))
+            .and_then(|v| v.as_str())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let (order_ref, payment_method) = match (field("order_ref"), field("payment_method")) {
+        (Some(order_ref), Some(payment_method)) => (order_ref, payment_method),
+        _ => {
+            return create_response(
+                StatusCode::BAD_REQUEST,
+                LocalError::WrongParameters.to_string(),
+            )
+        }
+    };
+
+    if let Err(e) = get_checkout_for(parts, &context, &order_ref, ORDERS_MANAGE).await {
+        return error_response(e);
+    }
+
+    // only one payment of the checkout at a time, for the orders it was claimed with
+    let claimed = context
+        .db
+        .postgres_db
+        .claim_payment(
+            &order_ref,
+            &[orders::UNPAID, orders::FAILED],
+            orders::PENDING,
+        )
+        .await;
+    if let Err(e) = claimed {
+        return error_response(e);
+    }
+    let (amount, currency) = checkout_total(&claimed.ok().unwrap());
+
+    let payment = PaymentRequest {
+        order_ref: order_ref.clone(),
+        amount,
+        currency: currency.clone(),
+        payment_method,
+    };
+    let res = payments::call(&context.payment_provider, move |provider| {
+        provider.authorize(&payment)
+    })
+    .await;
+    record_attempt(
+        &context,
+        &order_ref,
+        payment_attempt::AUTHORIZE,
+        amount,
+        &currency,
+        &res,
+    )
+    .await;
+
+    let (status, status_code) = match &res {
+        // nothing happened at the provider
+        Err(_) => (orders::UNPAID, StatusCode::BAD_GATEWAY),
+        Ok(outcome) if outcome.status == payment_attempt::SUCCEEDED => {
+            (orders::AUTHORIZED, StatusCode::OK)
+        }
+        // the webhook settles it
+        Ok(outcome) if outcome.status == payment_attempt::PENDING => {
+            (orders::PENDING, StatusCode::ACCEPTED)
+        }
+        Ok(_) => (orders::FAILED, StatusCode::PAYMENT_REQUIRED),
+    };
+    let res_status = context
+        .db
+        .postgres_db
+        .set_payment_status(&order_ref, &[orders::PENDING], status)
+        .await;
+    if let Err(e) = res_status {
+        return error_response(e);
+    }
+
+    if status == orders::AUTHORIZED && context.auto_capture {
+        let reference = &res.as_ref().ok().unwrap().reference;
+        if let Err(e) = capture_checkout(&context, &order_ref, reference, amount, &currency).await {
+            // the authorization holds, the capture can be tried again
+            println!("Error when capturing {}: {}", order_ref, e.to_string());
+        }
+    }
+
+    payment_response(&context, &order_ref, status_code).await
+}
+
+// /order/payment
+pub async fn get_payment(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = get_params(&parts.uri);
+    let order_ref = match params.get("order_ref") {
+        Some(order_ref) => order_ref,
+        None => {
+            return create_response(
+                StatusCode::BAD_REQUEST,
+                LocalError::WrongParameters.to_string(),
+            )
+        }
+    };
+
+    if let Err(e) = get_checkout_for(parts, &context, order_ref, ORDERS_READ).await {
+        return error_response(e);
+    }
+
+    payment_response(&context, order_ref, StatusCode::OK).await
+}
+
+// The checkout of `order_ref`, for staff managing payments, if its payment is in one of
+// the `statuses`.
+async fn get_managed_checkout(
+    parts: &Parts,
+    context: &Context,
+    statuses: &[&str],
+) -> Result<(String, Vec<orders::Model>), LocalError> {
+    authorize(parts, &context.auth_secret, ORDERS_MANAGE)?;
+
+    let params = get_params(&parts.uri);
+    let order_ref = params.get("order_ref").ok_or(LocalError::WrongParameters)?;
+    let orders = context.db.postgres_db.get_checkout(order_ref).await?;
+    if !statuses.contains(&orders[0].payment_status.as_str()) {
+        return Err(LocalError::WrongPaymentStatus);
+    }
+
+    Ok((order_ref.clone(), orders))
+}
+
+// /order/payment/capture
+pub async fn capture_payment(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let checkout = get_managed_checkout(parts, &context, &[orders::AUTHORIZED]).await;
+    if let Err(e) = checkout {
+        return error_response(e);
+    }
+    let (order_ref, orders) = checkout.ok().unwrap();
+    let (amount, currency) = checkout_total(&orders);
+
+    let reference = context
+        .db
+        .postgres_db
+        .get_payment_reference(&order_ref)
+        .await;
+    if let Err(e) = reference {
+        return error_response(e);
+    }
+
+    let status_code = match capture_checkout(
+        &context,
+        &order_ref,
+        &reference.ok().unwrap(),
+        amount,
+        &currency,
+    )
+    .await
+    {
+        Err(LocalError::PaymentDeclined) => StatusCode::PAYMENT_REQUIRED,
+        Err(e) => return error_response(e),
+        Ok(()) => StatusCode::OK,
+    };
+
+    payment_response(&context, &order_ref, status_code).await
+}
+
+// /order/payment/void
+// Releases an authorization that wasn't captured.
+pub async fn void_payment(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let checkout = get_managed_checkout(parts, &context, &[orders::AUTHORIZED]).await;
+    if let Err(e) = checkout {
+        return error_response(e);
+    }
+    let (order_ref, orders) = checkout.ok().unwrap();
+    let (amount, currency) = checkout_total(&orders);
+
+    match void_checkout(&context, &order_ref, amount, &currency).await {
+        Err(LocalError::PaymentDeclined) => {
+            payment_response(&context, &order_ref, StatusCode::PAYMENT_REQUIRED).await
+        }
+        Err(e) => error_response(e),
+        Ok(()) => payment_response(&context, &order_ref, StatusCode::OK).await,
+    }
+}
+
+async fn void_checkout(
+    context: &Context,
+    order_ref: &str,
+    amount: Decimal,
+    currency: &str,
+) -> Result<(), LocalError> {
+    let reference = context
+        .db
+        .postgres_db
+        .get_payment_reference(order_ref)
+        .await?;
+
+    let res = payments::call(&context.payment_provider, move |provider| {
+        provider.void(&reference)
+    })
+    .await;
+    record_attempt(
+        context,
+        order_ref,
+        payment_attempt::VOID,
+        amount,
+        currency,
+        &res,
+    )
+    .await;
+    if !succeeded(&res) {
+        return Err(res.err().unwrap_or(LocalError::PaymentDeclined));
+    }
+
+    context
+        .db
+        .postgres_db
+        .set_payment_status(order_ref, &[orders::AUTHORIZED], orders::VOIDED)
+        .await?;
+    Ok(())
+}
+
+// /order/payment/refund
+// Refunds `amount`, or all that is left of the payment, spread over the orders.
+pub async fn refund_payment(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let statuses = [orders::PAID, orders::PARTIALLY_REFUNDED];
+    let checkout = get_managed_checkout(parts, &context, &statuses).await;
+    if let Err(e) = checkout {
+        return error_response(e);
+    }
+    let (order_ref, orders) = checkout.ok().unwrap();
+
+    let refundable = refundable(&orders);
+    let left: Decimal = refundable.iter().map(|r| r.1).sum();
+    let amount = match get_params(&parts.uri).get("amount") {
+        None => Some(left),
+        Some(amount) => money::parse_amount(&json!(amount)),
+    };
+    let amount = amount.filter(|a| *a > Decimal::ZERO && *a <= left);
+    if amount.is_none() {
+        return error_response(LocalError::WrongParameters);
+    }
+
+    let weights: Vec<Decimal> = refundable.iter().map(|r| r.1).collect();
+    let amounts: Vec<(i32, Decimal)> = refundable
+        .iter()
+        .map(|r| r.0)
+        .zip(money::allocate(amount.unwrap(), &weights))
+        .collect();
+
+    match refund_orders(&context, &order_ref, &amounts).await {
+        Err(LocalError::PaymentDeclined) => {
+            payment_response(&context, &order_ref, StatusCode::PAYMENT_REQUIRED).await
+        }
+        Err(e) => error_response(e),
+        Ok(_) => payment_response(&context, &order_ref, StatusCode::OK).await,
+    }
+}
+
+// Gives `amounts` back on the checkout's payment, each against its order by order id, and
+// returns the total.
+async fn refund_orders(
+    context: &Context,
+    order_ref: &str,
+    amounts: &[(i32, Decimal)],
+) -> Result<Decimal, LocalError> {
+    let total: Decimal = amounts.iter().map(|a| a.1).sum();
+    if total.is_zero() {
+        return Ok(total);
+    }
+
+    let orders = context.db.postgres_db.get_checkout(order_ref).await?;
+    let statuses = [orders::PAID, orders::PARTIALLY_REFUNDED];
+    if !statuses.contains(&orders[0].payment_status.as_str()) {
+        return Err(LocalError::WrongPaymentStatus);
+    }
+    let (_, currency) = checkout_total(&orders);
+    let reference = context
+        .db
+        .postgres_db
+        .get_payment_reference(order_ref)
+        .await?;
+
+    let res = payments::call(&context.payment_provider, move |provider| {
+        provider.refund(&reference, total)
+    })
+    .await;
+    record_attempt(
+        context,
+        order_ref,
+        payment_attempt::REFUND,
+        total,
+        &currency,
+        &res,
+    )
+    .await;
+    if !succeeded(&res) {
+        return Err(res.err().unwrap_or(LocalError::PaymentDeclined));
+    }
+    context.db.postgres_db.add_refunds(amounts).await?;
+
+    let orders = context.db.postgres_db.get_checkout(order_ref).await?;
+    let left: Decimal = refundable(&orders).iter().map(|r| r.1).sum();
+    let status = if left.is_zero() {
+        orders::REFUNDED
+    } else {
+        orders::PARTIALLY_REFUNDED
+    };
+    context
+        .db
+        .postgres_db
+        .set_payment_status(order_ref, &statuses, status)
+        .await?;
+
+    Ok(total)
+}
+
+// /order/payment/webhook
+// Events of the payment provider, signed with `payments.webhook_secret`. Each event is
+// handled once; anything but a 2xx makes the provider send it again.
+pub async fn payment_webhook(
+    parts: &Parts,
+    body: Bytes,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let signature = parts
+        .headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if let Err(e) = context.webhooks.verify(signature, &body) {
+        return error_response(e);
+    }
+
+    let event: Value = serde_json::from_slice(&body).unwrap_or_default();
+    let field = |key: &str| event.get(key).and_then(|v| v.as_str());
+    let (id, event_type, reference, order_ref) = match (
+        field("id"),
+        field("type"),
+        field("reference"),
+        field("order_ref"),
+    ) {
+        (Some(id), Some(event_type), Some(reference), Some(order_ref)) => {
+            (id, event_type, reference, order_ref)
+        }
+        _ => {
+            return create_response(
+                StatusCode::BAD_REQUEST,
+                LocalError::WrongParameters.to_string(),
+            )
+        }
+    };
+
+    let (status, payment_status) = match event_type {
+        PAYMENT_SUCCEEDED => (payment_attempt::SUCCEEDED, orders::PAID),
+        PAYMENT_FAILED => (payment_attempt::FAILED, orders::FAILED),
+        // not ours to handle
+        _ => return create_response(StatusCode::OK, String::new()),
+    };
+
+    let orders = context.db.postgres_db.get_checkout(order_ref).await;
+    if let Err(e) = orders {
+        return error_response(e);
+    }
+    let (amount, currency) = checkout_total(&orders.ok().unwrap());
+
+    // only for the checkout's current payment, not one that was tried before; the status
+    // moves once, so an event sent again changes nothing
+    let current = context
+        .db
+        .postgres_db
+        .get_payment_reference(order_ref)
+        .await;
+    if current.as_deref() == Ok(reference) {
+        let res = context
+            .db
+            .postgres_db
+            .set_payment_status(
+                order_ref,
+                &[orders::PENDING, orders::AUTHORIZED],
+                payment_status,
+            )
+            .await;
+        match res {
+            Err(e) => return error_response(e),
+            Ok(false) => println!(
+                "Webhook {} came after the payment of {} was settled",
+                id, order_ref
+            ),
+            Ok(true) if payment_status == orders::PAID => {
+                if let Err(e) = issue_invoice(&context, order_ref).await {
+                    println!(
+                        "Error when issuing the invoice of {}: {}",
+                        order_ref,
+                        e.to_string()
+                    );
+                }
+            }
+            Ok(true) => {}
+        }
+    } else {
+        println!("Webhook {} is for an old payment of {}", id, order_ref);
+    }
+
+    let attempt = Attempt {
+        order_ref,
+        provider: context.payment_provider.name(),
+        operation: payment_attempt::WEBHOOK,
+        reference: Some(reference.to_string()),
+        amount: event
+            .get("amount")
+            .and_then(money::parse_amount)
+            .unwrap_or(amount),
+        currency: field("currency").unwrap_or(&currency),
+        status,
+        error: field("error").map(|e| e.to_string()),
+        event_id: Some(id.to_string()),
+    };
+    // None when the event was recorded already
+    match context.db.postgres_db.add_payment_attempt(attempt).await {
+        Err(e) => error_response(e),
+        Ok(_) => create_response(StatusCode::OK, String::new()),
+    }
+}
+
+// GETs `uri` of another service as order_manager.
+async fn service_get(context: &Context, uri: &str) -> Result<Value, LocalError> {
+    let request = hyper::Request::get(uri)
+        .header(
+            http::header::AUTHORIZATION,
+            format!("Bearer {}", service_token(&context.auth_secret, &[])),
+        )
+        .body(Body::empty())
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    if !response.status().is_success() {
+        return Err(LocalError::OperationFailed);
+    }
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    serde_json::from_slice(&body).map_err(|_| LocalError::OperationFailed)
+}
+
+// Puts units of the order back in stock at product_manager. The same `key` restocks them
+// only once, however often it is sent.
+async fn restock(
+    context: &Context,
+    order: &orders::Model,
+    quantity: i32,
+    reason: &str,
+    key: &str,
+) -> Result<(), LocalError> {
+    let movement = json!({
+        "product_id": order.product_id,
+        "variant_id": order.variant_id,
+        "kind": "return",
+        "quantity": quantity,
+        "reason": reason,
+        "order_ref": order.order_ref,
+    });
+    let request = hyper::Request::post(format!(
+        "{}{}",
+        context.product_manager.uri, context.product_manager.stock_movement_endpoint
+    ))
+    .header(IDEMPOTENCY_KEY_HEADER, key)
+    .header(
+        http::header::AUTHORIZATION,
+        format!("Bearer {}", service_token(&context.auth_secret, &[])),
+    )
+    .body(Body::from(movement.to_string()))
+    .map_err(|_| LocalError::OperationFailed)?;
+
+    let response = Client::new()
+        .request(request)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    if !response.status().is_success() {
+        return Err(LocalError::OperationFailed);
+    }
+    Ok(())
+}
+
+async fn restock_with_retry(
+    context: &Context,
+    order: &orders::Model,
+    quantity: i32,
+    reason: &str,
+    key: &str,
+) -> Result<(), LocalError> {
+    if restock(context, order, quantity, reason, key).await.is_ok() {
+        return Ok(());
+    }
+    let res = restock(context, order, quantity, reason, key).await;
+    if let Err(ref e) = res {
+        println!(
+            "Error when restocking order {}: {}",
+            order.order_id,
+            e.to_string()
+        );
+    }
+    res
+}
+
+// /order/cancel
+// Cancels the order `order_id`, or every order of the checkout `order_ref`, before they
+// ship. The units go back in stock, and what was paid for them is refunded; the orders are
+// CANCELLING until both went through.
+pub async fn cancel_order(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = get_params(&parts.uri);
+    let order_ref = match (
+        get_id_from_uri(&parts.uri, "order_id"),
+        params.get("order_ref"),
+    ) {
+        (Ok(order_id), _) => context
+            .db
+            .postgres_db
+            .get_order(order_id)
+            .await
+            .and_then(|o| o.order_ref.ok_or(LocalError::WrongParameters)),
+        (_, Some(order_ref)) => Ok(order_ref.clone()),
+        _ => Err(LocalError::WrongParameters),
+    };
+    if let Err(e) = order_ref {
+        return error_response(e);
+    }
+    let order_ref = order_ref.ok().unwrap();
+
+    let checkout = get_checkout_for(parts, &context, &order_ref, ORDERS_MANAGE).await;
+    if let Err(e) = checkout {
+        return error_response(e);
+    }
+    let checkout = checkout.ok().unwrap();
+    // the outcome of a pending payment isn't known yet
+    if checkout[0].payment_status == orders::PENDING {
+        return error_response(LocalError::WrongPaymentStatus);
+    }
+
+    let order_ids: Vec<i32> = match get_id_from_uri(&parts.uri, "order_id") {
+        Ok(order_id) => vec![order_id],
+        Err(_) => checkout
+            .iter()
+            .filter(|o| o.status != orders::CANCELLED)
+            .map(|o| o.order_id)
+            .collect(),
+    };
+    // orders are only cancelled once their units are back in stock and, when paid, their
+    // refund went through; until then they are CANCELLING, not shipped but not done with,
+    // and cancelling them again retries what is left
+    let paid = [orders::PAID, orders::PARTIALLY_REFUNDED];
+    let paid = paid.contains(&checkout[0].payment_status.as_str());
+    let cancelled = context
+        .db
+        .postgres_db
+        .cancel_orders(&order_ids, orders::CANCELLING)
+        .await;
+    if let Err(e) = cancelled {
+        return error_response(e);
+    }
+    let mut cancelled = cancelled.ok().unwrap();
+
+    let mut restocked = Ok(());
+    let mut done = Vec::new();
+    for order in cancelled.iter() {
+        let res = if order.restocked {
+            Ok(())
+        } else {
+            let reason = format!("order {} cancelled", order.order_id);
+            let key = format!("cancel:{}", order.order_id);
+            match restock_with_retry(&context, order, order.quantity, &reason, &key).await {
+                Ok(()) => {
+                    context
+                        .db
+                        .postgres_db
+                        .set_order_restocked(order.order_id)
+                        .await
+                }
+                Err(e) => Err(e),
+            }
+        };
+        match res {
+            Ok(()) => done.push(order.order_id),
+            Err(e) => restocked = Err(e),
+        }
+    }
+
+    let refunded = match checkout[0].payment_status.as_str() {
+        orders::PAID | orders::PARTIALLY_REFUNDED => {
+            let amounts: Vec<(i32, Decimal)> = cancelled
+                .iter()
+                .map(|o| (o.order_id, (o.total_price - o.refunded).max(Decimal::ZERO)))
+                .collect();
+            refund_orders(&context, &order_ref, &amounts).await
+        }
+        // nothing was taken yet; the capture leaves the cancelled orders out, and with
+        // all of them cancelled the authorization is released
+        orders::AUTHORIZED => match context.db.postgres_db.get_checkout(&order_ref).await {
+            Ok(orders) if checkout_total(&orders).0.is_zero() => {
+                let authorized = money::round(orders.iter().map(|o| o.total_price).sum());
+                void_checkout(&context, &order_ref, authorized, &orders[0].currency)
+                    .await
+                    .map(|_| Decimal::ZERO)
+            }
+            _ => Ok(Decimal::ZERO),
+        },
+        _ => Ok(Decimal::ZERO),
+    };
+    if let Err(ref e) = refunded {
+        println!(
+            "Error when refunding the cancellation of {}: {}",
+            order_ref,
+            e.to_string()
+        );
+    }
+
+    if (refunded.is_ok() || !paid) && !done.is_empty() {
+        match context
+            .db
+            .postgres_db
+            .cancel_orders(&done, orders::CANCELLED)
+            .await
+        {
+            Ok(done) => {
+                for order in done {
+                    if let Some(o) = cancelled.iter_mut().find(|o| o.order_id == order.order_id) {
+                        *o = order;
+                    }
+                }
+            }
+            Err(e) => println!(
+                "Error when cancelling the orders of {}: {}",
+                order_ref,
+                e.to_string()
+            ),
+        }
+    }
+
+    let payment = get_payment_summary(&context, &order_ref)
+        .await
+        .unwrap_or_default();
+    let ids = |status: &str| -> Vec<i32> {
+        cancelled
+            .iter()
+            .filter(|o| o.status == status)
+            .map(|o| o.order_id)
+            .collect()
+    };
+    let body = json!({
+        "cancelled": ids(orders::CANCELLED),
+        // waiting for their units to go back in stock or their refund
+        "cancelling": ids(orders::CANCELLING),
+        "restock_error": restocked.err().map(|e| e.to_string()),
+        "refunded": refunded.as_ref().ok(),
+        "refund_error": refunded.err().map(|e| e.to_string()),
+        "payment": payment,
+    });
+    create_response(StatusCode::OK, body.to_string())
+}
+
+// Fulfillments need the checkout paid for.
+async fn check_paid(context: &Context, order_ref: &str) -> Result<(), LocalError> {
+    let orders = context.db.postgres_db.get_checkout(order_ref).await?;
+    let paid = [orders::PAID, orders::PARTIALLY_REFUNDED];
+    if !paid.contains(&orders[0].payment_status.as_str()) {
+        return Err(LocalError::WrongPaymentStatus);
+    }
+    Ok(())
+}
+
+fn fulfillment_json(fulfillment: &fulfillment::Model, items: &[fulfillment_item::Model]) -> Value {
+    let mut res = json!(fulfillment);
+    res["items"] = json!(items);
+    res
+}
+
+// /order/ship
+// Ships what is left of the order `order_id` in one parcel, with `carrier` and
+// `tracking_number` if given.
+pub async fn ship_order(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, ORDERS_MANAGE) {
+        return response_auth_error(e);
+    }
+
+    let order_id = get_id_from_uri(&parts.uri, "order_id");
+    if let Err(e) = order_id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+    let order_id = order_id.ok().unwrap();
+
+    let order_ref = context
+        .db
+        .postgres_db
+        .get_order(order_id)
+        .await
+        .and_then(|o| o.order_ref.ok_or(LocalError::WrongParameters));
+    if let Err(e) = order_ref {
+        return error_response(e);
+    }
+    let order_ref = order_ref.ok().unwrap();
+    if let Err(e) = check_paid(&context, &order_ref).await {
+        return error_response(e);
+    }
+
+    let params = get_params(&parts.uri);
+    let items = vec![NewFulfillmentItem {
+        order_id,
+        quantity: None,
+    }];
+    let res = context
+        .db
+        .postgres_db
+        .add_fulfillment(
+            &order_ref,
+            params.get("carrier").cloned(),
+            params.get("tracking_number").cloned(),
+            items,
+        )
+        .await;
+    if let Err(e) = res {
+        return error_response(e);
+    }
+
+    match context.db.postgres_db.get_order(order_id).await {
+        Err(e) => error_response(e),
+        Ok(order) => create_response(StatusCode::OK, json!(order).to_string()),
+    }
+}
+
+// /order/fulfillment
+// A parcel sent for the checkout: {"order_ref", "carrier", "tracking_number",
+// "items": [{"order_id", "quantity"}]}. Without items, everything left to ship is in it.
+pub async fn add_fulfillment(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, ORDERS_MANAGE) {
+        return response_auth_error(e);
+    }
+
+    let body = body.unwrap_or_default();
+    let text = |key: &str| {
+        body.get(key)
+            .and_then(|v| v.as_str())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let order_ref = text("order_ref");
+    let carrier = text("carrier");
+    let tracking_number = text("tracking_number");
+    let items: Option<Vec<NewFulfillmentItem>> = match body.get("items") {
+        None => Some(Vec::new()),
+        Some(items) => items.as_array().and_then(|items| {
+            items
+                .iter()
+                .map(|item| {
+                    Some(NewFulfillmentItem {
+                        order_id: item.get("order_id")?.as_i64()? as i32,
+                        quantity: Some(item.get("quantity")?.as_i64()? as i32),
+                    })
+                })
+                .collect()
+        }),
+    };
+    if order_ref.is_none() || carrier.is_none() || tracking_number.is_none() || items.is_none() {
+        return error_response(LocalError::WrongParameters);
+    }
+    let order_ref = order_ref.unwrap();
+
+    if let Err(e) = check_paid(&context, &order_ref).await {
+        return error_response(e);
+    }
+
+    match context
+        .db
+        .postgres_db
+        .add_fulfillment(&order_ref, carrier, tracking_number, items.unwrap())
+        .await
+    {
+        Err(e) => error_response(e),
+        Ok((fulfillment, items)) => create_response(
+            StatusCode::OK,
+            fulfillment_json(&fulfillment, &items).to_string(),
+        ),
+    }
+}
+
+// /order/fulfillment/deliver
+pub async fn deliver_fulfillment(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, ORDERS_MANAGE) {
+        return response_auth_error(e);
+    }
+
+    let fulfillment_id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = fulfillment_id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .deliver_fulfillment(fulfillment_id.ok().unwrap())
+        .await
+    {
+        Err(e) => error_response(e),
+        Ok(fulfillment) => create_response(StatusCode::OK, json!(fulfillment).to_string()),
+    }
+}
+
+// /order/fulfillments
+// The parcels of the checkout `order_ref`, to its buyer or to staff reading orders.
+pub async fn get_fulfillments(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = get_params(&parts.uri);
+    let order_ref = params.get("order_ref");
+    if order_ref.is_none() {
+        return error_response(LocalError::WrongParameters);
+    }
+    let order_ref = order_ref.unwrap();
+
+    if let Err(e) = get_checkout_for(parts, &context, order_ref, ORDERS_READ).await {
+        return error_response(e);
+    }
+
+    match context.db.postgres_db.get_fulfillments(order_ref).await {
+        Err(e) => error_response(e),
+        Ok(fulfillments) => {
+            let fulfillments: Vec<Value> = fulfillments
+                .iter()
+                .map(|(fulfillment, items)| fulfillment_json(fulfillment, items))
+                .collect();
+            create_response(StatusCode::OK, json!(fulfillments).to_string())
+        }
+    }
+}
+
+fn return_json(order_return: &order_return::Model, items: &[order_return_item::Model]) -> Value {
+    let mut res = json!(order_return);
+    res["items"] = json!(items);
+    res
+}
+
+// /order/return
+// A return request for shipped items of one checkout:
+// {"items": [{"order_id", "quantity", "reason"}], "note"}.
+pub async fn add_return(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let auth = auth.ok().unwrap();
+
+    let body = body.unwrap_or_default();
+    let items: Option<Vec<NewReturnItem>> =
+        body.get("items")
+            .and_then(|i| i.as_array())
+            .and_then(|items| {
+                items
+                    .iter()
+                    .map(|item| {
+                        Some(NewReturnItem {
+                            order_id: item.get("order_id")?.as_i64()? as i32,
+                            quantity: item.get("quantity")?.as_i64()? as i32,
+                            reason: item.get("reason")?.as_str()?.to_string(),
+                        })
+                    })
+                    .collect()
+            });
+    if items.is_none() {
+        return error_response(LocalError::WrongParameters);
+    }
+    let note = body
+        .get("note")
+        .and_then(|n| n.as_str())
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty());
+
+    // staff may request it for the customer
+    let user_id = match body.get("user_id").and_then(|id| id.as_i64()) {
+        Some(user_id) if auth.has_permission(ORDERS_MANAGE) => user_id as i32,
+        _ => auth.user_id,
+    };
+
+    match context
+        .db
+        .postgres_db
+        .add_return(user_id, note, items.unwrap())
+        .await
+    {
+        Err(e) => error_response(e),
+        Ok((order_return, items)) => create_response(
+            StatusCode::OK,
+            return_json(&order_return, &items).to_string(),
+        ),
+    }
+}
+
+// The return `id`, to the customer who asked for it or to staff with `permission`.
+async fn get_return_for(
+    parts: &Parts,
+    context: &Context,
+    permission: &str,
+) -> Result<(order_return::Model, Vec<order_return_item::Model>), LocalError> {
+    let auth = authenticate(parts, &context.auth_secret)?;
+    let return_id = get_id_from_uri(&parts.uri, "id")?;
+    let (order_return, items) = context.db.postgres_db.get_return(return_id).await?;
+    if order_return.user_id != auth.user_id && !auth.has_permission(permission) {
+        return Err(LocalError::Forbidden);
+    }
+
+    Ok((order_return, items))
+}
+
+// /order/return
+pub async fn get_return(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    match get_return_for(parts, &context, ORDERS_READ).await {
+        Err(e) => error_response(e),
+        Ok((order_return, items)) => create_response(
+            StatusCode::OK,
+            return_json(&order_return, &items).to_string(),
+        ),
+    }
+}
+
+// /order/returns
+// The caller's returns, or for staff all of them, by `user_id` and `status` if given.
+pub async fn get_returns(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let auth = auth.ok().unwrap();
+
+    let params = get_params(&parts.uri);
+    let user_id = if auth.has_permission(ORDERS_READ) {
+        get_id_from_uri(&parts.uri, "user_id").ok()
+    } else {
+        Some(auth.user_id)
+    };
+    let limit: u64 = params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .filter(|l| *l > 0)
+        .unwrap_or(50);
+
+    match context
+        .db
+        .postgres_db
+        .get_returns(user_id, params.get("status").map(|s| s.as_str()), limit)
+        .await
+    {
+        Err(e) => error_response(e),
+        Ok(returns) => create_response(StatusCode::OK, json!(returns).to_string()),
+    }
+}
+
+// Moves the return `id` a step on, for staff managing returns.
+async fn move_return(
+    parts: &Parts,
+    context: &Context,
+    from: &str,
+    to: &str,
+) -> Result<(order_return::Model, Vec<order_return_item::Model>), LocalError> {
+    let (order_return, _) = get_return_for(parts, context, ORDERS_MANAGE).await?;
+    authorize(parts, &context.auth_secret, ORDERS_MANAGE)?;
+
+    let moved = context
+        .db
+        .postgres_db
+        .set_return_status(order_return.return_id, from, to)
+        .await?;
+    if !moved {
+        return Err(LocalError::WrongOrderStatus);
+    }
+
+    context
+        .db
+        .postgres_db
+        .get_return(order_return.return_id)
+        .await
+}
+
+// /order/return/approve
+pub async fn approve_return(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let res = move_return(
+        parts,
+        &context,
+        order_return::REQUESTED,
+        order_return::APPROVED,
+    )
+    .await;
+    match res {
+        Err(e) => error_response(e),
+        Ok((order_return, items)) => create_response(
+            StatusCode::OK,
+            return_json(&order_return, &items).to_string(),
+        ),
+    }
+}
+
+// /order/return/reject
+pub async fn reject_return(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let res = move_return(
+        parts,
+        &context,
+        order_return::REQUESTED,
+        order_return::REJECTED,
+    )
+    .await;
+    match res {
+        Err(e) => error_response(e),
+        Ok((order_return, items)) => create_response(
+            StatusCode::OK,
+            return_json(&order_return, &items).to_string(),
+        ),
+    }
+}
+
+// /order/return/receive
+// The returned items arrived; they go back in stock unless `restock=false`, e.g. when
+// they came back damaged. The return is RECEIVING until every item is back in stock, and
+// receiving it again retries the items that aren't.
+pub async fn receive_return(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let res = get_return_for(parts, &context, ORDERS_MANAGE).await;
+    if let Err(e) = res {
+        return error_response(e);
+    }
+    if let Err(e) = authorize(parts, &context.auth_secret, ORDERS_MANAGE) {
+        return response_auth_error(e);
+    }
+    let (order_return, items) = res.ok().unwrap();
+    let return_id = order_return.return_id;
+    let params = get_params(&parts.uri);
+    let restock = params.get("restock").map(|r| r.as_str()) != Some("false");
+
+    let from = order_return.status.as_str();
+    if from != order_return::APPROVED && from != order_return::RECEIVING {
+        return error_response(LocalError::WrongOrderStatus);
+    }
+    let to = if restock {
+        order_return::RECEIVING
+    } else {
+        order_return::RECEIVED
+    };
+    if from != to {
+        match context
+            .db
+            .postgres_db
+            .set_return_status(return_id, from, to)
+            .await
+        {
+            Err(e) => return error_response(e),
+            Ok(false) => return error_response(LocalError::WrongOrderStatus),
+            Ok(true) => {}
+        }
+    }
+
+    let mut restocked = Ok(());
+    if restock {
+        for item in items.iter().filter(|i| !i.restocked) {
+            let res = match context.db.postgres_db.get_order(item.order_id).await {
+                Ok(order) => {
+                    let reason = format!("return {} of order {}", return_id, order.order_id);
+                    let key = format!("return:{}", item.return_item_id);
+                    match restock_with_retry(&context, &order, item.quantity, &reason, &key).await {
+                        Ok(()) => {
+                            context
+                                .db
+                                .postgres_db
+                                .set_return_item_restocked(item.return_item_id)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            if res.is_err() {
+                restocked = res;
+            }
+        }
+        if restocked.is_ok() {
+            restocked = context.db.postgres_db.set_return_restocked(return_id).await;
+        }
+        if restocked.is_ok() {
+            restocked = context
+                .db
+                .postgres_db
+                .set_return_status(return_id, order_return::RECEIVING, order_return::RECEIVED)
+                .await
+                .map(|_| ());
+        }
+    }
+
+    match context.db.postgres_db.get_return(return_id).await {
+        Err(e) => error_response(e),
+        Ok((order_return, items)) => {
+            let mut body = return_json(&order_return, &items);
+            if let Err(e) = restocked {
+                body["restock_error"] = json!(e.to_string());
+            }
+            create_response(StatusCode::OK, body.to_string())
+        }
+    }
+}
+
+// /order/return/refund
+// Refunds the received items: `amount`, or by default each unit's share of what was paid
+// for its order.
+pub async fn refund_return(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let res = get_return_for(parts, &context, ORDERS_MANAGE).await;
+    if let Err(e) = res {
+        return error_response(e);
+    }
+    if let Err(e) = authorize(parts, &context.auth_secret, ORDERS_MANAGE) {
+        return response_auth_error(e);
+    }
+    let (order_return, items) = res.ok().unwrap();
+    if order_return.status != order_return::RECEIVED {
+        return error_response(LocalError::WrongOrderStatus);
+    }
+
+    let orders = context
+        .db
+        .postgres_db
+        .get_checkout(&order_return.order_ref)
+        .await;
+    if let Err(e) = orders {
+        return error_response(e);
+    }
+    let orders = orders.ok().unwrap();
+
+    // each unit's share of its order, never more than is left of the order
+    let mut left: std::collections::HashMap<i32, Decimal> =
+        refundable(&orders).into_iter().collect();
+    let due: Vec<Decimal> = items
+        .iter()
+        .map(|item| {
+            let order = orders.iter().find(|o| o.order_id == item.order_id);
+            let share = order
+                .map(|o| {
+                    money::round(
+                        o.total_price * Decimal::from(item.quantity)
+                            / Decimal::from(o.quantity.max(1)),
+                    )
+                })
+                .unwrap_or_default();
+            let left = left.entry(item.order_id).or_default();
+            let share = share.min(*left);
+            *left -= share;
+            share
+        })
+        .collect();
+    let total_due: Decimal = due.iter().sum();
+
+    let amount = match get_params(&parts.uri).get("amount") {
+        None => Some(total_due),
+        Some(amount) => money::parse_amount(&json!(amount)),
+    };
+    let amount = amount.filter(|a| !a.is_sign_negative() && *a <= total_due);
+    if amount.is_none() {
+        return error_response(LocalError::WrongParameters);
+    }
+    let shares = money::allocate(amount.unwrap(), &due);
+
+    // claimed first, so that it is refunded once
+    let moved = context
+        .db
+        .postgres_db
+        .set_return_status(
+            order_return.return_id,
+            order_return::RECEIVED,
+            order_return::REFUNDED,
+        )
+        .await;
+    match moved {
+        Err(e) => return error_response(e),
+        Ok(false) => return error_response(LocalError::WrongOrderStatus),
+        Ok(true) => {}
+    }
+
+    let amounts: Vec<(i32, Decimal)> = items
+        .iter()
+        .map(|i| i.order_id)
+        .zip(shares.iter().copied())
+        .collect();
+    if let Err(e) = refund_orders(&context, &order_return.order_ref, &amounts).await {
+        let _ = context
+            .db
+            .postgres_db
+            .set_return_status(
+                order_return.return_id,
+                order_return::REFUNDED,
+                order_return::RECEIVED,
+            )
+            .await;
+        return error_response(e);
+    }
+
+    let item_amounts: Vec<(i32, Decimal)> =
+        items.iter().map(|i| i.return_item_id).zip(shares).collect();
+    if let Err(e) = context
+        .db
+        .postgres_db
+        .set_return_refunds(order_return.return_id, &item_amounts)
+        .await
+    {
+        println!(
+            "Error when recording the refund of return {}: {}",
+            order_return.return_id,
+            e.to_string()
+        );
+    }
+
+    match context
+        .db
+        .postgres_db
+        .get_return(order_return.return_id)
+        .await
+    {
+        Err(e) => error_response(e),
+        Ok((order_return, items)) => create_response(
+            StatusCode::OK,
+            return_json(&order_return, &items).to_string(),
+        ),
+    }
+}
+
+// Issues the invoice of the paid checkout unless it has one already, with the buyer's
+// details from user_manager and the products' names from product_manager.
+async fn issue_invoice(context: &Context, order_ref: &str) -> Result<invoice::Model, LocalError> {
+    if let Some(invoice) = context.db.postgres_db.get_invoice(order_ref).await? {
+        return Ok(invoice);
+    }
+
+    let orders = context.db.postgres_db.get_checkout(order_ref).await?;
+    let paid = [orders::PAID, orders::PARTIALLY_REFUNDED, orders::REFUNDED];
+    if !paid.contains(&orders[0].payment_status.as_str()) {
+        return Err(LocalError::WrongPaymentStatus);
+    }
+    if checkout_total(&orders).0.is_zero() {
+        return Err(LocalError::WrongOrderStatus);
+    }
+
+    let user_id = orders[0].user_id;
+    let profile = service_get(
+        context,
+        &format!(
+            "{}{}?user_id={}",
+            context.user_manager.uri, context.user_manager.profile_endpoint, user_id
+        ),
+    )
+    .await?;
+    let buyer = Buyer::from_profile(user_id, &profile, orders[0].billing_address.as_ref());
+
+    let ids: Vec<String> = orders.iter().map(|o| o.product_id.to_string()).collect();
+    let products = service_get(
+        context,
+        &format!(
+            "{}{}?ids={}",
+            context.product_manager.uri,
+            context.product_manager.products_endpoint,
+            ids.join(",")
+        ),
+    )
+    .await?;
+    let names: HashMap<i32, String> = products
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|p| {
+            Some((
+                p.get("product_id")?.as_i64()? as i32,
+                p.get("name")?.as_str()?.to_string(),
+            ))
+        })
+        .collect();
+
+    let invoices = &context.invoices;
+    context
+        .db
+        .postgres_db
+        .add_invoice(order_ref, &invoices.number_prefix, |number, issued_at| {
+            let invoice = invoices.invoice(number, issued_at, &orders, buyer, &names);
+            NewInvoice {
+                user_id,
+                currency: invoice.currency.clone(),
+                total: invoice.totals.total,
+                data: json!(invoice),
+                html: render_html(&invoice),
+                pdf: render_pdf(&invoice),
+            }
+        })
+        .await
+}
+
+// /order/invoice
+// The invoice of the checkout of `order_id`, as a PDF or with `format` as "html" or "json".
+// It is issued when the checkout is paid, or here if that didn't go through.
+pub async fn get_invoice(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authenticate(parts, &context.auth_secret) {
+        return response_auth_error(e);
+    }
+
+    let order_id = get_id_from_uri(&parts.uri, "order_id");
+    if let Err(e) = order_id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+    let order = context
+        .db
+        .postgres_db
+        .get_order(order_id.ok().unwrap())
+        .await;
+    let order_ref = order.and_then(|o| o.order_ref.ok_or(LocalError::IdNotFound));
+    if let Err(e) = order_ref {
+        return error_response(e);
+    }
+    let order_ref = order_ref.ok().unwrap();
+
+    if let Err(e) = get_checkout_for(parts, &context, &order_ref, ORDERS_READ).await {
+        return error_response(e);
+    }
+    let invoice = issue_invoice(&context, &order_ref).await;
+    if let Err(e) = invoice {
+        return error_response(e);
+    }
+    let invoice = invoice.ok().unwrap();
+
+    let params = get_params(&parts.uri);
+    let (content_type, disposition, body) = match params.get("format").map(|f| f.as_str()) {
+        None | Some("pdf") => (
+            "application/pdf",
+            "attachment",
+            Body::from(invoice.pdf.clone()),
+        ),
+        Some("html") => (
+            "text/html; charset=utf-8",
+            "inline",
+            Body::from(invoice.html.clone()),
+        ),
+        Some("json") => (
+            "application/json",
+            "inline",
+            Body::from(json!(invoice).to_string()),
+        ),
+        _ => return error_response(LocalError::WrongParameters),
+    };
+    let extension = match content_type {
+        "application/pdf" => "pdf",
+        "application/json" => "json",
+        _ => "html",
+    };
+    let disposition = format!(
+        "{}; filename=\"{}.{}\"",
+        disposition, invoice.invoice_number, extension
+    );
+
+    let mut response = create_response(StatusCode::OK, String::new())?;
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+        headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+    *response.body_mut() = body;
+    Ok(response)
+}
+// What every report is asked with: the format, the range of dates and for some a limit.
+struct ReportQuery {
+    format: reports::Format,
+    range: Range,
+    limit: u64,
+    params: HashMap<String, String>,
+}
+
+// Reports are for staff reading orders.
+fn get_report_query(parts: &Parts, context: &Context) -> Result<ReportQuery, LocalError> {
+    authorize(parts, &context.auth_secret, ORDERS_READ)?;
+
+    let params = get_params(&parts.uri);
+    let format = match params.get("format") {
+        None => Some(reports::Format::Json),
+        Some(format) => reports::Format::parse(format),
+    }
+    .ok_or(LocalError::WrongParameters)?;
+    let range = reports::parse_range(&params)?;
+    let limit = match params.get("limit") {
+        None => reports::DEFAULT_LIMIT,
+        Some(limit) => limit
+            .parse()
+            .ok()
+            .filter(|l| *l > 0 && *l <= reports::MAX_LIMIT)
+            .ok_or(LocalError::WrongParameters)?,
+    };
+
+    Ok(ReportQuery {
+        format,
+        range,
+        limit,
+        params,
+    })
+}
+
+// The report's rows, as a CSV file called `name` when asked for one.
+fn report_response(
+    name: &str,
+    columns: &[&str],
+    format: reports::Format,
+    rows: Result<Vec<Value>, LocalError>,
+) -> Result<Response<Body>, hyper::Error> {
+    let data = rows.and_then(|rows| reports::write_rows(&rows, columns, format));
+    if let Err(e) = data {
+        return error_response(e);
+    }
+
+    let mut response = create_response(StatusCode::OK, String::new())?;
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    if format == reports::Format::Csv {
+        let disposition = format!("attachment; filename=\"{}.csv\"", name);
+        if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+            headers.insert(CONTENT_DISPOSITION, disposition);
+        }
+    }
+    *response.body_mut() = Body::from(data.ok().unwrap());
+    Ok(response)
+}
+
+// /order/report/sales
+// Revenue and orders by `period`: "day", "week" or "month".
+pub async fn get_sales_report(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = get_report_query(parts, &context);
+    if let Err(e) = query {
+        return error_response(e);
+    }
+    let query = query.ok().unwrap();
+
+    let period = match query.params.get("period") {
+        None => Some(Period::Day),
+        Some(period) => Period::parse(period),
+    };
+    if period.is_none() {
+        return error_response(LocalError::WrongParameters);
+    }
+
+    let rows = context
+        .db
+        .postgres_db
+        .get_sales_report(period.unwrap(), &query.range)
+        .await
+        .map(reports::sales_rows);
+    report_response("sales", &reports::SALES_COLUMNS, query.format, rows)
+}
+
+// /order/report/products
+// The best selling products, by revenue or with `sort` as "units" by units sold.
+pub async fn get_products_report(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = get_report_query(parts, &context);
+    if let Err(e) = query {
+        return error_response(e);
+    }
+    let query = query.ok().unwrap();
+
+    let order = match query.params.get("sort").map(|s| s.as_str()) {
+        None | Some("revenue") => ProductOrder::Revenue,
+        Some("units") => ProductOrder::Units,
+        _ => return error_response(LocalError::WrongParameters),
+    };
+
+    let rows = context
+        .db
+        .postgres_db
+        .get_products_report(order, &query.range, query.limit)
+        .await
+        .map(reports::product_rows);
+    report_response("products", &reports::PRODUCT_COLUMNS, query.format, rows)
+}
+
+// /order/report/customers
+// What customers spent, the most first: their lifetime value unless a range is given.
+pub async fn get_customers_report(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = get_report_query(parts, &context);
+    if let Err(e) = query {
+        return error_response(e);
+    }
+    let query = query.ok().unwrap();
+
+    let rows = context
+        .db
+        .postgres_db
+        .get_customers_report(&query.range, query.limit)
+        .await
+        .map(reports::customer_rows);
+    report_response("customers", &reports::CUSTOMER_COLUMNS, query.format, rows)
+}
+
+// /order/report/summary
+// Totals of the range with the average order value, per currency.
+pub async fn get_sales_summary(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let query = get_report_query(parts, &context);
+    if let Err(e) = query {
+        return error_response(e);
+    }
+    let query = query.ok().unwrap();
+
+    let rows = context
+        .db
+        .postgres_db
+        .get_sales_summary(&query.range)
+        .await
+        .map(reports::summary_rows);
+    report_response("summary", &reports::SUMMARY_COLUMNS, query.format, rows)
+}
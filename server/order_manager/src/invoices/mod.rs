@@ -0,0 +1,444 @@
+use crate::invoices::pdf::{fit, Document, PAGE_HEIGHT, PAGE_WIDTH};
+use chrono::NaiveDateTime;
+use common::money;
+use common::settings::Settings;
+use order_manager::entities::orders;
+use sea_orm::prelude::Decimal;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+pub mod pdf;
+
+#[derive(Clone, Serialize)]
+pub struct Seller {
+    pub name: String,
+    pub address: String,
+    pub tax_id: String,
+}
+
+#[derive(Serialize)]
+pub struct Buyer {
+    pub user_id: i32,
+    pub name: String,
+    pub email: String,
+    pub phone: String,
+    // lines of the billing address, if the orders have one
+    pub address: Vec<String>,
+}
+
+impl Buyer {
+    // From the account at user_manager, and the billing address the orders were placed with.
+    pub fn from_profile(user_id: i32, profile: &Value, address: Option<&Value>) -> Buyer {
+        let field = |key: &str| {
+            profile
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        Buyer {
+            user_id,
+            name: field("full_name"),
+            email: field("email"),
+            phone: field("phone"),
+            address: address.map(address_lines).unwrap_or_default(),
+        }
+    }
+}
+
+fn address_lines(address: &Value) -> Vec<String> {
+    let field = |key: &str| {
+        address
+            .get(key)
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+    };
+    [
+        field("line1").to_string(),
+        field("line2").to_string(),
+        format!("{} {}", field("postal_code"), field("city")),
+        field("region").to_string(),
+        field("country").to_string(),
+    ]
+    .into_iter()
+    .map(|line| line.trim().to_string())
+    .filter(|line| !line.is_empty())
+    .collect()
+}
+
+#[derive(Serialize)]
+pub struct Line {
+    pub order_id: i32,
+    pub product_id: i32,
+    pub variant_id: Option<i32>,
+    pub description: String,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+    pub discount: Decimal,
+    // percent
+    pub tax_rate: Decimal,
+    pub tax: Decimal,
+    // quantity * unit_price - discount
+    pub amount: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct TaxLine {
+    pub rate: Decimal,
+    pub tax: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct Totals {
+    pub subtotal: Decimal,
+    pub discount: Decimal,
+    pub shipping: Decimal,
+    pub tax: Decimal,
+    // by rate
+    pub taxes: Vec<TaxLine>,
+    // the prices include the tax, it isn't added to the total
+    pub tax_included: bool,
+    pub total: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct Invoice {
+    pub invoice_number: String,
+    pub issued_at: NaiveDateTime,
+    pub order_ref: String,
+    pub currency: String,
+    pub seller: Seller,
+    pub buyer: Buyer,
+    pub lines: Vec<Line>,
+    pub totals: Totals,
+}
+
+// Invoices of paid checkouts, from the `invoices` section: the seller shown on them and the
+// prefix of their numbers.
+pub struct Invoices {
+    pub number_prefix: String,
+    pub seller: Seller,
+}
+
+impl Invoices {
+    pub fn init(settings: &Settings) -> Invoices {
+        Invoices {
+            number_prefix: settings.get("invoices", "number_prefix"),
+            seller: Seller {
+                name: settings.get("invoices", "seller_name"),
+                address: settings.get("invoices", "seller_address"),
+                tax_id: settings.get("invoices", "seller_tax_id"),
+            },
+        }
+    }
+
+    // The invoice of the checkout's `orders`; those cancelled before it was issued aren't
+    // on it. `names` are the products' names by product id.
+    pub fn invoice(
+        &self,
+        invoice_number: &str,
+        issued_at: NaiveDateTime,
+        orders: &[orders::Model],
+        buyer: Buyer,
+        names: &HashMap<i32, String>,
+    ) -> Invoice {
+        let orders: Vec<&orders::Model> = orders.iter().filter(|o| !o.is_cancelled()).collect();
+
+        let lines: Vec<Line> = orders
+            .iter()
+            .map(|o| {
+                let name = names
+                    .get(&o.product_id)
+                    .cloned()
+                    .unwrap_or_else(|| format!("Product {}", o.product_id));
+                let description = match o.variant_id {
+                    Some(variant_id) => format!("{} (variant {})", name, variant_id),
+                    None => name,
+                };
+                Line {
+                    order_id: o.order_id,
+                    product_id: o.product_id,
+                    variant_id: o.variant_id,
+                    description,
+                    quantity: o.quantity,
+                    unit_price: money::round(o.unit_price),
+                    discount: money::round(o.discount),
+                    tax_rate: o.tax_rate.normalize(),
+                    tax: money::round(o.tax),
+                    amount: money::round(o.subtotal - o.discount),
+                }
+            })
+            .collect();
+
+        let mut taxes: BTreeMap<Decimal, Decimal> = BTreeMap::new();
+        for o in orders.iter().filter(|o| !o.tax.is_zero()) {
+            *taxes.entry(o.tax_rate.normalize()).or_default() += o.tax;
+        }
+
+        let sum = |amount: fn(&orders::Model) -> Decimal| {
+            money::round(orders.iter().map(|o| amount(o)).sum())
+        };
+        let subtotal = sum(|o| o.subtotal);
+        let discount = sum(|o| o.discount);
+        let shipping = sum(|o| o.shipping);
+        let tax = sum(|o| o.tax);
+        let total = sum(|o| o.total_price);
+
+        Invoice {
+            invoice_number: invoice_number.to_string(),
+            issued_at,
+            order_ref: orders
+                .first()
+                .and_then(|o| o.order_ref.clone())
+                .unwrap_or_default(),
+            currency: orders
+                .first()
+                .map(|o| o.currency.clone())
+                .unwrap_or_default(),
+            seller: self.seller.clone(),
+            buyer,
+            lines,
+            totals: Totals {
+                subtotal,
+                discount,
+                shipping,
+                tax,
+                taxes: taxes
+                    .into_iter()
+                    .map(|(rate, tax)| TaxLine {
+                        rate,
+                        tax: money::round(tax),
+                    })
+                    .collect(),
+                tax_included: !tax.is_zero() && total == subtotal - discount + shipping,
+                total,
+            },
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// The amounts of the totals, with their labels.
+fn total_rows(invoice: &Invoice) -> Vec<(String, Decimal)> {
+    let totals = &invoice.totals;
+    let mut rows = vec![("Subtotal".to_string(), totals.subtotal)];
+    if !totals.discount.is_zero() {
+        rows.push(("Discount".to_string(), -totals.discount));
+    }
+    if !totals.shipping.is_zero() {
+        rows.push(("Shipping".to_string(), totals.shipping));
+    }
+    for tax in totals.taxes.iter() {
+        let label = if totals.tax_included {
+            format!("Included tax {}%", tax.rate)
+        } else {
+            format!("Tax {}%", tax.rate)
+        };
+        rows.push((label, tax.tax));
+    }
+    rows
+}
+
+pub fn render_html(invoice: &Invoice) -> String {
+    let lines: String = invoice
+        .lines
+        .iter()
+        .map(|line| {
+            format!(
+                "<tr><td>{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td>\
+                 <td class=\"n\">{}</td><td class=\"n\">{}%</td><td class=\"n\">{}</td></tr>\n",
+                escape(&line.description),
+                line.quantity,
+                line.unit_price,
+                line.discount,
+                line.tax_rate,
+                line.amount
+            )
+        })
+        .collect();
+    let totals: String = total_rows(invoice)
+        .iter()
+        .map(|(label, amount)| {
+            format!(
+                "<tr><td colspan=\"5\" class=\"n\">{}</td><td class=\"n\">{}</td></tr>\n",
+                escape(label),
+                amount
+            )
+        })
+        .collect();
+    let address: String = invoice
+        .buyer
+        .address
+        .iter()
+        .map(|line| format!("{}<br>", escape(line)))
+        .collect();
+
+    format!(
+        "<!DOCTYPE html>
+<html lang=\"en\">
+<head>
+<meta charset=\"utf-8\">
+<title>Invoice {number}</title>
+<style>
+body {{ font-family: Helvetica, Arial, sans-serif; font-size: 14px; margin: 40px; }}
+table {{ width: 100%; border-collapse: collapse; margin-top: 24px; }}
+th, td {{ padding: 6px 8px; border-bottom: 1px solid #ddd; text-align: left; }}
+.n {{ text-align: right; }}
+.parties {{ display: flex; justify-content: space-between; margin-top: 24px; }}
+.total td {{ font-weight: bold; border-bottom: none; }}
+</style>
+</head>
+<body>
+<h1>Invoice {number}</h1>
+<p>Issued {issued}<br>Order {order_ref}</p>
+<div class=\"parties\">
+<div><strong>{seller}</strong><br>{seller_address}<br>Tax ID {tax_id}</div>
+<div><strong>Billed to</strong><br>{buyer}<br>{address}{email}<br>{phone}</div>
+</div>
+<table>
+<tr><th>Description</th><th class=\"n\">Qty</th><th class=\"n\">Unit price</th>\
+<th class=\"n\">Discount</th><th class=\"n\">Tax</th><th class=\"n\">Amount</th></tr>
+{lines}{totals}<tr class=\"total\"><td colspan=\"5\" class=\"n\">Total {currency}</td>\
+<td class=\"n\">{total}</td></tr>
+</table>
+<p>Paid in full. Amounts in {currency}.</p>
+</body>
+</html>
+",
+        number = escape(&invoice.invoice_number),
+        issued = invoice.issued_at.format("%Y-%m-%d"),
+        order_ref = escape(&invoice.order_ref),
+        seller = escape(&invoice.seller.name),
+        seller_address = escape(&invoice.seller.address),
+        tax_id = escape(&invoice.seller.tax_id),
+        buyer = escape(&invoice.buyer.name),
+        address = address,
+        email = escape(&invoice.buyer.email),
+        phone = escape(&invoice.buyer.phone),
+        lines = lines,
+        totals = totals,
+        currency = escape(&invoice.currency),
+        total = invoice.totals.total,
+    )
+}
+
+const MARGIN: f32 = 50.0;
+const RIGHT: f32 = PAGE_WIDTH - MARGIN;
+// right edges of the amount columns: quantity, unit price, discount, tax, amount
+const COLUMNS: [f32; 5] = [310.0, 380.0, 440.0, 485.0, RIGHT];
+const ROW: f32 = 16.0;
+
+fn table_header(doc: &mut Document, y: f32) {
+    doc.text(MARGIN, y, 9.0, true, "Description");
+    let titles = ["Qty", "Unit price", "Discount", "Tax", "Amount"];
+    for (right, title) in COLUMNS.iter().zip(titles) {
+        doc.text_right(*right, y, 9.0, true, title);
+    }
+    doc.line(MARGIN, y - 5.0, RIGHT, y - 5.0);
+}
+
+pub fn render_pdf(invoice: &Invoice) -> Vec<u8> {
+    let mut doc = Document::default();
+    let mut y = PAGE_HEIGHT - 70.0;
+
+    doc.text(MARGIN, y, 20.0, true, "Invoice");
+    doc.text_right(RIGHT, y, 10.0, true, &invoice.invoice_number);
+    let issued = format!("Issued {}", invoice.issued_at.format("%Y-%m-%d"));
+    doc.text_right(RIGHT, y - 14.0, 9.0, false, &issued);
+    doc.text_right(
+        RIGHT,
+        y - 28.0,
+        9.0,
+        false,
+        &format!("Order {}", invoice.order_ref),
+    );
+
+    y -= 70.0;
+    let seller = [
+        invoice.seller.address.clone(),
+        format!("Tax ID {}", invoice.seller.tax_id),
+    ];
+    let mut buyer = vec![&invoice.buyer.name];
+    buyer.extend(invoice.buyer.address.iter());
+    buyer.extend([&invoice.buyer.email, &invoice.buyer.phone]);
+    doc.text(
+        MARGIN,
+        y,
+        10.0,
+        true,
+        &fit(&invoice.seller.name, 10.0, 240.0),
+    );
+    doc.text(320.0, y, 10.0, true, "Billed to");
+    for (i, line) in seller.iter().enumerate() {
+        doc.text(
+            MARGIN,
+            y - 14.0 * (i + 1) as f32,
+            9.0,
+            false,
+            &fit(line, 9.0, 240.0),
+        );
+    }
+    for (i, line) in buyer.iter().enumerate() {
+        doc.text(
+            320.0,
+            y - 14.0 * (i + 1) as f32,
+            9.0,
+            false,
+            &fit(line, 9.0, 225.0),
+        );
+    }
+
+    y -= 48.0 + 14.0 * buyer.len().max(seller.len()) as f32;
+    table_header(&mut doc, y);
+    y -= ROW + 4.0;
+    for line in invoice.lines.iter() {
+        if y < 80.0 {
+            doc.new_page();
+            y = PAGE_HEIGHT - 60.0;
+            table_header(&mut doc, y);
+            y -= ROW + 4.0;
+        }
+        doc.text(MARGIN, y, 9.0, false, &fit(&line.description, 9.0, 220.0));
+        let cells = [
+            line.quantity.to_string(),
+            line.unit_price.to_string(),
+            line.discount.to_string(),
+            format!("{}%", line.tax_rate),
+            line.amount.to_string(),
+        ];
+        for (right, cell) in COLUMNS.iter().zip(cells) {
+            doc.text_right(*right, y, 9.0, false, &cell);
+        }
+        y -= ROW;
+    }
+
+    let rows = total_rows(invoice);
+    if y - ROW * ((rows.len() + 3) as f32) < 50.0 {
+        doc.new_page();
+        y = PAGE_HEIGHT - 60.0;
+    }
+    doc.line(320.0, y + ROW - 5.0, RIGHT, y + ROW - 5.0);
+    y -= 4.0;
+    for (label, amount) in rows.iter() {
+        doc.text_right(COLUMNS[3], y, 9.0, false, label);
+        doc.text_right(RIGHT, y, 9.0, false, &amount.to_string());
+        y -= ROW;
+    }
+    let total = format!("Total {}", invoice.currency);
+    doc.text_right(COLUMNS[3], y, 10.0, true, &total);
+    doc.text_right(RIGHT, y, 10.0, true, &invoice.totals.total.to_string());
+
+    y -= 2.0 * ROW;
+    let paid = format!("Paid in full. Amounts in {}.", invoice.currency);
+    doc.text(MARGIN, y, 9.0, false, &paid);
+
+    doc.finish()
+}
@@ -0,0 +1,162 @@
+// Just enough of PDF 1.4 for printed documents: A4 pages of text and rules in Helvetica,
+// one of the standard fonts every reader has, so that nothing needs to be embedded.
+// Coordinates are in points from the bottom left corner of the page.
+
+pub const PAGE_WIDTH: f32 = 595.0;
+pub const PAGE_HEIGHT: f32 = 842.0;
+
+// Helvetica's widths of the printable ASCII characters, in 1/1000 of the font size, from
+// its font metrics. Helvetica-Bold is a little wider, except for digits.
+const WIDTHS: [u16; 95] = [
+    278, 278, 355, 556, 556, 889, 667, 191, 333, 333, 389, 584, 278, 333, 278, 278, 556, 556, 556,
+    556, 556, 556, 556, 556, 556, 556, 278, 278, 584, 584, 584, 556, 1015, 667, 667, 722, 722, 667,
+    611, 778, 722, 278, 500, 667, 556, 833, 722, 778, 667, 778, 722, 667, 611, 722, 667, 944, 667,
+    667, 611, 278, 278, 278, 469, 556, 333, 556, 556, 500, 556, 556, 278, 556, 556, 222, 222, 500,
+    222, 833, 556, 556, 556, 556, 333, 500, 278, 556, 500, 722, 500, 500, 500, 334, 260, 334, 584,
+];
+
+pub fn text_width(text: &str, size: f32) -> f32 {
+    let units: u32 = text
+        .chars()
+        .map(|c| match c as u32 {
+            code @ 32..=126 => WIDTHS[(code - 32) as usize] as u32,
+            _ => 556,
+        })
+        .sum();
+    units as f32 * size / 1000.0
+}
+
+// `text` cut to `width`, with an ellipsis when it doesn't fit.
+pub fn fit(text: &str, size: f32, width: f32) -> String {
+    if text_width(text, size) <= width {
+        return text.to_string();
+    }
+    let mut fitted = String::new();
+    for c in text.chars() {
+        fitted.push(c);
+        if text_width(&fitted, size) + text_width("...", size) > width {
+            fitted.pop();
+            break;
+        }
+    }
+    format!("{}...", fitted.trim_end())
+}
+
+// A string in the fonts' WinAnsi encoding, which matches Latin-1 past ASCII; other
+// characters can't be shown and become "?".
+fn pdf_string(text: &str) -> Vec<u8> {
+    let mut res = vec![b'('];
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                res.push(b'\\');
+                res.push(c as u8);
+            }
+            ' '..='~' | '\u{a0}'..='\u{ff}' => res.push(c as u32 as u8),
+            _ => res.push(b'?'),
+        }
+    }
+    res.push(b')');
+    res
+}
+
+#[derive(Default)]
+pub struct Document {
+    // content stream of each page
+    pages: Vec<Vec<u8>>,
+}
+
+impl Document {
+    pub fn new_page(&mut self) {
+        self.pages.push(Vec::new());
+    }
+
+    fn content(&mut self) -> &mut Vec<u8> {
+        if self.pages.is_empty() {
+            self.new_page();
+        }
+        self.pages.last_mut().unwrap()
+    }
+
+    pub fn text(&mut self, x: f32, y: f32, size: f32, bold: bool, text: &str) {
+        let font = if bold { "F2" } else { "F1" };
+        let mut op = format!("BT /{} {:.1} Tf {:.2} {:.2} Td ", font, size, x, y).into_bytes();
+        op.extend(pdf_string(text));
+        op.extend(b" Tj ET\n");
+        self.content().extend(op);
+    }
+
+    // Text ending at `right`, for columns of amounts.
+    pub fn text_right(&mut self, right: f32, y: f32, size: f32, bold: bool, text: &str) {
+        self.text(right - text_width(text, size), y, size, bold, text);
+    }
+
+    pub fn line(&mut self, x1: f32, y1: f32, x2: f32, y2: f32) {
+        let op = format!("0.5 w {:.2} {:.2} m {:.2} {:.2} l S\n", x1, y1, x2, y2);
+        self.content().extend(op.into_bytes());
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.content();
+        let page_count = self.pages.len();
+        // catalog, page tree, the two fonts, then a page and its content for each page
+        let page_id = |i: usize| 5 + 2 * i;
+        let kids: Vec<String> = (0..page_count)
+            .map(|i| format!("{} 0 R", page_id(i)))
+            .collect();
+
+        let mut objects: Vec<Vec<u8>> = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids.join(" "),
+                page_count
+            )
+            .into_bytes(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>"
+                .to_vec(),
+            b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>"
+                .to_vec(),
+        ];
+        for (i, content) in self.pages.into_iter().enumerate() {
+            objects.push(
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                     /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                    PAGE_WIDTH,
+                    PAGE_HEIGHT,
+                    page_id(i) + 1
+                )
+                .into_bytes(),
+            );
+            let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+            stream.extend(content);
+            stream.extend(b"\nendstream");
+            objects.push(stream);
+        }
+
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend(format!("{} 0 obj\n", i + 1).into_bytes());
+            pdf.extend(object);
+            pdf.extend(b"\nendobj\n");
+        }
+
+        let xref = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).into_bytes());
+        for offset in offsets {
+            pdf.extend(format!("{:010} 00000 n \n", offset).into_bytes());
+        }
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref
+            )
+            .into_bytes(),
+        );
+        pdf
+    }
+}
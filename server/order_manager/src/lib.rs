@@ -0,0 +1,3 @@
+// The database layer, shared with the `shopctl` admin tool.
+pub mod db;
+pub mod entities;
@@ -1,38 +1,123 @@
 extern crate core;
 
-use crate::context::Context;
+use crate::context::{Context, ProductManagerContext, UserManagerContext};
+use crate::invoices::Invoices;
+use common::auth::{check_secrets, MIN_SECRET_LEN};
+use common::idempotency::{Begin, Idempotency};
+use common::rate_limit::RateLimiter;
 use common::request_response_utils::{get_json_from_body, response_ok};
 use common::settings::Settings;
 use http::{Method, StatusCode};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{http, Body, Request, Response, Server};
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 mod context;
-mod db;
-mod entities;
 mod handlers;
+mod invoices;
+mod payments;
+mod reports;
+
+use order_manager::db;
 
 async fn route_service(
     req: Request<Body>,
     _addr: String,
+    remote_addr: SocketAddr,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
     let (parts, body) = req.into_parts();
 
+    let rate_limit = context.rate_limiter.check(&parts, remote_addr);
+    if !rate_limit.allowed {
+        return rate_limit.response();
+    }
+
     println!("method: {}, uri: {}", &parts.method, parts.uri.path());
 
+    // webhooks are signed over the raw body
+    if parts.method == Method::POST && parts.uri.path() == "/order/payment/webhook" {
+        let body = hyper::body::to_bytes(body).await?;
+        let response = handlers::payment_webhook(&parts, body, context).await;
+        return response.map(|mut response| {
+            rate_limit.add_headers(&mut response);
+            response
+        });
+    }
+
     let body_json = get_json_from_body(body).await;
 
-    match (&parts.method, parts.uri.path()) {
-        (&Method::POST, "/order/add") => handlers::add_order(body_json, context).await,
+    // handlers take the context by value
+    let route_context = context.clone();
+    let claim = match context.idempotency.begin(&parts, &body_json) {
+        Begin::Handle(claim) => claim,
+        Begin::Respond(response) => return response,
+    };
+
+    let response = match (&parts.method, parts.uri.path()) {
+        (&Method::POST, "/order/add") => handlers::add_order(&parts, body_json, context).await,
         (&Method::OPTIONS, "/order/add") => response_ok(),
+        (&Method::POST, "/order/payment") => handlers::pay(&parts, body_json, context).await,
+        (&Method::GET, "/order/payment") => handlers::get_payment(&parts, context).await,
+        (&Method::OPTIONS, "/order/payment") => response_ok(),
+        (&Method::PUT, "/order/payment/capture") => {
+            handlers::capture_payment(&parts, context).await
+        }
+        (&Method::OPTIONS, "/order/payment/capture") => response_ok(),
+        (&Method::PUT, "/order/payment/void") => handlers::void_payment(&parts, context).await,
+        (&Method::OPTIONS, "/order/payment/void") => response_ok(),
+        (&Method::PUT, "/order/payment/refund") => handlers::refund_payment(&parts, context).await,
+        (&Method::OPTIONS, "/order/payment/refund") => response_ok(),
+        (&Method::PUT, "/order/cancel") => handlers::cancel_order(&parts, context).await,
+        (&Method::OPTIONS, "/order/cancel") => response_ok(),
+        (&Method::PUT, "/order/ship") => handlers::ship_order(&parts, context).await,
+        (&Method::OPTIONS, "/order/ship") => response_ok(),
+        (&Method::POST, "/order/fulfillment") => {
+            handlers::add_fulfillment(&parts, body_json, context).await
+        }
+        (&Method::OPTIONS, "/order/fulfillment") => response_ok(),
+        (&Method::PUT, "/order/fulfillment/deliver") => {
+            handlers::deliver_fulfillment(&parts, context).await
+        }
+        (&Method::OPTIONS, "/order/fulfillment/deliver") => response_ok(),
+        (&Method::GET, "/order/fulfillments") => handlers::get_fulfillments(&parts, context).await,
+        (&Method::OPTIONS, "/order/fulfillments") => response_ok(),
+        (&Method::POST, "/order/return") => handlers::add_return(&parts, body_json, context).await,
+        (&Method::GET, "/order/return") => handlers::get_return(&parts, context).await,
+        (&Method::OPTIONS, "/order/return") => response_ok(),
+        (&Method::GET, "/order/returns") => handlers::get_returns(&parts, context).await,
+        (&Method::PUT, "/order/return/approve") => handlers::approve_return(&parts, context).await,
+        (&Method::OPTIONS, "/order/return/approve") => response_ok(),
+        (&Method::PUT, "/order/return/reject") => handlers::reject_return(&parts, context).await,
+        (&Method::OPTIONS, "/order/return/reject") => response_ok(),
+        (&Method::PUT, "/order/return/receive") => handlers::receive_return(&parts, context).await,
+        (&Method::OPTIONS, "/order/return/receive") => response_ok(),
+        (&Method::PUT, "/order/return/refund") => handlers::refund_return(&parts, context).await,
+        (&Method::OPTIONS, "/order/return/refund") => response_ok(),
+        (&Method::GET, "/order/invoice") => handlers::get_invoice(&parts, context).await,
+        (&Method::GET, "/order/report/sales") => handlers::get_sales_report(&parts, context).await,
+        (&Method::GET, "/order/report/products") => {
+            handlers::get_products_report(&parts, context).await
+        }
+        (&Method::GET, "/order/report/customers") => {
+            handlers::get_customers_report(&parts, context).await
+        }
+        (&Method::GET, "/order/report/summary") => {
+            handlers::get_sales_summary(&parts, context).await
+        }
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Body::empty())
             .unwrap()),
-    }
+    };
+    let response = route_context.idempotency.finish(claim, response).await;
+
+    response.map(|mut response| {
+        rate_limit.add_headers(&mut response);
+        response
+    })
 }
 
 #[tokio::main]
@@ -41,12 +126,13 @@ pub async fn run_server(settings: &Settings, context: Arc<Context>) {
     let in_addr: SocketAddr = addr.parse().unwrap();
     println!("{}", settings.get("network", "listen_on"));
 
-    let service = make_service_fn(move |_| {
+    let service = make_service_fn(move |conn: &AddrStream| {
         let addr = addr.clone();
+        let remote_addr = conn.remote_addr();
         let context = context.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
-                route_service(req, addr.clone(), context.clone())
+                route_service(req, addr.clone(), remote_addr, context.clone())
             }))
         }
     });
@@ -65,6 +151,14 @@ fn main() {
     let postgres_url = settings.get("postgres", "uri");
     let postgres_name = settings.get("postgres", "name");
 
+    if let Err(setting) = check_secrets(&settings) {
+        println!(
+            "Error: {} is a placeholder or shorter than {} bytes",
+            setting, MIN_SECRET_LEN
+        );
+        return;
+    }
+
     let context;
     match db::DB::init(&postgres_url, &postgres_name) {
         None => {
@@ -74,7 +168,59 @@ fn main() {
         Some(db) => {
             println!("Database initialized: {}", postgres_name);
 
-            context = Arc::new(Context { db });
+            let idempotency = Idempotency::init(&settings);
+            if idempotency.is_err() {
+                println!("Error when initializing idempotency store");
+                return;
+            }
+
+            let rate_limiter = RateLimiter::init(&settings);
+            if rate_limiter.is_err() {
+                println!("Error when initializing rate limiter");
+                return;
+            }
+
+            let webhooks = payments::Webhooks::init(&settings);
+            if webhooks.is_err() {
+                println!(
+                    "Error when reading payment webhook settings, the secret must be at least {} bytes and not a placeholder",
+                    MIN_SECRET_LEN
+                );
+                return;
+            }
+            let webhooks = webhooks.ok().unwrap();
+
+            let payment_provider = payments::init(&settings, &webhooks);
+            if payment_provider.is_err() {
+                println!("Error when initializing payment provider");
+                return;
+            }
+
+            let product_manager = ProductManagerContext {
+                uri: settings.get("product_manager", "uri"),
+                stock_movement_endpoint: "/product/stock/movement".to_string(),
+                products_endpoint: "/product/product/batch".to_string(),
+            };
+
+            let user_manager = UserManagerContext {
+                uri: settings.get("user_manager", "uri"),
+                profile_endpoint: "/account/profile".to_string(),
+                addresses_endpoint: "/account/addresses".to_string(),
+            };
+
+            context = Arc::new(Context {
+                db,
+                product_manager,
+                user_manager,
+                rate_limiter: rate_limiter.ok().unwrap(),
+                idempotency: idempotency.ok().unwrap(),
+                currency: settings.get("money", "currency"),
+                auth_secret: settings.get("auth", "secret"),
+                payment_provider: payment_provider.ok().unwrap(),
+                webhooks,
+                auto_capture: settings.get("payments", "auto_capture") == "true",
+                invoices: Invoices::init(&settings),
+            });
         }
     };
 
@@ -0,0 +1,217 @@
+use crate::payments::simulator::Simulator;
+use common::auth::check_secret;
+use common::settings::Settings;
+use common::utils::LocalError;
+use hmac::{Hmac, Mac};
+use sea_orm::prelude::Decimal;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod simulator;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "X-Payment-Signature";
+
+// webhook event types
+pub const PAYMENT_SUCCEEDED: &str = "payment.succeeded";
+pub const PAYMENT_FAILED: &str = "payment.failed";
+
+pub struct PaymentRequest {
+    pub order_ref: String,
+    pub amount: Decimal,
+    pub currency: String,
+    // token of the card or account, from the provider's checkout form
+    pub payment_method: String,
+}
+
+// What the provider answered, with the status of `payment_attempt`. A pending payment is
+// settled later by a webhook.
+pub struct Outcome {
+    pub reference: String,
+    pub status: &'static str,
+    pub error: Option<String>,
+}
+
+// Calls are blocking, like the provider's API, so handlers make them through `call`. An
+// `Err` means the provider couldn't be reached, a refusal is an `Outcome` with the failed
+// status.
+pub trait PaymentProvider: Send + Sync {
+    fn name(&self) -> &str;
+    fn authorize(&self, payment: &PaymentRequest) -> Result<Outcome, LocalError>;
+    fn capture(&self, reference: &str, amount: Decimal) -> Result<Outcome, LocalError>;
+    fn void(&self, reference: &str) -> Result<Outcome, LocalError>;
+    fn refund(&self, reference: &str, amount: Decimal) -> Result<Outcome, LocalError>;
+}
+
+// `payments.provider` selects the implementation; only the "simulator" for now.
+pub fn init(
+    settings: &Settings,
+    webhooks: &Webhooks,
+) -> Result<Arc<dyn PaymentProvider>, LocalError> {
+    match settings.get("payments", "provider").as_str() {
+        "simulator" => Ok(Arc::new(Simulator::init(settings, webhooks)?)),
+        _ => Err(LocalError::WrongParameters),
+    }
+}
+
+// Runs a provider call on the blocking pool, so that a slow provider doesn't hold up the
+// async workers.
+pub async fn call<T, F>(provider: &Arc<dyn PaymentProvider>, call: F) -> Result<T, LocalError>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn PaymentProvider) -> Result<T, LocalError> + Send + 'static,
+{
+    let provider = provider.clone();
+    tokio::task::spawn_blocking(move || call(provider.as_ref()))
+        .await
+        .map_err(|_| LocalError::OperationFailed)?
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Webhooks are signed with the secret shared with the provider, over the timestamp and the
+// raw body: `X-Payment-Signature: t=<unix secs>,v1=<base64url hmac-sha256 of "t.body">`.
+// Old timestamps are refused so that a captured webhook can't be replayed later.
+#[derive(Clone)]
+pub struct Webhooks {
+    secret: String,
+    tolerance_secs: u64,
+}
+
+impl Webhooks {
+    pub fn init(settings: &Settings) -> Result<Webhooks, LocalError> {
+        let tolerance_secs = settings
+            .get("payments", "webhook_tolerance_secs")
+            .parse()
+            .map_err(|_| LocalError::WrongParameters)?;
+        let secret = settings.get("payments", "webhook_secret");
+        check_secret(&secret)?;
+
+        Ok(Webhooks {
+            secret,
+            tolerance_secs,
+        })
+    }
+
+    fn mac(&self, timestamp: u64, body: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        mac
+    }
+
+    pub fn sign(&self, body: &[u8]) -> String {
+        let timestamp = now_secs();
+        let signature = self.mac(timestamp, body).finalize().into_bytes();
+        format!(
+            "t={},v1={}",
+            timestamp,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    pub fn verify(&self, header: &str, body: &[u8]) -> Result<(), LocalError> {
+        let mut timestamp = None;
+        let mut signature = None;
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", t)) => timestamp = t.parse::<u64>().ok(),
+                Some(("v1", v1)) => signature = Some(v1),
+                _ => {}
+            }
+        }
+        let timestamp = timestamp.ok_or(LocalError::InvalidSignature)?;
+        let signature = signature
+            .and_then(|s| base64::decode_config(s, base64::URL_SAFE_NO_PAD).ok())
+            .ok_or(LocalError::InvalidSignature)?;
+
+        if now_secs().abs_diff(timestamp) > self.tolerance_secs {
+            return Err(LocalError::InvalidSignature);
+        }
+        self.mac(timestamp, body)
+            .verify_slice(&signature)
+            .map_err(|_| LocalError::InvalidSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhooks(secret: &str) -> Webhooks {
+        Webhooks {
+            secret: secret.to_string(),
+            tolerance_secs: 300,
+        }
+    }
+
+    fn signature(webhooks: &Webhooks, timestamp: u64, body: &[u8]) -> String {
+        let signature = webhooks.mac(timestamp, body).finalize().into_bytes();
+        format!(
+            "t={},v1={}",
+            timestamp,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    fn is_invalid(res: Result<(), LocalError>) -> bool {
+        matches!(res, Err(LocalError::InvalidSignature))
+    }
+
+    #[test]
+    fn verify_accepts_a_fresh_signature() {
+        let webhooks = webhooks("secret");
+        let body = br#"{"reference":"pay_1"}"#;
+        assert!(webhooks.verify(&webhooks.sign(body), body).is_ok());
+        let late = signature(&webhooks, now_secs() - 200, body);
+        assert!(webhooks.verify(&late, body).is_ok());
+    }
+
+    #[test]
+    fn verify_refuses_an_expired_signature() {
+        let webhooks = webhooks("secret");
+        let body = br#"{"reference":"pay_1"}"#;
+        let old = signature(&webhooks, now_secs() - 301, body);
+        assert!(is_invalid(webhooks.verify(&old, body)));
+        let ahead = signature(&webhooks, now_secs() + 301, body);
+        assert!(is_invalid(webhooks.verify(&ahead, body)));
+    }
+
+    #[test]
+    fn verify_refuses_a_forged_signature() {
+        let guessed = webhooks("guessed");
+        let webhooks = webhooks("secret");
+        let body = br#"{"reference":"pay_1"}"#;
+        let forged = signature(&guessed, now_secs(), body);
+        assert!(is_invalid(webhooks.verify(&forged, body)));
+
+        // a genuine signature doesn't carry over to another body or timestamp
+        let signed_at = now_secs() - 10;
+        let header = signature(&webhooks, signed_at, body);
+        let other_body = br#"{"reference":"pay_2"}"#;
+        assert!(is_invalid(webhooks.verify(&header, other_body)));
+        let moved = header.replacen(&signed_at.to_string(), &(signed_at - 10).to_string(), 1);
+        assert!(is_invalid(webhooks.verify(&moved, body)));
+    }
+
+    #[test]
+    fn verify_refuses_malformed_headers() {
+        let webhooks = webhooks("secret");
+        let body = b"{}";
+        assert!(is_invalid(webhooks.verify("", body)));
+        assert!(is_invalid(webhooks.verify("v1=abc", body)));
+        let unsigned = format!("t={}", now_secs());
+        assert!(is_invalid(webhooks.verify(&unsigned, body)));
+        let garbled = format!("t={},v1=not*base64", now_secs());
+        assert!(is_invalid(webhooks.verify(&garbled, body)));
+    }
+}
@@ -0,0 +1,267 @@
+use crate::payments::{
+    Outcome, PaymentProvider, PaymentRequest, Webhooks, PAYMENT_FAILED, PAYMENT_SUCCEEDED,
+    SIGNATURE_HEADER,
+};
+use common::auth::random_token;
+use common::settings::Settings;
+use common::utils::LocalError;
+use http::header::CONTENT_TYPE;
+use hyper::{Body, Client};
+use order_manager::entities::payment_attempt;
+use sea_orm::prelude::Decimal;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// scenarios, picked by the payment method in `payment_simulator_scenarios`
+const APPROVE: &str = "approve";
+const DECLINE: &str = "decline";
+const INSUFFICIENT_FUNDS: &str = "insufficient_funds";
+const ERROR: &str = "error";
+const PENDING_APPROVE: &str = "pending_approve";
+const PENDING_DECLINE: &str = "pending_decline";
+const CAPTURE_FAILS: &str = "capture_fails";
+const REFUND_FAILS: &str = "refund_fails";
+
+struct Payment {
+    scenario: String,
+    authorized: Decimal,
+    captured: Decimal,
+    refunded: Decimal,
+    // or declined after being pending
+    voided: bool,
+}
+
+// In-process payment gateway for local and test use. Payments are kept in memory, and
+// the outcome of each one is set by its payment method, e.g. "tok_declined", so that every
+// path can be tried without a real provider. Pending payments are settled by a signed
+// webhook sent to `payment_simulator.webhook_uri`, as a real provider would.
+pub struct Simulator {
+    scenarios: BTreeMap<String, String>,
+    payments: Mutex<HashMap<String, Payment>>,
+    webhooks: Webhooks,
+    webhook_uri: String,
+    settle_after: Duration,
+}
+
+fn succeeded(reference: &str) -> Outcome {
+    Outcome {
+        reference: reference.to_string(),
+        status: payment_attempt::SUCCEEDED,
+        error: None,
+    }
+}
+
+fn failed(reference: &str, error: &str) -> Outcome {
+    Outcome {
+        reference: reference.to_string(),
+        status: payment_attempt::FAILED,
+        error: Some(error.to_string()),
+    }
+}
+
+impl Simulator {
+    pub fn init(settings: &Settings, webhooks: &Webhooks) -> Result<Simulator, LocalError> {
+        let known = [
+            APPROVE,
+            DECLINE,
+            INSUFFICIENT_FUNDS,
+            ERROR,
+            PENDING_APPROVE,
+            PENDING_DECLINE,
+            CAPTURE_FAILS,
+            REFUND_FAILS,
+        ];
+        let scenarios = settings
+            .section("payment_simulator_scenarios")
+            .cloned()
+            .unwrap_or_default();
+        if scenarios.values().any(|s| !known.contains(&s.as_str())) {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let settle_after = settings
+            .get("payment_simulator", "settle_after_ms")
+            .parse()
+            .map_err(|_| LocalError::WrongParameters)?;
+
+        Ok(Simulator {
+            scenarios,
+            payments: Mutex::new(HashMap::new()),
+            webhooks: webhooks.clone(),
+            webhook_uri: settings.get("payment_simulator", "webhook_uri"),
+            settle_after: Duration::from_millis(settle_after),
+        })
+    }
+
+    // Sends the outcome of a pending payment once `settle_after` has passed.
+    fn settle_later(&self, payment: &PaymentRequest, reference: &str, approved: bool) {
+        let (event_type, error) = if approved {
+            (PAYMENT_SUCCEEDED, None)
+        } else {
+            (PAYMENT_FAILED, Some("card_declined"))
+        };
+        let body = json!({
+            "id": format!("evt_{}", random_token()),
+            "type": event_type,
+            "reference": reference,
+            "order_ref": payment.order_ref,
+            "amount": payment.amount,
+            "currency": payment.currency,
+            "error": error,
+        })
+        .to_string();
+
+        let runtime = tokio::runtime::Handle::try_current();
+        if runtime.is_err() {
+            println!(
+                "Simulator can't send the webhook of {} outside of a runtime",
+                reference
+            );
+            return;
+        }
+
+        let webhooks = self.webhooks.clone();
+        let uri = self.webhook_uri.clone();
+        let settle_after = self.settle_after;
+        let reference = reference.to_string();
+        runtime.ok().unwrap().spawn(async move {
+            tokio::time::sleep(settle_after).await;
+
+            let request = hyper::Request::post(uri.as_str())
+                .header(CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, webhooks.sign(body.as_bytes()))
+                .body(Body::from(body));
+            if let Ok(request) = request {
+                let response = Client::new().request(request).await;
+                if let Err(e) = response {
+                    println!(
+                        "Simulator failed to send the webhook of {}: {}",
+                        reference, e
+                    );
+                }
+            }
+        });
+    }
+
+    fn scenario(&self, reference: &str) -> Result<String, LocalError> {
+        let payments = self
+            .payments
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+        payments
+            .get(reference)
+            .map(|p| p.scenario.clone())
+            .ok_or(LocalError::IdNotFound)
+    }
+}
+
+impl PaymentProvider for Simulator {
+    fn name(&self) -> &str {
+        "simulator"
+    }
+
+    fn authorize(&self, payment: &PaymentRequest) -> Result<Outcome, LocalError> {
+        let scenario = self
+            .scenarios
+            .get(&payment.payment_method)
+            .cloned()
+            .unwrap_or_else(|| APPROVE.to_string());
+        let reference = format!("sim_{}", random_token());
+
+        match scenario.as_str() {
+            ERROR => return Err(LocalError::OperationFailed),
+            DECLINE => return Ok(failed(&reference, "card_declined")),
+            INSUFFICIENT_FUNDS => return Ok(failed(&reference, "insufficient_funds")),
+            _ => {}
+        }
+
+        let approved = scenario == PENDING_APPROVE;
+        let pending = approved || scenario == PENDING_DECLINE;
+        let mut payments = self
+            .payments
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+        payments.insert(
+            reference.clone(),
+            Payment {
+                scenario,
+                authorized: payment.amount,
+                // a pending payment that goes through is captured at once
+                captured: if approved {
+                    payment.amount
+                } else {
+                    Decimal::ZERO
+                },
+                refunded: Decimal::ZERO,
+                voided: pending && !approved,
+            },
+        );
+        drop(payments);
+
+        if !pending {
+            return Ok(succeeded(&reference));
+        }
+        self.settle_later(payment, &reference, approved);
+
+        Ok(Outcome {
+            reference,
+            status: payment_attempt::PENDING,
+            error: None,
+        })
+    }
+
+    fn capture(&self, reference: &str, amount: Decimal) -> Result<Outcome, LocalError> {
+        if self.scenario(reference)? == CAPTURE_FAILS {
+            return Ok(failed(reference, "capture_failed"));
+        }
+
+        let mut payments = self
+            .payments
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+        let payment = payments.get_mut(reference).ok_or(LocalError::IdNotFound)?;
+        if payment.voided || !payment.captured.is_zero() {
+            return Ok(failed(reference, "not_capturable"));
+        }
+        if amount > payment.authorized {
+            return Ok(failed(reference, "amount_too_large"));
+        }
+        payment.captured = amount;
+
+        Ok(succeeded(reference))
+    }
+
+    fn void(&self, reference: &str) -> Result<Outcome, LocalError> {
+        let mut payments = self
+            .payments
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+        let payment = payments.get_mut(reference).ok_or(LocalError::IdNotFound)?;
+        if payment.voided || !payment.captured.is_zero() {
+            return Ok(failed(reference, "not_voidable"));
+        }
+        payment.voided = true;
+
+        Ok(succeeded(reference))
+    }
+
+    fn refund(&self, reference: &str, amount: Decimal) -> Result<Outcome, LocalError> {
+        if self.scenario(reference)? == REFUND_FAILS {
+            return Ok(failed(reference, "refund_failed"));
+        }
+
+        let mut payments = self
+            .payments
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+        let payment = payments.get_mut(reference).ok_or(LocalError::IdNotFound)?;
+        if amount > payment.captured - payment.refunded {
+            return Ok(failed(reference, "amount_too_large"));
+        }
+        payment.refunded += amount;
+
+        Ok(succeeded(reference))
+    }
+}
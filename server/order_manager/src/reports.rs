@@ -0,0 +1,288 @@
+// Sales reports for management, as JSON or as CSV for spreadsheets.
+
+use chrono::{Duration, NaiveDate};
+use common::money;
+use common::utils::LocalError;
+use order_manager::db::reports::{CustomerRow, ProductRow, Range, SalesRow, SummaryRow};
+use sea_orm::prelude::Decimal;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+pub const SALES_COLUMNS: [&str; 7] = [
+    "period",
+    "currency",
+    "orders",
+    "units",
+    "revenue",
+    "refunded",
+    "average_order_value",
+];
+
+pub const PRODUCT_COLUMNS: [&str; 5] = ["product_id", "currency", "orders", "units", "revenue"];
+
+pub const CUSTOMER_COLUMNS: [&str; 7] = [
+    "user_id",
+    "currency",
+    "orders",
+    "revenue",
+    "average_order_value",
+    "first_order",
+    "last_order",
+];
+
+pub const SUMMARY_COLUMNS: [&str; 7] = [
+    "currency",
+    "orders",
+    "units",
+    "customers",
+    "revenue",
+    "refunded",
+    "average_order_value",
+];
+
+pub const DEFAULT_LIMIT: u64 = 20;
+pub const MAX_LIMIT: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+        }
+    }
+}
+
+// `from` and `to` as dates, both days included.
+pub fn parse_range(params: &HashMap<String, String>) -> Result<Range, LocalError> {
+    let date = |key: &str| match params.get(key) {
+        None => Ok(None),
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| LocalError::WrongParameters),
+    };
+    let from = date("from")?;
+    let to = date("to")?;
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(LocalError::WrongParameters);
+        }
+    }
+
+    // from midnight, to the midnight after `to`
+    let start = |date: NaiveDate| date.and_hms_opt(0, 0, 0);
+    let to = to
+        .map(|to| {
+            to.checked_add_signed(Duration::days(1))
+                .ok_or(LocalError::WrongParameters)
+        })
+        .transpose()?;
+    Ok(Range {
+        from: from.and_then(start),
+        to: to.and_then(start),
+    })
+}
+
+fn average(revenue: Decimal, orders: i64) -> Decimal {
+    if orders == 0 {
+        return Decimal::ZERO;
+    }
+    money::round(revenue / Decimal::from(orders))
+}
+
+pub fn sales_rows(rows: Vec<SalesRow>) -> Vec<Value> {
+    rows.into_iter()
+        .map(|row| {
+            json!({
+                "period": row.period,
+                "currency": row.currency,
+                "orders": row.orders,
+                "units": row.units,
+                "revenue": money::round(row.revenue),
+                "refunded": money::round(row.refunded),
+                "average_order_value": average(row.revenue, row.orders),
+            })
+        })
+        .collect()
+}
+
+pub fn product_rows(rows: Vec<ProductRow>) -> Vec<Value> {
+    rows.into_iter()
+        .map(|row| {
+            json!({
+                "product_id": row.product_id,
+                "currency": row.currency,
+                "orders": row.orders,
+                "units": row.units,
+                "revenue": money::round(row.revenue),
+            })
+        })
+        .collect()
+}
+
+pub fn customer_rows(rows: Vec<CustomerRow>) -> Vec<Value> {
+    rows.into_iter()
+        .map(|row| {
+            json!({
+                "user_id": row.user_id,
+                "currency": row.currency,
+                "orders": row.orders,
+                "revenue": money::round(row.revenue),
+                "average_order_value": average(row.revenue, row.orders),
+                "first_order": row.first_order,
+                "last_order": row.last_order,
+            })
+        })
+        .collect()
+}
+
+pub fn summary_rows(rows: Vec<SummaryRow>) -> Vec<Value> {
+    rows.into_iter()
+        .map(|row| {
+            json!({
+                "currency": row.currency,
+                "orders": row.orders,
+                "units": row.units,
+                "customers": row.customers,
+                "revenue": money::round(row.revenue),
+                "refunded": money::round(row.refunded),
+                "average_order_value": average(row.revenue, row.orders),
+            })
+        })
+        .collect()
+}
+
+pub fn write_rows(rows: &[Value], columns: &[&str], format: Format) -> Result<Vec<u8>, LocalError> {
+    match format {
+        Format::Json => serde_json::to_vec(rows).map_err(|_| LocalError::OperationFailed),
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer
+                .write_record(columns)
+                .map_err(|_| LocalError::OperationFailed)?;
+            for row in rows {
+                let record: Vec<String> = columns
+                    .iter()
+                    .map(|column| match row.get(*column) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::String(s)) => s.clone(),
+                        Some(value) => value.to_string(),
+                    })
+                    .collect();
+                writer
+                    .write_record(&record)
+                    .map_err(|_| LocalError::OperationFailed)?;
+            }
+            writer.into_inner().map_err(|_| LocalError::OperationFailed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn day(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    fn is_wrong(res: Result<Range, LocalError>) -> bool {
+        matches!(res, Err(LocalError::WrongParameters))
+    }
+
+    #[test]
+    fn parse_range_includes_the_last_day() {
+        let range = parse_range(&params(&[("from", "2024-02-01"), ("to", "2024-02-29")]))
+            .ok()
+            .unwrap();
+        assert_eq!(range.from, day("2024-02-01").and_hms_opt(0, 0, 0));
+        assert_eq!(range.to, day("2024-03-01").and_hms_opt(0, 0, 0));
+
+        let range = parse_range(&params(&[("from", "2024-05-04"), ("to", "2024-05-04")]))
+            .ok()
+            .unwrap();
+        assert_eq!(range.to, day("2024-05-05").and_hms_opt(0, 0, 0));
+    }
+
+    #[test]
+    fn parse_range_leaves_missing_ends_open() {
+        let range = parse_range(&params(&[])).ok().unwrap();
+        assert!(range.from.is_none() && range.to.is_none());
+
+        let range = parse_range(&params(&[("from", "2024-01-01")]))
+            .ok()
+            .unwrap();
+        assert!(range.from.is_some() && range.to.is_none());
+    }
+
+    #[test]
+    fn parse_range_refuses_wrong_dates() {
+        assert!(is_wrong(parse_range(&params(&[("from", "01/02/2024")]))));
+        assert!(is_wrong(parse_range(&params(&[("to", "2023-02-29")]))));
+        assert!(is_wrong(parse_range(&params(&[
+            ("from", "2024-02-02"),
+            ("to", "2024-02-01"),
+        ]))));
+        // the last day of the calendar has no day after it
+        let last = NaiveDate::MAX.format("%Y-%m-%d").to_string();
+        assert!(is_wrong(parse_range(&params(&[("to", last.as_str())]))));
+    }
+
+    fn rows() -> Vec<Value> {
+        vec![
+            json!({"product_id": 7, "currency": "EUR", "orders": 2, "units": 3, "revenue": "59.97"}),
+            json!({"product_id": 9, "currency": "EUR, old", "orders": 1, "units": null}),
+        ]
+    }
+
+    #[test]
+    fn write_rows_as_csv_follows_the_columns() {
+        let csv = write_rows(&rows(), &PRODUCT_COLUMNS, Format::Csv)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "product_id,currency,orders,units,revenue\n\
+             7,EUR,2,3,59.97\n\
+             9,\"EUR, old\",1,,\n"
+        );
+    }
+
+    #[test]
+    fn write_rows_as_csv_has_a_header_without_rows() {
+        let csv = write_rows(&[], &PRODUCT_COLUMNS, Format::Csv).ok().unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "product_id,currency,orders,units,revenue\n"
+        );
+    }
+
+    #[test]
+    fn write_rows_as_json_keeps_the_rows() {
+        let json = write_rows(&rows(), &PRODUCT_COLUMNS, Format::Json)
+            .ok()
+            .unwrap();
+        let written: Vec<Value> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(written, rows());
+    }
+}
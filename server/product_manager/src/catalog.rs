@@ -0,0 +1,828 @@
+use crate::db::catalog::get_product_by_name;
+use crate::db::postgres::{add_product, update_product, PostgresDB};
+use crate::db::variants::{add_variant, update_variant};
+use crate::entities::product_variant;
+use common::money;
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::TransactionTrait;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+
+// Columns of a catalog row. Rows with a "sku" are variants of the product called "name".
+pub const COLUMNS: [&str; 8] = [
+    "name",
+    "sku",
+    "options",
+    "image",
+    "count",
+    "price",
+    "category",
+    "reorder_threshold",
+];
+
+const IMPORT_REASON: &str = "catalog import";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    // From the extension of a file name.
+    pub fn from_path(path: &str) -> Option<Format> {
+        Format::parse(&path.rsplit('.').next()?.to_lowercase())
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv",
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    // false when nothing was written: a dry run, or rows that failed validation
+    pub applied: bool,
+    pub created: Vec<Value>,
+    pub updated: Vec<Value>,
+    pub unchanged: usize,
+    pub errors: Vec<Value>,
+    // products written by the import, whose cache entries are stale
+    #[serde(skip)]
+    pub product_ids: Vec<i32>,
+}
+
+enum Action {
+    AddProduct(Value),
+    UpdateProduct(i32, Map<String, Value>),
+    // the product is looked up by name when applied, as it may be created by an earlier row
+    AddVariant(String, Value),
+    UpdateVariant(i32, i32, Map<String, Value>),
+}
+
+struct Planned {
+    row: usize,
+    action: Action,
+    report: Value,
+}
+
+// CSV cells are text; counts and options are read back into their JSON types, and empty
+// cells are left out like missing keys in JSON. Prices stay text, which `money::parse_amount`
+// reads without going through a float.
+fn csv_cell(column: &str, cell: &str) -> Option<Value> {
+    let cell = cell.trim();
+    if cell.is_empty() {
+        return None;
+    }
+
+    let value = match column {
+        "count" | "reorder_threshold" => cell.parse::<i64>().ok().map(|v| json!(v)),
+        "options" => serde_json::from_str(cell).ok(),
+        _ => None,
+    };
+    // values that don't parse stay text, so that validation reports them
+    Some(value.unwrap_or_else(|| json!(cell)))
+}
+
+pub fn parse_rows(data: &[u8], format: Format) -> Result<Vec<Value>, LocalError> {
+    match format {
+        Format::Json => match serde_json::from_slice(data) {
+            Ok(Value::Array(rows)) => Ok(rows),
+            _ => Err(LocalError::WrongParameters),
+        },
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(data);
+            let headers = reader
+                .headers()
+                .map_err(|_| LocalError::WrongParameters)?
+                .clone();
+
+            let mut rows = Vec::new();
+            for record in reader.records() {
+                let record = record.map_err(|_| LocalError::WrongParameters)?;
+                let mut row = Map::new();
+                for (column, cell) in headers.iter().zip(record.iter()) {
+                    let column = column.trim();
+                    if let Some(value) = csv_cell(column, cell) {
+                        row.insert(column.to_string(), value);
+                    }
+                }
+                rows.push(Value::Object(row));
+            }
+            Ok(rows)
+        }
+    }
+}
+
+pub fn write_rows(rows: &[Value], format: Format) -> Result<Vec<u8>, LocalError> {
+    match format {
+        Format::Json => serde_json::to_vec_pretty(rows).map_err(|_| LocalError::OperationFailed),
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer
+                .write_record(COLUMNS)
+                .map_err(|_| LocalError::OperationFailed)?;
+            for row in rows {
+                let record: Vec<String> = COLUMNS
+                    .iter()
+                    .map(|column| match row.get(*column) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::String(s)) => s.clone(),
+                        Some(value) => value.to_string(),
+                    })
+                    .collect();
+                writer
+                    .write_record(&record)
+                    .map_err(|_| LocalError::OperationFailed)?;
+            }
+            writer.into_inner().map_err(|_| LocalError::OperationFailed)
+        }
+    }
+}
+
+// Prices are written as decimal strings, e.g. "19.90", so that they keep their cents exactly.
+fn price_json(price: &Decimal) -> Value {
+    json!(price.to_string())
+}
+
+// Products first, then the variants, so that the rows can be imported back in order.
+pub async fn export(db: &PostgresDB) -> Result<Vec<Value>, LocalError> {
+    let (products, variants) = db.get_catalog().await?;
+    let names: HashMap<i32, &str> = products
+        .iter()
+        .map(|p| (p.product_id, p.name.as_str()))
+        .collect();
+    let with_variants: HashSet<i32> = variants.iter().map(|v| v.product_id).collect();
+
+    let mut rows = Vec::new();
+    for prod in products.iter() {
+        let mut row = json!({
+            "name": prod.name,
+            "image": prod.image,
+            "price": price_json(&prod.price),
+            "category": prod.category,
+            "reorder_threshold": prod.reorder_threshold,
+        });
+        // the stock of a product with variants is on the variant rows
+        if !with_variants.contains(&prod.product_id) {
+            row["count"] = json!(prod.count);
+        }
+        rows.push(row);
+    }
+
+    for variant in variants {
+        rows.push(json!({
+            "name": names.get(&variant.product_id),
+            "sku": variant.sku,
+            "options": variant.options,
+            "image": variant.image,
+            "count": variant.count,
+            "price": variant.price.as_ref().map(price_json),
+        }));
+    }
+    Ok(rows)
+}
+
+fn string_field(row: &Map<String, Value>, key: &str) -> Result<Option<String>, String> {
+    match row.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) if !s.trim().is_empty() => Ok(Some(s.trim().to_string())),
+        Some(_) => Err(format!("\"{}\" must be a non-empty string", key)),
+    }
+}
+
+fn count_field(row: &Map<String, Value>, key: &str) -> Result<Option<i32>, String> {
+    match row.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => match value.as_i64() {
+            Some(count) if (0..=i32::MAX as i64).contains(&count) => Ok(Some(count as i32)),
+            _ => Err(format!("\"{}\" must be a whole number of at least 0", key)),
+        },
+    }
+}
+
+fn price_field(row: &Map<String, Value>, key: &str) -> Result<Option<Decimal>, String> {
+    match row.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => money::parse_amount(value)
+            .filter(|price| !price.is_sign_negative())
+            .map(Some)
+            .ok_or(format!("\"{}\" must be a number of at least 0", key)),
+    }
+}
+
+fn options_field(row: &Map<String, Value>) -> Result<Option<Value>, String> {
+    match row.get("options") {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Object(options))
+            if !options.is_empty() && options.values().all(|v| v.is_string()) =>
+        {
+            Ok(Some(Value::Object(options.clone())))
+        }
+        Some(_) => Err("\"options\" must be an object of strings".to_string()),
+    }
+}
+
+fn axes(options: &Value) -> Vec<String> {
+    let mut axes: Vec<String> = options
+        .as_object()
+        .map(|o| o.keys().cloned().collect())
+        .unwrap_or_default();
+    axes.sort();
+    axes
+}
+
+// Records `key` as changed from `from` to `to`, unless they are equal.
+fn diff(
+    changes: &mut Map<String, Value>,
+    report: &mut Map<String, Value>,
+    key: &str,
+    from: Value,
+    to: Value,
+) {
+    if from != to {
+        changes.insert(key.to_string(), to.clone());
+        report.insert(key.to_string(), json!({ "from": from, "to": to }));
+    }
+}
+
+struct Planner<'a> {
+    db: &'a PostgresDB,
+    names: HashSet<String>,
+    skus: HashSet<String>,
+    // products created by earlier rows
+    new_products: HashSet<String>,
+    // option axes of products that get their first variants from the import
+    new_axes: HashMap<String, Vec<String>>,
+}
+
+impl<'a> Planner<'a> {
+    async fn plan_product(
+        &mut self,
+        row: &Map<String, Value>,
+    ) -> Result<Option<(Action, Value)>, String> {
+        let name = string_field(row, "name")?.ok_or("\"name\" is required")?;
+        let image = string_field(row, "image")?;
+        let count = count_field(row, "count")?;
+        let price = price_field(row, "price")?;
+        let threshold = count_field(row, "reorder_threshold")?;
+        let category_id = match row.get("category") {
+            None | Some(Value::Null) => None,
+            Some(category) => self
+                .db
+                .resolve_category(&json!({ "category": category }))
+                .await
+                .map_err(|e| e.to_string())?,
+        };
+
+        if !self.names.insert(name.clone()) {
+            return Err(format!("Duplicate product \"{}\"", name));
+        }
+
+        let existing = self
+            .db
+            .get_product_by_name(&name)
+            .await
+            .map_err(|e| e.to_string())?;
+        let product = match existing {
+            Some(product) => product,
+            None => {
+                let price = price.ok_or("\"price\" is required for a new product")?;
+                let category_id =
+                    category_id.ok_or("\"category\" is required for a new product")?;
+                self.new_products.insert(name.clone());
+
+                let product = json!({
+                    "name": name,
+                    "image": image,
+                    "count": count.unwrap_or(0),
+                    "price": price_json(&price),
+                    "category_id": category_id,
+                    "reorder_threshold": threshold,
+                });
+                return Ok(Some((Action::AddProduct(product), json!({ "name": name }))));
+            }
+        };
+
+        let mut changes = Map::new();
+        let mut report = Map::new();
+        if let Some(image) = image {
+            diff(
+                &mut changes,
+                &mut report,
+                "image",
+                json!(product.image),
+                json!(image),
+            );
+        }
+        if let Some(count) = count {
+            diff(
+                &mut changes,
+                &mut report,
+                "count",
+                json!(product.count),
+                json!(count),
+            );
+        }
+        if let Some(price) = price {
+            if price != product.price {
+                changes.insert("price".to_string(), price_json(&price));
+                report.insert(
+                    "price".to_string(),
+                    json!({ "from": price_json(&product.price), "to": price_json(&price) }),
+                );
+            }
+        }
+        if let Some(category_id) = category_id {
+            diff(
+                &mut changes,
+                &mut report,
+                "category_id",
+                json!(product.category_id),
+                json!(category_id),
+            );
+        }
+        if row.contains_key("reorder_threshold") {
+            diff(
+                &mut changes,
+                &mut report,
+                "reorder_threshold",
+                json!(product.reorder_threshold),
+                json!(threshold),
+            );
+        }
+
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        if changes.contains_key("count") {
+            let variants = self
+                .db
+                .get_variants(product.product_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            if !variants.is_empty() {
+                return Err(LocalError::VariantRequired.to_string());
+            }
+            changes.insert("reason".to_string(), json!(IMPORT_REASON));
+        }
+
+        Ok(Some((
+            Action::UpdateProduct(product.product_id, changes),
+            json!({ "name": name, "changes": report }),
+        )))
+    }
+
+    async fn plan_variant(
+        &mut self,
+        row: &Map<String, Value>,
+        sku: String,
+    ) -> Result<Option<(Action, Value)>, String> {
+        let name = string_field(row, "name")?;
+        let options = options_field(row)?;
+        let image = string_field(row, "image")?;
+        let count = count_field(row, "count")?;
+        let price = price_field(row, "price")?;
+
+        if !self.skus.insert(sku.clone()) {
+            return Err(format!("Duplicate SKU \"{}\"", sku));
+        }
+
+        let existing = self
+            .db
+            .get_variant_by_sku(&sku)
+            .await
+            .map_err(|e| e.to_string())?;
+        let variant: product_variant::Model = match existing {
+            Some(variant) => variant,
+            None => {
+                let name = name.ok_or("\"name\" of the product is required for a new variant")?;
+                let options = options.ok_or("\"options\" is required for a new variant")?;
+                self.check_new_variant(&name, &options).await?;
+
+                let variant = json!({
+                    "sku": sku,
+                    "options": options,
+                    "image": image,
+                    "count": count.unwrap_or(0),
+                    "price": price.as_ref().map(price_json),
+                });
+                return Ok(Some((
+                    Action::AddVariant(name.clone(), variant),
+                    json!({ "name": name, "sku": sku }),
+                )));
+            }
+        };
+
+        if let Some(name) = &name {
+            let product = self
+                .db
+                .get_product_by_name(name)
+                .await
+                .map_err(|e| e.to_string())?;
+            if product.map(|p| p.product_id) != Some(variant.product_id) {
+                return Err(format!("SKU \"{}\" belongs to another product", sku));
+            }
+        }
+
+        let mut changes = Map::new();
+        let mut report = Map::new();
+        if let Some(options) = options {
+            if axes(&options) != axes(&variant.options) {
+                return Err("\"options\" must keep the axes of the product".to_string());
+            }
+            diff(
+                &mut changes,
+                &mut report,
+                "options",
+                variant.options.clone(),
+                options,
+            );
+        }
+        if row.contains_key("image") {
+            diff(
+                &mut changes,
+                &mut report,
+                "image",
+                json!(variant.image),
+                json!(image),
+            );
+        }
+        if let Some(count) = count {
+            diff(
+                &mut changes,
+                &mut report,
+                "count",
+                json!(variant.count),
+                json!(count),
+            );
+        }
+        if row.contains_key("price") && price != variant.price {
+            changes.insert("price".to_string(), json!(price.as_ref().map(price_json)));
+            report.insert(
+                "price".to_string(),
+                json!({
+                    "from": variant.price.as_ref().map(price_json),
+                    "to": price.as_ref().map(price_json),
+                }),
+            );
+        }
+
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        if changes.contains_key("count") {
+            changes.insert("reason".to_string(), json!(IMPORT_REASON));
+        }
+
+        Ok(Some((
+            Action::UpdateVariant(variant.variant_id, variant.product_id, changes),
+            json!({ "name": name, "sku": sku, "changes": report }),
+        )))
+    }
+
+    // The product must exist or come from an earlier row, and all its variants share the
+    // same option axes.
+    async fn check_new_variant(&mut self, name: &str, options: &Value) -> Result<(), String> {
+        let option_axes = axes(options);
+        if let Some(known) = self.new_axes.get(name) {
+            if *known != option_axes {
+                return Err("\"options\" must keep the axes of the product".to_string());
+            }
+            return Ok(());
+        }
+
+        if !self.new_products.contains(name) {
+            let product = self
+                .db
+                .get_product_by_name(name)
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or(format!("Unknown product \"{}\"", name))?;
+            let variants = self
+                .db
+                .get_variants(product.product_id)
+                .await
+                .map_err(|e| e.to_string())?;
+            match variants.first() {
+                Some(variant) if axes(&variant.options) != option_axes => {
+                    return Err("\"options\" must keep the axes of the product".to_string());
+                }
+                // a product only gets variants while it holds no stock of its own
+                None if product.count != 0 => return Err(LocalError::ItemInStock.to_string()),
+                _ => {}
+            }
+        }
+
+        self.new_axes.insert(name.to_string(), option_axes);
+        Ok(())
+    }
+}
+
+// Upserts products by name and variants by SKU. Every row is validated first, then all are
+// written in one transaction; nothing is written when a row fails or on a dry run.
+pub async fn import(db: &PostgresDB, rows: Vec<Value>, dry_run: bool, actor: &str) -> ImportReport {
+    let mut report = ImportReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut planner = Planner {
+        db,
+        names: HashSet::new(),
+        skus: HashSet::new(),
+        new_products: HashSet::new(),
+        new_axes: HashMap::new(),
+    };
+
+    let mut plan = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        let row_number = i + 1;
+        let planned = match row.as_object() {
+            None => Err("Row must be an object".to_string()),
+            Some(row) => match string_field(row, "sku") {
+                Err(e) => Err(e),
+                Ok(None) => planner.plan_product(row).await,
+                Ok(Some(sku)) => planner.plan_variant(row, sku).await,
+            },
+        };
+
+        match planned {
+            Err(error) => report
+                .errors
+                .push(json!({ "row": row_number, "error": error })),
+            Ok(None) => report.unchanged += 1,
+            Ok(Some((action, row_report))) => plan.push(Planned {
+                row: row_number,
+                action,
+                report: row_report,
+            }),
+        }
+    }
+
+    let creates = |planned: &Planned| {
+        matches!(
+            planned.action,
+            Action::AddProduct(_) | Action::AddVariant(_, _)
+        )
+    };
+    if dry_run || !report.errors.is_empty() {
+        for planned in plan {
+            let mut row_report = planned.report.clone();
+            row_report["row"] = json!(planned.row);
+            if creates(&planned) {
+                report.created.push(row_report);
+            } else {
+                report.updated.push(row_report);
+            }
+        }
+        return report;
+    }
+
+    // all the rows are written or none; the transaction is rolled back when dropped
+    let txn = match db.db.begin().await {
+        Err(_) => {
+            let error = LocalError::OperationFailed.to_string();
+            report.errors.push(json!({ "error": error }));
+            return report;
+        }
+        Ok(txn) => txn,
+    };
+    let mut written = ImportReport::default();
+    for planned in plan {
+        let created = creates(&planned);
+        let result = match planned.action {
+            Action::AddProduct(product) => add_product(&txn, product, actor).await,
+            Action::UpdateProduct(product_id, changes) => {
+                update_product(&txn, product_id, Value::Object(changes), actor)
+                    .await
+                    .map(|_| product_id)
+            }
+            Action::AddVariant(name, mut variant) => match get_product_by_name(&txn, &name).await {
+                Ok(Some(product)) => {
+                    variant["product_id"] = json!(product.product_id);
+                    add_variant(&txn, variant, actor)
+                        .await
+                        .map(|_| product.product_id)
+                }
+                Ok(None) => Err(LocalError::IdNotFound),
+                Err(e) => Err(e),
+            },
+            Action::UpdateVariant(variant_id, product_id, changes) => {
+                update_variant(&txn, variant_id, Value::Object(changes), actor)
+                    .await
+                    .map(|_| product_id)
+            }
+        };
+
+        let mut row_report = planned.report;
+        row_report["row"] = json!(planned.row);
+        match result {
+            Err(error) => {
+                report
+                    .errors
+                    .push(json!({ "row": planned.row, "error": error.to_string() }));
+                return report;
+            }
+            Ok(product_id) => {
+                written.product_ids.push(product_id);
+                if created {
+                    written.created.push(row_report);
+                } else {
+                    written.updated.push(row_report);
+                }
+            }
+        }
+    }
+
+    if txn.commit().await.is_err() {
+        let error = LocalError::OperationFailed.to_string();
+        report.errors.push(json!({ "error": error }));
+        return report;
+    }
+    report.applied = true;
+    report.created = written.created;
+    report.updated = written.updated;
+    report.product_ids = written.product_ids;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm::DatabaseConnection;
+
+    fn row(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    // Only for rows that fail validation, which happens before the planner reads the database.
+    fn planner(db: &PostgresDB) -> Planner<'_> {
+        Planner {
+            db,
+            names: HashSet::new(),
+            skus: HashSet::new(),
+            new_products: HashSet::new(),
+            new_axes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn csv_cell_reads_counts_and_options_and_keeps_prices_as_text() {
+        assert_eq!(csv_cell("count", " 12 "), Some(json!(12)));
+        assert_eq!(csv_cell("reorder_threshold", "-3"), Some(json!(-3)));
+        assert_eq!(csv_cell("price", "19.90"), Some(json!("19.90")));
+        assert_eq!(csv_cell("price", "-0.01"), Some(json!("-0.01")));
+        assert_eq!(
+            csv_cell("options", r#"{"size": "L"}"#),
+            Some(json!({ "size": "L" }))
+        );
+        assert_eq!(csv_cell("count", "many"), Some(json!("many")));
+        assert_eq!(csv_cell("name", "Mug"), Some(json!("Mug")));
+        assert_eq!(csv_cell("count", ""), None);
+        assert_eq!(csv_cell("name", "   "), None);
+    }
+
+    #[test]
+    fn parse_rows_reads_quoted_and_empty_csv_cells() {
+        let csv = "name,sku,options,count,price\n\
+                   \"Mug, large\",,,-2,\"1,5\"\n\
+                   Shirt,SH-L,\"{\"\"size\"\": \"\"L\"\"}\",,\"\"\n";
+        let rows = parse_rows(csv.as_bytes(), Format::Csv).ok().unwrap();
+
+        assert_eq!(
+            rows,
+            vec![
+                json!({ "name": "Mug, large", "count": -2, "price": "1,5" }),
+                json!({ "name": "Shirt", "sku": "SH-L", "options": { "size": "L" } }),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rows_refuses_json_that_is_not_an_array() {
+        let result = parse_rows(br#"{"name": "Mug"}"#, Format::Json);
+        assert!(matches!(result, Err(LocalError::WrongParameters)));
+    }
+
+    #[test]
+    fn written_rows_are_read_back_unchanged() {
+        let rows = vec![
+            json!({
+                "name": "Mug \"classic\", large",
+                "image": "mug.png",
+                "count": 4,
+                "price": price_json(&Decimal::new(1990, 2)),
+                "category": "Kitchen",
+                "reorder_threshold": 2,
+            }),
+            json!({
+                "name": "Shirt",
+                "sku": "SH-L",
+                "options": { "size": "L" },
+                "count": 0,
+                "price": price_json(&Decimal::new(1, 2)),
+            }),
+        ];
+
+        for format in [Format::Csv, Format::Json] {
+            let data = write_rows(&rows, format).ok().unwrap();
+            assert_eq!(parse_rows(&data, format).ok().unwrap(), rows);
+        }
+    }
+
+    #[test]
+    fn count_field_takes_whole_numbers_of_at_least_zero() {
+        let values = row(json!({
+            "zero": 0,
+            "negative": -1,
+            "too_large": i64::from(i32::MAX) + 1,
+            "fraction": 1.5,
+            "text": "3",
+            "null": null,
+        }));
+
+        assert_eq!(count_field(&values, "zero"), Ok(Some(0)));
+        assert_eq!(count_field(&values, "null"), Ok(None));
+        assert_eq!(count_field(&values, "missing"), Ok(None));
+        for key in ["negative", "too_large", "fraction", "text"] {
+            assert!(count_field(&values, key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn price_field_reads_prices_as_decimals() {
+        let values = row(json!({
+            "text": "19.90",
+            "number": 0.1,
+            "cents": "1.005",
+            "zero": "0",
+            "negative": "-0.01",
+            "empty": "",
+            "word": "free",
+        }));
+
+        assert_eq!(
+            price_field(&values, "text"),
+            Ok(Some(Decimal::new(1990, 2)))
+        );
+        assert_eq!(
+            price_field(&values, "number"),
+            Ok(Some(Decimal::new(10, 2)))
+        );
+        assert_eq!(
+            price_field(&values, "cents"),
+            Ok(Some(Decimal::new(101, 2)))
+        );
+        assert_eq!(price_field(&values, "zero"), Ok(Some(Decimal::ZERO)));
+        assert_eq!(price_field(&values, "missing"), Ok(None));
+        for key in ["negative", "empty", "word"] {
+            assert!(price_field(&values, key).is_err(), "{}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn planner_refuses_invalid_cells() {
+        let db = PostgresDB {
+            db: DatabaseConnection::Disconnected,
+        };
+        let mut planner = planner(&db);
+
+        let csv = "name,count,price\n,1,2\nMug,-1,2\nMug,1,-2\n\"  \",1,2\n";
+        let rows = parse_rows(csv.as_bytes(), Format::Csv).ok().unwrap();
+        let mut errors = Vec::new();
+        for row in rows.iter() {
+            let planned = planner.plan_product(row.as_object().unwrap()).await;
+            errors.push(planned.err().unwrap());
+        }
+        assert_eq!(
+            errors,
+            vec![
+                "\"name\" is required",
+                "\"count\" must be a whole number of at least 0",
+                "\"price\" must be a number of at least 0",
+                "\"name\" is required",
+            ]
+        );
+
+        let variant = row(json!({ "name": "Shirt", "options": { "size": 1 } }));
+        let planned = planner.plan_variant(&variant, "SH-L".to_string()).await;
+        assert_eq!(
+            planned.err().unwrap(),
+            "\"options\" must be an object of strings"
+        );
+    }
+}
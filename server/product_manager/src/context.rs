@@ -1,20 +1,48 @@
+use crate::db::warehouses::AllocationStrategy;
 use crate::db::DB;
+use crate::notifier::Notifier;
 use crate::redis_cache::Cache;
+use crate::totals::{ShippingRates, TaxRates};
+use chrono::Duration;
+use common::idempotency::Idempotency;
+use common::money::ExchangeRates;
+use common::rate_limit::RateLimiter;
 
 pub struct Context {
     pub db: DB,
     pub cache: Cache,
+    pub auth_secret: String,
+    pub rate_limiter: RateLimiter,
+    pub idempotency: Idempotency,
     pub user_manager: UserManagerContext,
     pub order_manager: OrderManagerContext,
+    pub reservations: ReservationsContext,
+    pub allocation_strategy: AllocationStrategy,
+    pub notifier: Box<dyn Notifier>,
+    pub default_reorder_threshold: i32,
+    pub tax_rates: TaxRates,
+    pub shipping_rates: ShippingRates,
+    pub exchange_rates: ExchangeRates,
 }
 
+// `api_key` is sent as X-Api-Key, so that the other service rate limits our calls as ours
+// rather than as its customers'.
 pub struct UserManagerContext {
     pub uri: String,
+    pub api_key: String,
     pub product_viewed_endpoint: String,
     pub product_purchased_endpoint: String,
+    pub interactions_endpoint: String,
+    pub profile_endpoint: String,
 }
 
 pub struct OrderManagerContext {
     pub uri: String,
+    pub api_key: String,
     pub add_order_endpoint: String,
-}
\ No newline at end of file
+}
+
+pub struct ReservationsContext {
+    pub ttl: Duration,
+    pub max_ttl: Duration,
+}
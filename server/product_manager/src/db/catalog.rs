@@ -0,0 +1,76 @@
+use crate::db::categories::find_category_id;
+use crate::db::postgres::PostgresDB;
+use crate::entities::{category, product, product_variant};
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::{ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder};
+use serde_json::Value;
+use std::collections::HashMap;
+
+pub async fn get_product_by_name<C: ConnectionTrait>(
+    db: &C,
+    name: &str,
+) -> Result<Option<product::Model>, LocalError> {
+    product::Entity::find()
+        .filter(product::Column::Name.eq(name))
+        .one(db)
+        .await
+        .to_local_error(RecordType::Product)
+}
+
+impl PostgresDB {
+    pub async fn get_product_by_name(
+        &self,
+        name: &str,
+    ) -> Result<Option<product::Model>, LocalError> {
+        get_product_by_name(&self.db, name).await
+    }
+
+    pub async fn get_variant_by_sku(
+        &self,
+        sku: &str,
+    ) -> Result<Option<product_variant::Model>, LocalError> {
+        product_variant::Entity::find()
+            .filter(product_variant::Column::Sku.eq(sku))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // Category named by "category_id", or by "category" as a slug or a name.
+    pub async fn resolve_category(&self, product_json: &Value) -> Result<Option<i32>, LocalError> {
+        find_category_id(&self.db, product_json).await
+    }
+
+    // All products and variants, ordered by id. `category` holds the category slug, which
+    // unlike the name is unique and so can be imported back.
+    pub async fn get_catalog(
+        &self,
+    ) -> Result<(Vec<product::Model>, Vec<product_variant::Model>), LocalError> {
+        let slugs: HashMap<i32, String> = category::Entity::find()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?
+            .into_iter()
+            .map(|c| (c.category_id, c.slug))
+            .collect();
+
+        let mut products: Vec<product::Model> = product::Entity::find()
+            .order_by_asc(product::Column::ProductId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        for prod in products.iter_mut() {
+            prod.category = slugs.get(&prod.category_id).cloned().unwrap_or_default();
+        }
+
+        let variants: Vec<product_variant::Model> = product_variant::Entity::find()
+            .order_by_asc(product_variant::Column::ProductId)
+            .order_by_asc(product_variant::Column::VariantId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        Ok((products, variants))
+    }
+}
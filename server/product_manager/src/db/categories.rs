@@ -0,0 +1,335 @@
+use crate::db::postgres::PostgresDB;
+use crate::entities::{category, product};
+use common::db_utils::{unique_violation, RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::{entity::*, ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// Surfaces violations of the unique slug and of unique names among siblings.
+fn to_category_error<T>(res: Result<T, DbErr>) -> Result<T, LocalError> {
+    if let Err(ref e) = res {
+        match unique_violation(e) {
+            Some("category_slug_key") | Some("category_parent_id_name_key") => {
+                return Err(LocalError::CategoryExists)
+            }
+            _ => {}
+        }
+    }
+
+    res.to_local_error(RecordType::Product)
+}
+
+// Lowercase words joined by "-", as the migration derives them from the old category strings.
+fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+// Ids of `root` and all categories below it.
+pub(super) fn subtree(categories: &[category::Model], root: i32) -> Vec<i32> {
+    let mut ids = vec![root];
+    let mut i = 0;
+    while i < ids.len() {
+        let parent = ids[i];
+        ids.extend(
+            categories
+                .iter()
+                .filter(|c| c.parent_id == Some(parent))
+                .map(|c| c.category_id),
+        );
+        i += 1;
+    }
+    ids
+}
+
+// Nested categories under `parent_id`, each with its "children", ordered by position.
+fn tree(categories: &[category::Model], parent_id: Option<i32>) -> Vec<Value> {
+    let mut children: Vec<&category::Model> = categories
+        .iter()
+        .filter(|c| c.parent_id == parent_id)
+        .collect();
+    children.sort_by(|a, b| a.position.cmp(&b.position).then(a.name.cmp(&b.name)));
+
+    children
+        .into_iter()
+        .map(|c| {
+            let mut node = json!(c);
+            node["children"] = json!(tree(categories, Some(c.category_id)));
+            node
+        })
+        .collect()
+}
+
+// Category of a product given as "category_id", or as "category" by slug or by name.
+// None when the JSON names no category.
+pub(super) async fn find_category_id<C: ConnectionTrait>(
+    db: &C,
+    product_json: &Value,
+) -> Result<Option<i32>, LocalError> {
+    if let Some(id) = product_json.get("category_id") {
+        let id = id.as_i64().ok_or(LocalError::WrongParameters)? as i32;
+        let category: Option<category::Model> = category::Entity::find_by_id(id)
+            .one(db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        return category
+            .map(|c| Some(c.category_id))
+            .ok_or(LocalError::UnknownCategory);
+    }
+
+    let name = match product_json.get("category") {
+        None => return Ok(None),
+        Some(name) => name.as_str().ok_or(LocalError::WrongParameters)?.trim(),
+    };
+
+    let categories: Vec<category::Model> = category::Entity::find()
+        .all(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+    if let Some(category) = categories.iter().find(|c| c.slug == name) {
+        return Ok(Some(category.category_id));
+    }
+
+    // names are only unique among siblings
+    let named: Vec<&category::Model> = categories
+        .iter()
+        .filter(|c| c.name.eq_ignore_ascii_case(name))
+        .collect();
+    match named.as_slice() {
+        [category] => Ok(Some(category.category_id)),
+        _ => Err(LocalError::UnknownCategory),
+    }
+}
+
+pub(super) async fn set_category_names<C: ConnectionTrait>(
+    db: &C,
+    products: &mut [product::Model],
+) -> Result<(), LocalError> {
+    let ids: Vec<i32> = products.iter().map(|p| p.category_id).collect();
+    let names: HashMap<i32, String> = category::Entity::find()
+        .filter(category::Column::CategoryId.is_in(ids))
+        .all(db)
+        .await
+        .to_local_error(RecordType::Product)?
+        .into_iter()
+        .map(|c| (c.category_id, c.name))
+        .collect();
+
+    for prod in products.iter_mut() {
+        prod.category = names.get(&prod.category_id).cloned().unwrap_or_default();
+    }
+    Ok(())
+}
+
+impl PostgresDB {
+    pub async fn get_category_tree(&self) -> Result<Value, LocalError> {
+        let categories: Vec<category::Model> = category::Entity::find()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        Ok(json!(tree(&categories, None)))
+    }
+
+    // Slugs from each category up to the root, e.g. [apples, fruit, food].
+    pub async fn get_category_paths(&self) -> Result<HashMap<i32, Vec<String>>, LocalError> {
+        let categories: Vec<category::Model> = category::Entity::find()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let by_id: HashMap<i32, &category::Model> =
+            categories.iter().map(|c| (c.category_id, c)).collect();
+
+        let mut paths = HashMap::new();
+        for category in categories.iter() {
+            let mut path = vec![category.slug.clone()];
+            let mut parent_id = category.parent_id;
+            // a cycle can't be created, but a bad row mustn't hang the checkout
+            while let Some(parent) = parent_id.and_then(|id| by_id.get(&id)) {
+                if path.len() > categories.len() {
+                    break;
+                }
+                path.push(parent.slug.clone());
+                parent_id = parent.parent_id;
+            }
+            paths.insert(category.category_id, path);
+        }
+
+        Ok(paths)
+    }
+
+    pub async fn get_category_by_slug(&self, slug: &str) -> Result<category::Model, LocalError> {
+        let category: Option<category::Model> = category::Entity::find()
+            .filter(category::Column::Slug.eq(slug))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        category.ok_or(LocalError::IdNotFound)
+    }
+
+    // Products of a category and of all its subcategories.
+    pub async fn get_products_in_subtree(&self, category_id: i32) -> Result<Value, LocalError> {
+        let categories: Vec<category::Model> = category::Entity::find()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if !categories.iter().any(|c| c.category_id == category_id) {
+            return Err(LocalError::IdNotFound);
+        }
+
+        self.get_products_in_categories(subtree(&categories, category_id))
+            .await
+    }
+
+    // The slug defaults to the parent's slug followed by the name, e.g. "snowboard-boots".
+    pub async fn add_category(&self, category_json: Value) -> Result<i32, LocalError> {
+        let name = category_json
+            .get("name")
+            .and_then(|n| n.as_str())
+            .map(|n| n.trim())
+            .filter(|n| !n.is_empty())
+            .ok_or(LocalError::WrongParameters)?;
+
+        let parent = match category_json.get("parent_id").and_then(|id| id.as_i64()) {
+            None => None,
+            Some(id) => {
+                let parent: Option<category::Model> = category::Entity::find_by_id(id as i32)
+                    .one(&self.db)
+                    .await
+                    .to_local_error(RecordType::Product)?;
+                Some(parent.ok_or(LocalError::UnknownCategory)?)
+            }
+        };
+
+        let slug = match category_json.get("slug").and_then(|s| s.as_str()) {
+            Some(slug) => slugify(slug),
+            None => match &parent {
+                Some(parent) => slugify(&format!("{} {}", parent.slug, name)),
+                None => slugify(name),
+            },
+        };
+        if slug.is_empty() {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let position = category_json
+            .get("position")
+            .and_then(|p| p.as_i64())
+            .unwrap_or(0);
+
+        let new_category = category::ActiveModel {
+            category_id: NotSet,
+            parent_id: Set(parent.map(|p| p.category_id)),
+            name: Set(name.to_string()),
+            slug: Set(slug),
+            position: Set(position as i32),
+        };
+        let res = to_category_error(category::Entity::insert(new_category).exec(&self.db).await)?;
+        Ok(res.last_insert_id)
+    }
+
+    pub async fn update_category(
+        &self,
+        category_id: i32,
+        updates: Value,
+    ) -> Result<Value, LocalError> {
+        let categories: Vec<category::Model> = category::Entity::find()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let category = categories
+            .iter()
+            .find(|c| c.category_id == category_id)
+            .cloned()
+            .ok_or(LocalError::IdNotFound)?;
+        let mut category: category::ActiveModel = category.into();
+
+        let updates = updates.as_object().ok_or(LocalError::WrongParameters)?;
+        for (key, val) in updates.iter() {
+            match key.as_str() {
+                "name" => {
+                    let name = val.as_str().map(|n| n.trim()).unwrap_or_default();
+                    if name.is_empty() {
+                        return Err(LocalError::WrongParameters);
+                    }
+                    category.name = Set(name.to_string());
+                }
+                "slug" => {
+                    let slug = slugify(val.as_str().unwrap_or_default());
+                    if slug.is_empty() {
+                        return Err(LocalError::WrongParameters);
+                    }
+                    category.slug = Set(slug);
+                }
+                "position" => {
+                    let position = val.as_i64().ok_or(LocalError::WrongParameters)?;
+                    category.position = Set(position as i32);
+                }
+                // null moves the category to the top level
+                "parent_id" => {
+                    let parent_id = match val {
+                        Value::Null => None,
+                        _ => Some(val.as_i64().ok_or(LocalError::WrongParameters)? as i32),
+                    };
+                    if let Some(parent_id) = parent_id {
+                        if !categories.iter().any(|c| c.category_id == parent_id) {
+                            return Err(LocalError::UnknownCategory);
+                        }
+                        // a category can't be moved below itself
+                        if subtree(&categories, category_id).contains(&parent_id) {
+                            return Err(LocalError::WrongParameters);
+                        }
+                    }
+                    category.parent_id = Set(parent_id);
+                }
+                _ => {}
+            }
+        }
+
+        let category: category::Model = to_category_error(category.update(&self.db).await)?;
+
+        Ok(json!(category))
+    }
+
+    // Only empty categories can be deleted; move their products and subcategories first.
+    pub async fn delete_category(&self, category_id: i32) -> Result<(), LocalError> {
+        let child: Option<category::Model> = category::Entity::find()
+            .filter(category::Column::ParentId.eq(category_id))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let product: Option<product::Model> = product::Entity::find()
+            .filter(product::Column::CategoryId.eq(category_id))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if child.is_some() || product.is_some() {
+            return Err(LocalError::CategoryNotEmpty);
+        }
+
+        let res = category::Entity::delete_by_id(category_id)
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if res.rows_affected == 0 {
+            return Err(LocalError::IdNotFound);
+        }
+        Ok(())
+    }
+
+    // Ids of the products directly in a category, e.g. to drop them from the cache on a rename.
+    pub async fn get_category_product_ids(&self, category_id: i32) -> Result<Vec<i32>, LocalError> {
+        let products: Vec<product::Model> = product::Entity::find()
+            .filter(product::Column::CategoryId.eq(category_id))
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        Ok(products.into_iter().map(|p| p.product_id).collect())
+    }
+}
@@ -0,0 +1,12 @@
+CREATE TABLE reservation (
+     reservation_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     count INT NOT NULL,
+     holder VARCHAR ( 200 ) NOT NULL,
+     status VARCHAR ( 20 ) NOT NULL,
+     created_at TIMESTAMP NOT NULL,
+     expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX reservation_active_idx ON reservation ( product_id, expires_at ) WHERE status = 'active';
+CREATE INDEX reservation_holder_idx ON reservation ( holder );
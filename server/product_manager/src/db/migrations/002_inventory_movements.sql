@@ -0,0 +1,21 @@
+CREATE TABLE inventory_movements (
+     movement_id serial PRIMARY KEY,
+     product_id INT NOT NULL,
+     kind VARCHAR ( 20 ) NOT NULL,
+     quantity INT NOT NULL,
+     reason VARCHAR ( 500 ) NOT NULL,
+     actor VARCHAR ( 100 ) NOT NULL,
+     order_ref VARCHAR ( 200 ),
+     created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX inventory_movements_product_idx ON inventory_movements ( product_id, created_at );
+
+-- opening balance, so existing stock reconciles against the ledger
+INSERT INTO inventory_movements ( product_id, kind, quantity, reason, actor, created_at )
+SELECT product_id, 'adjustment', count, 'opening balance', 'system', NOW()
+FROM product WHERE count <> 0;
+
+INSERT INTO inventory_movements ( product_id, kind, quantity, reason, actor, created_at )
+SELECT product_id, 'reservation', SUM(count), 'opening balance', 'system', NOW()
+FROM reservation WHERE status = 'active' GROUP BY product_id;
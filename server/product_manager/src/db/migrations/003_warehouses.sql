@@ -0,0 +1,24 @@
+CREATE TABLE warehouse (
+     warehouse_id serial PRIMARY KEY,
+     name VARCHAR ( 500 ) UNIQUE NOT NULL,
+     latitude DOUBLE PRECISION,
+     longitude DOUBLE PRECISION,
+     priority INT NOT NULL DEFAULT 0,
+     active BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE product_stock (
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     warehouse_id INT NOT NULL REFERENCES warehouse ( warehouse_id ),
+     count INT NOT NULL CHECK ( count >= 0 ),
+     PRIMARY KEY ( product_id, warehouse_id )
+);
+
+-- existing stock is moved to a default warehouse
+INSERT INTO warehouse ( name ) VALUES ( 'main' );
+
+INSERT INTO product_stock ( product_id, warehouse_id, count )
+SELECT product_id, ( SELECT warehouse_id FROM warehouse WHERE name = 'main' ), count
+FROM product WHERE count > 0;
+
+ALTER TABLE inventory_movements ADD COLUMN warehouse_id INT;
@@ -0,0 +1,12 @@
+ALTER TABLE product ADD COLUMN reorder_threshold INT;
+
+CREATE TABLE stock_subscription (
+     subscription_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     user_id INT NOT NULL,
+     email VARCHAR ( 500 ) NOT NULL,
+     created_at TIMESTAMP NOT NULL,
+     UNIQUE ( product_id, user_id )
+);
+
+CREATE INDEX stock_subscription_user_idx ON stock_subscription ( user_id );
@@ -0,0 +1,46 @@
+CREATE TABLE category (
+     category_id serial PRIMARY KEY,
+     parent_id INT REFERENCES category ( category_id ),
+     name VARCHAR ( 200 ) NOT NULL,
+     slug VARCHAR ( 200 ) UNIQUE NOT NULL,
+     position INT NOT NULL DEFAULT 0,
+     UNIQUE ( parent_id, name )
+);
+
+-- spellings that only differ in case, spacing or punctuation share a slug
+CREATE FUNCTION pg_temp.category_slug ( category TEXT ) RETURNS TEXT AS $$
+     SELECT trim ( BOTH '-' FROM regexp_replace ( lower ( category ), '[^a-z0-9]+', '-', 'g' ) )
+$$ LANGUAGE SQL IMMUTABLE;
+
+-- one category per slug, named after its most used spelling
+INSERT INTO category ( name, slug )
+SELECT DISTINCT ON ( pg_temp.category_slug ( category ) )
+     regexp_replace ( trim ( category ), '\s+', ' ', 'g' ),
+     pg_temp.category_slug ( category )
+FROM product
+GROUP BY category
+ORDER BY pg_temp.category_slug ( category ), count ( * ) DESC, category;
+
+-- "Snowboard Boots" becomes "Boots" under "Snowboard", nested under the longest matching name
+UPDATE category c
+SET parent_id = p.category_id,
+    name = trim ( substr ( c.name, length ( p.name ) + 1 ) )
+FROM category p
+WHERE lower ( c.name ) LIKE lower ( p.name ) || ' %'
+  AND NOT EXISTS (
+     SELECT 1 FROM category q
+     WHERE lower ( c.name ) LIKE lower ( q.name ) || ' %'
+       AND length ( q.name ) > length ( p.name )
+  );
+
+ALTER TABLE product ADD COLUMN category_id INT REFERENCES category ( category_id );
+
+UPDATE product p
+SET category_id = c.category_id
+FROM category c
+WHERE c.slug = pg_temp.category_slug ( p.category );
+
+ALTER TABLE product ALTER COLUMN category_id SET NOT NULL;
+ALTER TABLE product DROP COLUMN category;
+
+CREATE INDEX product_category_idx ON product ( category_id );
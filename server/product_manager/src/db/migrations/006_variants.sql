@@ -0,0 +1,13 @@
+CREATE TABLE product_variant (
+     variant_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     sku VARCHAR ( 100 ) UNIQUE NOT NULL,
+     options JSONB NOT NULL,
+     count INT NOT NULL CHECK ( count >= 0 ),
+     price DECIMAL,
+     image VARCHAR ( 1000 ),
+     UNIQUE ( product_id, options )
+);
+
+ALTER TABLE reservation ADD COLUMN variant_id INT;
+ALTER TABLE inventory_movements ADD COLUMN variant_id INT;
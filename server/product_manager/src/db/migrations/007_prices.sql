@@ -0,0 +1,29 @@
+CREATE TABLE price_history (
+     price_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     price DECIMAL NOT NULL,
+     effective_from TIMESTAMP NOT NULL,
+     effective_to TIMESTAMP,
+     actor VARCHAR ( 100 ) NOT NULL,
+     reason VARCHAR ( 500 ) NOT NULL
+);
+
+CREATE INDEX price_history_product_idx ON price_history ( product_id, effective_from );
+
+CREATE TABLE price_schedule (
+     schedule_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     price DECIMAL NOT NULL CHECK ( price >= 0 ),
+     effective_from TIMESTAMP NOT NULL,
+     effective_to TIMESTAMP CHECK ( effective_to > effective_from ),
+     previous_price DECIMAL,
+     status VARCHAR ( 20 ) NOT NULL,
+     actor VARCHAR ( 100 ) NOT NULL,
+     created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX price_schedule_due_idx ON price_schedule ( effective_from ) WHERE status IN ( 'pending', 'active' );
+
+-- current prices open the history
+INSERT INTO price_history ( product_id, price, effective_from, actor, reason )
+SELECT product_id, price, now() AT TIME ZONE 'utc', 'migration', 'initial price' FROM product;
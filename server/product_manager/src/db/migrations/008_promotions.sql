@@ -0,0 +1,42 @@
+CREATE TABLE promotion (
+     promotion_id serial PRIMARY KEY,
+     name VARCHAR ( 200 ) NOT NULL,
+     kind VARCHAR ( 20 ) NOT NULL,
+     value DECIMAL NOT NULL CHECK ( value >= 0 ),
+     buy_quantity INT CHECK ( buy_quantity > 0 ),
+     get_quantity INT CHECK ( get_quantity > 0 ),
+     product_id INT REFERENCES product ( product_id ) ON DELETE CASCADE,
+     category_id INT REFERENCES category ( category_id ) ON DELETE CASCADE,
+     min_basket DECIMAL CHECK ( min_basket >= 0 ),
+     requires_coupon BOOLEAN NOT NULL DEFAULT FALSE,
+     stackable BOOLEAN NOT NULL DEFAULT TRUE,
+     priority INT NOT NULL DEFAULT 0,
+     starts_at TIMESTAMP,
+     ends_at TIMESTAMP CHECK ( ends_at > starts_at ),
+     active BOOLEAN NOT NULL DEFAULT TRUE,
+     created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE coupon (
+     coupon_id serial PRIMARY KEY,
+     promotion_id INT NOT NULL REFERENCES promotion ( promotion_id ) ON DELETE CASCADE,
+     code VARCHAR ( 50 ) UNIQUE NOT NULL,
+     usage_limit INT CHECK ( usage_limit > 0 ),
+     per_user_limit INT CHECK ( per_user_limit > 0 ),
+     times_used INT NOT NULL DEFAULT 0,
+     starts_at TIMESTAMP,
+     ends_at TIMESTAMP CHECK ( ends_at > starts_at ),
+     active BOOLEAN NOT NULL DEFAULT TRUE,
+     created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE coupon_redemption (
+     redemption_id serial PRIMARY KEY,
+     coupon_id INT NOT NULL REFERENCES coupon ( coupon_id ) ON DELETE CASCADE,
+     user_id INT NOT NULL,
+     order_ref VARCHAR ( 100 ) NOT NULL,
+     redeemed_at TIMESTAMP NOT NULL,
+     UNIQUE ( coupon_id, order_ref )
+);
+
+CREATE INDEX coupon_redemption_user_idx ON coupon_redemption ( coupon_id, user_id );
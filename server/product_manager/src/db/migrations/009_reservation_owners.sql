@@ -0,0 +1,2 @@
+ALTER TABLE reservation ADD COLUMN user_id INT;
+ALTER TABLE reservation ADD COLUMN token VARCHAR ( 100 );
@@ -3,8 +3,15 @@ use crate::db::postgres::PostgresDB;
 use mongodb::{error::Result as MongoResult, Client, Database as MongoDatabase};
 use sea_orm::{Database, DatabaseConnection, DbErr};
 
+pub mod catalog;
+pub mod categories;
 pub mod mongo;
 pub mod postgres;
+pub mod prices;
+pub mod promotions;
+pub mod stock_alerts;
+pub mod variants;
+pub mod warehouses;
 
 pub struct DB {
     pub postgres_db: PostgresDB,
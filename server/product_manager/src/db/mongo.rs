@@ -1,61 +1,268 @@
-use chrono::Utc;
-use mongodb::bson::{doc, Document};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use mongodb::options::{FindOneOptions, ReplaceOptions, UpdateOptions};
 use mongodb::{error::Result as MongoResult, Database as MongoDatabase};
+use std::collections::BTreeMap;
+
+const PRODUCTS_STATS: &str = "products_stats";
+const RECOMMENDATIONS: &str = "recommendations";
+// collection name used by the old `update_product_stats`
+const LEGACY_PRODUCTS_STATS: &str = "product_stats";
+const MAX_SAMPLES: i32 = 100;
 
 pub struct MongoDB {
     pub db: MongoDatabase,
 }
 
-impl MongoDB {
-    pub async fn add_product(&self, product_id: i32) -> MongoResult<()> {
-        let collection = self.db.collection::<Document>("products_stats");
+#[derive(Clone, Copy)]
+enum ProductEvent {
+    Viewed,
+    Purchased,
+}
 
-        let record = doc! {
-            "product_id": product_id,
-            "viewed": [],
-            "purchased": [],
-        };
+impl ProductEvent {
+    fn counter(&self) -> &'static str {
+        match self {
+            ProductEvent::Viewed => "views",
+            ProductEvent::Purchased => "purchases",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ProductEvent::Viewed => "viewed",
+            ProductEvent::Purchased => "purchased",
+        }
+    }
+}
+
+// Stats are stored as one document per product per hour:
+// { product_id, bucket, views, purchases, samples: [{ event, at }] }
+// with `samples` capped to the last MAX_SAMPLES events of the hour.
+// Buckets written by `migrate_stats` also list in `migrated_from` the old documents whose
+// events they took.
+fn hour_bucket(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.duration_trunc(Duration::hours(1)).unwrap_or(at)
+}
 
-        collection
-            .insert_one(record, None)
+fn to_bson_date(at: DateTime<Utc>) -> BsonDateTime {
+    BsonDateTime::from_millis(at.timestamp_millis())
+}
+
+impl MongoDB {
+    pub async fn record_product_viewed(&self, product_id: i32) -> MongoResult<()> {
+        self.record_events(product_id, ProductEvent::Viewed, &[Utc::now()])
             .await
-            .and_then(|_| Ok(()))
     }
 
-    pub async fn record_product_viewed(
+    pub async fn record_product_purchased(&self, product_id: i32) -> MongoResult<()> {
+        self.record_events(product_id, ProductEvent::Purchased, &[Utc::now()])
+            .await
+    }
+
+    async fn record_events(
         &self,
         product_id: i32,
-    ) -> Result<(), mongodb::error::Error> {
-        let update = doc! {"$push": {"viewed": Utc::now().to_string()}};
-
-        self.update_product_stats(product_id, update).await
+        event: ProductEvent,
+        times: &[DateTime<Utc>],
+    ) -> MongoResult<()> {
+        self.add_to_buckets(product_id, event, times, None).await
     }
 
-    pub async fn record_product_purchased(
+    // With `migrated_from`, the `_id` of an old layout document, a bucket takes that
+    // document's events only once, so that a migration stopped halfway can be run again.
+    async fn add_to_buckets(
         &self,
         product_id: i32,
-    ) -> Result<(), mongodb::error::Error> {
-        let update = doc! {"$push": {"purchased.{}": Utc::now().to_string()}};
+        event: ProductEvent,
+        times: &[DateTime<Utc>],
+        migrated_from: Option<&Bson>,
+    ) -> MongoResult<()> {
+        let mut buckets: BTreeMap<DateTime<Utc>, Vec<DateTime<Utc>>> = BTreeMap::new();
+        for at in times {
+            buckets.entry(hour_bucket(*at)).or_default().push(*at);
+        }
+
+        let collection = self.db.collection::<Document>(PRODUCTS_STATS);
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        for (bucket, times) in buckets {
+            let samples: Vec<Document> = times
+                .iter()
+                .map(|at| doc! { "event": event.name(), "at": to_bson_date(*at) })
+                .collect();
 
-        self.update_product_stats(product_id, update).await
+            let mut filter = doc! { "product_id": product_id, "bucket": to_bson_date(bucket) };
+            let mut counters = doc! { "views": 0i64, "purchases": 0i64 };
+            counters.insert(event.counter(), times.len() as i64);
+            let mut update = doc! {
+                "$inc": counters,
+                "$push": { "samples": { "$each": samples, "$slice": -MAX_SAMPLES } },
+            };
+
+            match migrated_from {
+                None => {
+                    collection
+                        .update_one(filter, update, options.clone())
+                        .await?;
+                }
+                Some(id) => {
+                    // an upsert can't tell a missing bucket from one that already has the
+                    // events, so the bucket is created first and updated only without them
+                    let empty = doc! { "$setOnInsert": { "views": 0i64, "purchases": 0i64 } };
+                    collection
+                        .update_one(filter.clone(), empty, options.clone())
+                        .await?;
+
+                    let migration = doc! { "id": id.clone(), "event": event.name() };
+                    filter.insert("migrated_from", doc! { "$ne": migration.clone() });
+                    update.insert("$addToSet", doc! { "migrated_from": migration });
+                    collection.update_one(filter, update, None).await?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
-    async fn update_product_stats(
+    // Products ordered by views and purchases since `since`; a purchase weighs as much
+    // as `purchase_weight` views.
+    pub async fn get_popular_products(
         &self,
-        product_id: i32,
-        updates: Document,
-    ) -> Result<(), mongodb::error::Error> {
-        let filter = doc! { "product_id": product_id };
-        let collection = self.db.collection::<Document>("product_stats");
+        since: DateTime<Utc>,
+        purchase_weight: f64,
+        limit: i64,
+    ) -> MongoResult<Vec<(i32, f64)>> {
+        let pipeline = vec![
+            doc! { "$match": { "bucket": { "$gte": to_bson_date(hour_bucket(since)) } } },
+            doc! { "$group": {
+                "_id": "$product_id",
+                "views": { "$sum": "$views" },
+                "purchases": { "$sum": "$purchases" },
+            } },
+            doc! { "$project": {
+                "score": { "$add": ["$views", { "$multiply": ["$purchases", purchase_weight] }] },
+            } },
+            doc! { "$sort": { "score": -1, "_id": 1 } },
+            doc! { "$limit": limit },
+        ];
 
-        let res = collection
-            .update_one(filter.clone(), updates.clone(), None)
+        let docs: Vec<Document> = self
+            .db
+            .collection::<Document>(PRODUCTS_STATS)
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
             .await?;
-        if res.matched_count == 0 {
-            let _ = self.add_product(product_id);
-            let _ = collection.update_one(filter, updates, None).await?;
+
+        Ok(docs
+            .iter()
+            .filter_map(|d| {
+                let score = d.get("score")?;
+                let score = score
+                    .as_f64()
+                    .or_else(|| score.as_i64().map(|s| s as f64))?;
+                Some((d.get_i32("_id").ok()?, score))
+            })
+            .collect())
+    }
+
+    // Materialized recommendations are stored as `{ kind, id, items: [{ product_id, score }] }`
+    // where `kind` is "product", "user" or "popular".
+    pub async fn save_recommendations(
+        &self,
+        kind: &str,
+        id: i32,
+        items: &[(i32, f64)],
+    ) -> MongoResult<()> {
+        let items: Vec<Document> = items
+            .iter()
+            .map(|(product_id, score)| doc! { "product_id": product_id, "score": score })
+            .collect();
+
+        let filter = doc! { "kind": kind, "id": id };
+        let record = doc! {
+            "kind": kind,
+            "id": id,
+            "items": items,
+            "updated_at": to_bson_date(Utc::now()),
+        };
+        let options = ReplaceOptions::builder().upsert(true).build();
+
+        self.db
+            .collection::<Document>(RECOMMENDATIONS)
+            .replace_one(filter, record, options)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn get_recommendations(&self, kind: &str, id: i32) -> MongoResult<Vec<i32>> {
+        let filter = doc! { "kind": kind, "id": id };
+        let options = FindOneOptions::builder()
+            .projection(doc! { "items.product_id": 1 })
+            .build();
+
+        let record = self
+            .db
+            .collection::<Document>(RECOMMENDATIONS)
+            .find_one(filter, options)
+            .await?;
+
+        Ok(record
+            .as_ref()
+            .and_then(|r| r.get_array("items").ok())
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|item| item.as_document()?.get_i32("product_id").ok())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    // Converts documents of the old `{ product_id, viewed: [..], purchased: [..] }`
+    // layout into hourly buckets and removes them. Safe to run again after a failure.
+    pub async fn migrate_stats(&self) -> MongoResult<u64> {
+        let mut migrated = 0;
+
+        for name in [PRODUCTS_STATS, LEGACY_PRODUCTS_STATS] {
+            let collection = self.db.collection::<Document>(name);
+            let filter = doc! { "bucket": { "$exists": false } };
+            let old_docs: Vec<Document> =
+                collection.find(filter, None).await?.try_collect().await?;
+
+            for old in old_docs {
+                let product_id = match old.get_i32("product_id") {
+                    Ok(id) => id,
+                    Err(_) => continue,
+                };
+
+                for event in [ProductEvent::Viewed, ProductEvent::Purchased] {
+                    let times: Vec<DateTime<Utc>> = old
+                        .get_array(event.name())
+                        .map(|values| {
+                            values
+                                .iter()
+                                .filter_map(|v| v.as_str())
+                                .filter_map(|s| s.parse::<DateTime<Utc>>().ok())
+                                .collect()
+                        })
+                        .unwrap_or_default();
+
+                    if !times.is_empty() {
+                        self.add_to_buckets(product_id, event, &times, old.get("_id"))
+                            .await?;
+                    }
+                }
+
+                if let Some(id) = old.get("_id") {
+                    collection.delete_one(doc! { "_id": id }, None).await?;
+                }
+                migrated += 1;
+            }
         }
 
-        Ok(())
+        Ok(migrated)
     }
 }
@@ -1,16 +1,396 @@
-use sea_orm::{entity::*, ActiveModelTrait, ActiveValue, DatabaseConnection, EntityTrait};
+use chrono::{Duration, Utc};
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DatabaseConnection,
+    EntityTrait, PaginatorTrait, QueryFilter, QueryOrder, QuerySelect, TransactionTrait,
+};
 use serde_json::{json, Map, Value};
+use std::collections::HashMap;
 
-use crate::entities::product;
+use crate::db::categories::{find_category_id, set_category_names};
+use crate::db::prices::record_price;
+use crate::db::variants::{
+    change_variant_stock, check_variant_given, lock_variant, with_variant_availability,
+};
+use crate::db::warehouses::{
+    allocate, change_stock, default_warehouse, inactive_counts, spread_change, Allocation,
+};
+use crate::entities::{
+    coupon_redemption, inventory_movements, product, product_stock, product_variant, reservation,
+};
+use common::auth::random_token;
 use common::db_utils::{RecordType, ToError};
-use common::utils::{round, LocalError};
+use common::money;
+use common::utils::LocalError;
 
-use sea_orm::prelude::Decimal;
 
 pub struct PostgresDB {
     pub db: DatabaseConnection,
 }
 
+// Units held by active reservations, per product (all products when `product_ids` is None).
+pub(super) async fn reserved_counts<C: ConnectionTrait>(
+    db: &C,
+    product_ids: Option<Vec<i32>>,
+) -> Result<HashMap<i32, i32>, LocalError> {
+    let mut query = reservation::Entity::find()
+        .select_only()
+        .column(reservation::Column::ProductId)
+        .column_as(Expr::col(reservation::Column::Count).sum(), "reserved")
+        .filter(reservation::Column::Status.eq(reservation::ACTIVE))
+        .filter(reservation::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+        .group_by(reservation::Column::ProductId);
+    if let Some(product_ids) = product_ids {
+        query = query.filter(reservation::Column::ProductId.is_in(product_ids));
+    }
+
+    let rows: Vec<Value> = query
+        .into_json()
+        .all(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            Some((
+                row.get("product_id")?.as_i64()? as i32,
+                row.get("reserved")?.as_i64()? as i32,
+            ))
+        })
+        .collect())
+}
+
+// The stock of the product according to its movements; reservations only hold stock.
+async fn ledger_count<C: ConnectionTrait>(db: &C, product_id: i32) -> Result<i64, LocalError> {
+    let ledger: Option<Value> = inventory_movements::Entity::find()
+        .select_only()
+        .column_as(
+            Expr::col(inventory_movements::Column::Quantity).sum(),
+            "ledger_count",
+        )
+        .filter(inventory_movements::Column::ProductId.eq(product_id))
+        .filter(inventory_movements::Column::Kind.ne(inventory_movements::RESERVATION))
+        .into_json()
+        .one(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    Ok(ledger
+        .and_then(|l| l.get("ledger_count")?.as_i64())
+        .unwrap_or(0))
+}
+
+// Kinds of movements that can be recorded by hand; sales and reservations only come
+// from purchases and checkouts.
+fn manual_movement_kind(kind: &str) -> Option<&'static str> {
+    match kind {
+        "restock" => Some(inventory_movements::RESTOCK),
+        "return" => Some(inventory_movements::RETURN),
+        "adjustment" => Some(inventory_movements::ADJUSTMENT),
+        _ => None,
+    }
+}
+
+pub struct Movement<'a> {
+    pub product_id: i32,
+    pub warehouse_id: Option<i32>,
+    pub variant_id: Option<i32>,
+    pub kind: &'a str,
+    pub quantity: i32,
+    pub reason: &'a str,
+    pub order_ref: Option<&'a str>,
+}
+
+pub struct NewReservation<'a> {
+    pub product_id: i32,
+    pub variant_id: Option<i32>,
+    pub count: i32,
+    pub holder: &'a str,
+    // the signed-in buyer, None for a guest
+    pub user_id: Option<i32>,
+    pub ttl: Duration,
+}
+
+// Ledger entry for a stock change, written with the same connection (transaction) as the change.
+pub(super) async fn record_movement<C: ConnectionTrait>(
+    db: &C,
+    movement: Movement<'_>,
+    actor: &str,
+) -> Result<(), LocalError> {
+    let movement = inventory_movements::ActiveModel {
+        movement_id: NotSet,
+        product_id: Set(movement.product_id),
+        warehouse_id: Set(movement.warehouse_id),
+        variant_id: Set(movement.variant_id),
+        kind: Set(movement.kind.to_string()),
+        quantity: Set(movement.quantity),
+        reason: Set(movement.reason.to_string()),
+        actor: Set(actor.to_string()),
+        order_ref: Set(movement.order_ref.map(|r| r.to_string())),
+        created_at: Set(Utc::now().naive_utc()),
+    };
+
+    movement
+        .insert(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    Ok(())
+}
+
+// Units that can't be sold, per product: held by active reservations or in inactive
+// warehouses.
+async fn held_counts<C: ConnectionTrait>(
+    db: &C,
+    product_ids: Option<Vec<i32>>,
+) -> Result<HashMap<i32, i32>, LocalError> {
+    let mut held = reserved_counts(db, product_ids.clone()).await?;
+    for (product_id, inactive) in inactive_counts(db, product_ids).await? {
+        *held.entry(product_id).or_default() += inactive;
+    }
+    Ok(held)
+}
+
+fn set_availability(products: &mut [product::Model], held: &HashMap<i32, i32>) {
+    for prod in products.iter_mut() {
+        prod.available = prod.count - held.get(&prod.product_id).copied().unwrap_or(0);
+        prod.status = prod.available > 0;
+    }
+}
+
+pub(super) async fn with_availability<C: ConnectionTrait>(
+    db: &C,
+    mut products: Vec<product::Model>,
+) -> Result<Vec<product::Model>, LocalError> {
+    let ids = products.iter().map(|p| p.product_id).collect();
+    let held = held_counts(db, Some(ids)).await?;
+    set_availability(&mut products, &held);
+    Ok(products)
+}
+
+// Availability and category names, for products returned to clients.
+async fn with_details<C: ConnectionTrait>(
+    db: &C,
+    products: Vec<product::Model>,
+) -> Result<Vec<product::Model>, LocalError> {
+    let mut products = with_availability(db, products).await?;
+    set_category_names(db, &mut products).await?;
+    Ok(products)
+}
+
+// On `db` rather than `PostgresDB` so that the catalog import can run them in its
+// transaction.
+pub async fn add_product<C: ConnectionTrait + TransactionTrait>(
+    db: &C,
+    mut product_json: Value,
+    actor: &str,
+) -> Result<i32, LocalError> {
+    // initial stock goes to "warehouse_id", or the default warehouse
+    let warehouse_id = product_json
+        .get("warehouse_id")
+        .and_then(|id| id.as_i64())
+        .map(|id| id as i32);
+
+    let category_id = find_category_id(db, &product_json)
+        .await?
+        .ok_or(LocalError::UnknownCategory)?;
+
+    let price = product_json
+        .get("price")
+        .and_then(money::parse_amount)
+        .filter(|p| !p.is_sign_negative())
+        .ok_or(LocalError::WrongParameters)?;
+
+    // `from_json` deserializes a whole `Model`, so it needs a placeholder primary key
+    if let Some(json_map) = product_json.as_object_mut() {
+        json_map.insert("product_id".to_string(), json!(0));
+        json_map.insert("category_id".to_string(), json!(category_id));
+        json_map.insert("price".to_string(), json!(price));
+    }
+    let mut new_product =
+        product::ActiveModel::from_json(product_json).to_local_error(RecordType::Product)?;
+    new_product.product_id = NotSet;
+
+    let txn = db.begin().await.to_local_error(RecordType::Product)?;
+    let product: product::Model = new_product
+        .insert(&txn)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    record_price(
+        &txn,
+        product.product_id,
+        product.price,
+        Utc::now().naive_utc(),
+        actor,
+        "initial price",
+    )
+    .await?;
+
+    if product.count != 0 {
+        let warehouse_id = match warehouse_id {
+            Some(id) => id,
+            None => default_warehouse(&txn).await?,
+        };
+        change_stock(&txn, product.product_id, warehouse_id, product.count).await?;
+
+        record_movement(
+            &txn,
+            Movement {
+                product_id: product.product_id,
+                warehouse_id: Some(warehouse_id),
+                variant_id: None,
+                kind: inventory_movements::RESTOCK,
+                quantity: product.count,
+                reason: "initial stock",
+                order_ref: None,
+            },
+            actor,
+        )
+        .await?;
+    }
+    txn.commit().await.to_local_error(RecordType::Product)?;
+
+    Ok(product.product_id)
+}
+
+pub async fn update_product<C: ConnectionTrait + TransactionTrait>(
+    db: &C,
+    product_id: i32,
+    updates: Value,
+    actor: &str,
+) -> Result<Value, LocalError> {
+    let txn = db.begin().await.to_local_error(RecordType::Product)?;
+
+    let product: Option<product::Model> = product::Entity::find_by_id(product_id)
+        .lock_exclusive()
+        .one(&txn)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    if product.is_none() {
+        return Err(LocalError::OperationFailed);
+    }
+
+    let product = product.unwrap();
+    let old_count = product.count;
+    let old_price = product.price;
+    let mut product: product::ActiveModel = product.into();
+
+    let updates: Map<String, Value> = updates.as_object().unwrap().clone();
+    for (key, val) in updates.iter() {
+        match key.as_str() {
+            "name" => {
+                let name = val.as_str();
+                if name.is_none() {
+                    return Err(LocalError::WrongParameters);
+                }
+                product.name = Set(name.unwrap().to_string());
+            }
+            "image" => {
+                let image = val.as_str();
+                if image.is_none() {
+                    return Err(LocalError::WrongParameters);
+                }
+                product.image = Set(Some(image.unwrap().to_string()));
+            }
+            "count" => {
+                let count = val.as_i64();
+                if count.is_none() {
+                    return Err(LocalError::WrongParameters);
+                }
+                // stock of variants is changed with `update_variant`
+                check_variant_given(&txn, product_id, None).await?;
+                product.count = Set(count.unwrap() as i32);
+            }
+            "price" => {
+                let price = money::parse_amount(val);
+                if price.is_none() {
+                    return Err(LocalError::WrongParameters);
+                }
+                product.price = Set(price.unwrap());
+            }
+            "category" | "category_id" => {
+                let category_id = find_category_id(&txn, &json!({ key: val })).await?;
+                product.category_id = Set(category_id.unwrap());
+            }
+            "reorder_threshold" => {
+                // null falls back to the default threshold
+                if !val.is_null() && val.as_i64().is_none() {
+                    return Err(LocalError::WrongParameters);
+                }
+                product.reorder_threshold = Set(val.as_i64().map(|t| t as i32));
+            }
+            _ => {}
+        }
+    }
+
+    let product: product::Model = product
+        .update(&txn)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    if product.price != old_price {
+        record_price(
+            &txn,
+            product_id,
+            product.price,
+            Utc::now().naive_utc(),
+            actor,
+            "price updated",
+        )
+        .await?;
+    }
+
+    let quantity = product.count - old_count;
+    if quantity != 0 {
+        // "kind", "reason" and "order_ref" describe the stock change for the ledger
+        let default_kind = if quantity > 0 {
+            inventory_movements::RESTOCK
+        } else {
+            inventory_movements::ADJUSTMENT
+        };
+        let kind = match updates.get("kind").and_then(|k| k.as_str()) {
+            None => default_kind,
+            Some(kind) => manual_movement_kind(kind).ok_or(LocalError::WrongParameters)?,
+        };
+        let reason = updates
+            .get("reason")
+            .and_then(|r| r.as_str())
+            .unwrap_or("stock updated");
+        let order_ref = updates.get("order_ref").and_then(|r| r.as_str());
+
+        // the change is applied to "warehouse_id", or spread over the warehouses
+        let changes = match updates.get("warehouse_id").and_then(|id| id.as_i64()) {
+            Some(id) => vec![(id as i32, quantity)],
+            None => spread_change(&txn, product_id, quantity).await?,
+        };
+        for (warehouse_id, quantity) in changes {
+            change_stock(&txn, product_id, warehouse_id, quantity).await?;
+
+            record_movement(
+                &txn,
+                Movement {
+                    product_id,
+                    warehouse_id: Some(warehouse_id),
+                    variant_id: None,
+                    kind,
+                    quantity,
+                    reason,
+                    order_ref,
+                },
+                actor,
+            )
+            .await?;
+        }
+    }
+    txn.commit().await.to_local_error(RecordType::Product)?;
+
+    let product = with_details(db, vec![product]).await?;
+    Ok(json!(product[0]))
+}
+
 impl PostgresDB {
     pub async fn get_product(&self, product_id: i32) -> Result<Value, LocalError> {
         let product: Option<product::Model> = product::Entity::find_by_id(product_id)
@@ -18,7 +398,13 @@ impl PostgresDB {
             .await
             .to_local_error(RecordType::Product)?;
 
-        Ok(json!(product))
+        let product = with_details(&self.db, product.into_iter().collect()).await?;
+
+        let mut product = json!(product.first());
+        if !product.is_null() {
+            product["variants"] = json!(self.get_variants(product_id).await?);
+        }
+        Ok(product)
     }
 
     pub async fn get_all_products(&self) -> Result<Value, LocalError> {
@@ -27,105 +413,159 @@ impl PostgresDB {
             .await
             .to_local_error(RecordType::Product)?;
 
-        for mut prod in products.iter_mut() {
-            prod.status = prod.count > 0;
-        }
+        let held = held_counts(&self.db, None).await?;
+        set_availability(&mut products, &held);
+        set_category_names(&self.db, &mut products).await?;
 
         Ok(json!(products))
     }
 
-    pub async fn add_product(&self, product_json: Value) -> Result<i32, LocalError> {
-        let new_product =
-            product::ActiveModel::from_json(product_json).to_local_error(RecordType::Product)?;
-        let res = product::Entity::insert(new_product)
-            .exec(&self.db)
+    pub async fn get_products(&self, product_ids: Vec<i32>) -> Result<Value, LocalError> {
+        let products = product::Entity::find()
+            .filter(product::Column::ProductId.is_in(product_ids))
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let products = with_details(&self.db, products).await?;
+
+        Ok(json!(products))
+    }
+
+    pub async fn get_products_in_categories(
+        &self,
+        category_ids: Vec<i32>,
+    ) -> Result<Value, LocalError> {
+        let products = product::Entity::find()
+            .filter(product::Column::CategoryId.is_in(category_ids))
+            .all(&self.db)
             .await
             .to_local_error(RecordType::Product)?;
-        Ok(res.last_insert_id)
+        let products = with_details(&self.db, products).await?;
+
+        Ok(json!(products))
+    }
+
+    pub async fn add_product(&self, product_json: Value, actor: &str) -> Result<i32, LocalError> {
+        add_product(&self.db, product_json, actor).await
     }
 
     pub async fn update_product(
         &self,
         product_id: i32,
         updates: Value,
+        actor: &str,
+    ) -> Result<Value, LocalError> {
+        update_product(&self.db, product_id, updates, actor).await
+    }
+
+    pub async fn delete_product(&self, product_id: i32) -> Result<(), LocalError> {
+        let _ = product::Entity::delete_by_id(product_id)
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        Ok(())
+    }
+
+    // Reserved units are held for their holders, so only `available` can be bought.
+    // Products with variants are bought by variant.
+    pub async fn purchase(
+        &self,
+        product_id: i32,
+        variant_id: Option<i32>,
+        count: i32,
+        actor: &str,
+        order_ref: &str,
+        allocation: &Allocation,
     ) -> Result<Value, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
         let product: Option<product::Model> = product::Entity::find_by_id(product_id)
-            .one(&self.db)
+            .lock_exclusive()
+            .one(&txn)
             .await
             .to_local_error(RecordType::Product)?;
 
         if product.is_none() {
-            return Err(LocalError::OperationFailed);
+            return Err(LocalError::IdNotFound);
         }
 
-        let mut product: product::ActiveModel = product.unwrap().into();
+        let product = with_availability(&txn, vec![product.unwrap()])
+            .await?
+            .remove(0);
+        if count <= 0 || !product.is_available(Some(count)) {
+            return Err(LocalError::ItemNotAvailable);
+        }
 
-        let updates: Map<String, Value> = updates.as_object().unwrap().clone();
-        for (key, val) in updates.iter() {
-            match key.as_str() {
-                "name" => {
-                    let name = val.as_str();
-                    if name.is_none() {
-                        return Err(LocalError::WrongParameters);
-                    }
-                    product.name = Set(name.unwrap().to_string());
-                }
-                "image" => {
-                    let image = val.as_str();
-                    if image.is_none() {
-                        return Err(LocalError::WrongParameters);
-                    }
-                    product.image = Set(Some(image.unwrap().to_string()));
+        check_variant_given(&txn, product_id, variant_id).await?;
+        let variant = match variant_id {
+            None => None,
+            Some(variant_id) => {
+                let variant = lock_variant(&txn, product_id, variant_id).await?;
+                if variant.available < count {
+                    return Err(LocalError::ItemNotAvailable);
                 }
-                "count" => {
-                    let count = val.as_i64();
-                    if count.is_none() {
-                        return Err(LocalError::WrongParameters);
-                    }
-                    product.count = Set(count.unwrap() as i32);
-                }
-                "price" => {
-                    let price = val.as_f64();
-                    if price.is_none() {
-                        return Err(LocalError::WrongParameters);
-                    }
-                    let price = Decimal::from_str_exact(&round(price.unwrap(), 2).to_string());
-                    if price.is_err() {
-                        return Err(LocalError::WrongParameters);
-                    }
-                    product.price = Set(price.unwrap());
-                }
-                "category" => {
-                    let category = val.as_str();
-                    if category.is_none() {
-                        return Err(LocalError::WrongParameters);
-                    }
-                    product.category = Set(category.unwrap().to_string());
-                }
-                _ => {}
+                Some(change_variant_stock(&txn, product_id, variant_id, -count).await?)
             }
-        }
+        };
+
+        let product_count = product.count;
+        let mut product: product::ActiveModel = product.into();
+        product.count = ActiveValue::Set(product_count - count);
 
         let product: product::Model = product
-            .update(&self.db)
+            .update(&txn)
             .await
             .to_local_error(RecordType::Product)?;
 
-        Ok(json!(product))
+        for (warehouse_id, count) in allocate(&txn, product_id, count, allocation).await? {
+            change_stock(&txn, product_id, warehouse_id, -count).await?;
+            record_movement(
+                &txn,
+                Movement {
+                    product_id,
+                    warehouse_id: Some(warehouse_id),
+                    variant_id,
+                    kind: inventory_movements::SALE,
+                    quantity: -count,
+                    reason: "purchase",
+                    order_ref: Some(order_ref),
+                },
+                actor,
+            )
+            .await?;
+        }
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        let product = with_details(&self.db, vec![product]).await?;
+        let mut product = json!(product[0]);
+        if let Some(variant) = variant {
+            let variant = with_variant_availability(&self.db, vec![variant]).await?;
+            product["variant"] = json!(variant[0]);
+        }
+        Ok(product)
     }
 
-    pub async fn delete_product(&self, product_id: i32) -> Result<(), LocalError> {
-        let _ = product::Entity::delete_by_id(product_id)
-            .exec(&self.db)
-            .await
-            .to_local_error(RecordType::Product)?;
+    // Holds `count` units of a product for `holder` (a cart or session id) until `ttl` passes.
+    pub async fn reserve(&self, new: NewReservation<'_>, actor: &str) -> Result<Value, LocalError> {
+        let NewReservation {
+            product_id,
+            variant_id,
+            count,
+            holder,
+            user_id,
+            ttl,
+        } = new;
+        if count <= 0 || holder.is_empty() {
+            return Err(LocalError::WrongParameters);
+        }
 
-        Ok(())
-    }
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
 
-    pub async fn purchase(&self, product_id: i32, count: i32) -> Result<Value, LocalError> {
+        // the row lock serializes reservations and purchases of the product
         let product: Option<product::Model> = product::Entity::find_by_id(product_id)
-            .one(&self.db)
+            .lock_exclusive()
+            .one(&txn)
             .await
             .to_local_error(RecordType::Product)?;
 
@@ -133,23 +573,509 @@ impl PostgresDB {
             return Err(LocalError::IdNotFound);
         }
 
-        let product = product.unwrap();
+        let product = with_availability(&txn, vec![product.unwrap()])
+            .await?
+            .remove(0);
         if !product.is_available(Some(count)) {
             return Err(LocalError::ItemNotAvailable);
         }
 
+        check_variant_given(&txn, product_id, variant_id).await?;
+        if let Some(variant_id) = variant_id {
+            if lock_variant(&txn, product_id, variant_id).await?.available < count {
+                return Err(LocalError::ItemNotAvailable);
+            }
+        }
+
+        let now = Utc::now().naive_utc();
+        let new_reservation = reservation::ActiveModel {
+            reservation_id: NotSet,
+            product_id: Set(product_id),
+            variant_id: Set(variant_id),
+            count: Set(count),
+            holder: Set(holder.to_string()),
+            user_id: Set(user_id),
+            token: Set(Some(random_token())),
+            status: Set(reservation::ACTIVE.to_string()),
+            created_at: Set(now),
+            expires_at: Set(now + ttl),
+        };
+
+        let reservation: reservation::Model = new_reservation
+            .insert(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        record_movement(
+            &txn,
+            Movement {
+                product_id,
+                warehouse_id: None,
+                variant_id,
+                kind: inventory_movements::RESERVATION,
+                quantity: count,
+                reason: &format!("reserved for {}", holder),
+                order_ref: None,
+            },
+            actor,
+        )
+        .await?;
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(json!(reservation))
+    }
+
+    pub async fn get_reservation(
+        &self,
+        reservation_id: i32,
+    ) -> Result<Option<reservation::Model>, LocalError> {
+        reservation::Entity::find_by_id(reservation_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // Active reservations of a cart or session.
+    pub async fn get_reservations(
+        &self,
+        holder: &str,
+    ) -> Result<Vec<reservation::Model>, LocalError> {
+        reservation::Entity::find()
+            .filter(reservation::Column::Holder.eq(holder))
+            .filter(reservation::Column::Status.eq(reservation::ACTIVE))
+            .filter(reservation::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // Turns reservations into sales: the reserved units are taken off `count`.
+    // Either all of them are committed or, if any is no longer active, none is.
+    pub async fn commit_reservations(
+        &self,
+        reservation_ids: Vec<i32>,
+        actor: &str,
+        order_ref: &str,
+        allocation: &Allocation,
+    ) -> Result<
+        Vec<(
+            reservation::Model,
+            product::Model,
+            Option<product_variant::Model>,
+        )>,
+        LocalError,
+    > {
+        if reservation_ids.is_empty() {
+            return Err(LocalError::IdNotFound);
+        }
+
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        // products are locked in id order so concurrent commits can't deadlock
+        let reservations: Vec<reservation::Model> = reservation::Entity::find()
+            .filter(reservation::Column::ReservationId.is_in(reservation_ids.clone()))
+            .order_by_asc(reservation::Column::ProductId)
+            .lock_exclusive()
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        if reservations.len() != reservation_ids.len() {
+            return Err(LocalError::IdNotFound);
+        }
+
+        let now = Utc::now().naive_utc();
+        for reservation in reservations.iter() {
+            if reservation.status != reservation::ACTIVE {
+                return Err(LocalError::IdNotFound);
+            }
+            if reservation.expires_at <= now {
+                return Err(LocalError::ReservationExpired);
+            }
+        }
+
+        let mut committed = Vec::new();
+        for reservation in reservations {
+            let product: Option<product::Model> =
+                product::Entity::find_by_id(reservation.product_id)
+                    .lock_exclusive()
+                    .one(&txn)
+                    .await
+                    .to_local_error(RecordType::Product)?;
+            let product = product.ok_or(LocalError::IdNotFound)?;
+
+            // the reservation already holds the variant's units
+            let variant = match reservation.variant_id {
+                None => None,
+                Some(variant_id) => Some(
+                    change_variant_stock(&txn, product.product_id, variant_id, -reservation.count)
+                        .await?,
+                ),
+            };
+
+            let product_count = product.count;
+            let mut product: product::ActiveModel = product.into();
+            product.count = Set(product_count - reservation.count);
+            let product: product::Model = product
+                .update(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+
+            let reason = format!("reservation {} committed", reservation.reservation_id);
+            record_movement(
+                &txn,
+                Movement {
+                    product_id: reservation.product_id,
+                    warehouse_id: None,
+                    variant_id: reservation.variant_id,
+                    kind: inventory_movements::RESERVATION,
+                    quantity: -reservation.count,
+                    reason: &reason,
+                    order_ref: Some(order_ref),
+                },
+                actor,
+            )
+            .await?;
+            let product_id = reservation.product_id;
+            for (warehouse_id, count) in
+                allocate(&txn, product_id, reservation.count, allocation).await?
+            {
+                change_stock(&txn, product_id, warehouse_id, -count).await?;
+                record_movement(
+                    &txn,
+                    Movement {
+                        product_id,
+                        warehouse_id: Some(warehouse_id),
+                        variant_id: reservation.variant_id,
+                        kind: inventory_movements::SALE,
+                        quantity: -count,
+                        reason: "purchase",
+                        order_ref: Some(order_ref),
+                    },
+                    actor,
+                )
+                .await?;
+            }
+
+            let mut reservation: reservation::ActiveModel = reservation.into();
+            reservation.status = Set(reservation::COMMITTED.to_string());
+            let reservation: reservation::Model = reservation
+                .update(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+
+            committed.push((reservation, product, variant));
+        }
+
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(committed)
+    }
+
+    // Gives the reserved units back. Returns the products whose availability changed.
+    pub async fn release_reservations(
+        &self,
+        reservation_ids: Vec<i32>,
+        actor: &str,
+    ) -> Result<Vec<i32>, LocalError> {
+        self.end_reservations(reservation_ids, reservation::RELEASED, actor)
+            .await
+    }
+
+    // Marks reservations past their expiry as expired. Returns the affected products.
+    pub async fn expire_reservations(&self) -> Result<Vec<i32>, LocalError> {
+        let expired: Vec<reservation::Model> = reservation::Entity::find()
+            .filter(reservation::Column::Status.eq(reservation::ACTIVE))
+            .filter(reservation::Column::ExpiresAt.lte(Utc::now().naive_utc()))
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        self.end_reservations(
+            expired.iter().map(|r| r.reservation_id).collect(),
+            reservation::EXPIRED,
+            "system",
+        )
+        .await
+    }
+
+    async fn end_reservations(
+        &self,
+        reservation_ids: Vec<i32>,
+        status: &str,
+        actor: &str,
+    ) -> Result<Vec<i32>, LocalError> {
+        if reservation_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        // the lock and status filter keep a reservation committed meanwhile from being ended
+        let reservations: Vec<reservation::Model> = reservation::Entity::find()
+            .filter(reservation::Column::ReservationId.is_in(reservation_ids.clone()))
+            .filter(reservation::Column::Status.eq(reservation::ACTIVE))
+            .lock_exclusive()
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        reservation::Entity::update_many()
+            .col_expr(reservation::Column::Status, Expr::value(status))
+            .filter(
+                reservation::Column::ReservationId
+                    .is_in(reservations.iter().map(|r| r.reservation_id)),
+            )
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        for reservation in reservations.iter() {
+            let reason = format!("reservation {} {}", reservation.reservation_id, status);
+            record_movement(
+                &txn,
+                Movement {
+                    product_id: reservation.product_id,
+                    warehouse_id: None,
+                    variant_id: reservation.variant_id,
+                    kind: inventory_movements::RESERVATION,
+                    quantity: -reservation.count,
+                    reason: &reason,
+                    order_ref: None,
+                },
+                actor,
+            )
+            .await?;
+        }
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        let mut product_ids: Vec<i32> = reservations.iter().map(|r| r.product_id).collect();
+        product_ids.sort_unstable();
+        product_ids.dedup();
+        Ok(product_ids)
+    }
+
+    // Restocks, returns and manual adjustments of `count`, in `movement.warehouse_id`
+    // or spread over the warehouses (see `spread_change`).
+    pub async fn adjust_stock(
+        &self,
+        mut movement: Movement<'_>,
+        actor: &str,
+    ) -> Result<Value, LocalError> {
+        movement.kind = manual_movement_kind(movement.kind).ok_or(LocalError::WrongParameters)?;
+        if movement.quantity == 0 {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let product: Option<product::Model> = product::Entity::find_by_id(movement.product_id)
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let product = product.ok_or(LocalError::IdNotFound)?;
+
+        check_variant_given(&txn, movement.product_id, movement.variant_id).await?;
+        // a removal can't take the units active reservations hold
+        if movement.quantity < 0 {
+            let reserved = reserved_counts(&txn, Some(vec![movement.product_id])).await?;
+            let reserved = reserved.get(&movement.product_id).copied().unwrap_or(0);
+            if product.count - reserved + movement.quantity < 0 {
+                return Err(LocalError::ItemNotAvailable);
+            }
+            if let Some(variant_id) = movement.variant_id {
+                let variant = lock_variant(&txn, movement.product_id, variant_id).await?;
+                if variant.available + movement.quantity < 0 {
+                    return Err(LocalError::ItemNotAvailable);
+                }
+            }
+        }
+        if let Some(variant_id) = movement.variant_id {
+            change_variant_stock(&txn, movement.product_id, variant_id, movement.quantity).await?;
+        }
+
+        let changes = match movement.warehouse_id {
+            Some(id) => vec![(id, movement.quantity)],
+            None => spread_change(&txn, movement.product_id, movement.quantity).await?,
+        };
+        for (warehouse_id, quantity) in changes.iter().copied() {
+            change_stock(&txn, movement.product_id, warehouse_id, quantity).await?;
+        }
+
         let product_count = product.count;
         let mut product: product::ActiveModel = product.into();
-        product.count = ActiveValue::Set(product_count - count);
+        product.count = Set(product_count + movement.quantity);
+        let product: product::Model = product
+            .update(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        for (warehouse_id, quantity) in changes {
+            let movement = Movement {
+                warehouse_id: Some(warehouse_id),
+                quantity,
+                ..movement
+            };
+            record_movement(&txn, movement, actor).await?;
+        }
+        txn.commit().await.to_local_error(RecordType::Product)?;
 
-        let product: Result<product::Model, LocalError> = product
-            .update(&self.db)
+        let product = with_details(&self.db, vec![product]).await?;
+        Ok(json!(product[0]))
+    }
+
+    // Movements of a product, newest first.
+    pub async fn get_movements(
+        &self,
+        product_id: i32,
+        skip: u64,
+        limit: u64,
+    ) -> Result<Vec<inventory_movements::Model>, LocalError> {
+        inventory_movements::Entity::find()
+            .filter(inventory_movements::Column::ProductId.eq(product_id))
+            .order_by_desc(inventory_movements::Column::CreatedAt)
+            .order_by_desc(inventory_movements::Column::MovementId)
+            .offset(skip)
+            .limit(limit)
+            .all(&self.db)
             .await
-            .to_local_error(RecordType::Product);
+            .to_local_error(RecordType::Product)
+    }
 
-        match product {
-            Ok(product) => Ok(json!(product)),
-            Err(e) => Err(e),
+    // Compares `count` with the sum of the product's ledger movements. Products are
+    // listed with `count`, `ledger_count` and their `difference`, and with `stock_count`,
+    // the total over warehouses, which should always equal `count`.
+    pub async fn reconcile(&self, product_ids: Option<Vec<i32>>) -> Result<Vec<Value>, LocalError> {
+        let mut products = product::Entity::find();
+        let mut movements = inventory_movements::Entity::find()
+            .select_only()
+            .column(inventory_movements::Column::ProductId)
+            .column_as(
+                Expr::col(inventory_movements::Column::Quantity).sum(),
+                "ledger_count",
+            )
+            .filter(inventory_movements::Column::Kind.ne(inventory_movements::RESERVATION))
+            .group_by(inventory_movements::Column::ProductId);
+        let mut stock = product_stock::Entity::find()
+            .select_only()
+            .column(product_stock::Column::ProductId)
+            .column_as(Expr::col(product_stock::Column::Count).sum(), "stock_count")
+            .group_by(product_stock::Column::ProductId);
+        if let Some(product_ids) = product_ids {
+            products = products.filter(product::Column::ProductId.is_in(product_ids.clone()));
+            movements =
+                movements.filter(inventory_movements::Column::ProductId.is_in(product_ids.clone()));
+            stock = stock.filter(product_stock::Column::ProductId.is_in(product_ids));
         }
+
+        let products: Vec<product::Model> = products
+            .order_by_asc(product::Column::ProductId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let ledger: HashMap<i32, i64> = movements
+            .into_json()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?
+            .iter()
+            .filter_map(|row| {
+                Some((
+                    row.get("product_id")?.as_i64()? as i32,
+                    row.get("ledger_count")?.as_i64()?,
+                ))
+            })
+            .collect();
+
+        let stock: HashMap<i32, i64> = stock
+            .into_json()
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?
+            .iter()
+            .filter_map(|row| {
+                Some((
+                    row.get("product_id")?.as_i64()? as i32,
+                    row.get("stock_count")?.as_i64()?,
+                ))
+            })
+            .collect();
+
+        Ok(products
+            .iter()
+            .map(|product| {
+                let ledger_count = ledger.get(&product.product_id).copied().unwrap_or(0);
+                json!({
+                    "product_id": product.product_id,
+                    "count": product.count,
+                    "ledger_count": ledger_count,
+                    "difference": product.count as i64 - ledger_count,
+                    "stock_count": stock.get(&product.product_id).copied().unwrap_or(0),
+                })
+            })
+            .collect())
+    }
+
+    // Whether a sale was recorded or a coupon used with `order_ref`.
+    pub async fn is_order_ref_used(&self, order_ref: &str) -> Result<bool, LocalError> {
+        let sales = inventory_movements::Entity::find()
+            .filter(inventory_movements::Column::Kind.eq(inventory_movements::SALE))
+            .filter(inventory_movements::Column::OrderRef.eq(order_ref))
+            .count(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let redemptions = coupon_redemption::Entity::find()
+            .filter(coupon_redemption::Column::OrderRef.eq(order_ref))
+            .count(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        Ok(sales + redemptions > 0)
+    }
+
+    // Records an adjustment for the difference between `count` and the ledger, so they
+    // agree again. `count` itself is left as it is.
+    pub async fn fix_reconciliation(
+        &self,
+        product_id: i32,
+        actor: &str,
+    ) -> Result<Value, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let product: Option<product::Model> = product::Entity::find_by_id(product_id)
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let product = product.ok_or(LocalError::IdNotFound)?;
+
+        let difference = product.count as i64 - ledger_count(&txn, product_id).await?;
+        if difference != 0 {
+            record_movement(
+                &txn,
+                Movement {
+                    product_id,
+                    warehouse_id: None,
+                    variant_id: None,
+                    kind: inventory_movements::ADJUSTMENT,
+                    quantity: i32::try_from(difference).map_err(|_| LocalError::OperationFailed)?,
+                    reason: "reconciliation",
+                    order_ref: None,
+                },
+                actor,
+            )
+            .await?;
+        }
+
+        // read back, as the reconciliation reports it
+        let ledger_count = ledger_count(&txn, product_id).await?;
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(json!({
+            "product_id": product_id,
+            "count": product.count,
+            "ledger_count": ledger_count,
+            "difference": product.count as i64 - ledger_count,
+        }))
     }
 }
@@ -0,0 +1,380 @@
+use crate::db::postgres::PostgresDB;
+use crate::entities::{price_history, price_schedule, product};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use common::db_utils::{RecordType, ToError};
+use common::money;
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ColumnTrait, Condition, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect, TransactionTrait,
+};
+use serde_json::Value;
+
+// Actor of the price changes made by the scheduler.
+const SCHEDULER: &str = "scheduler";
+
+// RFC 3339, or UTC when the offset is left out.
+pub fn parse_time(time: &str) -> Option<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|t| t.naive_utc())
+        .ok()
+        .or_else(|| NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f").ok())
+}
+
+pub(super) fn parse_price(price: Option<&Value>) -> Result<Decimal, LocalError> {
+    price
+        .and_then(money::parse_amount)
+        .filter(|p| !p.is_sign_negative())
+        .ok_or(LocalError::WrongParameters)
+}
+
+// A change with an `effective_to` holds over its window, one without it only marks the
+// instant the price changes. Windows can't contain other changes, as reverting at the end
+// would undo them.
+fn overlaps(
+    a_from: NaiveDateTime,
+    a_to: Option<NaiveDateTime>,
+    b_from: NaiveDateTime,
+    b_to: Option<NaiveDateTime>,
+) -> bool {
+    match (a_to, b_to) {
+        (None, None) => a_from == b_from,
+        (Some(a_to), None) => a_from <= b_from && b_from < a_to,
+        (None, Some(b_to)) => b_from <= a_from && a_from < b_to,
+        (Some(a_to), Some(b_to)) => a_from < b_to && b_from < a_to,
+    }
+}
+
+// Closes the open entry of the product's history and opens one for `price` from `at`.
+pub(super) async fn record_price<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    price: Decimal,
+    at: NaiveDateTime,
+    actor: &str,
+    reason: &str,
+) -> Result<(), LocalError> {
+    price_history::Entity::update_many()
+        .col_expr(price_history::Column::EffectiveTo, Expr::value(at))
+        .filter(price_history::Column::ProductId.eq(product_id))
+        .filter(price_history::Column::EffectiveTo.is_null())
+        .exec(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    price_history::ActiveModel {
+        price_id: NotSet,
+        product_id: Set(product_id),
+        price: Set(price),
+        effective_from: Set(at),
+        effective_to: Set(None),
+        actor: Set(actor.to_string()),
+        reason: Set(reason.to_string()),
+    }
+    .insert(db)
+    .await
+    .to_local_error(RecordType::Product)?;
+
+    Ok(())
+}
+
+// Changes the price of a product locked by the caller.
+async fn set_price<C: ConnectionTrait>(
+    db: &C,
+    product: product::Model,
+    price: Decimal,
+    at: NaiveDateTime,
+    reason: &str,
+) -> Result<(), LocalError> {
+    let product_id = product.product_id;
+    let mut product: product::ActiveModel = product.into();
+    product.price = Set(price);
+    product
+        .update(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    record_price(db, product_id, price, at, SCHEDULER, reason).await
+}
+
+impl PostgresDB {
+    // Newest first.
+    pub async fn get_price_history(
+        &self,
+        product_id: i32,
+    ) -> Result<Vec<price_history::Model>, LocalError> {
+        price_history::Entity::find()
+            .filter(price_history::Column::ProductId.eq(product_id))
+            .order_by_desc(price_history::Column::EffectiveFrom)
+            .order_by_desc(price_history::Column::PriceId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // The entry of the history in effect at `at`.
+    pub async fn get_price_at(
+        &self,
+        product_id: i32,
+        at: NaiveDateTime,
+    ) -> Result<price_history::Model, LocalError> {
+        let price: Option<price_history::Model> = price_history::Entity::find()
+            .filter(price_history::Column::ProductId.eq(product_id))
+            .filter(price_history::Column::EffectiveFrom.lte(at))
+            .filter(
+                Condition::any()
+                    .add(price_history::Column::EffectiveTo.is_null())
+                    .add(price_history::Column::EffectiveTo.gt(at)),
+            )
+            .order_by_desc(price_history::Column::EffectiveFrom)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        price.ok_or(LocalError::IdNotFound)
+    }
+
+    pub async fn add_price_schedule(
+        &self,
+        schedule_json: Value,
+        actor: &str,
+    ) -> Result<i32, LocalError> {
+        let product_id = schedule_json
+            .get("product_id")
+            .and_then(|id| id.as_i64())
+            .ok_or(LocalError::WrongParameters)? as i32;
+        let price = parse_price(schedule_json.get("price"))?;
+        let time = |key: &str| -> Result<Option<NaiveDateTime>, LocalError> {
+            match schedule_json.get(key) {
+                None | Some(Value::Null) => Ok(None),
+                Some(time) => time
+                    .as_str()
+                    .and_then(parse_time)
+                    .map(Some)
+                    .ok_or(LocalError::WrongParameters),
+            }
+        };
+        let effective_from = time("effective_from")?.ok_or(LocalError::WrongParameters)?;
+        let effective_to = time("effective_to")?;
+
+        let now = Utc::now().naive_utc();
+        if effective_to.is_some_and(|to| to <= effective_from || to <= now) {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        // serializes the checks for overlapping changes of the product
+        let product: Option<product::Model> = product::Entity::find_by_id(product_id)
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        product.ok_or(LocalError::IdNotFound)?;
+
+        let scheduled: Vec<price_schedule::Model> = price_schedule::Entity::find()
+            .filter(price_schedule::Column::ProductId.eq(product_id))
+            .filter(
+                price_schedule::Column::Status
+                    .is_in([price_schedule::PENDING, price_schedule::ACTIVE]),
+            )
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if scheduled.iter().any(|s| {
+            overlaps(
+                s.effective_from,
+                s.effective_to,
+                effective_from,
+                effective_to,
+            )
+        }) {
+            return Err(LocalError::PriceScheduleOverlaps);
+        }
+
+        let schedule = price_schedule::ActiveModel {
+            schedule_id: NotSet,
+            product_id: Set(product_id),
+            price: Set(price),
+            effective_from: Set(effective_from),
+            effective_to: Set(effective_to),
+            previous_price: Set(None),
+            status: Set(price_schedule::PENDING.to_string()),
+            actor: Set(actor.to_string()),
+            created_at: Set(now),
+        }
+        .insert(&txn)
+        .await
+        .to_local_error(RecordType::Product)?;
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(schedule.schedule_id)
+    }
+
+    // Pending and active changes, of one product or of all of them, soonest first.
+    pub async fn get_price_schedules(
+        &self,
+        product_id: Option<i32>,
+    ) -> Result<Vec<price_schedule::Model>, LocalError> {
+        let mut query = price_schedule::Entity::find().filter(
+            price_schedule::Column::Status.is_in([price_schedule::PENDING, price_schedule::ACTIVE]),
+        );
+        if let Some(product_id) = product_id {
+            query = query.filter(price_schedule::Column::ProductId.eq(product_id));
+        }
+
+        query
+            .order_by_asc(price_schedule::Column::EffectiveFrom)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // A pending change is dropped; an active one ends now and is reverted by the next run
+    // of the scheduler.
+    pub async fn cancel_price_schedule(
+        &self,
+        schedule_id: i32,
+    ) -> Result<price_schedule::Model, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let schedule: Option<price_schedule::Model> =
+            price_schedule::Entity::find_by_id(schedule_id)
+                .lock_exclusive()
+                .one(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+        let schedule = schedule.ok_or(LocalError::IdNotFound)?;
+
+        let status = schedule.status.clone();
+        let mut schedule: price_schedule::ActiveModel = schedule.into();
+        match status.as_str() {
+            price_schedule::PENDING => {
+                schedule.status = Set(price_schedule::CANCELLED.to_string());
+            }
+            price_schedule::ACTIVE => {
+                schedule.effective_to = Set(Some(Utc::now().naive_utc()));
+            }
+            _ => return Err(LocalError::WrongParameters),
+        }
+
+        let schedule: price_schedule::Model = schedule
+            .update(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(schedule)
+    }
+
+    // Starts the changes that are due and reverts the ones that ended. Returns the
+    // products whose price changed.
+    pub async fn apply_price_schedules(&self) -> Result<Vec<i32>, LocalError> {
+        let now = Utc::now().naive_utc();
+        let due: Vec<price_schedule::Model> = price_schedule::Entity::find()
+            .filter(
+                Condition::any()
+                    .add(
+                        Condition::all()
+                            .add(price_schedule::Column::Status.eq(price_schedule::PENDING))
+                            .add(price_schedule::Column::EffectiveFrom.lte(now)),
+                    )
+                    .add(
+                        Condition::all()
+                            .add(price_schedule::Column::Status.eq(price_schedule::ACTIVE))
+                            .add(price_schedule::Column::EffectiveTo.lte(now)),
+                    ),
+            )
+            .order_by_asc(price_schedule::Column::EffectiveFrom)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        let mut product_ids = Vec::new();
+        for schedule_id in due.iter().map(|s| s.schedule_id) {
+            match self.apply_price_schedule(schedule_id, now).await {
+                Ok(Some(product_id)) => product_ids.push(product_id),
+                Ok(None) => {}
+                Err(e) => println!(
+                    "Error when applying price schedule {}: {}",
+                    schedule_id,
+                    e.to_string()
+                ),
+            }
+        }
+
+        product_ids.sort_unstable();
+        product_ids.dedup();
+        Ok(product_ids)
+    }
+
+    // The schedule is read again under lock, as it may have been cancelled meanwhile.
+    async fn apply_price_schedule(
+        &self,
+        schedule_id: i32,
+        now: NaiveDateTime,
+    ) -> Result<Option<i32>, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let schedule: Option<price_schedule::Model> =
+            price_schedule::Entity::find_by_id(schedule_id)
+                .lock_exclusive()
+                .one(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+        let schedule = schedule.ok_or(LocalError::IdNotFound)?;
+
+        let product: Option<product::Model> = product::Entity::find_by_id(schedule.product_id)
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let product = product.ok_or(LocalError::IdNotFound)?;
+
+        let ended = schedule.effective_to.is_some_and(|to| to <= now);
+        let mut changed = false;
+        let mut update: price_schedule::ActiveModel = schedule.clone().into();
+
+        if schedule.status == price_schedule::PENDING && schedule.effective_from <= now {
+            // a window that passed while the scheduler wasn't running is skipped
+            if !ended {
+                update.previous_price = Set(Some(product.price));
+                let reason = format!("price schedule {}", schedule_id);
+                set_price(&txn, product, schedule.price, now, &reason).await?;
+                changed = true;
+            }
+            let status = if schedule.effective_to.is_some() && !ended {
+                price_schedule::ACTIVE
+            } else {
+                price_schedule::DONE
+            };
+            update.status = Set(status.to_string());
+        } else if schedule.status == price_schedule::ACTIVE && ended {
+            // a price set by hand during the window is kept
+            if let Some(previous_price) = schedule.previous_price {
+                if product.price == schedule.price {
+                    let reason = format!("end of price schedule {}", schedule_id);
+                    set_price(&txn, product, previous_price, now, &reason).await?;
+                    changed = true;
+                }
+            }
+            update.status = Set(price_schedule::DONE.to_string());
+        } else {
+            return Ok(None);
+        }
+
+        update
+            .update(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(if changed {
+            Some(schedule.product_id)
+        } else {
+            None
+        })
+    }
+}
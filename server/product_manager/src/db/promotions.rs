@@ -0,0 +1,591 @@
+use crate::db::categories::{find_category_id, subtree};
+use crate::db::postgres::PostgresDB;
+use crate::db::prices::{parse_price, parse_time};
+use crate::db::variants::check_variant_given;
+use crate::entities::{category, coupon, coupon_redemption, product, product_variant, promotion};
+use chrono::{NaiveDateTime, Utc};
+use common::auth::random_token;
+use common::db_utils::{unique_violation, RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ColumnTrait, DbErr, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, TransactionTrait,
+};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+// A promotion that can apply to a checkout, with the coupon that unlocked it.
+pub struct Offer {
+    pub promotion: promotion::Model,
+    pub coupon: Option<coupon::Model>,
+    // the promotion's category and all below it
+    pub category_ids: Vec<i32>,
+}
+
+fn to_coupon_error<T>(res: Result<T, DbErr>) -> Result<T, LocalError> {
+    if let Err(ref e) = res {
+        if unique_violation(e) == Some("coupon_code_key") {
+            return Err(LocalError::CouponCodeTaken);
+        }
+    }
+
+    res.to_local_error(RecordType::Product)
+}
+
+// Codes are matched regardless of case and surrounding spaces.
+pub fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn random_code() -> String {
+    random_token()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(10)
+        .collect::<String>()
+        .to_uppercase()
+}
+
+fn in_window(
+    starts_at: Option<NaiveDateTime>,
+    ends_at: Option<NaiveDateTime>,
+    now: NaiveDateTime,
+) -> bool {
+    starts_at.is_none_or(|from| from <= now) && ends_at.is_none_or(|to| now < to)
+}
+
+fn optional_time(val: &Value) -> Result<Option<NaiveDateTime>, LocalError> {
+    match val {
+        Value::Null => Ok(None),
+        time => time
+            .as_str()
+            .and_then(parse_time)
+            .map(Some)
+            .ok_or(LocalError::WrongParameters),
+    }
+}
+
+fn optional_count(val: &Value) -> Result<Option<i32>, LocalError> {
+    match val {
+        Value::Null => Ok(None),
+        count => count
+            .as_i64()
+            .and_then(|c| i32::try_from(c).ok())
+            .filter(|c| *c > 0)
+            .map(Some)
+            .ok_or(LocalError::WrongParameters),
+    }
+}
+
+fn optional_id(val: &Value) -> Result<Option<i32>, LocalError> {
+    match val {
+        Value::Null => Ok(None),
+        id => id
+            .as_i64()
+            .and_then(|id| i32::try_from(id).ok())
+            .map(Some)
+            .ok_or(LocalError::WrongParameters),
+    }
+}
+
+fn check_promotion(promotion: &promotion::Model) -> Result<(), LocalError> {
+    let hundred = Decimal::from(100);
+    let valid = !promotion.name.trim().is_empty()
+        && promotion::KINDS.contains(&promotion.kind.as_str())
+        && (promotion.kind == promotion::FIXED_OFF || promotion.value <= hundred)
+        && (promotion.kind != promotion::BUY_X_GET_Y
+            || promotion
+                .buy_quantity
+                .zip(promotion.get_quantity)
+                .and_then(|(buy, get)| buy.checked_add(get))
+                .is_some())
+        && (promotion.product_id.is_none() || promotion.category_id.is_none())
+        && match (promotion.starts_at, promotion.ends_at) {
+            (Some(from), Some(to)) => from < to,
+            _ => true,
+        };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(LocalError::WrongParameters)
+    }
+}
+
+fn into_active(promotion: promotion::Model) -> promotion::ActiveModel {
+    promotion::ActiveModel {
+        promotion_id: Unchanged(promotion.promotion_id),
+        name: Set(promotion.name),
+        kind: Set(promotion.kind),
+        value: Set(promotion.value),
+        buy_quantity: Set(promotion.buy_quantity),
+        get_quantity: Set(promotion.get_quantity),
+        product_id: Set(promotion.product_id),
+        category_id: Set(promotion.category_id),
+        min_basket: Set(promotion.min_basket),
+        requires_coupon: Set(promotion.requires_coupon),
+        stackable: Set(promotion.stackable),
+        priority: Set(promotion.priority),
+        starts_at: Set(promotion.starts_at),
+        ends_at: Set(promotion.ends_at),
+        active: Set(promotion.active),
+        created_at: Set(promotion.created_at),
+    }
+}
+
+impl PostgresDB {
+    // Sets the fields given in `promotion_json` on `promotion`.
+    async fn read_promotion(
+        &self,
+        mut promotion: promotion::Model,
+        promotion_json: &Value,
+    ) -> Result<promotion::Model, LocalError> {
+        let fields = promotion_json
+            .as_object()
+            .ok_or(LocalError::WrongParameters)?;
+        for (key, val) in fields.iter() {
+            match key.as_str() {
+                "name" => {
+                    let name = val.as_str().ok_or(LocalError::WrongParameters)?;
+                    promotion.name = name.trim().to_string();
+                }
+                "kind" => {
+                    let kind = val.as_str().ok_or(LocalError::WrongParameters)?;
+                    promotion.kind = kind.to_string();
+                }
+                "value" => promotion.value = parse_price(Some(val))?,
+                "buy_quantity" => promotion.buy_quantity = optional_count(val)?,
+                "get_quantity" => promotion.get_quantity = optional_count(val)?,
+                "product_id" => {
+                    promotion.product_id = optional_id(val)?;
+                    if let Some(product_id) = promotion.product_id {
+                        let product: Option<product::Model> =
+                            product::Entity::find_by_id(product_id)
+                                .one(&self.db)
+                                .await
+                                .to_local_error(RecordType::Product)?;
+                        product.ok_or(LocalError::IdNotFound)?;
+                    }
+                }
+                "category" | "category_id" => {
+                    promotion.category_id = if val.is_null() {
+                        None
+                    } else {
+                        find_category_id(&self.db, &json!({ key: val })).await?
+                    };
+                }
+                "min_basket" => {
+                    promotion.min_basket = if val.is_null() {
+                        None
+                    } else {
+                        Some(parse_price(Some(val))?)
+                    };
+                }
+                "requires_coupon" => {
+                    promotion.requires_coupon = val.as_bool().ok_or(LocalError::WrongParameters)?;
+                }
+                "stackable" => {
+                    promotion.stackable = val.as_bool().ok_or(LocalError::WrongParameters)?;
+                }
+                "priority" => {
+                    promotion.priority = val.as_i64().ok_or(LocalError::WrongParameters)? as i32;
+                }
+                "starts_at" => promotion.starts_at = optional_time(val)?,
+                "ends_at" => promotion.ends_at = optional_time(val)?,
+                "active" => promotion.active = val.as_bool().ok_or(LocalError::WrongParameters)?,
+                _ => {}
+            }
+        }
+
+        check_promotion(&promotion)?;
+        Ok(promotion)
+    }
+
+    // Highest priority first.
+    pub async fn get_promotions(&self) -> Result<Vec<promotion::Model>, LocalError> {
+        promotion::Entity::find()
+            .order_by_desc(promotion::Column::Priority)
+            .order_by_asc(promotion::Column::PromotionId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // "name" and "kind" are required; a "buy_x_get_y" promotion gives its items away
+    // unless a "value" is given.
+    pub async fn add_promotion(&self, promotion_json: Value) -> Result<i32, LocalError> {
+        let kind = promotion_json
+            .get("kind")
+            .and_then(|k| k.as_str())
+            .ok_or(LocalError::WrongParameters)?;
+        if promotion_json.get("name").is_none()
+            || (kind != promotion::BUY_X_GET_Y && promotion_json.get("value").is_none())
+        {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let defaults = promotion::Model {
+            promotion_id: 0,
+            name: String::new(),
+            kind: kind.to_string(),
+            value: Decimal::from(100),
+            buy_quantity: None,
+            get_quantity: None,
+            product_id: None,
+            category_id: None,
+            min_basket: None,
+            requires_coupon: false,
+            stackable: true,
+            priority: 0,
+            starts_at: None,
+            ends_at: None,
+            active: true,
+            created_at: Utc::now().naive_utc(),
+        };
+        let promotion = self.read_promotion(defaults, &promotion_json).await?;
+
+        let mut promotion = into_active(promotion);
+        promotion.promotion_id = NotSet;
+        let promotion: promotion::Model = promotion
+            .insert(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        Ok(promotion.promotion_id)
+    }
+
+    pub async fn update_promotion(
+        &self,
+        promotion_id: i32,
+        updates: Value,
+    ) -> Result<promotion::Model, LocalError> {
+        let promotion: Option<promotion::Model> = promotion::Entity::find_by_id(promotion_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let promotion = promotion.ok_or(LocalError::IdNotFound)?;
+
+        let promotion = self.read_promotion(promotion, &updates).await?;
+        into_active(promotion)
+            .update(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // Coupons of one promotion or of all of them.
+    pub async fn get_coupons(
+        &self,
+        promotion_id: Option<i32>,
+    ) -> Result<Vec<coupon::Model>, LocalError> {
+        let mut query = coupon::Entity::find();
+        if let Some(promotion_id) = promotion_id {
+            query = query.filter(coupon::Column::PromotionId.eq(promotion_id));
+        }
+
+        query
+            .order_by_asc(coupon::Column::CouponId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // A random code is made up when "code" is left out.
+    pub async fn add_coupon(&self, coupon_json: Value) -> Result<coupon::Model, LocalError> {
+        let promotion_id = coupon_json
+            .get("promotion_id")
+            .and_then(|id| id.as_i64())
+            .ok_or(LocalError::WrongParameters)? as i32;
+        let promotion: Option<promotion::Model> = promotion::Entity::find_by_id(promotion_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        // the other promotions apply without a code
+        if !promotion.ok_or(LocalError::IdNotFound)?.requires_coupon {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let code = match coupon_json.get("code") {
+            None | Some(Value::Null) => random_code(),
+            Some(code) => normalize_code(code.as_str().ok_or(LocalError::WrongParameters)?),
+        };
+        let field = |key: &str| coupon_json.get(key).unwrap_or(&Value::Null);
+        let starts_at = optional_time(field("starts_at"))?;
+        let ends_at = optional_time(field("ends_at"))?;
+        if code.is_empty() || starts_at.zip(ends_at).is_some_and(|(from, to)| from >= to) {
+            return Err(LocalError::WrongParameters);
+        }
+
+        to_coupon_error(
+            coupon::ActiveModel {
+                coupon_id: NotSet,
+                promotion_id: Set(promotion_id),
+                code: Set(code),
+                usage_limit: Set(optional_count(field("usage_limit"))?),
+                per_user_limit: Set(optional_count(field("per_user_limit"))?),
+                times_used: Set(0),
+                starts_at: Set(starts_at),
+                ends_at: Set(ends_at),
+                active: Set(true),
+                created_at: Set(Utc::now().naive_utc()),
+            }
+            .insert(&self.db)
+            .await,
+        )
+    }
+
+    // Coupons are kept once issued, for the per-user limits.
+    pub async fn deactivate_coupon(&self, coupon_id: i32) -> Result<coupon::Model, LocalError> {
+        let coupon: Option<coupon::Model> = coupon::Entity::find_by_id(coupon_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let mut coupon: coupon::ActiveModel = coupon.ok_or(LocalError::IdNotFound)?.into();
+        coupon.active = Set(false);
+
+        coupon
+            .update(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // Products and variants of a basket, for pricing it.
+    pub async fn get_basket_items(
+        &self,
+        items: &[(i32, Option<i32>)],
+    ) -> Result<Vec<(product::Model, Option<product_variant::Model>)>, LocalError> {
+        let mut basket = Vec::new();
+        for (product_id, variant_id) in items.iter() {
+            let product: Option<product::Model> = product::Entity::find_by_id(*product_id)
+                .one(&self.db)
+                .await
+                .to_local_error(RecordType::Product)?;
+            let product = product.ok_or(LocalError::IdNotFound)?;
+
+            check_variant_given(&self.db, *product_id, *variant_id).await?;
+            let variant = match variant_id {
+                None => None,
+                Some(variant_id) => {
+                    let variant: Option<product_variant::Model> =
+                        product_variant::Entity::find_by_id(*variant_id)
+                            .one(&self.db)
+                            .await
+                            .to_local_error(RecordType::Product)?;
+                    Some(
+                        variant
+                            .filter(|v| v.product_id == *product_id)
+                            .ok_or(LocalError::IdNotFound)?,
+                    )
+                }
+            };
+
+            basket.push((product, variant));
+        }
+
+        Ok(basket)
+    }
+
+    // Running promotions that apply by themselves, and those of the given coupon codes.
+    // Fails if a code can't be used, so a checkout doesn't go through without the discount
+    // the customer expects. Without `user_id` the per-user limits are not checked.
+    pub async fn get_offers(
+        &self,
+        codes: &[String],
+        user_id: Option<i32>,
+        now: NaiveDateTime,
+    ) -> Result<Vec<Offer>, LocalError> {
+        let promotions: Vec<promotion::Model> = promotion::Entity::find()
+            .filter(promotion::Column::Active.eq(true))
+            .filter(promotion::Column::RequiresCoupon.eq(false))
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let mut offers: Vec<Offer> = promotions
+            .into_iter()
+            .filter(|p| in_window(p.starts_at, p.ends_at, now))
+            .map(|promotion| Offer {
+                promotion,
+                coupon: None,
+                category_ids: Vec::new(),
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        for code in codes.iter().map(|c| normalize_code(c)) {
+            if code.is_empty() || !seen.insert(code.clone()) {
+                continue;
+            }
+
+            let found: Option<(coupon::Model, Option<promotion::Model>)> = coupon::Entity::find()
+                .filter(coupon::Column::Code.eq(code))
+                .find_also_related(promotion::Entity)
+                .one(&self.db)
+                .await
+                .to_local_error(RecordType::Product)?;
+            let (coupon, promotion) = match found {
+                Some((coupon, Some(promotion)))
+                    if coupon.active
+                        && promotion.active
+                        && in_window(coupon.starts_at, coupon.ends_at, now)
+                        && in_window(promotion.starts_at, promotion.ends_at, now) =>
+                {
+                    (coupon, promotion)
+                }
+                _ => return Err(LocalError::InvalidCoupon),
+            };
+
+            if coupon
+                .usage_limit
+                .is_some_and(|limit| coupon.times_used >= limit)
+            {
+                return Err(LocalError::CouponLimitReached);
+            }
+            if let (Some(limit), Some(user_id)) = (coupon.per_user_limit, user_id) {
+                if self.count_redemptions(coupon.coupon_id, user_id).await? >= limit as usize {
+                    return Err(LocalError::CouponLimitReached);
+                }
+            }
+
+            // two codes of one promotion don't apply it twice
+            if offers
+                .iter()
+                .all(|o| o.promotion.promotion_id != promotion.promotion_id)
+            {
+                offers.push(Offer {
+                    promotion,
+                    coupon: Some(coupon),
+                    category_ids: Vec::new(),
+                });
+            }
+        }
+
+        if offers.iter().any(|o| o.promotion.category_id.is_some()) {
+            let categories: Vec<category::Model> = category::Entity::find()
+                .all(&self.db)
+                .await
+                .to_local_error(RecordType::Product)?;
+            for offer in offers.iter_mut() {
+                if let Some(category_id) = offer.promotion.category_id {
+                    offer.category_ids = subtree(&categories, category_id);
+                }
+            }
+        }
+
+        Ok(offers)
+    }
+
+    async fn count_redemptions(&self, coupon_id: i32, user_id: i32) -> Result<usize, LocalError> {
+        coupon_redemption::Entity::find()
+            .filter(coupon_redemption::Column::CouponId.eq(coupon_id))
+            .filter(coupon_redemption::Column::UserId.eq(user_id))
+            .count(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // Uses the coupons for the purchase of `order_ref`, all of them or none. The limits are
+    // checked again under lock, as other checkouts may have used them meanwhile. A retry
+    // with the same `order_ref` doesn't use them twice.
+    pub async fn redeem_coupons(
+        &self,
+        coupon_ids: &[i32],
+        user_id: i32,
+        order_ref: &str,
+    ) -> Result<(), LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let coupons: Vec<coupon::Model> = coupon::Entity::find()
+            .filter(coupon::Column::CouponId.is_in(coupon_ids.to_vec()))
+            .order_by_asc(coupon::Column::CouponId)
+            .lock_exclusive()
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        let now = Utc::now().naive_utc();
+        for coupon in coupons {
+            let redeemed = coupon_redemption::Entity::find()
+                .filter(coupon_redemption::Column::CouponId.eq(coupon.coupon_id))
+                .filter(coupon_redemption::Column::OrderRef.eq(order_ref))
+                .count(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+            if redeemed > 0 {
+                continue;
+            }
+
+            let used_by_user = coupon_redemption::Entity::find()
+                .filter(coupon_redemption::Column::CouponId.eq(coupon.coupon_id))
+                .filter(coupon_redemption::Column::UserId.eq(user_id))
+                .count(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+            if coupon
+                .usage_limit
+                .is_some_and(|limit| coupon.times_used >= limit)
+                || coupon
+                    .per_user_limit
+                    .is_some_and(|limit| used_by_user >= limit as usize)
+            {
+                return Err(LocalError::CouponLimitReached);
+            }
+
+            coupon_redemption::ActiveModel {
+                redemption_id: NotSet,
+                coupon_id: Set(coupon.coupon_id),
+                user_id: Set(user_id),
+                order_ref: Set(order_ref.to_string()),
+                redeemed_at: Set(now),
+            }
+            .insert(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+            let times_used = coupon.times_used + 1;
+            let mut coupon: coupon::ActiveModel = coupon.into();
+            coupon.times_used = Set(times_used);
+            coupon
+                .update(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+        }
+
+        txn.commit().await.to_local_error(RecordType::Product)?;
+        Ok(())
+    }
+
+    // Gives back coupons used for `order_ref`, when the purchase failed or didn't need them.
+    pub async fn release_coupons(
+        &self,
+        coupon_ids: &[i32],
+        order_ref: &str,
+    ) -> Result<(), LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        for coupon_id in coupon_ids.iter() {
+            let res = coupon_redemption::Entity::delete_many()
+                .filter(coupon_redemption::Column::CouponId.eq(*coupon_id))
+                .filter(coupon_redemption::Column::OrderRef.eq(order_ref))
+                .exec(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+            if res.rows_affected == 0 {
+                continue;
+            }
+
+            coupon::Entity::update_many()
+                .col_expr(
+                    coupon::Column::TimesUsed,
+                    Expr::col(coupon::Column::TimesUsed).sub(res.rows_affected as i32),
+                )
+                .filter(coupon::Column::CouponId.eq(*coupon_id))
+                .exec(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+        }
+
+        txn.commit().await.to_local_error(RecordType::Product)?;
+        Ok(())
+    }
+}
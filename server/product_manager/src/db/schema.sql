@@ -1,10 +1,162 @@
 CREATE DATABASE product_db;
 
+CREATE TABLE category (
+     category_id serial PRIMARY KEY,
+     parent_id INT REFERENCES category ( category_id ),
+     name VARCHAR ( 200 ) NOT NULL,
+     slug VARCHAR ( 200 ) UNIQUE NOT NULL,
+     position INT NOT NULL DEFAULT 0,
+     UNIQUE ( parent_id, name )
+);
+
 CREATE TABLE product (
      product_id serial PRIMARY KEY,
      name VARCHAR ( 500 ) UNIQUE NOT NULL,
      image VARCHAR ( 1000 ),
      count INT NOT NULL,
      price DECIMAL NOT NULL,
-     category VARCHAR ( 500 ) NOT NULL
+     category_id INT NOT NULL REFERENCES category ( category_id ),
+     reorder_threshold INT
+);
+
+CREATE INDEX product_category_idx ON product ( category_id );
+
+CREATE TABLE product_variant (
+     variant_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     sku VARCHAR ( 100 ) UNIQUE NOT NULL,
+     options JSONB NOT NULL,
+     count INT NOT NULL CHECK ( count >= 0 ),
+     price DECIMAL,
+     image VARCHAR ( 1000 ),
+     UNIQUE ( product_id, options )
+);
+
+CREATE TABLE warehouse (
+     warehouse_id serial PRIMARY KEY,
+     name VARCHAR ( 500 ) UNIQUE NOT NULL,
+     latitude DOUBLE PRECISION,
+     longitude DOUBLE PRECISION,
+     priority INT NOT NULL DEFAULT 0,
+     active BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+CREATE TABLE product_stock (
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     warehouse_id INT NOT NULL REFERENCES warehouse ( warehouse_id ),
+     count INT NOT NULL CHECK ( count >= 0 ),
+     PRIMARY KEY ( product_id, warehouse_id )
+);
+
+INSERT INTO warehouse ( name ) VALUES ( 'main' );
+
+CREATE TABLE reservation (
+     reservation_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     variant_id INT,
+     count INT NOT NULL,
+     holder VARCHAR ( 200 ) NOT NULL,
+     user_id INT,
+     token VARCHAR ( 100 ),
+     status VARCHAR ( 20 ) NOT NULL,
+     created_at TIMESTAMP NOT NULL,
+     expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX reservation_active_idx ON reservation ( product_id, expires_at ) WHERE status = 'active';
+CREATE INDEX reservation_holder_idx ON reservation ( holder );
+
+CREATE TABLE inventory_movements (
+     movement_id serial PRIMARY KEY,
+     product_id INT NOT NULL,
+     warehouse_id INT,
+     variant_id INT,
+     kind VARCHAR ( 20 ) NOT NULL,
+     quantity INT NOT NULL,
+     reason VARCHAR ( 500 ) NOT NULL,
+     actor VARCHAR ( 100 ) NOT NULL,
+     order_ref VARCHAR ( 200 ),
+     created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX inventory_movements_product_idx ON inventory_movements ( product_id, created_at );
+
+CREATE TABLE stock_subscription (
+     subscription_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     user_id INT NOT NULL,
+     email VARCHAR ( 500 ) NOT NULL,
+     created_at TIMESTAMP NOT NULL,
+     UNIQUE ( product_id, user_id )
+);
+
+CREATE INDEX stock_subscription_user_idx ON stock_subscription ( user_id );
+
+CREATE TABLE price_history (
+     price_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     price DECIMAL NOT NULL,
+     effective_from TIMESTAMP NOT NULL,
+     effective_to TIMESTAMP,
+     actor VARCHAR ( 100 ) NOT NULL,
+     reason VARCHAR ( 500 ) NOT NULL
+);
+
+CREATE INDEX price_history_product_idx ON price_history ( product_id, effective_from );
+
+CREATE TABLE price_schedule (
+     schedule_id serial PRIMARY KEY,
+     product_id INT NOT NULL REFERENCES product ( product_id ) ON DELETE CASCADE,
+     price DECIMAL NOT NULL CHECK ( price >= 0 ),
+     effective_from TIMESTAMP NOT NULL,
+     effective_to TIMESTAMP CHECK ( effective_to > effective_from ),
+     previous_price DECIMAL,
+     status VARCHAR ( 20 ) NOT NULL,
+     actor VARCHAR ( 100 ) NOT NULL,
+     created_at TIMESTAMP NOT NULL
 );
+
+CREATE INDEX price_schedule_due_idx ON price_schedule ( effective_from ) WHERE status IN ( 'pending', 'active' );
+
+CREATE TABLE promotion (
+     promotion_id serial PRIMARY KEY,
+     name VARCHAR ( 200 ) NOT NULL,
+     kind VARCHAR ( 20 ) NOT NULL,
+     value DECIMAL NOT NULL CHECK ( value >= 0 ),
+     buy_quantity INT CHECK ( buy_quantity > 0 ),
+     get_quantity INT CHECK ( get_quantity > 0 ),
+     product_id INT REFERENCES product ( product_id ) ON DELETE CASCADE,
+     category_id INT REFERENCES category ( category_id ) ON DELETE CASCADE,
+     min_basket DECIMAL CHECK ( min_basket >= 0 ),
+     requires_coupon BOOLEAN NOT NULL DEFAULT FALSE,
+     stackable BOOLEAN NOT NULL DEFAULT TRUE,
+     priority INT NOT NULL DEFAULT 0,
+     starts_at TIMESTAMP,
+     ends_at TIMESTAMP CHECK ( ends_at > starts_at ),
+     active BOOLEAN NOT NULL DEFAULT TRUE,
+     created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE coupon (
+     coupon_id serial PRIMARY KEY,
+     promotion_id INT NOT NULL REFERENCES promotion ( promotion_id ) ON DELETE CASCADE,
+     code VARCHAR ( 50 ) UNIQUE NOT NULL,
+     usage_limit INT CHECK ( usage_limit > 0 ),
+     per_user_limit INT CHECK ( per_user_limit > 0 ),
+     times_used INT NOT NULL DEFAULT 0,
+     starts_at TIMESTAMP,
+     ends_at TIMESTAMP CHECK ( ends_at > starts_at ),
+     active BOOLEAN NOT NULL DEFAULT TRUE,
+     created_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE coupon_redemption (
+     redemption_id serial PRIMARY KEY,
+     coupon_id INT NOT NULL REFERENCES coupon ( coupon_id ) ON DELETE CASCADE,
+     user_id INT NOT NULL,
+     order_ref VARCHAR ( 100 ) NOT NULL,
+     redeemed_at TIMESTAMP NOT NULL,
+     UNIQUE ( coupon_id, order_ref )
+);
+
+CREATE INDEX coupon_redemption_user_idx ON coupon_redemption ( coupon_id, user_id );
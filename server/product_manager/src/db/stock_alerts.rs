@@ -0,0 +1,142 @@
+use crate::db::postgres::{with_availability, PostgresDB};
+use crate::entities::{product, stock_subscription};
+use chrono::Utc;
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ColumnTrait, ConnectionTrait, EntityTrait, QueryFilter, QueryOrder, QuerySelect, TransactionTrait,
+};
+
+// Units that can still be bought: the count less active reservations.
+async fn available<C: ConnectionTrait>(db: &C, product: product::Model) -> Result<i32, LocalError> {
+    let products = with_availability(db, vec![product]).await?;
+    Ok(products.first().map(|p| p.available).unwrap_or(0))
+}
+
+impl PostgresDB {
+    // Products at or below their reorder threshold, emptiest first.
+    pub async fn get_low_stock(
+        &self,
+        default_threshold: i32,
+    ) -> Result<Vec<product::Model>, LocalError> {
+        product::Entity::find()
+            .filter(Expr::cust_with_values(
+                "count <= COALESCE(reorder_threshold, $1)",
+                vec![default_threshold],
+            ))
+            .order_by_asc(product::Column::Count)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    // Subscribing twice to the same product keeps the first subscription.
+    pub async fn add_subscription(
+        &self,
+        product_id: i32,
+        user_id: i32,
+        email: &str,
+    ) -> Result<i32, LocalError> {
+        let product: Option<product::Model> = product::Entity::find_by_id(product_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let product = product.ok_or(LocalError::IdNotFound)?;
+        if available(&self.db, product).await? > 0 {
+            return Err(LocalError::ItemInStock);
+        }
+
+        let existing: Option<stock_subscription::Model> = stock_subscription::Entity::find()
+            .filter(stock_subscription::Column::ProductId.eq(product_id))
+            .filter(stock_subscription::Column::UserId.eq(user_id))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if let Some(subscription) = existing {
+            return Ok(subscription.subscription_id);
+        }
+
+        let subscription = stock_subscription::ActiveModel {
+            subscription_id: NotSet,
+            product_id: Set(product_id),
+            user_id: Set(user_id),
+            email: Set(email.to_string()),
+            created_at: Set(Utc::now().naive_utc()),
+        };
+        let res = stock_subscription::Entity::insert(subscription)
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        Ok(res.last_insert_id)
+    }
+
+    pub async fn get_available(&self, product_id: i32) -> Result<i32, LocalError> {
+        let product: Option<product::Model> = product::Entity::find_by_id(product_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        available(&self.db, product.ok_or(LocalError::IdNotFound)?).await
+    }
+
+    pub async fn get_subscriptions(
+        &self,
+        user_id: i32,
+    ) -> Result<Vec<stock_subscription::Model>, LocalError> {
+        stock_subscription::Entity::find()
+            .filter(stock_subscription::Column::UserId.eq(user_id))
+            .order_by_asc(stock_subscription::Column::CreatedAt)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    pub async fn delete_subscription(
+        &self,
+        subscription_id: i32,
+        user_id: i32,
+    ) -> Result<(), LocalError> {
+        let res = stock_subscription::Entity::delete_many()
+            .filter(stock_subscription::Column::SubscriptionId.eq(subscription_id))
+            .filter(stock_subscription::Column::UserId.eq(user_id))
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        if res.rows_affected == 0 {
+            return Err(LocalError::IdNotFound);
+        }
+        Ok(())
+    }
+
+    // Removes and returns the product's subscriptions once it is back in stock, so each
+    // subscriber is notified only once.
+    pub async fn take_subscriptions(
+        &self,
+        product_id: i32,
+    ) -> Result<Vec<stock_subscription::Model>, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let subscriptions: Vec<stock_subscription::Model> = stock_subscription::Entity::find()
+            .filter(stock_subscription::Column::ProductId.eq(product_id))
+            .lock_exclusive()
+            .all(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if subscriptions.is_empty() {
+            return Ok(subscriptions);
+        }
+
+        stock_subscription::Entity::delete_many()
+            .filter(
+                stock_subscription::Column::SubscriptionId
+                    .is_in(subscriptions.iter().map(|s| s.subscription_id)),
+            )
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(subscriptions)
+    }
+}
@@ -0,0 +1,454 @@
+use crate::db::postgres::{record_movement, Movement, PostgresDB};
+use crate::db::warehouses::{change_stock, spread_change};
+use crate::entities::{inventory_movements, product, product_variant, reservation};
+use chrono::Utc;
+use common::db_utils::{unique_violation, RecordType, ToError};
+use common::money;
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ActiveModelTrait, ColumnTrait, ConnectionTrait, DbErr, EntityTrait, QueryFilter,
+    QueryOrder, QuerySelect, TransactionTrait,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+// Surfaces violations of the unique SKU and of unique option combinations per product.
+fn to_variant_error<T>(res: Result<T, DbErr>) -> Result<T, LocalError> {
+    if let Err(ref e) = res {
+        match unique_violation(e) {
+            Some("product_variant_sku_key") => return Err(LocalError::SkuTaken),
+            Some("product_variant_product_id_options_key") => {
+                return Err(LocalError::VariantExists)
+            }
+            _ => {}
+        }
+    }
+
+    res.to_local_error(RecordType::Product)
+}
+
+// Options are a non-empty object of strings, with the same axes as the product's other variants.
+fn check_options(options: &Value, axes: Option<&Value>) -> Result<(), LocalError> {
+    let options = options.as_object().ok_or(LocalError::WrongParameters)?;
+    if options.is_empty() || options.values().any(|v| !v.is_string()) {
+        return Err(LocalError::WrongParameters);
+    }
+
+    if let Some(axes) = axes.and_then(|a| a.as_object()) {
+        if options.len() != axes.len() || options.keys().any(|k| !axes.contains_key(k)) {
+            return Err(LocalError::WrongParameters);
+        }
+    }
+    Ok(())
+}
+
+fn parse_price(price: &Value) -> Result<Option<Decimal>, LocalError> {
+    if price.is_null() {
+        return Ok(None);
+    }
+    money::parse_amount(price)
+        .filter(|p| !p.is_sign_negative())
+        .map(Some)
+        .ok_or(LocalError::WrongParameters)
+}
+
+// Options of another variant of the product, which fix its axes.
+async fn product_axes<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    except: Option<i32>,
+) -> Result<Option<Value>, LocalError> {
+    let mut query =
+        product_variant::Entity::find().filter(product_variant::Column::ProductId.eq(product_id));
+    if let Some(variant_id) = except {
+        query = query.filter(product_variant::Column::VariantId.ne(variant_id));
+    }
+
+    let variant: Option<product_variant::Model> =
+        query.one(db).await.to_local_error(RecordType::Product)?;
+    Ok(variant.map(|v| v.options))
+}
+
+pub(super) async fn has_variants<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+) -> Result<bool, LocalError> {
+    Ok(product_axes(db, product_id, None).await?.is_some())
+}
+
+// Stock of products with variants is kept per variant, so changes must name one.
+pub(super) async fn check_variant_given<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    variant_id: Option<i32>,
+) -> Result<(), LocalError> {
+    if variant_id.is_none() && has_variants(db, product_id).await? {
+        return Err(LocalError::VariantRequired);
+    }
+    Ok(())
+}
+
+// Units held by active reservations, per variant.
+async fn reserved_variant_counts<C: ConnectionTrait>(
+    db: &C,
+    variant_ids: Vec<i32>,
+) -> Result<HashMap<i32, i32>, LocalError> {
+    let rows: Vec<Value> = reservation::Entity::find()
+        .select_only()
+        .column(reservation::Column::VariantId)
+        .column_as(Expr::col(reservation::Column::Count).sum(), "reserved")
+        .filter(reservation::Column::VariantId.is_in(variant_ids))
+        .filter(reservation::Column::Status.eq(reservation::ACTIVE))
+        .filter(reservation::Column::ExpiresAt.gt(Utc::now().naive_utc()))
+        .group_by(reservation::Column::VariantId)
+        .into_json()
+        .all(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            Some((
+                row.get("variant_id")?.as_i64()? as i32,
+                row.get("reserved")?.as_i64()? as i32,
+            ))
+        })
+        .collect())
+}
+
+pub(super) async fn with_variant_availability<C: ConnectionTrait>(
+    db: &C,
+    mut variants: Vec<product_variant::Model>,
+) -> Result<Vec<product_variant::Model>, LocalError> {
+    let ids = variants.iter().map(|v| v.variant_id).collect();
+    let reserved = reserved_variant_counts(db, ids).await?;
+    for variant in variants.iter_mut() {
+        variant.available = variant.count - reserved.get(&variant.variant_id).copied().unwrap_or(0);
+    }
+    Ok(variants)
+}
+
+// Locked variant of the product, with its availability.
+pub(super) async fn lock_variant<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    variant_id: i32,
+) -> Result<product_variant::Model, LocalError> {
+    let variant: Option<product_variant::Model> = product_variant::Entity::find_by_id(variant_id)
+        .lock_exclusive()
+        .one(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+    let variant = variant
+        .filter(|v| v.product_id == product_id)
+        .ok_or(LocalError::IdNotFound)?;
+
+    Ok(with_variant_availability(db, vec![variant])
+        .await?
+        .remove(0))
+}
+
+// Adds `delta` to a variant's count. `product.count` and warehouse stock are left to the caller.
+pub(super) async fn change_variant_stock<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    variant_id: i32,
+    delta: i32,
+) -> Result<product_variant::Model, LocalError> {
+    let variant = lock_variant(db, product_id, variant_id).await?;
+    if variant.count + delta < 0 {
+        return Err(LocalError::ItemNotAvailable);
+    }
+
+    let count = variant.count;
+    let mut variant: product_variant::ActiveModel = variant.into();
+    variant.count = Set(count + delta);
+    variant.update(db).await.to_local_error(RecordType::Product)
+}
+
+// Applies a stock change of a variant to it, its product and a warehouse, with its ledger entry.
+async fn apply_variant_movement<C: ConnectionTrait>(
+    db: &C,
+    movement: Movement<'_>,
+    actor: &str,
+) -> Result<product_variant::Model, LocalError> {
+    let product: Option<product::Model> = product::Entity::find_by_id(movement.product_id)
+        .lock_exclusive()
+        .one(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+    let product = product.ok_or(LocalError::IdNotFound)?;
+
+    let variant = change_variant_stock(
+        db,
+        movement.product_id,
+        movement.variant_id.ok_or(LocalError::VariantRequired)?,
+        movement.quantity,
+    )
+    .await?;
+
+    let changes = match movement.warehouse_id {
+        Some(id) => vec![(id, movement.quantity)],
+        None => spread_change(db, movement.product_id, movement.quantity).await?,
+    };
+    for (warehouse_id, quantity) in changes.iter().copied() {
+        change_stock(db, movement.product_id, warehouse_id, quantity).await?;
+    }
+
+    let product_count = product.count;
+    let mut product: product::ActiveModel = product.into();
+    product.count = Set(product_count + movement.quantity);
+    product
+        .update(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    for (warehouse_id, quantity) in changes {
+        let movement = Movement {
+            warehouse_id: Some(warehouse_id),
+            quantity,
+            ..movement
+        };
+        record_movement(db, movement, actor).await?;
+    }
+    Ok(variant)
+}
+
+// On `db` so that the catalog import can run them in its transaction.
+pub async fn add_variant<C: ConnectionTrait + TransactionTrait>(
+    db: &C,
+    variant_json: Value, actor: &str) -> Result<i32, LocalError> {
+    let product_id = variant_json
+        .get("product_id")
+        .and_then(|id| id.as_i64())
+        .ok_or(LocalError::WrongParameters)? as i32;
+    let sku = variant_json
+        .get("sku")
+        .and_then(|s| s.as_str())
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .ok_or(LocalError::WrongParameters)?;
+    let options = variant_json
+        .get("options")
+        .cloned()
+        .ok_or(LocalError::WrongParameters)?;
+    let count = variant_json
+        .get("count")
+        .map(|c| c.as_i64().ok_or(LocalError::WrongParameters))
+        .transpose()?
+        .unwrap_or(0) as i32;
+    let price = match variant_json.get("price") {
+        Some(price) => parse_price(price)?,
+        None => None,
+    };
+    let image = variant_json
+        .get("image")
+        .and_then(|i| i.as_str())
+        .map(|i| i.to_string());
+    if count < 0 {
+        return Err(LocalError::WrongParameters);
+    }
+
+    let txn = db.begin().await.to_local_error(RecordType::Product)?;
+
+    let product: Option<product::Model> = product::Entity::find_by_id(product_id)
+        .lock_exclusive()
+        .one(&txn)
+        .await
+        .to_local_error(RecordType::Product)?;
+    let product = product.ok_or(LocalError::IdNotFound)?;
+
+    let axes = product_axes(&txn, product_id, None).await?;
+    if axes.is_none() && product.count != 0 {
+        return Err(LocalError::ItemInStock);
+    }
+    check_options(&options, axes.as_ref())?;
+
+    let new_variant = product_variant::ActiveModel {
+        variant_id: NotSet,
+        product_id: Set(product_id),
+        sku: Set(sku.to_string()),
+        options: Set(options),
+        count: Set(0),
+        price: Set(price),
+        image: Set(image),
+    };
+    let variant: product_variant::Model = to_variant_error(new_variant.insert(&txn).await)?;
+
+    if count != 0 {
+        let warehouse_id = variant_json
+            .get("warehouse_id")
+            .and_then(|id| id.as_i64())
+            .map(|id| id as i32);
+        apply_variant_movement(
+            &txn,
+            Movement {
+                product_id,
+                warehouse_id,
+                variant_id: Some(variant.variant_id),
+                kind: inventory_movements::RESTOCK,
+                quantity: count,
+                reason: "initial stock",
+                order_ref: None,
+            },
+            actor,
+        )
+        .await?;
+    }
+    txn.commit().await.to_local_error(RecordType::Product)?;
+
+    Ok(variant.variant_id)
+}
+
+pub async fn update_variant<C: ConnectionTrait + TransactionTrait>(
+    db: &C,
+    variant_id: i32,
+    updates: Value,
+    actor: &str,
+) -> Result<Value, LocalError> {
+    let txn = db.begin().await.to_local_error(RecordType::Product)?;
+
+    let variant: Option<product_variant::Model> =
+        product_variant::Entity::find_by_id(variant_id)
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+    let variant = variant.ok_or(LocalError::IdNotFound)?;
+    let product_id = variant.product_id;
+    let old_count = variant.count;
+    let mut variant: product_variant::ActiveModel = variant.into();
+
+    let updates: Map<String, Value> = updates
+        .as_object()
+        .ok_or(LocalError::WrongParameters)?
+        .clone();
+    let mut new_count = old_count;
+    for (key, val) in updates.iter() {
+        match key.as_str() {
+            "sku" => {
+                let sku = val.as_str().map(|s| s.trim()).unwrap_or_default();
+                if sku.is_empty() {
+                    return Err(LocalError::WrongParameters);
+                }
+                variant.sku = Set(sku.to_string());
+            }
+            "options" => {
+                let axes = product_axes(&txn, product_id, Some(variant_id)).await?;
+                check_options(val, axes.as_ref())?;
+                variant.options = Set(val.clone());
+            }
+            // null falls back to the product price
+            "price" => variant.price = Set(parse_price(val)?),
+            "image" => variant.image = Set(val.as_str().map(|i| i.to_string())),
+            "count" => {
+                let count = val.as_i64().ok_or(LocalError::WrongParameters)?;
+                new_count = count as i32;
+            }
+            _ => {}
+        }
+    }
+
+    to_variant_error(variant.update(&txn).await)?;
+
+    let quantity = new_count - old_count;
+    if quantity != 0 {
+        let kind = if quantity > 0 {
+            inventory_movements::RESTOCK
+        } else {
+            inventory_movements::ADJUSTMENT
+        };
+        let reason = updates
+            .get("reason")
+            .and_then(|r| r.as_str())
+            .unwrap_or("stock updated");
+        let warehouse_id = updates
+            .get("warehouse_id")
+            .and_then(|id| id.as_i64())
+            .map(|id| id as i32);
+
+        apply_variant_movement(
+            &txn,
+            Movement {
+                product_id,
+                warehouse_id,
+                variant_id: Some(variant_id),
+                kind,
+                quantity,
+                reason,
+                order_ref: updates.get("order_ref").and_then(|r| r.as_str()),
+            },
+            actor,
+        )
+        .await?;
+    }
+
+    let variant: Option<product_variant::Model> =
+        product_variant::Entity::find_by_id(variant_id)
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+    txn.commit().await.to_local_error(RecordType::Product)?;
+
+    let variant = with_variant_availability(db, variant.into_iter().collect()).await?;
+    Ok(json!(variant.first()))
+}
+
+impl PostgresDB {
+    pub async fn get_variants(
+        &self,
+        product_id: i32,
+    ) -> Result<Vec<product_variant::Model>, LocalError> {
+        let variants: Vec<product_variant::Model> = product_variant::Entity::find()
+            .filter(product_variant::Column::ProductId.eq(product_id))
+            .order_by_asc(product_variant::Column::VariantId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        with_variant_availability(&self.db, variants).await
+    }
+
+    // A product only gets variants while it holds no stock of its own; stock is then kept
+    // per variant, and the product's count is their sum.
+    pub async fn add_variant(&self, variant_json: Value, actor: &str) -> Result<i32, LocalError> {
+        add_variant(&self.db, variant_json, actor).await
+    }
+
+    // A "count" change is recorded like one of the product's, see `update_product`.
+    pub async fn update_variant(
+        &self,
+        variant_id: i32,
+        updates: Value,
+        actor: &str,
+    ) -> Result<Value, LocalError> {
+        update_variant(&self.db, variant_id, updates, actor).await
+    }
+
+    // Only variants without stock can be deleted. Returns the product of the variant.
+    pub async fn delete_variant(&self, variant_id: i32) -> Result<i32, LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let variant: Option<product_variant::Model> =
+            product_variant::Entity::find_by_id(variant_id)
+                .lock_exclusive()
+                .one(&txn)
+                .await
+                .to_local_error(RecordType::Product)?;
+        let variant = variant.ok_or(LocalError::IdNotFound)?;
+        if variant.count != 0 {
+            return Err(LocalError::ItemInStock);
+        }
+
+        product_variant::Entity::delete_by_id(variant_id)
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(variant.product_id)
+    }
+}
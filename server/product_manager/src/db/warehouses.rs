@@ -0,0 +1,441 @@
+use crate::db::postgres::{record_movement, reserved_counts, Movement, PostgresDB};
+use crate::entities::{inventory_movements, product, product_stock, warehouse};
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    entity::*, ActiveModelTrait, ColumnTrait, ConnectionTrait, EntityTrait, JoinType,
+    QueryFilter, QueryOrder, QuerySelect, RelationTrait, TransactionTrait,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllocationStrategy {
+    // closest to the customer, when their location is known
+    Nearest,
+    MostStock,
+    // lowest `priority` first
+    Priority,
+}
+
+impl AllocationStrategy {
+    pub fn parse(strategy: &str) -> Option<AllocationStrategy> {
+        match strategy {
+            "nearest" => Some(AllocationStrategy::Nearest),
+            "most_stock" => Some(AllocationStrategy::MostStock),
+            "priority" => Some(AllocationStrategy::Priority),
+            _ => None,
+        }
+    }
+}
+
+pub struct Allocation {
+    pub strategy: AllocationStrategy,
+    // (latitude, longitude) of the customer
+    pub location: Option<(f64, f64)>,
+}
+
+// Great-circle distance in km.
+fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (from.0.to_radians(), from.1.to_radians());
+    let (lat2, lon2) = (to.0.to_radians(), to.1.to_radians());
+    let a = ((lat2 - lat1) / 2.0).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.0).sin().powi(2);
+    6371.0 * 2.0 * a.sqrt().asin()
+}
+
+// Warehouse that takes stock when none is named: the first active one by priority.
+pub(super) async fn default_warehouse<C: ConnectionTrait>(db: &C) -> Result<i32, LocalError> {
+    let warehouse: Option<warehouse::Model> = warehouse::Entity::find()
+        .filter(warehouse::Column::Active.eq(true))
+        .order_by_asc(warehouse::Column::Priority)
+        .order_by_asc(warehouse::Column::WarehouseId)
+        .one(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    warehouse
+        .map(|w| w.warehouse_id)
+        .ok_or(LocalError::OperationFailed)
+}
+
+// Units in inactive warehouses, per product (all products when `product_ids` is None).
+// They count in `product.count` but can't be allocated, so they aren't available.
+pub(super) async fn inactive_counts<C: ConnectionTrait>(
+    db: &C,
+    product_ids: Option<Vec<i32>>,
+) -> Result<HashMap<i32, i32>, LocalError> {
+    let mut query = product_stock::Entity::find()
+        .select_only()
+        .column(product_stock::Column::ProductId)
+        .column_as(
+            Expr::tbl(product_stock::Entity, product_stock::Column::Count).sum(),
+            "inactive",
+        )
+        .join(JoinType::InnerJoin, product_stock::Relation::Warehouse.def())
+        .filter(warehouse::Column::Active.eq(false))
+        .group_by(product_stock::Column::ProductId);
+    if let Some(product_ids) = product_ids {
+        query = query.filter(product_stock::Column::ProductId.is_in(product_ids));
+    }
+
+    let rows: Vec<Value> = query
+        .into_json()
+        .all(db)
+        .await
+        .to_local_error(RecordType::Product)?;
+
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            Some((
+                row.get("product_id")?.as_i64()? as i32,
+                row.get("inactive")?.as_i64()? as i32,
+            ))
+        })
+        .collect())
+}
+
+// Adds `delta` to the product's stock in a warehouse. `product.count` is left to the caller.
+pub(super) async fn change_stock<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    warehouse_id: i32,
+    delta: i32,
+) -> Result<(), LocalError> {
+    let stock: Option<product_stock::Model> =
+        product_stock::Entity::find_by_id((product_id, warehouse_id))
+            .lock_exclusive()
+            .one(db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+    match stock {
+        Some(stock) => {
+            if stock.count + delta < 0 {
+                return Err(LocalError::ItemNotAvailable);
+            }
+            let count = stock.count;
+            let mut stock: product_stock::ActiveModel = stock.into();
+            stock.count = Set(count + delta);
+            stock.update(db).await.to_local_error(RecordType::Product)?;
+        }
+        None => {
+            if delta < 0 {
+                return Err(LocalError::ItemNotAvailable);
+            }
+            let warehouse: Option<warehouse::Model> = warehouse::Entity::find_by_id(warehouse_id)
+                .one(db)
+                .await
+                .to_local_error(RecordType::Product)?;
+            if warehouse.is_none() {
+                return Err(LocalError::IdNotFound);
+            }
+
+            let stock = product_stock::ActiveModel {
+                product_id: Set(product_id),
+                warehouse_id: Set(warehouse_id),
+                count: Set(delta),
+            };
+            product_stock::Entity::insert(stock)
+                .exec(db)
+                .await
+                .to_local_error(RecordType::Product)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Picks the warehouses `count` units ship from, in the order of the strategy; an order
+// is split only when no single warehouse has enough. Returns (warehouse_id, count) pairs.
+pub(super) async fn allocate<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    count: i32,
+    allocation: &Allocation,
+) -> Result<Vec<(i32, i32)>, LocalError> {
+    let stocks: Vec<(product_stock::Model, Option<warehouse::Model>)> =
+        product_stock::Entity::find()
+            .find_also_related(warehouse::Entity)
+            .filter(product_stock::Column::ProductId.eq(product_id))
+            .filter(product_stock::Column::Count.gt(0))
+            .filter(warehouse::Column::Active.eq(true))
+            .all(db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+    let mut candidates: Vec<(product_stock::Model, warehouse::Model)> = stocks
+        .into_iter()
+        .filter_map(|(stock, warehouse)| Some((stock, warehouse?)))
+        .collect();
+
+    let by_priority = |a: &(product_stock::Model, warehouse::Model),
+                       b: &(product_stock::Model, warehouse::Model)| {
+        a.1.priority
+            .cmp(&b.1.priority)
+            .then(a.1.warehouse_id.cmp(&b.1.warehouse_id))
+    };
+    match (allocation.strategy, allocation.location) {
+        (AllocationStrategy::Nearest, Some(location)) => {
+            // warehouses without coordinates go last
+            let key = |w: &warehouse::Model| match (w.latitude, w.longitude) {
+                (Some(lat), Some(lon)) => distance(location, (lat, lon)),
+                _ => f64::MAX,
+            };
+            candidates.sort_by(|a, b| key(&a.1).total_cmp(&key(&b.1)).then(by_priority(a, b)));
+        }
+        (AllocationStrategy::MostStock, _) => {
+            candidates.sort_by(|a, b| b.0.count.cmp(&a.0.count).then(by_priority(a, b)));
+        }
+        _ => candidates.sort_by(by_priority),
+    }
+
+    if let Some((stock, _)) = candidates.iter().find(|(stock, _)| stock.count >= count) {
+        return Ok(vec![(stock.warehouse_id, count)]);
+    }
+
+    let mut allocated = Vec::new();
+    let mut left = count;
+    for (stock, _) in candidates {
+        if left == 0 {
+            break;
+        }
+        let taken = stock.count.min(left);
+        allocated.push((stock.warehouse_id, taken));
+        left -= taken;
+    }
+
+    if left > 0 {
+        return Err(LocalError::ItemNotAvailable);
+    }
+    Ok(allocated)
+}
+
+// Where a stock change lands when no warehouse is named: additions go to the default
+// warehouse, removals are drawn from the active warehouses by priority. Units held by
+// active reservations are left; they are only tied to a warehouse once committed, so they
+// are counted against the warehouses in the same order. Returns (warehouse_id, delta) pairs.
+pub(super) async fn spread_change<C: ConnectionTrait>(
+    db: &C,
+    product_id: i32,
+    delta: i32,
+) -> Result<Vec<(i32, i32)>, LocalError> {
+    if delta >= 0 {
+        return Ok(vec![(default_warehouse(db).await?, delta)]);
+    }
+
+    let stocks: Vec<(product_stock::Model, Option<warehouse::Model>)> =
+        product_stock::Entity::find()
+            .find_also_related(warehouse::Entity)
+            .filter(product_stock::Column::ProductId.eq(product_id))
+            .filter(product_stock::Column::Count.gt(0))
+            .filter(warehouse::Column::Active.eq(true))
+            .order_by_asc(warehouse::Column::Priority)
+            .order_by_asc(warehouse::Column::WarehouseId)
+            .all(db)
+            .await
+            .to_local_error(RecordType::Product)?;
+    let mut held = reserved_counts(db, Some(vec![product_id]))
+        .await?
+        .get(&product_id)
+        .copied()
+        .unwrap_or(0);
+
+    let mut changes = Vec::new();
+    let mut left = -delta;
+    for (stock, _) in stocks {
+        if left == 0 {
+            break;
+        }
+        let reserved = stock.count.min(held);
+        held -= reserved;
+        let taken = (stock.count - reserved).min(left);
+        if taken > 0 {
+            changes.push((stock.warehouse_id, -taken));
+            left -= taken;
+        }
+    }
+
+    if left > 0 {
+        return Err(LocalError::ItemNotAvailable);
+    }
+    Ok(changes)
+}
+
+impl PostgresDB {
+    pub async fn get_warehouses(&self) -> Result<Vec<warehouse::Model>, LocalError> {
+        warehouse::Entity::find()
+            .order_by_asc(warehouse::Column::Priority)
+            .order_by_asc(warehouse::Column::WarehouseId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::Product)
+    }
+
+    pub async fn add_warehouse(&self, mut warehouse_json: Value) -> Result<i32, LocalError> {
+        // `from_json` deserializes a whole `Model`, so it needs a placeholder primary key
+        if let Some(json_map) = warehouse_json.as_object_mut() {
+            json_map.insert("warehouse_id".to_string(), json!(0));
+        }
+        let mut new_warehouse = warehouse::ActiveModel::from_json(warehouse_json)
+            .to_local_error(RecordType::Product)?;
+        new_warehouse.warehouse_id = NotSet;
+
+        let res = warehouse::Entity::insert(new_warehouse)
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        Ok(res.last_insert_id)
+    }
+
+    pub async fn update_warehouse(
+        &self,
+        warehouse_id: i32,
+        updates: Value,
+    ) -> Result<Value, LocalError> {
+        let warehouse: Option<warehouse::Model> = warehouse::Entity::find_by_id(warehouse_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let mut warehouse: warehouse::ActiveModel = warehouse.ok_or(LocalError::IdNotFound)?.into();
+
+        let updates = updates.as_object().ok_or(LocalError::WrongParameters)?;
+        for (key, val) in updates.iter() {
+            match key.as_str() {
+                "name" => {
+                    let name = val.as_str().ok_or(LocalError::WrongParameters)?;
+                    warehouse.name = Set(name.to_string());
+                }
+                "latitude" => warehouse.latitude = Set(val.as_f64()),
+                "longitude" => warehouse.longitude = Set(val.as_f64()),
+                "priority" => {
+                    let priority = val.as_i64().ok_or(LocalError::WrongParameters)?;
+                    warehouse.priority = Set(priority as i32);
+                }
+                "active" => {
+                    let active = val.as_bool().ok_or(LocalError::WrongParameters)?;
+                    warehouse.active = Set(active);
+                }
+                _ => {}
+            }
+        }
+
+        let warehouse: warehouse::Model = warehouse
+            .update(&self.db)
+            .await
+            .to_local_error(RecordType::Product)?;
+
+        Ok(json!(warehouse))
+    }
+
+    // Only warehouses without stock can be deleted; transfer it away first.
+    pub async fn delete_warehouse(&self, warehouse_id: i32) -> Result<(), LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        let stocked = product_stock::Entity::find()
+            .filter(product_stock::Column::WarehouseId.eq(warehouse_id))
+            .filter(product_stock::Column::Count.gt(0))
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if stocked.is_some() {
+            return Err(LocalError::WarehouseNotEmpty);
+        }
+
+        product_stock::Entity::delete_many()
+            .filter(product_stock::Column::WarehouseId.eq(warehouse_id))
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        let res = warehouse::Entity::delete_by_id(warehouse_id)
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if res.rows_affected == 0 {
+            return Err(LocalError::IdNotFound);
+        }
+
+        txn.commit().await.to_local_error(RecordType::Product)
+    }
+
+    // Stock of a product per warehouse.
+    pub async fn get_stock(&self, product_id: i32) -> Result<Vec<Value>, LocalError> {
+        let stocks: Vec<(product_stock::Model, Option<warehouse::Model>)> =
+            product_stock::Entity::find()
+                .find_also_related(warehouse::Entity)
+                .filter(product_stock::Column::ProductId.eq(product_id))
+                .order_by_asc(product_stock::Column::WarehouseId)
+                .all(&self.db)
+                .await
+                .to_local_error(RecordType::Product)?;
+
+        Ok(stocks
+            .into_iter()
+            .map(|(stock, warehouse)| {
+                json!({
+                    "warehouse_id": stock.warehouse_id,
+                    "name": warehouse.map(|w| w.name),
+                    "count": stock.count,
+                })
+            })
+            .collect())
+    }
+
+    // Moves units between warehouses; the product's total is unchanged.
+    pub async fn transfer(
+        &self,
+        product_id: i32,
+        from_warehouse_id: i32,
+        to_warehouse_id: i32,
+        quantity: i32,
+        actor: &str,
+    ) -> Result<Value, LocalError> {
+        if quantity <= 0 || from_warehouse_id == to_warehouse_id {
+            return Err(LocalError::WrongParameters);
+        }
+
+        let txn = self.db.begin().await.to_local_error(RecordType::Product)?;
+
+        // the product lock serializes the transfer with purchases of the product
+        let product: Option<product::Model> = product::Entity::find_by_id(product_id)
+            .lock_exclusive()
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::Product)?;
+        if product.is_none() {
+            return Err(LocalError::IdNotFound);
+        }
+
+        change_stock(&txn, product_id, from_warehouse_id, -quantity).await?;
+        change_stock(&txn, product_id, to_warehouse_id, quantity).await?;
+
+        let reason = format!(
+            "transfer from warehouse {} to {}",
+            from_warehouse_id, to_warehouse_id
+        );
+        for (warehouse_id, quantity) in
+            [(from_warehouse_id, -quantity), (to_warehouse_id, quantity)]
+        {
+            record_movement(
+                &txn,
+                Movement {
+                    product_id,
+                    warehouse_id: Some(warehouse_id),
+                    variant_id: None,
+                    kind: inventory_movements::TRANSFER,
+                    quantity,
+                    reason: &reason,
+                    order_ref: None,
+                },
+                actor,
+            )
+            .await?;
+        }
+        txn.commit().await.to_local_error(RecordType::Product)?;
+
+        Ok(json!(self.get_stock(product_id).await?))
+    }
+}
@@ -2,4 +2,16 @@
 
 pub mod prelude;
 
+pub mod category;
+pub mod coupon;
+pub mod coupon_redemption;
+pub mod inventory_movements;
+pub mod price_history;
+pub mod price_schedule;
 pub mod product;
+pub mod product_stock;
+pub mod product_variant;
+pub mod promotion;
+pub mod reservation;
+pub mod stock_subscription;
+pub mod warehouse;
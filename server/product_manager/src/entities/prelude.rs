@@ -1,3 +1,15 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.0
 
+pub use super::category::Entity as Category;
+pub use super::coupon::Entity as Coupon;
+pub use super::coupon_redemption::Entity as CouponRedemption;
+pub use super::inventory_movements::Entity as InventoryMovements;
+pub use super::price_history::Entity as PriceHistory;
+pub use super::price_schedule::Entity as PriceSchedule;
 pub use super::product::Entity as Product;
+pub use super::product_stock::Entity as ProductStock;
+pub use super::product_variant::Entity as ProductVariant;
+pub use super::promotion::Entity as Promotion;
+pub use super::reservation::Entity as Reservation;
+pub use super::stock_subscription::Entity as StockSubscription;
+pub use super::warehouse::Entity as Warehouse;
@@ -0,0 +1,37 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Prices of a product over time; `effective_to` is null for the current price.
+// Variant price overrides are not part of the history.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "price_history")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub price_id: i32,
+    pub product_id: i32,
+    pub price: Decimal,
+    pub effective_from: DateTime,
+    pub effective_to: Option<DateTime>,
+    pub actor: String,
+    pub reason: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::product::Entity",
+        from = "Column::ProductId",
+        to = "super::product::Column::ProductId",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    Product,
+}
+
+impl Related<super::product::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Product.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
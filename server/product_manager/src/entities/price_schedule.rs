@@ -0,0 +1,45 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub const PENDING: &str = "pending";
+// applied, and reverted to `previous_price` once `effective_to` passes
+pub const ACTIVE: &str = "active";
+pub const DONE: &str = "done";
+pub const CANCELLED: &str = "cancelled";
+
+// A price change applied by the scheduler at `effective_from`. Without `effective_to`
+// the new price stays.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "price_schedule")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub schedule_id: i32,
+    pub product_id: i32,
+    pub price: Decimal,
+    pub effective_from: DateTime,
+    pub effective_to: Option<DateTime>,
+    pub previous_price: Option<Decimal>,
+    pub status: String,
+    pub actor: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::product::Entity",
+        from = "Column::ProductId",
+        to = "super::product::Column::ProductId",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    Product,
+}
+
+impl Related<super::product::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Product.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -11,16 +11,101 @@ pub struct Model {
     #[sea_orm(unique)]
     pub name: String,
     pub image: Option<String>,
+    // total over all warehouses, kept in step with `product_stock`
     pub count: i32,
     pub price: Decimal,
-    pub category: String,
+    pub category_id: i32,
+    // low-stock alert level; `stock_alerts.default_reorder_threshold` when unset
+    pub reorder_threshold: Option<i32>,
     #[sea_orm(ignore)]
     #[sea_orm(skip)]
+    #[serde(default)]
     pub status: bool,
+    // `count` minus active reservations
+    #[sea_orm(ignore)]
+    #[sea_orm(skip)]
+    #[serde(default)]
+    pub available: i32,
+    // name of the category, for display
+    #[sea_orm(ignore)]
+    #[sea_orm(skip)]
+    #[serde(default)]
+    pub category: String,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::category::Entity",
+        from = "Column::CategoryId",
+        to = "super::category::Column::CategoryId",
+        on_update = "NoAction",
+        on_delete = "NoAction"
+    )]
+    Category,
+    #[sea_orm(has_many = "super::price_history::Entity")]
+    PriceHistory,
+    #[sea_orm(has_many = "super::price_schedule::Entity")]
+    PriceSchedule,
+    #[sea_orm(has_many = "super::product_stock::Entity")]
+    ProductStock,
+    #[sea_orm(has_many = "super::product_variant::Entity")]
+    ProductVariant,
+    #[sea_orm(has_many = "super::promotion::Entity")]
+    Promotion,
+    #[sea_orm(has_many = "super::reservation::Entity")]
+    Reservation,
+    #[sea_orm(has_many = "super::stock_subscription::Entity")]
+    StockSubscription,
+}
+
+impl Related<super::category::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Category.def()
+    }
+}
+
+impl Related<super::price_history::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::PriceHistory.def()
+    }
+}
+
+impl Related<super::price_schedule::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::PriceSchedule.def()
+    }
+}
+
+impl Related<super::product_stock::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ProductStock.def()
+    }
+}
+
+impl Related<super::product_variant::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ProductVariant.def()
+    }
+}
+
+impl Related<super::promotion::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Promotion.def()
+    }
+}
+
+impl Related<super::reservation::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Reservation.def()
+    }
+}
+
+impl Related<super::stock_subscription::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::StockSubscription.def()
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
 
@@ -28,6 +113,6 @@ impl Model {
     pub fn is_available(&self, count: Option<i32>) -> bool {
         let count = count.unwrap_or(1);
 
-        self.count - count >= 0
+        self.available - count >= 0
     }
 }
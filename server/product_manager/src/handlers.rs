@@ -1,9 +1,28 @@
 use std::collections::HashMap;
+use crate::catalog;
 use crate::context::Context;
+use crate::db::postgres::{Movement, NewReservation};
+use crate::db::prices::parse_time;
+use crate::db::promotions::Offer;
+use crate::db::warehouses::Allocation;
+use crate::entities::{product, product_variant, reservation};
+use crate::promotions::{self, Line, PricedLine, Pricing};
+use crate::recommendations;
+use crate::stock_alerts;
+use crate::totals;
+use chrono::{Duration, Utc};
+use common::auth::{
+    authenticate, authorize, random_token, service_token, AuthContext, ACCOUNTS_MANAGE,
+    ORDERS_CREATE, PRODUCTS_DELETE, PRODUCTS_WRITE,
+};
+use common::idempotency::IDEMPOTENCY_KEY_HEADER;
+use common::money;
+use common::rate_limit::API_KEY_HEADER;
 use common::request_response_utils::*;
 use common::utils::LocalError;
+use http::header::CONTENT_TYPE;
 use http::request::Parts;
-use http::StatusCode;
+use http::{HeaderValue, StatusCode};
 use hyper::{Body, Client, Response};
 use hyper_tls::HttpsConnector;
 use serde_json::{json, Value};
@@ -18,6 +37,29 @@ pub async fn get_items(context: Arc<Context>) -> Result<Response<Body>, hyper::E
     }
 }
 
+// /product/product/batch
+pub async fn get_items_by_ids(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = get_params(&parts.uri);
+    let ids: Option<Vec<i32>> = params
+        .get("ids")
+        .and_then(|ids| ids.split(',').map(|id| id.trim().parse().ok()).collect());
+
+    if ids.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context.db.postgres_db.get_products(ids.unwrap()).await {
+        Err(error) => create_response(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+        Ok(items) => create_response(StatusCode::OK, items.to_string()),
+    }
+}
+
 // /product/product
 pub async fn get_item(
     parts: &Parts,
@@ -31,9 +73,19 @@ pub async fn get_item(
 
     let id = id.ok().unwrap();
 
+    let currency = get_params(&parts.uri)
+        .get("currency")
+        .map(|c| c.trim().to_uppercase());
+    if let Some(ref currency) = currency {
+        if let Err(e) = context.exchange_rates.rate(currency) {
+            return create_response(StatusCode::BAD_REQUEST, e.to_string());
+        }
+    }
+
     let res = context.cache.get_product(id);
 
     if let Some(item) = res {
+        let item = add_display_price(item, currency.as_deref(), &context);
         return create_response(StatusCode::OK, item.to_string());
     }
 
@@ -63,7 +115,8 @@ pub async fn get_item(
                     url.query_pairs_mut().append_pair("user_id", &user_id.to_string());
                     url.query_pairs_mut().append_pair("product_id", &id.to_string());
 
-                    let request = hyper::Request::get(url.as_str())
+                    let request = hyper::Request::put(url.as_str())
+                        .header(API_KEY_HEADER, &context.user_manager.api_key)
                         .body(Body::empty());
 
                     if let Ok(request) = request {
@@ -74,16 +127,84 @@ pub async fn get_item(
                 };
             }
 
+            let item = add_display_price(item, currency.as_deref(), &context);
             create_response(StatusCode::OK, item.to_string())
         }
     }
 }
 
+// The product's price in `currency` too, for showing only.
+fn add_display_price(mut item: Value, currency: Option<&str>, context: &Context) -> Value {
+    let currency = match currency {
+        Some(currency) => currency,
+        None => return item,
+    };
+    let price = item.get("price").and_then(money::parse_amount);
+    if let Some(price) = price {
+        if let Ok(display_price) = context.exchange_rates.convert(price, currency) {
+            item["display_price"] = json!({"currency": currency, "price": display_price});
+        }
+    }
+    item
+}
+
+// /product/recommendations
+pub async fn get_recommendations(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = get_params(&parts.uri);
+    let limit: usize = params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .filter(|l| *l > 0)
+        .unwrap_or(10);
+
+    // a user's recommendations come from their private history: the caller's own, or
+    // the `user_id` one's for account managers
+    let request = if let Ok(id) = get_id_from_uri(&parts.uri, "product_id") {
+        Ok((recommendations::PRODUCT, id))
+    } else {
+        authenticate(parts, &context.auth_secret).map(|auth| {
+            let id = match get_id_from_uri(&parts.uri, "user_id") {
+                Ok(id) if auth.has_permission(ACCOUNTS_MANAGE) => id,
+                _ => auth.user_id,
+            };
+            (recommendations::USER, id)
+        })
+    };
+
+    if let Err(e) = request {
+        return response_auth_error(e);
+    }
+
+    let (kind, id) = request.ok().unwrap();
+
+    match recommendations::get_recommendations(&context, kind, id, limit).await {
+        Err(error) => {
+            let status_code = if error == LocalError::IdNotFound {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            create_response(status_code, error.to_string())
+        }
+        Ok(items) => create_response(StatusCode::OK, json!(items).to_string()),
+    }
+}
+
 // /product/add
 pub async fn add_item(
+    parts: &Parts,
     mut body: Option<Value>,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
     let json_map: Option<&mut serde_json::Map<String, Value>> = body
         .as_mut()
         .and_then(|json: &mut Value| json.as_object_mut());
@@ -97,9 +218,10 @@ pub async fn add_item(
 
     let json_map = json_map.unwrap();
 
+    // the category is given by "category_id", or by "category" as a slug or a name
     if json_map.get("name").is_none()
         || json_map.get("price").is_none()
-        || json_map.get("category").is_none()
+        || (json_map.get("category").is_none() && json_map.get("category_id").is_none())
     {
         return create_response(
             StatusCode::BAD_REQUEST,
@@ -109,7 +231,7 @@ pub async fn add_item(
 
     json_map.entry("count").or_insert(json!(0));
 
-    match context.db.postgres_db.add_product(json!(json_map)).await {
+    match context.db.postgres_db.add_product(json!(json_map), &actor).await {
         Err(e) => {
             println!("{}", e.to_string());
             create_response(StatusCode::BAD_REQUEST, e.to_string())
@@ -124,6 +246,12 @@ pub async fn update_item(
     mut body: Option<Value>,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
     let id = get_id_from_uri(&parts.uri, "id");
 
     if let Err(e) = id {
@@ -149,7 +277,7 @@ pub async fn update_item(
     match context
         .db
         .postgres_db
-        .update_product(id, updates.clone())
+        .update_product(id, updates.clone(), &actor)
         .await
     {
         Err(e) => {
@@ -157,13 +285,21 @@ pub async fn update_item(
             create_response(StatusCode::BAD_REQUEST, e.to_string())
         }
         Ok(item) => {
-            let res = context.cache.update_product(id.clone(), updates);
+            // the cached category name can't be patched from the request
+            let res = if updates.get("category").is_some() || updates.get("category_id").is_some() {
+                Err(())
+            } else {
+                context.cache.update_product(id, updates)
+            };
             if res.is_err() {
                 let res = context.cache.delete_product(id.clone());
                 if res.is_err() {
                     let _ = context.cache.delete_product(id);
                 }
             }
+            if let Ok(product) = serde_json::from_value::<product::Model>(item.clone()) {
+                stock_alerts::notify_back_in_stock(&context, &product).await;
+            }
             create_response(StatusCode::OK, item.to_string())
         }
     }
@@ -174,6 +310,10 @@ pub async fn delete_item(
     parts: &Parts,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_DELETE) {
+        return response_auth_error(e);
+    }
+
     let id = get_id_from_uri(&parts.uri, "id");
 
     if let Err(e) = id {
@@ -215,59 +355,1731 @@ pub async fn buy_item(
         .get("count")
         .and_then(|c| c.parse().ok())
         .unwrap_or(1);
-    let user_id: Option<i32> = params.get("user_id").and_then(|c| c.parse().ok());
+    let variant_id: Option<i32> = params.get("variant_id").and_then(|v| v.parse().ok());
+
+    let buyer = get_buyer(parts, &context);
+    if let Err(e) = buyer {
+        return response_auth_error(e);
+    }
+    let buyer = buyer.ok().unwrap();
+    let user_id = buyer.unwrap_or(GUEST_USER_ID);
+    let coupon_codes = get_coupon_codes(parts);
+    // coupons are limited per user, so they are for signed-in buyers only
+    if buyer.is_none() && !coupon_codes.is_empty() {
+        return response_auth_error(LocalError::UnauthenticatedUser);
+    }
+    let order_ref = get_order_ref(parts, &context).await;
+    if let Err(e) = order_ref {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let order_ref = order_ref.ok().unwrap();
 
-    // if user_id.is_none() {
-    //     return create_response(
-    //         StatusCode::BAD_REQUEST,
-    //         LocalError::UnauthenticatedUser.to_string(),
-    //     );
-    // }
+    let destination = get_destination(params.get("region"), params.get("currency"), &context);
+    if let Err(e) = destination {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let (region, currency) = destination.ok().unwrap();
 
-    let user_id = user_id.unwrap_or(1);
+    let offers = claim_offers(&context, &coupon_codes, user_id, &order_ref).await;
+    if let Err(e) = offers {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let offers = offers.ok().unwrap();
 
-    match context.db.postgres_db.purchase(id, count).await {
-        Err(error) => create_response(StatusCode::BAD_REQUEST, error.to_string()),
-        Ok(item) => {
-            let res = context.db.mongo_db.record_product_purchased(id).await;
-            if res.is_err() {
-                let _ = context.db.mongo_db.record_product_purchased(id).await;
+    match context
+        .db
+        .postgres_db
+        .purchase(
+            id,
+            variant_id,
+            count,
+            &actor(user_id),
+            &order_ref,
+            &get_allocation(parts, &context),
+        )
+        .await
+    {
+        Err(error) => {
+            release_unused_coupons(&context, &offers, None, &order_ref).await;
+            create_response(StatusCode::BAD_REQUEST, error.to_string())
+        }
+        Ok(mut item) => {
+            let _ = context.cache.delete_product(id);
+            let product: product::Model = serde_json::from_value(item.clone()).ok().unwrap();
+            let variant: Option<product_variant::Model> = item
+                .get("variant")
+                .and_then(|v| serde_json::from_value(v.clone()).ok());
+            stock_alerts::check_low_stock(&context, &product, count);
+
+            // priced with the price the stock was taken at
+            let mut pricing =
+                promotions::price(&[Line::new(&product, variant.as_ref(), count)], &offers);
+            add_totals(&context, &mut pricing, &region, currency.as_deref()).await;
+            release_unused_coupons(&context, &offers, Some(&pricing), &order_ref).await;
+            record_purchase(
+                &context,
+                user_id,
+                &pricing,
+                &pricing.lines[0],
+                &order_ref,
+                &get_address_ids(parts),
+            )
+            .await;
+
+            item["pricing"] = json!(pricing);
+            create_response(StatusCode::OK, item.to_string())
+        }
+    }
+}
+
+// Ledger actor for a user.
+fn actor(user_id: i32) -> String {
+    format!("user:{}", user_id)
+}
+
+// Purchases of buyers that aren't signed in are recorded for the guest account.
+const GUEST_USER_ID: i32 = 1;
+
+// The signed-in buyer, None for a guest. A token that doesn't verify is an error, so that
+// it isn't taken for a guest.
+fn get_caller(parts: &Parts, context: &Context) -> Result<Option<AuthContext>, LocalError> {
+    if !parts.headers.contains_key(http::header::AUTHORIZATION) {
+        return Ok(None);
+    }
+    authenticate(parts, &context.auth_secret).map(Some)
+}
+
+fn get_buyer(parts: &Parts, context: &Context) -> Result<Option<i32>, LocalError> {
+    get_caller(parts, context).map(|caller| caller.map(|auth| auth.user_id))
+}
+
+// Reference tying ledger sales to the order they were added with. One given by the client
+// must not have been used yet, as orders are added once per reference and product.
+async fn get_order_ref(parts: &Parts, context: &Context) -> Result<String, LocalError> {
+    let order_ref = get_params(&parts.uri)
+        .get("order_ref")
+        .filter(|r| !r.is_empty())
+        .cloned();
+
+    match order_ref {
+        None => Ok(random_token()),
+        Some(order_ref) => match context.db.postgres_db.is_order_ref_used(&order_ref).await? {
+            true => Err(LocalError::OrderRefTaken),
+            false => Ok(order_ref),
+        },
+    }
+}
+
+// Customer location from `lat` and `lon`, used by the "nearest" strategy.
+fn get_allocation(parts: &Parts, context: &Context) -> Allocation {
+    let params = get_params(&parts.uri);
+    let coordinate = |name: &str| params.get(name).and_then(|c| c.parse::<f64>().ok());
+
+    Allocation {
+        strategy: context.allocation_strategy,
+        location: coordinate("lat").zip(coordinate("lon")),
+    }
+}
+
+// Coupon codes of the purchase, given comma separated as `coupon`.
+fn get_coupon_codes(parts: &Parts) -> Vec<String> {
+    get_params(&parts.uri)
+        .get("coupon")
+        .map(|codes| codes.split(',').map(|c| c.trim().to_string()).collect())
+        .unwrap_or_default()
+}
+
+// Address book entries the orders ship and bill to, given as `shipping_address_id` and
+// `billing_address_id`; order_manager takes the buyer's defaults for those not given.
+fn get_address_ids(parts: &Parts) -> Vec<(&'static str, i32)> {
+    let params = get_params(&parts.uri);
+    ["shipping_address_id", "billing_address_id"]
+        .into_iter()
+        .filter_map(|name| Some((name, params.get(name)?.parse().ok()?)))
+        .collect()
+}
+
+// Promotions for a purchase, with the coupons given used up front so that concurrent
+// checkouts can't go over their limits.
+async fn claim_offers(
+    context: &Context,
+    codes: &[String],
+    user_id: i32,
+    order_ref: &str,
+) -> Result<Vec<Offer>, LocalError> {
+    let now = Utc::now().naive_utc();
+    let offers = context
+        .db
+        .postgres_db
+        .get_offers(codes, Some(user_id), now)
+        .await?;
+
+    let coupon_ids: Vec<i32> = offers
+        .iter()
+        .filter_map(|o| o.coupon.as_ref().map(|c| c.coupon_id))
+        .collect();
+    if !coupon_ids.is_empty() {
+        context
+            .db
+            .postgres_db
+            .redeem_coupons(&coupon_ids, user_id, order_ref)
+            .await?;
+    }
+
+    Ok(offers)
+}
+
+// Gives back the claimed coupons the pricing didn't apply, or all of them when the
+// purchase failed.
+async fn release_unused_coupons(
+    context: &Context,
+    offers: &[Offer],
+    pricing: Option<&Pricing>,
+    order_ref: &str,
+) {
+    let applied: Vec<i32> = pricing
+        .map(|p| p.discounts.iter().filter_map(|d| d.coupon_id).collect())
+        .unwrap_or_default();
+    let unused: Vec<i32> = offers
+        .iter()
+        .filter_map(|o| o.coupon.as_ref().map(|c| c.coupon_id))
+        .filter(|id| !applied.contains(id))
+        .collect();
+    if unused.is_empty() {
+        return;
+    }
+
+    let res = context.db.postgres_db.release_coupons(&unused, order_ref).await;
+    if let Err(e) = res {
+        println!("Error when releasing coupons of {}: {}", order_ref, e.to_string());
+    }
+}
+
+// Delivery region of a checkout, the default one unless given, and the currency to show
+// its totals in.
+fn get_destination(
+    region: Option<&String>,
+    currency: Option<&String>,
+    context: &Context,
+) -> Result<(String, Option<String>), LocalError> {
+    let region = region
+        .map(|r| r.trim().to_uppercase())
+        .unwrap_or_else(|| context.tax_rates.default_region.clone());
+    context.tax_rates.check_region(&region)?;
+
+    let currency = currency.map(|c| c.trim().to_uppercase());
+    if let Some(ref currency) = currency {
+        context.exchange_rates.rate(currency)?;
+    }
+
+    Ok((region, currency))
+}
+
+// Tax and shipping of a priced basket. The region and currency are checked beforehand.
+async fn add_totals(
+    context: &Context,
+    pricing: &mut Pricing,
+    region: &str,
+    currency: Option<&str>,
+) {
+    // without the categories the region's own rate applies
+    let category_paths = context
+        .db
+        .postgres_db
+        .get_category_paths()
+        .await
+        .unwrap_or_default();
+
+    let res = totals::apply(
+        pricing,
+        region,
+        currency,
+        &category_paths,
+        &context.tax_rates,
+        &context.shipping_rates,
+        &context.exchange_rates,
+    );
+    if let Err(e) = res {
+        println!("Error when adding totals: {}", e.to_string());
+    }
+}
+
+fn error_status(error: &LocalError) -> StatusCode {
+    match error {
+        LocalError::IdNotFound => StatusCode::NOT_FOUND,
+        LocalError::ItemNotAvailable
+        | LocalError::ReservationExpired
+        | LocalError::WarehouseNotEmpty
+        | LocalError::ItemInStock
+        | LocalError::CategoryExists
+        | LocalError::CategoryNotEmpty
+        | LocalError::VariantExists
+        | LocalError::SkuTaken
+        | LocalError::PriceScheduleOverlaps
+        | LocalError::CouponLimitReached
+        | LocalError::CouponCodeTaken
+        | LocalError::OrderRefTaken => StatusCode::CONFLICT,
+        LocalError::OperationFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+// Whether the caller may act on the reservation: the buyer who made it, whoever holds its
+// `token` (guests), or staff.
+fn owns_reservation(
+    caller: Option<&AuthContext>,
+    token: Option<&String>,
+    reservation: &reservation::Model,
+) -> bool {
+    if let Some(auth) = caller {
+        if auth.has_permission(PRODUCTS_WRITE) || reservation.user_id == Some(auth.user_id) {
+            return true;
+        }
+    }
+    token.is_some() && reservation.token.as_ref() == token
+}
+
+// Reservations picked by `id`, or all active ones of `holder`, that the caller owns.
+// Someone else's reservation is not found.
+async fn get_owned_reservations(
+    parts: &Parts,
+    caller: Option<&AuthContext>,
+    context: &Context,
+) -> Result<Vec<reservation::Model>, LocalError> {
+    let params = get_params(&parts.uri);
+    let token = params.get("token");
+
+    if let Ok(id) = get_id_from_uri(&parts.uri, "id") {
+        return match context.db.postgres_db.get_reservation(id).await? {
+            Some(reservation) if owns_reservation(caller, token, &reservation) => {
+                Ok(vec![reservation])
             }
+            _ => Err(LocalError::IdNotFound),
+        };
+    }
 
-            // add product purchased for user
-            let url = Url::parse(&format!("{}{}", context.user_manager.uri, context.user_manager.product_purchased_endpoint));
-            if let Ok(mut url) = url {
-                url.query_pairs_mut().append_pair("user_id", &user_id.to_string());
-                url.query_pairs_mut().append_pair("product_id", &id.to_string());
+    let holder = params.get("holder").ok_or(LocalError::WrongParameters)?;
+    let reservations = context.db.postgres_db.get_reservations(holder).await?;
 
-                let request = hyper::Request::get(url.as_str())
-                    .body(Body::empty());
+    Ok(reservations
+        .into_iter()
+        .filter(|r| owns_reservation(caller, token, r))
+        .collect())
+}
 
-                if let Ok(request) = request {
-                    let https = HttpsConnector::new();
-                    let client = Client::builder().build::<_, hyper::Body>(https);
-                    let _response = client.request(request).await;
-                }
-            };
+// /product/reservation
+pub async fn reserve_item(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let buyer = get_buyer(parts, &context);
+    if let Err(e) = buyer {
+        return response_auth_error(e);
+    }
+
+    let body = body.unwrap_or_default();
+    let product_id = body.get("product_id").and_then(|id| id.as_i64());
+    let variant_id = body
+        .get("variant_id")
+        .and_then(|id| id.as_i64())
+        .map(|id| id as i32);
+    let holder = body.get("holder").and_then(|h| h.as_str());
+    let count = body
+        .get("count")
+        .map(|c| c.as_i64().and_then(|c| i32::try_from(c).ok()))
+        .unwrap_or(Some(1));
+    // bounded before it is made a `Duration`, which can't hold any number of seconds
+    let max_ttl = context.reservations.max_ttl.num_seconds();
+    let ttl = body
+        .get("ttl_secs")
+        .and_then(|t| t.as_i64())
+        .map(|t| Duration::seconds(t.clamp(0, max_ttl)))
+        .unwrap_or(context.reservations.ttl);
+
+    if product_id.is_none() || holder.is_none() || count.is_none() || ttl <= Duration::zero() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let product_id = product_id.unwrap() as i32;
+    let holder = holder.unwrap();
+    let user_id = buyer.ok().unwrap();
+    let actor = match user_id {
+        Some(user_id) => actor(user_id),
+        None => format!("holder:{}", holder),
+    };
+
+    match context
+        .db
+        .postgres_db
+        .reserve(
+            NewReservation {
+                product_id,
+                variant_id,
+                count: count.unwrap(),
+                holder,
+                user_id,
+                ttl,
+            },
+            &actor,
+        )
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(reservation) => {
+            let _ = context.cache.delete_product(product_id);
+            create_response(StatusCode::OK, reservation.to_string())
+        }
+    }
+}
+
+// /product/reservation
+pub async fn get_reservations(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let caller = get_caller(parts, &context);
+    if let Err(e) = caller {
+        return response_auth_error(e);
+    }
+    let caller = caller.ok().unwrap();
+
+    match get_owned_reservations(parts, caller.as_ref(), &context).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(mut reservations) if get_id_from_uri(&parts.uri, "id").is_ok() => {
+            create_response(StatusCode::OK, json!(reservations.remove(0)).to_string())
+        }
+        Ok(reservations) => create_response(StatusCode::OK, json!(reservations).to_string()),
+    }
+}
+
+// /product/reservation/commit
+pub async fn commit_reservation(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let caller = get_caller(parts, &context);
+    if let Err(e) = caller {
+        return response_auth_error(e);
+    }
+    let caller = caller.ok().unwrap();
+    let buyer = caller.as_ref().map(|auth| auth.user_id);
+    let user_id = buyer.unwrap_or(GUEST_USER_ID);
+    let coupon_codes = get_coupon_codes(parts);
+    if buyer.is_none() && !coupon_codes.is_empty() {
+        return response_auth_error(LocalError::UnauthenticatedUser);
+    }
+
+    let ids = get_owned_reservations(parts, caller.as_ref(), &context).await;
+    if let Err(e) = ids {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let ids: Vec<i32> = ids.ok().unwrap().iter().map(|r| r.reservation_id).collect();
+
+    let order_ref = get_order_ref(parts, &context).await;
+    if let Err(e) = order_ref {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let order_ref = order_ref.ok().unwrap();
+
+    let params = get_params(&parts.uri);
+    let destination = get_destination(params.get("region"), params.get("currency"), &context);
+    if let Err(e) = destination {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let (region, currency) = destination.ok().unwrap();
+
+    let offers = claim_offers(&context, &coupon_codes, user_id, &order_ref).await;
+    if let Err(e) = offers {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let offers = offers.ok().unwrap();
+
+    match context
+        .db
+        .postgres_db
+        .commit_reservations(
+            ids,
+            &actor(user_id),
+            &order_ref,
+            &get_allocation(parts, &context),
+        )
+        .await
+    {
+        Err(error) => {
+            release_unused_coupons(&context, &offers, None, &order_ref).await;
+            create_response(error_status(&error), error.to_string())
+        }
+        Ok(committed) => {
+            // the reservations are priced together, as one basket
+            let lines: Vec<Line> = committed
+                .iter()
+                .map(|(reservation, product, variant)| {
+                    Line::new(product, variant.as_ref(), reservation.count)
+                })
+                .collect();
+            let mut pricing = promotions::price(&lines, &offers);
+            add_totals(&context, &mut pricing, &region, currency.as_deref()).await;
+            release_unused_coupons(&context, &offers, Some(&pricing), &order_ref).await;
 
-            // add order
-            let url = Url::parse(&format!("{}{}", context.order_manager.uri, context.order_manager.add_order_endpoint));
-            if let Ok(url) = url {
-                let mut request_params = HashMap::new();
-                request_params.insert("user_id", json!(user_id));
-                request_params.insert("product_id", json!(id));
-                request_params.insert("total_price", json!(item.get("price").unwrap()));
-                let request = hyper::Request::get(url.as_str())
-                    .body(Body::from(serde_json::to_string(&request_params).unwrap()));
-
-                if let Ok(request) = request {
-                    let https = HttpsConnector::new();
-                    let client = Client::builder().build::<_, hyper::Body>(https);
-                    let _response = client.request(request).await;
+            let address_ids = get_address_ids(parts);
+            let mut items = Vec::new();
+            for ((reservation, product, variant), line) in committed.into_iter().zip(pricing.lines.iter()) {
+                let _ = context.cache.delete_product(product.product_id);
+                stock_alerts::check_low_stock(&context, &product, reservation.count);
+                let mut item = json!(product);
+                if let Some(variant) = variant {
+                    item["variant"] = json!(variant);
                 }
-            };
+                record_purchase(&context, user_id, &pricing, line, &order_ref, &address_ids).await;
+                items.push(json!({ "reservation": reservation, "product": item, "pricing": line }));
+            }
 
+            create_response(StatusCode::OK, json!(items).to_string())
+        }
+    }
+}
+
+// /product/reservation/release
+pub async fn release_reservation(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let caller = get_caller(parts, &context);
+    if let Err(e) = caller {
+        return response_auth_error(e);
+    }
+    let caller = caller.ok().unwrap();
+    let actor = match caller.as_ref() {
+        Some(auth) => actor(auth.user_id),
+        None => "anonymous".to_string(),
+    };
+
+    let ids = get_owned_reservations(parts, caller.as_ref(), &context).await;
+    if let Err(e) = ids {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let ids: Vec<i32> = ids.ok().unwrap().iter().map(|r| r.reservation_id).collect();
+
+    match context
+        .db
+        .postgres_db
+        .release_reservations(ids, &actor)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(product_ids) => {
+            for id in product_ids {
+                let _ = context.cache.delete_product(id);
+            }
+            response_ok()
+        }
+    }
+}
+
+// /product/stock/movement
+pub async fn add_stock_movement(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
+    let body = body.unwrap_or_default();
+    let product_id = body.get("product_id").and_then(|id| id.as_i64());
+    let kind = body.get("kind").and_then(|k| k.as_str());
+    let quantity = body
+        .get("quantity")
+        .and_then(|q| q.as_i64())
+        .and_then(|q| i32::try_from(q).ok());
+    let reason = body.get("reason").and_then(|r| r.as_str());
+    let order_ref = body.get("order_ref").and_then(|r| r.as_str());
+    let warehouse_id = body
+        .get("warehouse_id")
+        .and_then(|id| id.as_i64())
+        .map(|id| id as i32);
+    let variant_id = body
+        .get("variant_id")
+        .and_then(|id| id.as_i64())
+        .map(|id| id as i32);
+
+    if product_id.is_none() || kind.is_none() || quantity.is_none() || reason.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let product_id = product_id.unwrap() as i32;
+
+    match context
+        .db
+        .postgres_db
+        .adjust_stock(
+            Movement {
+                product_id,
+                warehouse_id,
+                variant_id,
+                kind: kind.unwrap(),
+                quantity: quantity.unwrap(),
+                reason: reason.unwrap(),
+                order_ref,
+            },
+            &actor,
+        )
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(item) => {
+            let _ = context.cache.delete_product(product_id);
+            if let Ok(product) = serde_json::from_value::<product::Model>(item.clone()) {
+                stock_alerts::notify_back_in_stock(&context, &product).await;
+            }
             create_response(StatusCode::OK, item.to_string())
         }
     }
 }
+
+// /product/stock/movements
+pub async fn get_stock_movements(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    let params = get_params(&parts.uri);
+    let offset: u64 = params
+        .get("offset")
+        .and_then(|o| o.parse().ok())
+        .unwrap_or(0);
+    let limit: u64 = params
+        .get("limit")
+        .and_then(|l| l.parse().ok())
+        .filter(|l| *l > 0)
+        .unwrap_or(50);
+
+    match context
+        .db
+        .postgres_db
+        .get_movements(id.ok().unwrap(), offset, limit)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(movements) => create_response(StatusCode::OK, json!(movements).to_string()),
+    }
+}
+
+// /product/stock/reconcile
+pub async fn get_stock_reconciliation(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    // all products unless one is asked for
+    let product_ids = get_id_from_uri(&parts.uri, "id").ok().map(|id| vec![id]);
+
+    match context.db.postgres_db.reconcile(product_ids).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(products) => create_response(StatusCode::OK, json!(products).to_string()),
+    }
+}
+
+// /product/stock/reconcile
+pub async fn fix_stock_reconciliation(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .fix_reconciliation(id.ok().unwrap(), &actor)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(product) => create_response(StatusCode::OK, product.to_string()),
+    }
+}
+
+// /product/warehouses
+pub async fn get_warehouses(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    match context.db.postgres_db.get_warehouses().await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(warehouses) => create_response(StatusCode::OK, json!(warehouses).to_string()),
+    }
+}
+
+// /product/warehouse
+pub async fn add_warehouse(
+    parts: &Parts,
+    mut body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let json_map: Option<&mut serde_json::Map<String, Value>> = body
+        .as_mut()
+        .and_then(|json: &mut Value| json.as_object_mut());
+
+    if json_map.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let json_map = json_map.unwrap();
+
+    if json_map.get("name").is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    json_map.entry("priority").or_insert(json!(0));
+    json_map.entry("active").or_insert(json!(true));
+
+    match context.db.postgres_db.add_warehouse(json!(json_map)).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(id) => create_response(StatusCode::OK, id.to_string()),
+    }
+}
+
+// /product/warehouse
+pub async fn update_warehouse(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .update_warehouse(id.ok().unwrap(), body.unwrap_or_default())
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(warehouse) => create_response(StatusCode::OK, warehouse.to_string()),
+    }
+}
+
+// /product/warehouse
+pub async fn delete_warehouse(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.delete_warehouse(id.ok().unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(_) => response_ok(),
+    }
+}
+
+// /product/stock
+pub async fn get_stock(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.get_stock(id.ok().unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(stock) => create_response(StatusCode::OK, json!(stock).to_string()),
+    }
+}
+
+// /product/stock/transfer
+pub async fn transfer_stock(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
+    let body = body.unwrap_or_default();
+    let field = |name: &str| body.get(name).and_then(|v| v.as_i64()).map(|v| v as i32);
+    let product_id = field("product_id");
+    let from = field("from_warehouse_id");
+    let to = field("to_warehouse_id");
+    let quantity = field("quantity");
+
+    if product_id.is_none() || from.is_none() || to.is_none() || quantity.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context
+        .db
+        .postgres_db
+        .transfer(
+            product_id.unwrap(),
+            from.unwrap(),
+            to.unwrap(),
+            quantity.unwrap(),
+            &actor,
+        )
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(stock) => create_response(StatusCode::OK, stock.to_string()),
+    }
+}
+
+// /product/stock/low
+pub async fn get_low_stock(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    match context
+        .db
+        .postgres_db
+        .get_low_stock(context.default_reorder_threshold)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(products) => create_response(StatusCode::OK, json!(products).to_string()),
+    }
+}
+
+// /product/subscription
+pub async fn add_subscription(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    let product_id = body
+        .unwrap_or_default()
+        .get("product_id")
+        .and_then(|id| id.as_i64());
+    if product_id.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let email = match get_verified_email(parts, &context).await {
+        Err(error) => return create_response(error_status(&error), error.to_string()),
+        Ok(email) => email,
+    };
+
+    match context
+        .db
+        .postgres_db
+        .add_subscription(product_id.unwrap() as i32, user_id, &email)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(id) => create_response(StatusCode::OK, id.to_string()),
+    }
+}
+
+// The caller's email as user_manager has it, once they have verified it.
+async fn get_verified_email(parts: &Parts, context: &Context) -> Result<String, LocalError> {
+    let url = Url::parse(&format!(
+        "{}{}",
+        context.user_manager.uri, context.user_manager.profile_endpoint
+    ))
+    .map_err(|_| LocalError::OperationFailed)?;
+
+    let authorization = parts
+        .headers
+        .get(http::header::AUTHORIZATION)
+        .ok_or(LocalError::UnauthenticatedUser)?;
+    let request = hyper::Request::get(url.as_str())
+        .header(API_KEY_HEADER, &context.user_manager.api_key)
+        .header(http::header::AUTHORIZATION, authorization)
+        .body(Body::empty())
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let response = client
+        .request(request)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    if !response.status().is_success() {
+        return Err(LocalError::OperationFailed);
+    }
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    let profile: Value = serde_json::from_slice(&body).map_err(|_| LocalError::OperationFailed)?;
+
+    if profile.get("email_verified").and_then(|v| v.as_bool()) != Some(true) {
+        return Err(LocalError::EmailNotVerified);
+    }
+    profile
+        .get("email")
+        .and_then(|e| e.as_str())
+        .map(|e| e.to_string())
+        .ok_or(LocalError::OperationFailed)
+}
+
+// /product/subscriptions
+pub async fn get_subscriptions(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+
+    match context
+        .db
+        .postgres_db
+        .get_subscriptions(auth.ok().unwrap().user_id)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(subscriptions) => create_response(StatusCode::OK, json!(subscriptions).to_string()),
+    }
+}
+
+// /product/subscription
+pub async fn delete_subscription(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .delete_subscription(id.ok().unwrap(), auth.ok().unwrap().user_id)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(_) => response_ok(),
+    }
+}
+
+// /product/categories
+pub async fn get_categories(context: Arc<Context>) -> Result<Response<Body>, hyper::Error> {
+    match context.db.postgres_db.get_category_tree().await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(tree) => create_response(StatusCode::OK, tree.to_string()),
+    }
+}
+
+// /product/category/products
+pub async fn get_category_products(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    // the category is picked by `id` or by `slug`
+    let id = match get_params(&parts.uri).get("slug") {
+        Some(slug) => context
+            .db
+            .postgres_db
+            .get_category_by_slug(slug)
+            .await
+            .map(|category| category.category_id),
+        None => get_id_from_uri(&parts.uri, "id"),
+    };
+    if let Err(e) = id {
+        return create_response(error_status(&e), e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .get_products_in_subtree(id.ok().unwrap())
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(products) => create_response(StatusCode::OK, products.to_string()),
+    }
+}
+
+// /product/category
+pub async fn add_category(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    if body.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context.db.postgres_db.add_category(body.unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(id) => create_response(StatusCode::OK, id.to_string()),
+    }
+}
+
+// /product/category
+pub async fn update_category(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+    let id = id.ok().unwrap();
+
+    match context
+        .db
+        .postgres_db
+        .update_category(id, body.unwrap_or_default())
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(category) => {
+            // cached products carry the category name
+            if let Ok(product_ids) = context.db.postgres_db.get_category_product_ids(id).await {
+                for product_id in product_ids {
+                    let _ = context.cache.delete_product(product_id);
+                }
+            }
+            create_response(StatusCode::OK, category.to_string())
+        }
+    }
+}
+
+// /product/category
+pub async fn delete_category(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.delete_category(id.ok().unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(_) => response_ok(),
+    }
+}
+
+// /product/variants
+pub async fn get_variants(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = get_id_from_uri(&parts.uri, "product_id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.get_variants(id.ok().unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(variants) => create_response(StatusCode::OK, json!(variants).to_string()),
+    }
+}
+
+// /product/variant
+pub async fn add_variant(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
+    let body = body.unwrap_or_default();
+    let product_id = body.get("product_id").and_then(|id| id.as_i64());
+    if product_id.is_none() || body.get("sku").is_none() || body.get("options").is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context.db.postgres_db.add_variant(body, &actor).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(id) => {
+            let _ = context.cache.delete_product(product_id.unwrap() as i32);
+            create_response(StatusCode::OK, id.to_string())
+        }
+    }
+}
+
+// /product/variant
+pub async fn update_variant(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .update_variant(id.ok().unwrap(), body.unwrap_or_default(), &actor)
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(variant) => {
+            if let Some(product_id) = variant.get("product_id").and_then(|id| id.as_i64()) {
+                let product_id = product_id as i32;
+                let _ = context.cache.delete_product(product_id);
+                if let Ok(product) = context.db.postgres_db.get_product(product_id).await {
+                    if let Ok(product) = serde_json::from_value::<product::Model>(product) {
+                        stock_alerts::notify_back_in_stock(&context, &product).await;
+                    }
+                }
+            }
+            create_response(StatusCode::OK, variant.to_string())
+        }
+    }
+}
+
+// /product/variant
+pub async fn delete_variant(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.delete_variant(id.ok().unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(product_id) => {
+            let _ = context.cache.delete_product(product_id);
+            response_ok()
+        }
+    }
+}
+
+fn get_catalog_format(parts: &Parts) -> Option<catalog::Format> {
+    match get_params(&parts.uri).get("format") {
+        None => Some(catalog::Format::Json),
+        Some(format) => catalog::Format::parse(format),
+    }
+}
+
+// /product/import
+pub async fn import_catalog(
+    parts: &Parts,
+    body: &[u8],
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
+    let format = get_catalog_format(parts);
+    if format.is_none() {
+        return create_response(StatusCode::BAD_REQUEST, LocalError::WrongParameters.to_string());
+    }
+    let dry_run = get_params(&parts.uri).get("dry_run").map(|v| v == "true").unwrap_or(false);
+
+    let rows = catalog::parse_rows(body, format.unwrap());
+    if let Err(e) = rows {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    let report = catalog::import(&context.db.postgres_db, rows.ok().unwrap(), dry_run, &actor).await;
+    for product_id in report.product_ids.iter() {
+        let _ = context.cache.delete_product(*product_id);
+    }
+
+    let status = if report.errors.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    create_response(status, json!(report).to_string())
+}
+
+// /product/export
+pub async fn export_catalog(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let format = get_catalog_format(parts);
+    if format.is_none() {
+        return create_response(StatusCode::BAD_REQUEST, LocalError::WrongParameters.to_string());
+    }
+    let format = format.unwrap();
+
+    let rows = catalog::export(&context.db.postgres_db).await;
+    let data = rows.and_then(|rows| catalog::write_rows(&rows, format));
+    match data {
+        Err(error) => create_response(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+        Ok(data) => {
+            let mut response = create_response(StatusCode::OK, String::new())?;
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+            *response.body_mut() = Body::from(data);
+            Ok(response)
+        }
+    }
+}
+
+// /product/price
+pub async fn get_price(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    // "at" defaults to now
+    let at = match get_params(&parts.uri).get("at") {
+        None => Some(Utc::now().naive_utc()),
+        Some(at) => parse_time(at),
+    };
+    if at.is_none() {
+        return create_response(StatusCode::BAD_REQUEST, LocalError::WrongParameters.to_string());
+    }
+
+    match context.db.postgres_db.get_price_at(id.ok().unwrap(), at.unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(price) => create_response(StatusCode::OK, json!(price).to_string()),
+    }
+}
+
+// /product/price/history
+pub async fn get_price_history(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.get_price_history(id.ok().unwrap()).await {
+        Err(error) => create_response(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+        Ok(history) => create_response(StatusCode::OK, json!(history).to_string()),
+    }
+}
+
+// /product/price/schedules
+pub async fn get_price_schedules(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    // all products without "product_id"
+    let product_id = get_id_from_uri(&parts.uri, "product_id").ok();
+
+    match context.db.postgres_db.get_price_schedules(product_id).await {
+        Err(error) => create_response(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+        Ok(schedules) => create_response(StatusCode::OK, json!(schedules).to_string()),
+    }
+}
+
+// /product/price/schedule
+pub async fn add_price_schedule(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, PRODUCTS_WRITE);
+    if let Err(e) = auth {
+        return response_auth_error(e);
+    }
+    let actor = actor(auth.ok().unwrap().user_id);
+
+    let body = body.unwrap_or_default();
+    if body.get("product_id").is_none()
+        || body.get("price").is_none()
+        || body.get("effective_from").is_none()
+    {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context.db.postgres_db.add_price_schedule(body, &actor).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(id) => create_response(StatusCode::OK, id.to_string()),
+    }
+}
+
+// /product/price/schedule
+pub async fn cancel_price_schedule(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.cancel_price_schedule(id.ok().unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(schedule) => create_response(StatusCode::OK, json!(schedule).to_string()),
+    }
+}
+
+// /product/promotions
+pub async fn get_promotions(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    match context.db.postgres_db.get_promotions().await {
+        Err(error) => create_response(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+        Ok(promotions) => create_response(StatusCode::OK, json!(promotions).to_string()),
+    }
+}
+
+// /product/promotion
+pub async fn add_promotion(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    match context
+        .db
+        .postgres_db
+        .add_promotion(body.unwrap_or_default())
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(id) => create_response(StatusCode::OK, id.to_string()),
+    }
+}
+
+// /product/promotion
+pub async fn update_promotion(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .update_promotion(id.ok().unwrap(), body.unwrap_or_default())
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(promotion) => create_response(StatusCode::OK, json!(promotion).to_string()),
+    }
+}
+
+// /product/promotion
+// Ends a promotion; it is kept, as orders name it in their discounts.
+pub async fn end_promotion(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context
+        .db
+        .postgres_db
+        .update_promotion(id.ok().unwrap(), json!({ "active": false }))
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(promotion) => create_response(StatusCode::OK, json!(promotion).to_string()),
+    }
+}
+
+// /product/coupons
+pub async fn get_coupons(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    // all promotions without "promotion_id"
+    let promotion_id = get_id_from_uri(&parts.uri, "promotion_id").ok();
+
+    match context.db.postgres_db.get_coupons(promotion_id).await {
+        Err(error) => create_response(StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+        Ok(coupons) => create_response(StatusCode::OK, json!(coupons).to_string()),
+    }
+}
+
+// /product/coupon
+pub async fn add_coupon(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    match context
+        .db
+        .postgres_db
+        .add_coupon(body.unwrap_or_default())
+        .await
+    {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(coupon) => create_response(StatusCode::OK, json!(coupon).to_string()),
+    }
+}
+
+// /product/coupon
+pub async fn deactivate_coupon(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, PRODUCTS_WRITE) {
+        return response_auth_error(e);
+    }
+
+    let id = get_id_from_uri(&parts.uri, "id");
+    if let Err(e) = id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    match context.db.postgres_db.deactivate_coupon(id.ok().unwrap()).await {
+        Err(error) => create_response(error_status(&error), error.to_string()),
+        Ok(coupon) => create_response(StatusCode::OK, json!(coupon).to_string()),
+    }
+}
+
+// Most units of an item a quote is made for, as anyone can ask for one.
+const MAX_QUOTE_COUNT: i64 = 10_000;
+
+// /product/checkout/quote
+// Prices a basket of {"product_id", "variant_id", "count"} items with the "coupons" given,
+// delivered to "region" and shown in "currency", without buying anything.
+pub async fn quote_checkout(
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let body = body.unwrap_or_default();
+    let items: Option<Vec<(i32, Option<i32>, i32)>> = body
+        .get("items")
+        .and_then(|items| items.as_array())
+        .and_then(|items| {
+            items
+                .iter()
+                .map(|item| {
+                    let product_id = item.get("product_id")?.as_i64()? as i32;
+                    let variant_id = item
+                        .get("variant_id")
+                        .and_then(|id| id.as_i64())
+                        .map(|id| id as i32);
+                    let count = item.get("count").and_then(|c| c.as_i64()).unwrap_or(1);
+                    if !(1..=MAX_QUOTE_COUNT).contains(&count) {
+                        return None;
+                    }
+                    Some((product_id, variant_id, count as i32))
+                })
+                .collect()
+        });
+    let codes: Vec<String> = body
+        .get("coupons")
+        .and_then(|codes| codes.as_array())
+        .map(|codes| {
+            codes
+                .iter()
+                .filter_map(|c| c.as_str().map(|c| c.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let user_id = body
+        .get("user_id")
+        .and_then(|id| id.as_i64())
+        .map(|id| id as i32);
+    let text = |key: &str| {
+        body.get(key)
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+    };
+    let destination = get_destination(text("region").as_ref(), text("currency").as_ref(), &context);
+    if let Err(e) = destination {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let (region, currency) = destination.ok().unwrap();
+
+    if items.as_ref().is_none_or(|items| items.is_empty()) {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+    let items = items.unwrap();
+
+    let keys: Vec<(i32, Option<i32>)> = items.iter().map(|i| (i.0, i.1)).collect();
+    let basket = context.db.postgres_db.get_basket_items(&keys).await;
+    if let Err(e) = basket {
+        return create_response(error_status(&e), e.to_string());
+    }
+
+    let now = Utc::now().naive_utc();
+    let offers = context.db.postgres_db.get_offers(&codes, user_id, now).await;
+    if let Err(e) = offers {
+        return create_response(error_status(&e), e.to_string());
+    }
+
+    let lines: Vec<Line> = basket
+        .ok()
+        .unwrap()
+        .iter()
+        .zip(items.iter())
+        .map(|((product, variant), item)| Line::new(product, variant.as_ref(), item.2))
+        .collect();
+    let mut pricing = promotions::price(&lines, &offers.ok().unwrap());
+    add_totals(&context, &mut pricing, &region, currency.as_deref()).await;
+
+    create_response(StatusCode::OK, json!(pricing).to_string())
+}
+
+// Stats, user history and the order for a completed purchase of a line of `pricing`.
+async fn record_purchase(
+    context: &Context,
+    user_id: i32,
+    pricing: &Pricing,
+    line: &PricedLine,
+    order_ref: &str,
+    address_ids: &[(&str, i32)],
+) {
+    let id = line.product_id;
+    let res = context.db.mongo_db.record_product_purchased(id).await;
+    if res.is_err() {
+        let _ = context.db.mongo_db.record_product_purchased(id).await;
+    }
+
+    // add product purchased for user
+    let url = Url::parse(&format!("{}{}", context.user_manager.uri, context.user_manager.product_purchased_endpoint));
+    if let Ok(mut url) = url {
+        url.query_pairs_mut().append_pair("user_id", &user_id.to_string());
+        url.query_pairs_mut().append_pair("product_id", &id.to_string());
+
+        let request = hyper::Request::put(url.as_str())
+            .header(API_KEY_HEADER, &context.user_manager.api_key)
+            .body(Body::empty());
+
+        if let Ok(request) = request {
+            let https = HttpsConnector::new();
+            let client = Client::builder().build::<_, hyper::Body>(https);
+            let _response = client.request(request).await;
+        }
+    };
+
+    // add order
+    let mut request_params = HashMap::new();
+    request_params.insert("user_id", json!(user_id));
+    request_params.insert("product_id", json!(id));
+    request_params.insert("order_ref", json!(order_ref));
+    request_params.insert("quantity", json!(line.quantity));
+    request_params.insert("unit_price", json!(line.unit_price));
+    request_params.insert("subtotal", json!(line.subtotal));
+    request_params.insert("discount", json!(line.discount));
+    request_params.insert("discounts", json!(line.discounts));
+    request_params.insert("tax_rate", json!(line.tax_rate));
+    request_params.insert("tax", json!(line.tax));
+    request_params.insert("shipping", json!(line.shipping));
+    request_params.insert("total_price", json!(line.grand_total));
+    request_params.insert("currency", json!(pricing.currency));
+    request_params.insert("region", json!(pricing.region));
+    if let Some(variant_id) = line.variant_id {
+        request_params.insert("variant_id", json!(variant_id));
+    }
+    for (name, address_id) in address_ids {
+        request_params.insert(*name, json!(address_id));
+    }
+    let order = serde_json::to_string(&request_params).unwrap();
+
+    // one order per product and variant of the reference; the same key on the retry adds
+    // it only once
+    let idempotency_key = match line.variant_id {
+        Some(variant_id) => format!("{}:{}:{}", order_ref, id, variant_id),
+        None => format!("{}:{}", order_ref, id),
+    };
+    if add_order(context, &order, &idempotency_key).await.is_err() {
+        let _ = add_order(context, &order, &idempotency_key).await;
+    }
+}
+
+async fn add_order(
+    context: &Context,
+    order: &str,
+    idempotency_key: &str,
+) -> Result<(), LocalError> {
+    let url = Url::parse(&format!(
+        "{}{}",
+        context.order_manager.uri, context.order_manager.add_order_endpoint
+    ))
+    .map_err(|_| LocalError::OperationFailed)?;
+
+    let request = hyper::Request::post(url.as_str())
+        .header(API_KEY_HEADER, &context.order_manager.api_key)
+        .header(
+            http::header::AUTHORIZATION,
+            format!(
+                "Bearer {}",
+                service_token(&context.auth_secret, &[ORDERS_CREATE])
+            ),
+        )
+        .header(IDEMPOTENCY_KEY_HEADER, idempotency_key)
+        .body(Body::from(order.to_string()))
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let response = client
+        .request(request)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    if response.status().is_server_error() {
+        return Err(LocalError::OperationFailed);
+    }
+    Ok(())
+}
@@ -0,0 +1,6 @@
+// The database layer and the product cache, shared with the `shopctl` admin tool.
+// The cache reports its failures as `()`, which only matters to callers that ignore them.
+#[allow(clippy::result_unit_err)]
+pub mod cache;
+pub mod db;
+pub mod entities;
@@ -1,47 +1,203 @@
 extern crate core;
 
 use crate::cache::redis_cache;
-use crate::context::{Context, OrderManagerContext, UserManagerContext};
-use common::request_response_utils::{get_json_from_body, response_ok, response_redirect};
+use crate::context::{Context, OrderManagerContext, ReservationsContext, UserManagerContext};
+use crate::db::warehouses::AllocationStrategy;
+use common::request_response_utils::{create_response, read_body, response_ok, response_redirect};
+use common::auth::{check_secrets, MIN_SECRET_LEN};
+use common::idempotency::{Begin, Idempotency};
+use common::money::ExchangeRates;
+use common::rate_limit::RateLimiter;
 use common::settings::Settings;
+use common::utils::LocalError;
 use http::{Method, StatusCode};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{http, Body, Request, Response, Server};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-mod cache;
+mod catalog;
 mod context;
-mod db;
-mod entities;
 mod handlers;
+mod notifier;
+mod prices;
+mod promotions;
+mod recommendations;
+mod reservations;
+mod stock_alerts;
+mod totals;
+
+use product_manager::{cache, db, entities};
+
+// Request bodies are read whole, catalog imports being the largest.
+const MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
 
 async fn route_service(
     req: Request<Body>,
     addr: String,
+    remote_addr: SocketAddr,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
     let (parts, body) = req.into_parts();
 
+    let rate_limit = context.rate_limiter.check(&parts, remote_addr);
+    if !rate_limit.allowed {
+        return rate_limit.response();
+    }
+
     println!("method: {}, uri: {}", &parts.method, parts.uri.path());
 
-    let body_json = get_json_from_body(body).await;
+    // the catalog import takes CSV as well as JSON
+    let body_bytes = match read_body(body, MAX_BODY_BYTES).await {
+        Err(LocalError::PayloadTooLarge) => {
+            let e = LocalError::PayloadTooLarge;
+            return create_response(StatusCode::PAYLOAD_TOO_LARGE, e.to_string());
+        }
+        res => res.unwrap_or_default(),
+    };
+    let body_json = serde_json::from_slice(&body_bytes).ok();
+
+    // handlers take the context by value
+    let route_context = context.clone();
+    let claim = match context.idempotency.begin(&parts, &body_json) {
+        Begin::Handle(claim) => claim,
+        Begin::Respond(response) => return response,
+    };
 
-    match (&parts.method, parts.uri.path()) {
+    let response = match (&parts.method, parts.uri.path()) {
         (&Method::GET, "/") => response_redirect(addr),
         (&Method::GET, "/product/product/products") => handlers::get_items(context).await,
+        (&Method::GET, "/product/product/batch") => handlers::get_items_by_ids(&parts, context).await,
+        (&Method::GET, "/product/recommendations") => handlers::get_recommendations(&parts, context).await,
         (&Method::GET, "/product/product") => handlers::get_item(&parts, context).await,
-        (&Method::POST, "/product/add") => handlers::add_item(body_json, context).await,
+        (&Method::POST, "/product/add") => handlers::add_item(&parts, body_json, context).await,
         (&Method::OPTIONS, "/product/add") => response_ok(),
         (&Method::PUT, "/product/update") => handlers::update_item(&parts, body_json, context).await,
         (&Method::DELETE, "/product/delete") => handlers::delete_item(&parts, context).await,
         (&Method::OPTIONS, "/product/purchase") => response_ok(),
         (&Method::PUT, "/product/purchase") => handlers::buy_item(&parts, context).await,
+        (&Method::POST, "/product/reservation") => handlers::reserve_item(&parts, body_json, context).await,
+        (&Method::GET, "/product/reservation") => handlers::get_reservations(&parts, context).await,
+        (&Method::OPTIONS, "/product/reservation") => response_ok(),
+        (&Method::PUT, "/product/reservation/commit") => {
+            handlers::commit_reservation(&parts, context).await
+        }
+        (&Method::OPTIONS, "/product/reservation/commit") => response_ok(),
+        (&Method::PUT, "/product/reservation/release") => {
+            handlers::release_reservation(&parts, context).await
+        }
+        (&Method::OPTIONS, "/product/reservation/release") => response_ok(),
+        (&Method::POST, "/product/stock/movement") => {
+            handlers::add_stock_movement(&parts, body_json, context).await
+        }
+        (&Method::OPTIONS, "/product/stock/movement") => response_ok(),
+        (&Method::GET, "/product/stock/movements") => {
+            handlers::get_stock_movements(&parts, context).await
+        }
+        (&Method::GET, "/product/stock/reconcile") => {
+            handlers::get_stock_reconciliation(&parts, context).await
+        }
+        (&Method::PUT, "/product/stock/reconcile") => {
+            handlers::fix_stock_reconciliation(&parts, context).await
+        }
+        (&Method::OPTIONS, "/product/stock/reconcile") => response_ok(),
+        (&Method::GET, "/product/stock") => handlers::get_stock(&parts, context).await,
+        (&Method::POST, "/product/stock/transfer") => {
+            handlers::transfer_stock(&parts, body_json, context).await
+        }
+        (&Method::OPTIONS, "/product/stock/transfer") => response_ok(),
+        (&Method::GET, "/product/warehouses") => handlers::get_warehouses(&parts, context).await,
+        (&Method::POST, "/product/warehouse") => {
+            handlers::add_warehouse(&parts, body_json, context).await
+        }
+        (&Method::PUT, "/product/warehouse") => {
+            handlers::update_warehouse(&parts, body_json, context).await
+        }
+        (&Method::DELETE, "/product/warehouse") => {
+            handlers::delete_warehouse(&parts, context).await
+        }
+        (&Method::OPTIONS, "/product/warehouse") => response_ok(),
+        (&Method::GET, "/product/stock/low") => handlers::get_low_stock(&parts, context).await,
+        (&Method::GET, "/product/subscriptions") => {
+            handlers::get_subscriptions(&parts, context).await
+        }
+        (&Method::POST, "/product/subscription") => {
+            handlers::add_subscription(&parts, body_json, context).await
+        }
+        (&Method::DELETE, "/product/subscription") => {
+            handlers::delete_subscription(&parts, context).await
+        }
+        (&Method::OPTIONS, "/product/subscription") => response_ok(),
+        (&Method::GET, "/product/categories") => handlers::get_categories(context).await,
+        (&Method::GET, "/product/category/products") => {
+            handlers::get_category_products(&parts, context).await
+        }
+        (&Method::POST, "/product/category") => {
+            handlers::add_category(&parts, body_json, context).await
+        }
+        (&Method::PUT, "/product/category") => {
+            handlers::update_category(&parts, body_json, context).await
+        }
+        (&Method::DELETE, "/product/category") => handlers::delete_category(&parts, context).await,
+        (&Method::OPTIONS, "/product/category") => response_ok(),
+        (&Method::GET, "/product/variants") => handlers::get_variants(&parts, context).await,
+        (&Method::POST, "/product/variant") => {
+            handlers::add_variant(&parts, body_json, context).await
+        }
+        (&Method::PUT, "/product/variant") => {
+            handlers::update_variant(&parts, body_json, context).await
+        }
+        (&Method::DELETE, "/product/variant") => handlers::delete_variant(&parts, context).await,
+        (&Method::OPTIONS, "/product/variant") => response_ok(),
+        (&Method::GET, "/product/price") => handlers::get_price(&parts, context).await,
+        (&Method::GET, "/product/price/history") => {
+            handlers::get_price_history(&parts, context).await
+        }
+        (&Method::GET, "/product/price/schedules") => {
+            handlers::get_price_schedules(&parts, context).await
+        }
+        (&Method::POST, "/product/price/schedule") => {
+            handlers::add_price_schedule(&parts, body_json, context).await
+        }
+        (&Method::DELETE, "/product/price/schedule") => {
+            handlers::cancel_price_schedule(&parts, context).await
+        }
+        (&Method::OPTIONS, "/product/price/schedule") => response_ok(),
+        (&Method::GET, "/product/promotions") => handlers::get_promotions(&parts, context).await,
+        (&Method::POST, "/product/promotion") => {
+            handlers::add_promotion(&parts, body_json, context).await
+        }
+        (&Method::PUT, "/product/promotion") => {
+            handlers::update_promotion(&parts, body_json, context).await
+        }
+        (&Method::DELETE, "/product/promotion") => handlers::end_promotion(&parts, context).await,
+        (&Method::OPTIONS, "/product/promotion") => response_ok(),
+        (&Method::GET, "/product/coupons") => handlers::get_coupons(&parts, context).await,
+        (&Method::POST, "/product/coupon") => handlers::add_coupon(&parts, body_json, context).await,
+        (&Method::DELETE, "/product/coupon") => handlers::deactivate_coupon(&parts, context).await,
+        (&Method::OPTIONS, "/product/coupon") => response_ok(),
+        (&Method::POST, "/product/checkout/quote") => {
+            handlers::quote_checkout(body_json, context).await
+        }
+        (&Method::OPTIONS, "/product/checkout/quote") => response_ok(),
+        (&Method::POST, "/product/import") => {
+            handlers::import_catalog(&parts, &body_bytes, context).await
+        }
+        (&Method::OPTIONS, "/product/import") => response_ok(),
+        (&Method::GET, "/product/export") => handlers::export_catalog(&parts, context).await,
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Body::empty())
             .unwrap()),
-    }
+    };
+    let response = route_context.idempotency.finish(claim, response).await;
+
+    response.map(|mut response| {
+        rate_limit.add_headers(&mut response);
+        response
+    })
 }
 
 #[tokio::main]
@@ -50,12 +206,31 @@ pub async fn run_server(settings: &Settings, context: Arc<Context>) {
     let in_addr: SocketAddr = addr.parse().unwrap();
     println!("{}", settings.get("network", "listen_on"));
 
-    let service = make_service_fn(move |_| {
+    let refresh_interval: u64 = settings
+        .get("recommendations", "refresh_interval_secs")
+        .parse()
+        .unwrap();
+    recommendations::spawn_refresh_job(context.clone(), Duration::from_secs(refresh_interval));
+
+    let sweep_interval: u64 = settings
+        .get("reservations", "sweep_interval_secs")
+        .parse()
+        .unwrap();
+    reservations::spawn_sweeper(context.clone(), Duration::from_secs(sweep_interval));
+
+    let schedule_interval: u64 = settings
+        .get("prices", "schedule_interval_secs")
+        .parse()
+        .unwrap();
+    prices::spawn_scheduler(context.clone(), Duration::from_secs(schedule_interval));
+
+    let service = make_service_fn(move |conn: &AddrStream| {
         let addr = addr.clone();
+        let remote_addr = conn.remote_addr();
         let context = context.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
-                route_service(req, addr.clone(), context.clone())
+                route_service(req, addr.clone(), remote_addr, context.clone())
             }))
         }
     });
@@ -69,6 +244,55 @@ pub async fn run_server(settings: &Settings, context: Arc<Context>) {
     }
 }
 
+#[tokio::main]
+async fn migrate_stats(context: Arc<Context>) {
+    match context.db.mongo_db.migrate_stats().await {
+        Ok(count) => println!("Migrated {} stats documents", count),
+        Err(e) => println!("Error when migrating stats: {}", e),
+    }
+}
+
+#[tokio::main]
+async fn import_catalog(context: Arc<Context>, path: &str, dry_run: bool) {
+    let format = catalog::Format::from_path(path);
+    if format.is_none() {
+        println!("Unknown catalog format: {}", path);
+        return;
+    }
+
+    let rows = std::fs::read(path)
+        .map_err(|_| LocalError::OperationFailed)
+        .and_then(|data| catalog::parse_rows(&data, format.unwrap()));
+    match rows {
+        Err(e) => println!("Error when reading catalog: {}", e.to_string()),
+        Ok(rows) => {
+            let report = catalog::import(&context.db.postgres_db, rows, dry_run, "import").await;
+            for product_id in report.product_ids.iter() {
+                let _ = context.cache.delete_product(*product_id);
+            }
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        }
+    }
+}
+
+#[tokio::main]
+async fn export_catalog(context: Arc<Context>, path: &str) {
+    let format = catalog::Format::from_path(path);
+    if format.is_none() {
+        println!("Unknown catalog format: {}", path);
+        return;
+    }
+
+    let data = catalog::export(&context.db.postgres_db)
+        .await
+        .and_then(|rows| catalog::write_rows(&rows, format.unwrap()))
+        .and_then(|data| std::fs::write(path, data).map_err(|_| LocalError::OperationFailed));
+    match data {
+        Ok(()) => println!("Catalog exported to {}", path),
+        Err(e) => println!("Error when exporting catalog: {}", e.to_string()),
+    }
+}
+
 fn main() {
     let settings = Settings::new("./config/config.yml");
     let postgres_url = settings.get("postgres", "uri");
@@ -77,6 +301,14 @@ fn main() {
     let mongodb_name = settings.get("mongodb", "name");
     let redis_uri = settings.get("redis", "uri");
 
+    if let Err(setting) = check_secrets(&settings) {
+        println!(
+            "Error: {} is a placeholder or shorter than {} bytes",
+            setting, MIN_SECRET_LEN
+        );
+        return;
+    }
+
     let context;
     match db::DB::init(&postgres_url, &postgres_name, &mongodb_uri, &mongodb_name) {
         None => {
@@ -95,23 +327,113 @@ fn main() {
 
             let user_manager = UserManagerContext {
                 uri: settings.get("user_manager", "uri"),
+                api_key: settings.try_get("user_manager", "api_key").unwrap_or_default(),
                 product_viewed_endpoint: "/account/add_product_view".to_string(),
-                product_purchased_endpoint: "/account/add_product_purchase".to_string()
+                product_purchased_endpoint: "/account/add_product_purchase".to_string(),
+                interactions_endpoint: "/account/interactions".to_string(),
+                profile_endpoint: "/account/profile".to_string(),
             };
 
             let order_manager = OrderManagerContext {
                 uri: settings.get("order_manager", "uri"),
+                api_key: settings.try_get("order_manager", "api_key").unwrap_or_default(),
                 add_order_endpoint: "/order/add".to_string(),
             };
 
+            let reservations = ReservationsContext {
+                ttl: chrono::Duration::seconds(
+                    settings.get("reservations", "ttl_secs").parse().unwrap(),
+                ),
+                max_ttl: chrono::Duration::seconds(
+                    settings.get("reservations", "max_ttl_secs").parse().unwrap(),
+                ),
+            };
+
+            let allocation_strategy =
+                AllocationStrategy::parse(&settings.get("warehouses", "allocation"));
+            if allocation_strategy.is_none() {
+                println!("Unknown warehouse allocation strategy");
+                return;
+            }
+
+            let notifier = notifier::init(&settings);
+            if notifier.is_err() {
+                println!("Error when initializing notifier");
+                return;
+            }
+
+            let idempotency = Idempotency::init(&settings);
+            if idempotency.is_err() {
+                println!("Error when initializing idempotency store");
+                return;
+            }
+
+            let rate_limiter = RateLimiter::init(&settings);
+            if rate_limiter.is_err() {
+                println!("Error when initializing rate limiter");
+                return;
+            }
+
+            let tax_rates = totals::TaxRates::init(&settings);
+            if tax_rates.is_err() {
+                println!("Error when reading tax rates");
+                return;
+            }
+
+            let shipping_rates = totals::ShippingRates::init(&settings);
+            if shipping_rates.is_err() {
+                println!("Error when reading shipping rates");
+                return;
+            }
+
+            let exchange_rates = ExchangeRates::init(&settings);
+            if exchange_rates.is_err() {
+                println!("Error when reading exchange rates");
+                return;
+            }
+
             context = Arc::new(Context {
                 db,
+                auth_secret: settings.get("auth", "secret"),
                 cache: cache.unwrap(),
+                rate_limiter: rate_limiter.ok().unwrap(),
+                idempotency: idempotency.ok().unwrap(),
                 user_manager,
                 order_manager,
+                reservations,
+                allocation_strategy: allocation_strategy.unwrap(),
+                notifier: notifier.ok().unwrap(),
+                default_reorder_threshold: settings
+                    .get("stock_alerts", "default_reorder_threshold")
+                    .parse()
+                    .unwrap(),
+                tax_rates: tax_rates.ok().unwrap(),
+                shipping_rates: shipping_rates.ok().unwrap(),
+                exchange_rates: exchange_rates.ok().unwrap(),
             });
         }
     };
 
+    if std::env::args().any(|arg| arg == "--migrate-stats") {
+        migrate_stats(context);
+        return;
+    }
+
+    // --import <path> [--dry-run] and --export <path>, the format is taken from the extension
+    let args: Vec<String> = std::env::args().collect();
+    let path_arg = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+    };
+    if let Some(path) = path_arg("--import") {
+        import_catalog(context, path, args.iter().any(|arg| arg == "--dry-run"));
+        return;
+    }
+    if let Some(path) = path_arg("--export") {
+        export_catalog(context, path);
+        return;
+    }
+
     run_server(&settings, context.clone());
 }
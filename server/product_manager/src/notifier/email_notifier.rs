@@ -0,0 +1,24 @@
+use crate::notifier::{Notification, Notifier};
+use common::mailer::{Mail, Mailer};
+use common::utils::LocalError;
+
+// Low-stock alerts go to `alerts_to`, back-in-stock mails to the subscriber.
+pub struct EmailNotifier {
+    pub mailer: Box<dyn Mailer>,
+    pub alerts_to: String,
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), LocalError> {
+        let to = match notification {
+            Notification::LowStock { .. } => self.alerts_to.clone(),
+            Notification::BackInStock { email, .. } => email.clone(),
+        };
+
+        self.mailer.send(&Mail {
+            to,
+            subject: notification.subject(),
+            body: notification.message(),
+        })
+    }
+}
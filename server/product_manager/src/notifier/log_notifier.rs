@@ -0,0 +1,16 @@
+use crate::notifier::{Notification, Notifier};
+use common::utils::LocalError;
+
+pub struct LogNotifier {}
+
+impl Notifier for LogNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), LocalError> {
+        // subscriber addresses stay out of the logs
+        let mut logged = serde_json::to_value(notification).unwrap();
+        if let Some(fields) = logged.as_object_mut() {
+            fields.remove("email");
+        }
+        println!("{}: {}", notification.subject(), logged);
+        Ok(())
+    }
+}
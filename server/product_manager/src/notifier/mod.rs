@@ -0,0 +1,76 @@
+use crate::notifier::email_notifier::EmailNotifier;
+use crate::notifier::log_notifier::LogNotifier;
+use crate::notifier::webhook_notifier::WebhookNotifier;
+use common::settings::Settings;
+use common::utils::LocalError;
+use serde::Serialize;
+
+pub mod email_notifier;
+pub mod log_notifier;
+pub mod webhook_notifier;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    // a sale took the product's count to or below its reorder threshold
+    LowStock {
+        product_id: i32,
+        name: String,
+        count: i32,
+        threshold: i32,
+    },
+    // a product a user subscribed to is available again
+    BackInStock {
+        product_id: i32,
+        name: String,
+        count: i32,
+        user_id: i32,
+        email: String,
+    },
+}
+
+impl Notification {
+    pub fn subject(&self) -> String {
+        match self {
+            Notification::LowStock { name, .. } => format!("Low stock: {}", name),
+            Notification::BackInStock { name, .. } => format!("{} is back in stock", name),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Notification::LowStock {
+                product_id,
+                name,
+                count,
+                threshold,
+            } => format!(
+                "{} (id {}) is down to {} units, at or below its reorder threshold of {}.",
+                name, product_id, count, threshold
+            ),
+            Notification::BackInStock { name, .. } => {
+                format!("Good news: {} is available again.", name)
+            }
+        }
+    }
+}
+
+// Sending is blocking; callers run it off the async workers (see `stock_alerts::notify`).
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification) -> Result<(), LocalError>;
+}
+
+// `notifier.kind` selects the implementation: "log", "webhook" or "email".
+pub fn init(settings: &Settings) -> Result<Box<dyn Notifier>, LocalError> {
+    match settings.get("notifier", "kind").as_str() {
+        "log" => Ok(Box::new(LogNotifier {})),
+        "webhook" => Ok(Box::new(WebhookNotifier {
+            url: settings.get("notifier", "webhook_url"),
+        })),
+        "email" => Ok(Box::new(EmailNotifier {
+            mailer: common::mailer::init(settings)?,
+            alerts_to: settings.get("notifier", "alerts_to"),
+        })),
+        _ => Err(LocalError::WrongParameters),
+    }
+}
@@ -0,0 +1,40 @@
+use crate::notifier::{Notification, Notifier};
+use common::utils::LocalError;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use std::time::Duration;
+use tokio::runtime::Handle;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// POSTs each notification as JSON, tagged with its "event".
+pub struct WebhookNotifier {
+    pub url: String,
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), LocalError> {
+        let request = Request::post(self.url.as_str())
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(notification).unwrap()))
+            .map_err(|_| LocalError::WrongParameters)?;
+
+        let https = HttpsConnector::new();
+        let client = Client::builder().build::<_, hyper::Body>(https);
+
+        // called from the blocking pool, which can wait on the runtime it belongs to
+        let response = Handle::current()
+            .block_on(tokio::time::timeout(TIMEOUT, client.request(request)))
+            .map_err(|_| LocalError::OperationFailed)?
+            .map_err(|e| {
+                println!("Error when calling notification webhook: {}", e);
+                LocalError::OperationFailed
+            })?;
+
+        if !response.status().is_success() {
+            println!("Notification webhook returned {}", response.status());
+            return Err(LocalError::OperationFailed);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+use crate::context::Context;
+use std::sync::Arc;
+
+// Periodically starts and ends scheduled price changes.
+pub fn spawn_scheduler(context: Arc<Context>, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match context.db.postgres_db.apply_price_schedules().await {
+                Ok(product_ids) => {
+                    for id in product_ids {
+                        let _ = context.cache.delete_product(id);
+                    }
+                }
+                Err(e) => println!("Error when applying price schedules: {}", e.to_string()),
+            }
+        }
+    });
+}
@@ -0,0 +1,532 @@
+use crate::db::promotions::Offer;
+use crate::entities::{product, product_variant, promotion};
+use common::money;
+use sea_orm::prelude::Decimal;
+use serde::Serialize;
+use std::cmp::Reverse;
+
+// A product of the basket, priced before any discount.
+pub struct Line {
+    pub product_id: i32,
+    pub variant_id: Option<i32>,
+    pub category_id: i32,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+}
+
+impl Line {
+    // A variant's price overrides the product's.
+    pub fn new(
+        product: &product::Model,
+        variant: Option<&product_variant::Model>,
+        quantity: i32,
+    ) -> Line {
+        Line {
+            product_id: product.product_id,
+            variant_id: variant.map(|v| v.variant_id),
+            category_id: product.category_id,
+            quantity,
+            unit_price: money::round(variant.and_then(|v| v.price).unwrap_or(product.price)),
+        }
+    }
+
+    fn subtotal(&self) -> Decimal {
+        self.unit_price * Decimal::from(self.quantity)
+    }
+}
+
+#[derive(Serialize)]
+pub struct LineDiscount {
+    pub promotion_id: i32,
+    pub name: String,
+    pub coupon: Option<String>,
+    pub amount: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct PricedLine {
+    pub product_id: i32,
+    pub variant_id: Option<i32>,
+    #[serde(skip)]
+    pub category_id: i32,
+    pub quantity: i32,
+    pub unit_price: Decimal,
+    pub subtotal: Decimal,
+    pub discount: Decimal,
+    // subtotal - discount
+    pub total: Decimal,
+    pub discounts: Vec<LineDiscount>,
+    // set by `totals::apply`
+    pub tax_rate: Decimal,
+    pub tax: Decimal,
+    pub shipping: Decimal,
+    pub grand_total: Decimal,
+}
+
+#[derive(Serialize)]
+pub struct Discount {
+    pub promotion_id: i32,
+    pub name: String,
+    pub kind: String,
+    pub coupon: Option<String>,
+    #[serde(skip)]
+    pub coupon_id: Option<i32>,
+    pub amount: Decimal,
+}
+
+// A promotion that was considered but didn't apply, and why.
+#[derive(Serialize)]
+pub struct Rejection {
+    pub promotion_id: i32,
+    pub name: String,
+    pub coupon: Option<String>,
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct Pricing {
+    pub subtotal: Decimal,
+    pub discount: Decimal,
+    // subtotal - discount
+    pub total: Decimal,
+    pub lines: Vec<PricedLine>,
+    pub discounts: Vec<Discount>,
+    pub rejected: Vec<Rejection>,
+    // set by `totals::apply`
+    pub currency: String,
+    pub region: String,
+    pub tax: Decimal,
+    pub shipping: Decimal,
+    pub grand_total: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display: Option<Display>,
+}
+
+// The totals in another currency, for showing only.
+#[derive(Serialize)]
+pub struct Display {
+    pub currency: String,
+    pub rate: Decimal,
+    pub subtotal: Decimal,
+    pub discount: Decimal,
+    pub tax: Decimal,
+    pub shipping: Decimal,
+    pub grand_total: Decimal,
+}
+
+// A promotion applied to the basket, with its discount of each line.
+struct Applied<'a> {
+    offer: &'a Offer,
+    amounts: Vec<Decimal>,
+}
+
+impl Applied<'_> {
+    fn total(&self) -> Decimal {
+        self.amounts.iter().sum()
+    }
+}
+
+fn percent(amount: Decimal, percent: Decimal) -> Decimal {
+    money::round(amount * percent / Decimal::from(100))
+}
+
+fn in_scope(offer: &Offer, line: &Line) -> bool {
+    match (offer.promotion.product_id, offer.promotion.category_id) {
+        (Some(product_id), _) => line.product_id == product_id,
+        (None, Some(_)) => offer.category_ids.contains(&line.category_id),
+        (None, None) => true,
+    }
+}
+
+// Units in each group of a buy-x-get-y promotion, None when its quantities can't make one.
+fn group_size(promotion: &promotion::Model) -> Option<i64> {
+    let buy = promotion.buy_quantity.unwrap_or(1);
+    let get = promotion.get_quantity.unwrap_or(1);
+    if buy <= 0 || get <= 0 {
+        return None;
+    }
+    buy.checked_add(get).map(i64::from)
+}
+
+// Why the promotion can't apply to the basket, whatever the other promotions.
+fn ineligible(offer: &Offer, lines: &[Line], subtotal: Decimal) -> Option<String> {
+    let promotion = &offer.promotion;
+    let units: i64 = lines
+        .iter()
+        .filter(|l| in_scope(offer, l))
+        .map(|l| l.quantity as i64)
+        .sum();
+
+    if units == 0 {
+        return Some("no item of the basket qualifies".to_string());
+    }
+    if let Some(min_basket) = promotion.min_basket {
+        if subtotal < min_basket {
+            return Some(format!("the basket is below the minimum of {}", min_basket));
+        }
+    }
+    if promotion.kind == promotion::BUY_X_GET_Y {
+        match group_size(promotion) {
+            None => return Some("the promotion's quantities are invalid".to_string()),
+            Some(needed) if units < needed => {
+                return Some(format!("needs {} qualifying items", needed));
+            }
+            Some(_) => {}
+        }
+    }
+    None
+}
+
+// Splits `amount` over the lines in `scope` in proportion to what is left of them.
+fn spread(amount: Decimal, scope: &[usize], left: &[Decimal], amounts: &mut [Decimal]) {
+    let weights: Vec<Decimal> = scope.iter().map(|&i| left[i]).collect();
+    for (&i, share) in scope.iter().zip(money::allocate(amount, &weights)) {
+        amounts[i] = share;
+    }
+}
+
+// Discount of each line, taken from what the promotions before left of it.
+fn discount(offer: &Offer, lines: &[Line], left: &[Decimal]) -> Vec<Decimal> {
+    let promotion = &offer.promotion;
+    let scope: Vec<usize> = (0..lines.len())
+        .filter(|&i| in_scope(offer, &lines[i]))
+        .collect();
+    let mut amounts = vec![Decimal::ZERO; lines.len()];
+
+    match promotion.kind.as_str() {
+        promotion::PERCENT_OFF => {
+            for &i in scope.iter() {
+                amounts[i] = percent(left[i], promotion.value);
+            }
+        }
+        promotion::FIXED_OFF
+            if promotion.product_id.is_none() && promotion.category_id.is_none() =>
+        {
+            let basket: Decimal = left.iter().sum();
+            spread(promotion.value.min(basket), &scope, left, &mut amounts);
+        }
+        promotion::FIXED_OFF => {
+            for &i in scope.iter() {
+                amounts[i] = promotion.value * Decimal::from(lines[i].quantity);
+            }
+        }
+        promotion::BUY_X_GET_Y => {
+            // units go from the dearest down in groups of buy + get, the `get` cheapest
+            // units of each full group being discounted
+            let group = match group_size(promotion) {
+                Some(group) => group,
+                None => return amounts,
+            };
+            let buy = promotion.buy_quantity.unwrap_or(1) as i64;
+            let mut by_price = scope.clone();
+            by_price.sort_by_key(|&i| Reverse(lines[i].unit_price));
+            let units: i64 = by_price.iter().map(|&i| lines[i].quantity as i64).sum();
+            let grouped = units / group * group;
+            // discounted units among the first `n`
+            let discounted = |n: i64| {
+                let n = n.min(grouped);
+                n / group * (group - buy) + (n % group - buy).max(0)
+            };
+            let mut start = 0;
+            for &i in by_price.iter() {
+                let end = start + lines[i].quantity as i64;
+                let count = discounted(end) - discounted(start);
+                amounts[i] = percent(lines[i].unit_price, promotion.value) * Decimal::from(count);
+                start = end;
+            }
+        }
+        _ => {}
+    }
+
+    // never more than what is left of a line
+    for (amount, left) in amounts.iter_mut().zip(left.iter()) {
+        *amount = money::round((*amount).min(*left));
+    }
+    amounts
+}
+
+// Applies the promotions one after the other.
+fn apply<'a>(offers: &[&'a Offer], lines: &[Line]) -> Vec<Applied<'a>> {
+    let mut left: Vec<Decimal> = lines.iter().map(|l| l.subtotal()).collect();
+    let mut applied = Vec::new();
+
+    for &offer in offers.iter() {
+        let amounts = discount(offer, lines, &left);
+        for (left, amount) in left.iter_mut().zip(amounts.iter()) {
+            *left -= *amount;
+        }
+        applied.push(Applied { offer, amounts });
+    }
+    applied
+}
+
+fn rejection(offer: &Offer, reason: String) -> Rejection {
+    Rejection {
+        promotion_id: offer.promotion.promotion_id,
+        name: offer.promotion.name.clone(),
+        coupon: offer.coupon.as_ref().map(|c| c.code.clone()),
+        reason,
+    }
+}
+
+// Prices the basket with the best combination of the offers: the stackable promotions
+// together, in order of priority, or a non-stackable one on its own, whichever saves the
+// customer more.
+pub fn price(lines: &[Line], offers: &[Offer]) -> Pricing {
+    let subtotal: Decimal = lines.iter().map(|l| l.subtotal()).sum();
+    let mut rejected = Vec::new();
+
+    let mut eligible: Vec<&Offer> = Vec::new();
+    for offer in offers.iter() {
+        match ineligible(offer, lines, subtotal) {
+            Some(reason) => rejected.push(rejection(offer, reason)),
+            None => eligible.push(offer),
+        }
+    }
+    eligible.sort_by(|a, b| {
+        b.promotion
+            .priority
+            .cmp(&a.promotion.priority)
+            .then(a.promotion.promotion_id.cmp(&b.promotion.promotion_id))
+    });
+
+    let (stackable, exclusive): (Vec<&Offer>, Vec<&Offer>) =
+        eligible.into_iter().partition(|o| o.promotion.stackable);
+
+    let mut best = apply(&stackable, lines);
+    let mut best_exclusive: Option<&Offer> = None;
+    for &offer in exclusive.iter() {
+        let alone = apply(&[offer], lines);
+        let best_total: Decimal = best.iter().map(|a| a.total()).sum();
+        if alone[0].total() > best_total {
+            best = alone;
+            best_exclusive = Some(offer);
+        }
+    }
+
+    match best_exclusive {
+        None => {
+            for &offer in exclusive.iter() {
+                let reason = "can't be combined, and the combined promotions save more";
+                rejected.push(rejection(offer, reason.to_string()));
+            }
+        }
+        Some(chosen) => {
+            for &offer in exclusive.iter().filter(|o| !std::ptr::eq(**o, chosen)) {
+                let reason = format!("{} saves more", chosen.promotion.name);
+                rejected.push(rejection(offer, reason));
+            }
+            for &offer in stackable.iter() {
+                let reason = format!("can't be combined with {}", chosen.promotion.name);
+                rejected.push(rejection(offer, reason));
+            }
+        }
+    }
+
+    let mut priced: Vec<PricedLine> = lines
+        .iter()
+        .map(|l| PricedLine {
+            product_id: l.product_id,
+            variant_id: l.variant_id,
+            category_id: l.category_id,
+            quantity: l.quantity,
+            unit_price: l.unit_price,
+            subtotal: l.subtotal(),
+            discount: Decimal::ZERO,
+            total: l.subtotal(),
+            discounts: Vec::new(),
+            tax_rate: Decimal::ZERO,
+            tax: Decimal::ZERO,
+            shipping: Decimal::ZERO,
+            grand_total: l.subtotal(),
+        })
+        .collect();
+    let mut discounts = Vec::new();
+
+    for applied in best.iter() {
+        let offer = applied.offer;
+        let coupon = offer.coupon.as_ref().map(|c| c.code.clone());
+        if applied.total().is_zero() {
+            rejected.push(rejection(offer, "nothing left to discount".to_string()));
+            continue;
+        }
+
+        for (line, amount) in priced.iter_mut().zip(applied.amounts.iter()) {
+            if amount.is_zero() {
+                continue;
+            }
+            line.discount += *amount;
+            line.total -= *amount;
+            line.grand_total -= *amount;
+            line.discounts.push(LineDiscount {
+                promotion_id: offer.promotion.promotion_id,
+                name: offer.promotion.name.clone(),
+                coupon: coupon.clone(),
+                amount: *amount,
+            });
+        }
+        discounts.push(Discount {
+            promotion_id: offer.promotion.promotion_id,
+            name: offer.promotion.name.clone(),
+            kind: offer.promotion.kind.clone(),
+            coupon,
+            coupon_id: offer.coupon.as_ref().map(|c| c.coupon_id),
+            amount: applied.total(),
+        });
+    }
+
+    let discount: Decimal = discounts.iter().map(|d| d.amount).sum();
+    Pricing {
+        subtotal,
+        discount,
+        total: subtotal - discount,
+        lines: priced,
+        discounts,
+        rejected,
+        currency: String::new(),
+        region: String::new(),
+        tax: Decimal::ZERO,
+        shipping: Decimal::ZERO,
+        grand_total: subtotal - discount,
+        display: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::str::FromStr;
+
+    fn dec(amount: &str) -> Decimal {
+        Decimal::from_str(amount).unwrap()
+    }
+
+    fn line(product_id: i32, quantity: i32, unit_price: &str) -> Line {
+        Line {
+            product_id,
+            variant_id: None,
+            category_id: 1,
+            quantity,
+            unit_price: dec(unit_price),
+        }
+    }
+
+    // a basket-wide promotion
+    fn offer(promotion_id: i32, kind: &str, value: &str, stackable: bool, priority: i32) -> Offer {
+        Offer {
+            promotion: promotion::Model {
+                promotion_id,
+                name: format!("promotion {}", promotion_id),
+                kind: kind.to_string(),
+                value: dec(value),
+                buy_quantity: None,
+                get_quantity: None,
+                product_id: None,
+                category_id: None,
+                min_basket: None,
+                requires_coupon: false,
+                stackable,
+                priority,
+                starts_at: None,
+                ends_at: None,
+                active: true,
+                created_at: Utc::now().naive_utc(),
+            },
+            coupon: None,
+            category_ids: Vec::new(),
+        }
+    }
+
+    fn buy_x_get_y(promotion_id: i32, buy: i32, get: i32) -> Offer {
+        let mut offer = offer(promotion_id, promotion::BUY_X_GET_Y, "100", true, 0);
+        offer.promotion.buy_quantity = Some(buy);
+        offer.promotion.get_quantity = Some(get);
+        offer
+    }
+
+    fn applied(pricing: &Pricing) -> Vec<i32> {
+        pricing.discounts.iter().map(|d| d.promotion_id).collect()
+    }
+
+    fn rejected(pricing: &Pricing) -> Vec<i32> {
+        pricing.rejected.iter().map(|r| r.promotion_id).collect()
+    }
+
+    #[test]
+    fn price_stacks_promotions_in_order_of_priority() {
+        let lines = [line(1, 1, "100.00")];
+        let offers = [
+            offer(1, promotion::FIXED_OFF, "5", true, 0),
+            offer(2, promotion::PERCENT_OFF, "10", true, 10),
+            offer(3, promotion::PERCENT_OFF, "12", false, 0),
+        ];
+        let pricing = price(&lines, &offers);
+
+        // 10% of 100, then 5 off what is left
+        assert_eq!(applied(&pricing), vec![2, 1]);
+        assert_eq!(pricing.discounts[0].amount, dec("10.00"));
+        assert_eq!(pricing.discounts[1].amount, dec("5.00"));
+        assert_eq!(pricing.total, dec("85.00"));
+        assert_eq!(rejected(&pricing), vec![3]);
+    }
+
+    #[test]
+    fn price_takes_an_exclusive_promotion_that_saves_more() {
+        let lines = [line(1, 1, "100.00")];
+        let offers = [
+            offer(1, promotion::FIXED_OFF, "5", true, 0),
+            offer(2, promotion::PERCENT_OFF, "10", true, 10),
+            offer(3, promotion::PERCENT_OFF, "20", false, 0),
+            offer(4, promotion::PERCENT_OFF, "18", false, 0),
+        ];
+        let pricing = price(&lines, &offers);
+
+        assert_eq!(applied(&pricing), vec![3]);
+        assert_eq!(pricing.discount, dec("20.00"));
+        assert_eq!(pricing.total, dec("80.00"));
+        assert_eq!(rejected(&pricing), vec![4, 2, 1]);
+        assert_eq!(pricing.rejected[0].reason, "promotion 3 saves more");
+    }
+
+    #[test]
+    fn price_discounts_the_cheapest_units_of_buy_x_get_y() {
+        let lines = [line(1, 2, "10.00"), line(2, 1, "4.00"), line(3, 1, "8.00")];
+        let pricing = price(&lines, &[buy_x_get_y(1, 2, 1)]);
+
+        // 10, 10 and 8 make a full group, the 4 is left over
+        assert_eq!(pricing.lines[0].discount, Decimal::ZERO);
+        assert_eq!(pricing.lines[1].discount, Decimal::ZERO);
+        assert_eq!(pricing.lines[2].discount, dec("8.00"));
+        assert_eq!(pricing.total, dec("24.00"));
+    }
+
+    #[test]
+    fn price_discounts_each_full_group_of_buy_x_get_y() {
+        let lines = [line(1, 6, "5.00")];
+        let pricing = price(&lines, &[buy_x_get_y(1, 2, 1)]);
+
+        assert_eq!(pricing.discount, dec("10.00"));
+        assert_eq!(pricing.total, dec("20.00"));
+    }
+
+    #[test]
+    fn price_rejects_buy_x_get_y_with_invalid_quantities() {
+        let lines = [line(1, 3, "10.00")];
+        let pricing = price(&lines, &[buy_x_get_y(1, 0, 0), buy_x_get_y(2, i32::MAX, 1)]);
+
+        assert!(pricing.discounts.is_empty());
+        assert_eq!(rejected(&pricing), vec![1, 2]);
+        assert_eq!(pricing.total, dec("30.00"));
+    }
+
+    #[test]
+    fn price_rejects_buy_x_get_y_without_enough_items() {
+        let lines = [line(1, 2, "10.00")];
+        let pricing = price(&lines, &[buy_x_get_y(1, 2, 1)]);
+
+        assert!(pricing.discounts.is_empty());
+        assert_eq!(pricing.rejected[0].reason, "needs 3 qualifying items");
+        assert_eq!(pricing.total, dec("20.00"));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::context::Context;
+use chrono::{Duration, Utc};
+use common::auth::{service_token, ACCOUNTS_MANAGE};
+use common::rate_limit::API_KEY_HEADER;
+use common::utils::LocalError;
+use hyper::{Body, Client};
+use hyper_tls::HttpsConnector;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use url::Url;
+
+pub const PRODUCT: &str = "product";
+pub const USER: &str = "user";
+pub const POPULAR: &str = "popular";
+
+const VIEW_WEIGHT: f64 = 1.0;
+const PURCHASE_WEIGHT: f64 = 3.0;
+const MAX_RECOMMENDATIONS: usize = 20;
+const POPULARITY_DAYS: i64 = 30;
+
+pub struct UserInteractions {
+    pub user_id: i32,
+    pub viewed: Vec<i32>,
+    pub purchased: Vec<i32>,
+}
+
+impl UserInteractions {
+    fn from_json(json: &Value) -> Option<UserInteractions> {
+        let ids = |field: &str| -> Vec<i32> {
+            json.get(field)
+                .and_then(|ids| ids.as_array())
+                .map(|ids| {
+                    ids.iter()
+                        .filter_map(|id| id.as_i64())
+                        .map(|id| id as i32)
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        Some(UserInteractions {
+            user_id: json.get("user_id")?.as_i64()? as i32,
+            viewed: ids("viewed"),
+            purchased: ids("purchased"),
+        })
+    }
+
+    // A purchase implies a view, so purchased products get the higher weight only.
+    fn weights(&self) -> HashMap<i32, f64> {
+        let mut weights: HashMap<i32, f64> = HashMap::new();
+        for id in &self.viewed {
+            weights.insert(*id, VIEW_WEIGHT);
+        }
+        for id in &self.purchased {
+            weights.insert(*id, PURCHASE_WEIGHT);
+        }
+        weights
+    }
+}
+
+fn top(scores: HashMap<i32, f64>) -> Vec<(i32, f64)> {
+    let mut scores: Vec<(i32, f64)> = scores.into_iter().collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    scores.truncate(MAX_RECOMMENDATIONS);
+    scores
+}
+
+// Item-item cosine similarity over the weighted co-view/co-purchase matrix.
+pub fn item_similarities(interactions: &[UserInteractions]) -> HashMap<i32, Vec<(i32, f64)>> {
+    let mut norms: HashMap<i32, f64> = HashMap::new();
+    let mut co_occurrences: HashMap<(i32, i32), f64> = HashMap::new();
+
+    for user in interactions {
+        let weights: Vec<(i32, f64)> = user.weights().into_iter().collect();
+        for (i, (a, wa)) in weights.iter().enumerate() {
+            *norms.entry(*a).or_default() += wa * wa;
+            for (b, wb) in weights.iter().skip(i + 1) {
+                let key = if a < b { (*a, *b) } else { (*b, *a) };
+                *co_occurrences.entry(key).or_default() += wa * wb;
+            }
+        }
+    }
+
+    let mut scores: HashMap<i32, HashMap<i32, f64>> = HashMap::new();
+    for ((a, b), co) in co_occurrences {
+        let score = co / (norms[&a] * norms[&b]).sqrt();
+        scores.entry(a).or_default().insert(b, score);
+        scores.entry(b).or_default().insert(a, score);
+    }
+
+    scores
+        .into_iter()
+        .map(|(id, similar)| (id, top(similar)))
+        .collect()
+}
+
+// Products similar to what the user interacted with, excluding what they already bought.
+pub fn user_recommendations(
+    user: &UserInteractions,
+    similarities: &HashMap<i32, Vec<(i32, f64)>>,
+) -> Vec<(i32, f64)> {
+    let purchased: HashSet<i32> = user.purchased.iter().copied().collect();
+    let mut scores: HashMap<i32, f64> = HashMap::new();
+
+    for (id, weight) in user.weights() {
+        for (similar, score) in similarities.get(&id).into_iter().flatten() {
+            if !purchased.contains(similar) {
+                *scores.entry(*similar).or_default() += weight * score;
+            }
+        }
+    }
+
+    top(scores)
+}
+
+async fn get_interactions(context: &Context) -> Result<Vec<UserInteractions>, LocalError> {
+    let url = Url::parse(&format!(
+        "{}{}",
+        context.user_manager.uri, context.user_manager.interactions_endpoint
+    ))
+    .map_err(|_| LocalError::OperationFailed)?;
+
+    let request = hyper::Request::get(url.as_str())
+        .header(API_KEY_HEADER, &context.user_manager.api_key)
+        .header(
+            http::header::AUTHORIZATION,
+            format!(
+                "Bearer {}",
+                service_token(&context.auth_secret, &[ACCOUNTS_MANAGE])
+            ),
+        )
+        .body(Body::empty())
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    let https = HttpsConnector::new();
+    let client = Client::builder().build::<_, hyper::Body>(https);
+    let response = client
+        .request(request)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    let interactions: Vec<Value> =
+        serde_json::from_slice(&body).map_err(|_| LocalError::OperationFailed)?;
+
+    Ok(interactions
+        .iter()
+        .filter_map(UserInteractions::from_json)
+        .collect())
+}
+
+// Recomputes and stores product, user and popularity recommendations.
+pub async fn refresh(context: &Context) -> Result<(), LocalError> {
+    let mongo_db = &context.db.mongo_db;
+    let interactions = get_interactions(context).await?;
+    let similarities = item_similarities(&interactions);
+
+    for (product_id, similar) in similarities.iter() {
+        mongo_db
+            .save_recommendations(PRODUCT, *product_id, similar)
+            .await
+            .map_err(|_| LocalError::OperationFailed)?;
+    }
+
+    for user in interactions.iter() {
+        let recommended = user_recommendations(user, &similarities);
+        mongo_db
+            .save_recommendations(USER, user.user_id, &recommended)
+            .await
+            .map_err(|_| LocalError::OperationFailed)?;
+    }
+
+    let since = Utc::now() - Duration::days(POPULARITY_DAYS);
+    let popular = mongo_db
+        .get_popular_products(since, PURCHASE_WEIGHT, MAX_RECOMMENDATIONS as i64 * 5)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    mongo_db
+        .save_recommendations(POPULAR, 0, &popular)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    Ok(())
+}
+
+pub fn spawn_refresh_job(context: Arc<Context>, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match refresh(&context).await {
+                Ok(_) => println!("Recommendations refreshed"),
+                Err(e) => println!("Error when refreshing recommendations: {}", e.to_string()),
+            }
+        }
+    });
+}
+
+fn product_id(product: &Value) -> Option<i32> {
+    product
+        .get("product_id")
+        .and_then(|id| id.as_i64())
+        .map(|id| id as i32)
+}
+
+// Products for `ids`, in the order of `ids`.
+async fn get_ordered_products(context: &Context, ids: Vec<i32>) -> Result<Vec<Value>, LocalError> {
+    let products = context.db.postgres_db.get_products(ids.clone()).await?;
+    let mut by_id: HashMap<i32, Value> = products
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|p| Some((product_id(&p)?, p)))
+        .collect();
+
+    Ok(ids.iter().filter_map(|id| by_id.remove(id)).collect())
+}
+
+// Materialized recommendations for a product or user, topped up from popular products
+// (in the same category first) and the rest of the category for cold start.
+pub async fn get_recommendations(
+    context: &Context,
+    kind: &str,
+    id: i32,
+    limit: usize,
+) -> Result<Vec<Value>, LocalError> {
+    let mongo_db = &context.db.mongo_db;
+    let postgres_db = &context.db.postgres_db;
+
+    let category = if kind == PRODUCT {
+        let product = postgres_db.get_product(id).await?;
+        if product.is_null() {
+            return Err(LocalError::IdNotFound);
+        }
+        product.get("category_id").and_then(|c| c.as_i64())
+    } else {
+        None
+    };
+
+    let recommended = mongo_db
+        .get_recommendations(kind, id)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+    let popular = mongo_db
+        .get_recommendations(POPULAR, 0)
+        .await
+        .map_err(|_| LocalError::OperationFailed)?;
+
+    let recommended = get_ordered_products(context, recommended).await?;
+    let mut fallback = get_ordered_products(context, popular).await?;
+    if let Some(category) = category {
+        let products = postgres_db
+            .get_products_in_categories(vec![category as i32])
+            .await?;
+        fallback.extend(products.as_array().cloned().unwrap_or_default());
+    }
+
+    // stable sort keeps popular products ahead of the rest of the category
+    let same_category = |p: &Value| p.get("category_id").and_then(|c| c.as_i64()) == category;
+    fallback.sort_by_key(|p| !same_category(p));
+
+    let mut seen: HashSet<i32> = HashSet::new();
+    if kind == PRODUCT {
+        seen.insert(id);
+    }
+
+    Ok(recommended
+        .into_iter()
+        .chain(fallback)
+        .filter(|p| p.get("status").and_then(|s| s.as_bool()).unwrap_or(false))
+        .filter(|p| product_id(p).map(|id| seen.insert(id)).unwrap_or(false))
+        .take(limit)
+        .collect())
+}
@@ -0,0 +1,20 @@
+use crate::context::Context;
+use std::sync::Arc;
+
+// Periodically expires abandoned reservations so their units become available again.
+pub fn spawn_sweeper(context: Arc<Context>, interval: std::time::Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match context.db.postgres_db.expire_reservations().await {
+                Ok(product_ids) => {
+                    for id in product_ids {
+                        let _ = context.cache.delete_product(id);
+                    }
+                }
+                Err(e) => println!("Error when expiring reservations: {}", e.to_string()),
+            }
+        }
+    });
+}
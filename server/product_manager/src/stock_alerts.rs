@@ -0,0 +1,74 @@
+use crate::context::Context;
+use crate::entities::product;
+use crate::notifier::Notification;
+use std::sync::Arc;
+
+// Notifiers block, so notifications are sent from the blocking pool without holding up the response.
+pub fn notify(context: Arc<Context>, notification: Notification) {
+    tokio::task::spawn_blocking(move || {
+        if context.notifier.notify(&notification).is_err() {
+            println!(
+                "Error when sending notification: {}",
+                notification.subject()
+            );
+        }
+    });
+}
+
+// Alerts once, when a sale of `sold` units takes the count across the reorder threshold.
+pub fn check_low_stock(context: &Arc<Context>, product: &product::Model, sold: i32) {
+    let threshold = product
+        .reorder_threshold
+        .unwrap_or(context.default_reorder_threshold);
+
+    if product.count + sold > threshold && product.count <= threshold {
+        notify(
+            context.clone(),
+            Notification::LowStock {
+                product_id: product.product_id,
+                name: product.name.clone(),
+                count: product.count,
+                threshold,
+            },
+        );
+    }
+}
+
+// Tells the product's subscribers it can be bought again, once some of its stock isn't
+// held by reservations.
+pub async fn notify_back_in_stock(context: &Arc<Context>, product: &product::Model) {
+    if product.count <= 0 {
+        return;
+    }
+    let available = match context.db.postgres_db.get_available(product.product_id).await {
+        Ok(available) if available > 0 => available,
+        Ok(_) => return,
+        Err(e) => {
+            println!("Error when checking availability: {}", e.to_string());
+            return;
+        }
+    };
+
+    match context
+        .db
+        .postgres_db
+        .take_subscriptions(product.product_id)
+        .await
+    {
+        Ok(subscriptions) => {
+            for subscription in subscriptions {
+                notify(
+                    context.clone(),
+                    Notification::BackInStock {
+                        product_id: product.product_id,
+                        name: product.name.clone(),
+                        count: available,
+                        user_id: subscription.user_id,
+                        email: subscription.email,
+                    },
+                );
+            }
+        }
+        Err(e) => println!("Error when loading stock subscriptions: {}", e.to_string()),
+    }
+}
@@ -0,0 +1,277 @@
+use crate::promotions::{Display, Pricing};
+use common::money::{self, ExchangeRates};
+use common::settings::Settings;
+use common::utils::LocalError;
+use sea_orm::prelude::Decimal;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+fn parse_rate(rate: &str) -> Result<Decimal, LocalError> {
+    Decimal::from_str(rate.trim())
+        .ok()
+        .filter(|r| !r.is_sign_negative())
+        .ok_or(LocalError::WrongParameters)
+}
+
+// Tax rates in percent from the `tax_rates` section, of a region ("DE") or of a category in
+// a region ("DE/food", by slug). A category without a rate takes the one of its parent, and
+// the region's rate applies when no category up the tree has one.
+pub struct TaxRates {
+    rates: HashMap<String, Decimal>,
+    pub default_region: String,
+    // prices are gross, the tax is taken out of them instead of added
+    pub prices_include_tax: bool,
+}
+
+impl TaxRates {
+    pub fn init(settings: &Settings) -> Result<TaxRates, LocalError> {
+        let mut rates = HashMap::new();
+        for (key, rate) in settings.section("tax_rates").into_iter().flatten() {
+            rates.insert(key.clone(), parse_rate(rate)?);
+        }
+
+        let tax_rates = TaxRates {
+            rates,
+            default_region: settings.get("tax", "default_region"),
+            prices_include_tax: settings.get("tax", "prices_include_tax") == "true",
+        };
+        tax_rates.check_region(&tax_rates.default_region)?;
+
+        Ok(tax_rates)
+    }
+
+    pub fn check_region(&self, region: &str) -> Result<(), LocalError> {
+        if self.rates.contains_key(region) {
+            Ok(())
+        } else {
+            Err(LocalError::UnknownRegion)
+        }
+    }
+
+    // `category_path` holds the slugs from the product's category up to the root.
+    pub fn rate(&self, region: &str, category_path: &[String]) -> Decimal {
+        category_path
+            .iter()
+            .find_map(|slug| self.rates.get(&format!("{}/{}", region, slug)))
+            .or_else(|| self.rates.get(region))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+// Shipping fee of a checkout: `shipping.flat`, or the region's fee from `shipping_rates`.
+// Nothing once the goods come to `shipping.free_over`, when it is set.
+pub struct ShippingRates {
+    flat: Decimal,
+    free_over: Option<Decimal>,
+    rates: HashMap<String, Decimal>,
+}
+
+impl ShippingRates {
+    pub fn init(settings: &Settings) -> Result<ShippingRates, LocalError> {
+        let mut rates = HashMap::new();
+        for (region, fee) in settings.section("shipping_rates").into_iter().flatten() {
+            rates.insert(region.clone(), money::round(parse_rate(fee)?));
+        }
+
+        let free_over = match settings.try_get("shipping", "free_over") {
+            Some(amount) if !amount.trim().is_empty() => Some(parse_rate(&amount)?),
+            _ => None,
+        };
+
+        Ok(ShippingRates {
+            flat: money::round(parse_rate(&settings.get("shipping", "flat"))?),
+            free_over,
+            rates,
+        })
+    }
+
+    pub fn fee(&self, region: &str, goods: Decimal) -> Decimal {
+        if self.free_over.is_some_and(|free_over| goods >= free_over) {
+            return money::round(Decimal::ZERO);
+        }
+        self.rates.get(region).copied().unwrap_or(self.flat)
+    }
+}
+
+// Adds tax and shipping to a priced basket delivered to `region`, and shows the totals in
+// `display_currency` when one is asked for. Amounts are kept in the base currency.
+// `category_paths` maps categories to the slugs up to the root, see `TaxRates::rate`.
+pub fn apply(
+    pricing: &mut Pricing,
+    region: &str,
+    display_currency: Option<&str>,
+    category_paths: &HashMap<i32, Vec<String>>,
+    tax_rates: &TaxRates,
+    shipping_rates: &ShippingRates,
+    exchange_rates: &ExchangeRates,
+) -> Result<(), LocalError> {
+    tax_rates.check_region(region)?;
+    let hundred = Decimal::from(100);
+
+    // shipping is shared out over the lines so that each order adds up on its own
+    let shipping = shipping_rates.fee(region, pricing.total);
+    let weights: Vec<Decimal> = pricing.lines.iter().map(|l| l.total).collect();
+    let shares = money::allocate(shipping, &weights);
+
+    for (line, shipping) in pricing.lines.iter_mut().zip(shares) {
+        let path = category_paths
+            .get(&line.category_id)
+            .map(|p| p.as_slice())
+            .unwrap_or_default();
+        line.tax_rate = tax_rates.rate(region, path);
+        line.shipping = shipping;
+        if tax_rates.prices_include_tax {
+            line.tax = money::round(line.total - line.total * hundred / (hundred + line.tax_rate));
+            line.grand_total = line.total + shipping;
+        } else {
+            line.tax = money::round(line.total * line.tax_rate / hundred);
+            line.grand_total = line.total + line.tax + shipping;
+        }
+    }
+
+    pricing.currency = exchange_rates.base.clone();
+    pricing.region = region.to_string();
+    pricing.tax = pricing.lines.iter().map(|l| l.tax).sum();
+    pricing.shipping = money::round(shipping);
+    pricing.grand_total = pricing.lines.iter().map(|l| l.grand_total).sum();
+
+    pricing.display = match display_currency {
+        None => None,
+        Some(currency) => Some(Display {
+            currency: currency.to_string(),
+            rate: exchange_rates.rate(currency)?,
+            subtotal: exchange_rates.convert(pricing.subtotal, currency)?,
+            discount: exchange_rates.convert(pricing.discount, currency)?,
+            tax: exchange_rates.convert(pricing.tax, currency)?,
+            shipping: exchange_rates.convert(pricing.shipping, currency)?,
+            grand_total: exchange_rates.convert(pricing.grand_total, currency)?,
+        }),
+    };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promotions::{self, Line};
+
+    fn dec(amount: &str) -> Decimal {
+        Decimal::from_str(amount).unwrap()
+    }
+
+    fn settings(name: &str, prices_include_tax: bool) -> Settings {
+        let path = std::env::temp_dir().join(format!("totals-{}-{}.yml", std::process::id(), name));
+        let config = format!(
+            "money:\n  currency: EUR\n\
+             exchange_rates:\n  USD: \"1.10\"\n\
+             tax:\n  default_region: DE\n  prices_include_tax: \"{}\"\n\
+             tax_rates:\n  DE: \"19\"\n  DE/food: \"7\"\n  FR: \"20\"\n\
+             shipping:\n  flat: \"4.90\"\n  free_over: \"100\"\n\
+             shipping_rates:\n  FR: \"6.00\"\n",
+            prices_include_tax
+        );
+        std::fs::write(&path, config).unwrap();
+        Settings::new(path.to_str().unwrap())
+    }
+
+    fn line(product_id: i32, category_id: i32, quantity: i32, unit_price: &str) -> Line {
+        Line {
+            product_id,
+            variant_id: None,
+            category_id,
+            quantity,
+            unit_price: dec(unit_price),
+        }
+    }
+
+    // category 1 is food, category 2 has no rate of its own
+    fn totals(
+        settings: &Settings,
+        lines: &[Line],
+        region: &str,
+        display_currency: Option<&str>,
+    ) -> Result<Pricing, LocalError> {
+        let mut pricing = promotions::price(lines, &[]);
+        let category_paths = HashMap::from([
+            (1, vec!["food".to_string()]),
+            (2, vec!["books".to_string()]),
+        ]);
+        apply(
+            &mut pricing,
+            region,
+            display_currency,
+            &category_paths,
+            &TaxRates::init(settings)?,
+            &ShippingRates::init(settings)?,
+            &ExchangeRates::init(settings)?,
+        )?;
+        Ok(pricing)
+    }
+
+    #[test]
+    fn apply_adds_tax_by_category_and_shares_out_shipping() {
+        let settings = settings("net", false);
+        let lines = [line(1, 1, 2, "10.00"), line(2, 2, 1, "10.00")];
+        let pricing = totals(&settings, &lines, "DE", None).ok().unwrap();
+
+        assert_eq!(pricing.currency, "EUR");
+        assert_eq!(pricing.lines[0].tax_rate, dec("7"));
+        assert_eq!(pricing.lines[0].tax, dec("1.40"));
+        assert_eq!(pricing.lines[1].tax_rate, dec("19"));
+        assert_eq!(pricing.lines[1].tax, dec("1.90"));
+        assert_eq!(pricing.lines[0].shipping, dec("3.27"));
+        assert_eq!(pricing.lines[1].shipping, dec("1.63"));
+        assert_eq!(pricing.tax, dec("3.30"));
+        assert_eq!(pricing.shipping, dec("4.90"));
+        assert_eq!(pricing.grand_total, dec("38.20"));
+        assert!(pricing.display.is_none());
+    }
+
+    #[test]
+    fn apply_takes_tax_out_of_gross_prices() {
+        let settings = settings("gross", true);
+        let lines = [line(2, 2, 1, "11.90")];
+        let pricing = totals(&settings, &lines, "FR", None).ok().unwrap();
+
+        assert_eq!(pricing.tax, dec("1.98"));
+        assert_eq!(pricing.shipping, dec("6.00"));
+        assert_eq!(pricing.grand_total, dec("17.90"));
+    }
+
+    #[test]
+    fn apply_ships_for_free_over_the_threshold() {
+        let settings = settings("free", false);
+        let lines = [line(2, 2, 10, "10.00")];
+        let pricing = totals(&settings, &lines, "DE", None).ok().unwrap();
+
+        assert!(pricing.shipping.is_zero());
+        assert_eq!(pricing.grand_total, dec("119.00"));
+    }
+
+    #[test]
+    fn apply_shows_the_totals_in_the_display_currency() {
+        let settings = settings("display", false);
+        let lines = [line(2, 2, 1, "10.00")];
+        let pricing = totals(&settings, &lines, "DE", Some("USD")).ok().unwrap();
+
+        let display = pricing.display.unwrap();
+        assert_eq!(display.currency, "USD");
+        assert_eq!(display.rate, dec("1.10"));
+        assert_eq!(display.subtotal, dec("11.00"));
+        assert_eq!(display.grand_total, dec("18.48"));
+        assert_eq!(pricing.grand_total, dec("16.80"));
+    }
+
+    #[test]
+    fn apply_refuses_unknown_regions_and_currencies() {
+        let settings = settings("unknown", false);
+        let lines = [line(2, 2, 1, "10.00")];
+
+        let region = totals(&settings, &lines, "XX", None);
+        assert!(matches!(region, Err(LocalError::UnknownRegion)));
+        let currency = totals(&settings, &lines, "DE", Some("JPY"));
+        assert!(matches!(currency, Err(LocalError::UnknownCurrency)));
+    }
+}
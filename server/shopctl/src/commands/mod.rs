@@ -0,0 +1,5 @@
+pub mod orders;
+pub mod products;
+pub mod stats;
+pub mod stock;
+pub mod users;
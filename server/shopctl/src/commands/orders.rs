@@ -0,0 +1,55 @@
+use crate::context::Context;
+use crate::output;
+use clap::Subcommand;
+use common::utils::LocalError;
+use serde_json::json;
+
+const COLUMNS: [&str; 16] = [
+    "order_id",
+    "date_time",
+    "user_id",
+    "product_id",
+    "variant_id",
+    "quantity",
+    "shipped_quantity",
+    "unit_price",
+    "discount",
+    "tax",
+    "shipping",
+    "total_price",
+    "currency",
+    "status",
+    "payment_status",
+    "refunded",
+];
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Latest orders, newest first
+    List {
+        /// Only the orders of this user
+        #[arg(long)]
+        user: Option<i32>,
+        #[arg(long, default_value_t = 50)]
+        limit: u64,
+    },
+    /// Show an order
+    Show { id: i32 },
+}
+
+pub async fn run(context: &Context, command: Command, json: bool) -> Result<(), LocalError> {
+    let db = context.order_db().await?;
+
+    match command {
+        Command::List { user, limit } => {
+            let orders = db.get_orders(user, limit).await?;
+            output::print(&json!(orders), &COLUMNS, json);
+        }
+        Command::Show { id } => {
+            let order = db.get_order(id).await?;
+            output::print(&json!(order), &COLUMNS, json);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use crate::context::Context;
+use crate::output;
+use clap::Subcommand;
+use common::utils::LocalError;
+use serde_json::{json, Map, Value};
+
+const COLUMNS: [&str; 6] = [
+    "product_id",
+    "name",
+    "category",
+    "price",
+    "count",
+    "available",
+];
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// List all products
+    List,
+    /// Show a product with its variants
+    Show { id: i32 },
+    /// Create a product
+    Add {
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        price: f64,
+        /// Category slug or name
+        #[arg(long)]
+        category: String,
+        /// Initial stock, put in the default warehouse
+        #[arg(long, default_value_t = 0)]
+        count: i32,
+        #[arg(long)]
+        image: Option<String>,
+        #[arg(long)]
+        reorder_threshold: Option<i32>,
+    },
+    /// Change a product; stock is changed with `stock adjust`
+    Update {
+        id: i32,
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        price: Option<f64>,
+        /// Category slug or name
+        #[arg(long)]
+        category: Option<String>,
+        #[arg(long)]
+        image: Option<String>,
+        #[arg(long)]
+        reorder_threshold: Option<i32>,
+    },
+    /// Delete a product
+    Delete { id: i32 },
+}
+
+pub async fn run(context: &Context, command: Command, json: bool) -> Result<(), LocalError> {
+    let db = context.product_db().await?;
+
+    match command {
+        Command::List => {
+            let products = db.get_all_products().await?;
+            output::print(&products, &COLUMNS, json);
+        }
+        Command::Show { id } => {
+            let product = db.get_product(id).await?;
+            if product.is_null() {
+                return Err(LocalError::IdNotFound);
+            }
+            output::print(&product, &COLUMNS, json);
+        }
+        Command::Add {
+            name,
+            price,
+            category,
+            count,
+            image,
+            reorder_threshold,
+        } => {
+            let product = json!({
+                "name": name,
+                "price": price,
+                "category": category,
+                "count": count,
+                "image": image,
+                "reorder_threshold": reorder_threshold,
+            });
+            let id = db.add_product(product, &context.actor).await?;
+            output::print(&json!({ "product_id": id }), &COLUMNS, json);
+        }
+        Command::Update {
+            id,
+            name,
+            price,
+            category,
+            image,
+            reorder_threshold,
+        } => {
+            let mut updates = Map::new();
+            if let Some(name) = name {
+                updates.insert("name".to_string(), json!(name));
+            }
+            if let Some(price) = price {
+                updates.insert("price".to_string(), json!(price));
+            }
+            if let Some(category) = category {
+                updates.insert("category".to_string(), json!(category));
+            }
+            if let Some(image) = image {
+                updates.insert("image".to_string(), json!(image));
+            }
+            if let Some(reorder_threshold) = reorder_threshold {
+                updates.insert("reorder_threshold".to_string(), json!(reorder_threshold));
+            }
+            if updates.is_empty() {
+                return Err(LocalError::WrongParameters);
+            }
+
+            let product = db
+                .update_product(id, Value::Object(updates), &context.actor)
+                .await?;
+            context.forget_products(&[id]);
+            output::print(&product, &COLUMNS, json);
+        }
+        Command::Delete { id } => {
+            db.delete_product(id).await?;
+            context.forget_products(&[id]);
+            output::print(&json!({ "deleted": id }), &COLUMNS, json);
+        }
+    }
+
+    Ok(())
+}
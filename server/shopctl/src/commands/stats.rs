@@ -0,0 +1,98 @@
+use crate::context::Context;
+use crate::output;
+use chrono::{Duration, Utc};
+use clap::Subcommand;
+use common::money;
+use common::utils::{round, LocalError};
+use sea_orm::prelude::Decimal;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+// as in product_manager's recommendations
+const PURCHASE_WEIGHT: f64 = 3.0;
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Most viewed and purchased products
+    Popular {
+        #[arg(long, default_value_t = 7)]
+        days: i64,
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
+    /// Totals of the catalog and its stock
+    Inventory,
+}
+
+// Prices are serialized as strings.
+fn price(product: &Value) -> Decimal {
+    money::parse_amount(&product["price"]).unwrap_or_default()
+}
+
+pub async fn run(context: &Context, command: Command, json: bool) -> Result<(), LocalError> {
+    let db = context.product_db().await?;
+
+    match command {
+        Command::Popular { days, limit } => {
+            let since = Utc::now() - Duration::days(days);
+            let popular = context
+                .product_stats()
+                .await?
+                .get_popular_products(since, PURCHASE_WEIGHT, limit)
+                .await
+                .map_err(|_| LocalError::OperationFailed)?;
+
+            let products = db
+                .get_products(popular.iter().map(|(id, _)| *id).collect())
+                .await?;
+            let names: HashMap<i64, Value> = products
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|p| Some((p["product_id"].as_i64()?, p["name"].clone())))
+                .collect();
+
+            let rows: Vec<Value> = popular
+                .iter()
+                .enumerate()
+                .map(|(i, (id, score))| {
+                    json!({
+                        "rank": i + 1,
+                        "product_id": id,
+                        "name": names.get(&(*id as i64)),
+                        "score": round(*score, 2),
+                    })
+                })
+                .collect();
+            output::print(&json!(rows), &["rank", "product_id", "name", "score"], json);
+        }
+        Command::Inventory => {
+            let products = db.get_all_products().await?;
+            let products = products.as_array().cloned().unwrap_or_default();
+            let threshold: i32 = context
+                .settings
+                .get("stock_alerts", "default_reorder_threshold")
+                .parse()
+                .map_err(|_| LocalError::WrongParameters)?;
+            let low_stock = db.get_low_stock(threshold).await?;
+
+            let count = |p: &Value, key: &str| p[key].as_i64().unwrap_or_default();
+            let stats = json!({
+                "products": products.len(),
+                "units": products.iter().map(|p| count(p, "count")).sum::<i64>(),
+                "available": products.iter().map(|p| count(p, "available")).sum::<i64>(),
+                "stock_value": money::round(
+                    products
+                        .iter()
+                        .map(|p| Decimal::from(count(p, "count")) * price(p))
+                        .sum(),
+                ),
+                "out_of_stock": products.iter().filter(|p| count(p, "count") == 0).count(),
+                "low_stock": low_stock.len(),
+            });
+            output::print(&stats, &[], json);
+        }
+    }
+
+    Ok(())
+}
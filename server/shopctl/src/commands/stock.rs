@@ -0,0 +1,135 @@
+use crate::context::Context;
+use crate::output;
+use clap::Subcommand;
+use common::utils::LocalError;
+use product_manager::db::postgres::Movement;
+use product_manager::entities::inventory_movements;
+use serde_json::json;
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Stock of a product per warehouse
+    Show { product_id: i32 },
+    /// Add or remove units, recorded in the movement ledger
+    Adjust {
+        product_id: i32,
+        /// Signed change of the stock
+        #[arg(allow_hyphen_values = true)]
+        quantity: i32,
+        #[arg(long)]
+        reason: String,
+        #[arg(long, default_value = inventory_movements::ADJUSTMENT,
+              value_parser = [inventory_movements::RESTOCK, inventory_movements::RETURN, inventory_movements::ADJUSTMENT])]
+        kind: String,
+        /// Defaults to the default warehouse
+        #[arg(long)]
+        warehouse: Option<i32>,
+        /// Required for products with variants
+        #[arg(long)]
+        variant: Option<i32>,
+    },
+    /// Ledger movements of a product, newest first
+    Movements {
+        product_id: i32,
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+    /// Products at or below their reorder threshold
+    Low,
+    /// Compare stock with the movement ledger
+    Reconcile {
+        /// Record adjustments for the differences found
+        #[arg(long)]
+        fix: bool,
+    },
+}
+
+pub async fn run(context: &Context, command: Command, json: bool) -> Result<(), LocalError> {
+    let db = context.product_db().await?;
+
+    match command {
+        Command::Show { product_id } => {
+            let stock = db.get_stock(product_id).await?;
+            output::print(&json!(stock), &["warehouse_id", "name", "count"], json);
+        }
+        Command::Adjust {
+            product_id,
+            quantity,
+            reason,
+            kind,
+            warehouse,
+            variant,
+        } => {
+            let movement = Movement {
+                product_id,
+                warehouse_id: warehouse,
+                variant_id: variant,
+                kind: &kind,
+                quantity,
+                reason: &reason,
+                order_ref: None,
+            };
+            let product = db.adjust_stock(movement, &context.actor).await?;
+            context.forget_products(&[product_id]);
+            output::print(&product, &[], json);
+        }
+        Command::Movements { product_id, limit } => {
+            let movements = db.get_movements(product_id, 0, limit).await?;
+            output::print(
+                &json!(movements),
+                &[
+                    "movement_id",
+                    "created_at",
+                    "kind",
+                    "quantity",
+                    "warehouse_id",
+                    "variant_id",
+                    "reason",
+                    "actor",
+                    "order_ref",
+                ],
+                json,
+            );
+        }
+        Command::Low => {
+            let threshold: i32 = context
+                .settings
+                .get("stock_alerts", "default_reorder_threshold")
+                .parse()
+                .map_err(|_| LocalError::WrongParameters)?;
+            let products = db.get_low_stock(threshold).await?;
+            output::print(
+                &json!(products),
+                &["product_id", "name", "count", "reorder_threshold"],
+                json,
+            );
+        }
+        Command::Reconcile { fix } => {
+            let mut rows = db.reconcile(None).await?;
+            rows.retain(|row| row["difference"] != json!(0) || row["stock_count"] != row["count"]);
+
+            if fix {
+                let mut fixed = Vec::new();
+                for row in rows.iter().filter(|row| row["difference"] != json!(0)) {
+                    let product_id = row["product_id"].as_i64().unwrap_or_default() as i32;
+                    fixed.push(db.fix_reconciliation(product_id, &context.actor).await?);
+                }
+                rows = fixed;
+            }
+
+            output::print(
+                &json!(rows),
+                &[
+                    "product_id",
+                    "count",
+                    "ledger_count",
+                    "difference",
+                    "stock_count",
+                ],
+                json,
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use crate::context::Context;
+use crate::output;
+use clap::{ArgGroup, Subcommand};
+use common::auth::Role;
+use common::utils::LocalError;
+use serde_json::json;
+use std::net::IpAddr;
+use user_manager::login_guard::redis_store::RedisAttemptStore;
+use user_manager::login_guard::{ip_key, user_key, AttemptStore};
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Show an account
+    Show { id: i32 },
+    /// Find an account by email
+    Find { email: String },
+    /// Lift a login lockout of a username or an address
+    #[command(group(ArgGroup::new("key").required(true).args(["username", "ip"])))]
+    Unlock {
+        #[arg(long)]
+        username: Option<String>,
+        #[arg(long)]
+        ip: Option<IpAddr>,
+    },
+    /// Change the role of an account
+    Role {
+        id: i32,
+        /// customer, staff or admin
+        role: String,
+        /// Comma separated permissions granted on top of the role's ones
+        #[arg(long)]
+        permissions: Option<String>,
+    },
+    /// Mark the email of an account as verified
+    Verify { id: i32 },
+    /// Latest logins of an account
+    Logins {
+        id: i32,
+        #[arg(long, default_value_t = 20)]
+        limit: u64,
+    },
+}
+
+const COLUMNS: [&str; 5] = ["user_id", "username", "email", "role", "email_verified"];
+
+pub async fn run(context: &Context, command: Command, json: bool) -> Result<(), LocalError> {
+    match command {
+        Command::Show { id } => {
+            let user = context.user_db().await?.get_user(id).await?;
+            output::print(&user, &COLUMNS, json);
+        }
+        Command::Find { email } => {
+            let user = context.user_db().await?.find_user_by_email(&email).await?;
+            output::print(&json!(user), &COLUMNS, json);
+        }
+        Command::Unlock { username, ip } => {
+            // only locks kept in Redis can be lifted from here; with the "memory" store they
+            // live in the user_manager process
+            let store = RedisAttemptStore::init(
+                &context
+                    .settings
+                    .get("user_manager", "login_guard_redis_uri"),
+            )?;
+            let key = match (username, ip) {
+                (Some(username), _) => user_key(&username),
+                (None, Some(ip)) => ip_key(&ip),
+                (None, None) => return Err(LocalError::WrongParameters),
+            };
+            store.clear(&key)?;
+            output::print(&json!({ "unlocked": key }), &COLUMNS, json);
+        }
+        Command::Role {
+            id,
+            role,
+            permissions,
+        } => {
+            let role = Role::parse(&role).ok_or(LocalError::WrongParameters)?;
+            let permissions = permissions.map(|p| {
+                p.split(',')
+                    .map(|p| p.trim().to_string())
+                    .filter(|p| !p.is_empty())
+                    .collect()
+            });
+            let user = context
+                .user_db()
+                .await?
+                .set_role(id, role, permissions)
+                .await?;
+            output::print(&user, &COLUMNS, json);
+        }
+        Command::Verify { id } => {
+            let db = context.user_db().await?;
+            db.set_email_verified(id).await?;
+            output::print(&db.get_user(id).await?, &COLUMNS, json);
+        }
+        Command::Logins { id, limit } => {
+            let logins = context
+                .user_stats()
+                .await?
+                .get_login_history(id, 0, limit)
+                .await
+                .map_err(|_| LocalError::OperationFailed)?;
+            output::print(&json!(logins), &["at"], json);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use common::settings::Settings;
+use common::utils::LocalError;
+use mongodb::{Client, Database as MongoDatabase};
+use product_manager::cache::redis_cache::Cache;
+use sea_orm::{Database, DatabaseConnection};
+
+// Connections are opened on demand, so a command only needs the databases it touches.
+pub struct Context {
+    pub settings: Settings,
+    // recorded as the actor of stock movements
+    pub actor: String,
+}
+
+impl Context {
+    pub fn new(settings: Settings) -> Context {
+        let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+
+        Context {
+            settings,
+            actor: format!("shopctl:{}", user),
+        }
+    }
+
+    async fn connect_postgres(&self, service: &str) -> Result<DatabaseConnection, LocalError> {
+        let uri = format!(
+            "{}/{}",
+            self.settings.get(service, "postgres_uri"),
+            self.settings.get(service, "postgres_name")
+        );
+
+        Database::connect(&uri).await.map_err(|e| {
+            eprintln!("Error when connecting to Postgres: {:?}", e);
+            LocalError::OperationFailed
+        })
+    }
+
+    async fn connect_mongo(&self, service: &str) -> Result<MongoDatabase, LocalError> {
+        let client = Client::with_uri_str(self.settings.get(service, "mongodb_uri"))
+            .await
+            .map_err(|e| {
+                eprintln!("Error when connecting to Mongo: {:?}", e);
+                LocalError::OperationFailed
+            })?;
+
+        Ok(client.database(&self.settings.get(service, "mongodb_name")))
+    }
+
+    pub async fn product_db(
+        &self,
+    ) -> Result<product_manager::db::postgres::PostgresDB, LocalError> {
+        Ok(product_manager::db::postgres::PostgresDB {
+            db: self.connect_postgres("product_manager").await?,
+        })
+    }
+
+    pub async fn product_stats(&self) -> Result<product_manager::db::mongo::MongoDB, LocalError> {
+        Ok(product_manager::db::mongo::MongoDB {
+            db: self.connect_mongo("product_manager").await?,
+        })
+    }
+
+    pub async fn user_db(&self) -> Result<user_manager::db::postgres::PostgresDB, LocalError> {
+        Ok(user_manager::db::postgres::PostgresDB {
+            db: self.connect_postgres("user_manager").await?,
+        })
+    }
+
+    pub async fn user_stats(&self) -> Result<user_manager::db::mongo::MongoDB, LocalError> {
+        Ok(user_manager::db::mongo::MongoDB {
+            db: self.connect_mongo("user_manager").await?,
+        })
+    }
+
+    pub async fn order_db(&self) -> Result<order_manager::db::postgres::PostgresDB, LocalError> {
+        Ok(order_manager::db::postgres::PostgresDB {
+            db: self.connect_postgres("order_manager").await?,
+        })
+    }
+
+    // product_manager caches products in Redis; changes made here have to drop them.
+    pub fn forget_products(&self, product_ids: &[i32]) {
+        let cache = Cache::init(self.settings.get("product_manager", "redis_uri"));
+        if cache.is_err() {
+            eprintln!("Error when connecting to the product cache, cached products may be stale");
+            return;
+        }
+
+        let cache = cache.unwrap();
+        for product_id in product_ids {
+            let _ = cache.delete_product(*product_id);
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::context::Context;
+use clap::{Parser, Subcommand};
+use common::settings::Settings;
+use common::utils::LocalError;
+
+mod commands;
+mod context;
+mod output;
+
+/// Day-to-day operation of the shop, straight on the services' databases
+#[derive(Parser)]
+#[command(name = "shopctl")]
+struct Cli {
+    #[arg(long, default_value = "./config/config.yml")]
+    config: String,
+    /// Print JSON instead of tables
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Products of the catalog
+    #[command(subcommand)]
+    Products(commands::products::Command),
+    /// Stock levels and the movement ledger
+    #[command(subcommand)]
+    Stock(commands::stock::Command),
+    /// Accounts and login lockouts
+    #[command(subcommand)]
+    Users(commands::users::Command),
+    /// Placed orders
+    #[command(subcommand)]
+    Orders(commands::orders::Command),
+    /// Popularity and inventory figures
+    #[command(subcommand)]
+    Stats(commands::stats::Command),
+}
+
+#[tokio::main]
+async fn run(context: Context, command: Command, json: bool) -> Result<(), LocalError> {
+    match command {
+        Command::Products(command) => commands::products::run(&context, command, json).await,
+        Command::Stock(command) => commands::stock::run(&context, command, json).await,
+        Command::Users(command) => commands::users::run(&context, command, json).await,
+        Command::Orders(command) => commands::orders::run(&context, command, json).await,
+        Command::Stats(command) => commands::stats::run(&context, command, json).await,
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let context = Context::new(Settings::new(&cli.config));
+
+    if let Err(e) = run(context, cli.command, cli.json) {
+        eprintln!("Error: {}", e.to_string());
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,42 @@
+use serde_json::Value;
+use tabled::builder::Builder;
+use tabled::settings::Style;
+
+// Strings are shown as they are, anything else as JSON.
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+// Lists are printed as a table of `columns`, a single record as a table of its fields.
+pub fn print(value: &Value, columns: &[&str], json: bool) {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value).unwrap());
+        return;
+    }
+
+    let mut builder = Builder::default();
+    match value {
+        Value::Array(rows) => {
+            builder.set_header(columns.iter().copied());
+            for row in rows {
+                builder.push_record(columns.iter().map(|column| cell(row.get(*column))));
+            }
+        }
+        Value::Object(record) => {
+            builder.set_header(["field", "value"]);
+            for (key, value) in record {
+                builder.push_record([key.clone(), cell(Some(value))]);
+            }
+        }
+        value => {
+            println!("{}", cell(Some(value)));
+            return;
+        }
+    }
+
+    println!("{}", builder.build().with(Style::psql()));
+}
@@ -1,5 +1,31 @@
 use crate::db::DB;
+use crate::login_guard::LoginGuard;
+use chrono::Duration;
+use common::idempotency::Idempotency;
+use common::mailer::Mailer;
+use common::rate_limit::RateLimiter;
+use std::net::IpAddr;
 
 pub struct Context {
     pub db: DB,
+    pub auth_secret: String,
+    pub mailer: Box<dyn Mailer>,
+    pub account: AccountContext,
+    pub login_guard: LoginGuard,
+    pub rate_limiter: RateLimiter,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub idempotency: Idempotency,
+    pub product_manager: ProductManagerContext,
+}
+
+pub struct AccountContext {
+    pub base_url: String,
+    pub require_verified_email: bool,
+    pub verification_token_ttl: Duration,
+    pub reset_token_ttl: Duration,
+}
+
+pub struct ProductManagerContext {
+    pub uri: String,
+    pub get_products_endpoint: String,
 }
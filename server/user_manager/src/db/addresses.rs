@@ -0,0 +1,212 @@
+use chrono::Utc;
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, EntityTrait, NotSet, QueryFilter,
+    QueryOrder, Set, TransactionTrait,
+};
+use serde_json::{Map, Value};
+
+use crate::db::postgres::PostgresDB;
+use crate::entities::address;
+use crate::validation::{validate_country, validate_phone, validate_postal_code};
+use common::db_utils::{RecordType, ToError};
+use common::utils::LocalError;
+
+const REQUIRED_FIELDS: [&str; 5] = ["full_name", "line1", "city", "postal_code", "country"];
+
+fn text(val: &Value, max_len: usize) -> Result<Option<String>, LocalError> {
+    match val {
+        Value::Null => Ok(None),
+        Value::String(s) if s.trim().chars().count() <= max_len => {
+            Ok(Some(s.trim().to_string()).filter(|s| !s.is_empty()))
+        }
+        _ => Err(LocalError::InvalidAddress),
+    }
+}
+
+fn required(val: &Value, max_len: usize) -> Result<String, LocalError> {
+    text(val, max_len)?.ok_or(LocalError::InvalidAddress)
+}
+
+// Sets the fields of `address` given in `fields`, others are left as they are.
+fn set_fields(
+    address: &mut address::ActiveModel,
+    fields: &Map<String, Value>,
+) -> Result<(), LocalError> {
+    for (key, val) in fields.iter() {
+        match key.as_str() {
+            "label" => address.label = Set(text(val, 100)?),
+            "full_name" => address.full_name = Set(required(val, 500)?),
+            "line1" => address.line1 = Set(required(val, 500)?),
+            "line2" => address.line2 = Set(text(val, 500)?),
+            "city" => address.city = Set(required(val, 200)?),
+            "postal_code" => {
+                let postal_code = required(val, 20)?.to_uppercase();
+                validate_postal_code(&postal_code)?;
+                address.postal_code = Set(postal_code);
+            }
+            "region" => address.region = Set(text(val, 200)?),
+            "country" => {
+                let country = required(val, 2)?.to_uppercase();
+                validate_country(&country)?;
+                address.country = Set(country);
+            }
+            "phone" => {
+                let phone = text(val, 100)?;
+                if let Some(ref phone) = phone {
+                    validate_phone(phone)?;
+                }
+                address.phone = Set(phone);
+            }
+            "default_shipping" => {
+                address.default_shipping = Set(val.as_bool().ok_or(LocalError::InvalidAddress)?)
+            }
+            "default_billing" => {
+                address.default_billing = Set(val.as_bool().ok_or(LocalError::InvalidAddress)?)
+            }
+            _ => return Err(LocalError::WrongParameters),
+        }
+    }
+    Ok(())
+}
+
+// Makes room for a new default of the user: the one a default column is set on.
+async fn clear_defaults<C: ConnectionTrait>(
+    db: &C,
+    user_id: i32,
+    address: &address::ActiveModel,
+) -> Result<(), LocalError> {
+    let columns = [
+        (address::Column::DefaultShipping, &address.default_shipping),
+        (address::Column::DefaultBilling, &address.default_billing),
+    ];
+    for (column, value) in columns {
+        if !matches!(value, ActiveValue::Set(true)) {
+            continue;
+        }
+        address::Entity::update_many()
+            .col_expr(column, Expr::value(false))
+            .filter(address::Column::UserId.eq(user_id))
+            .filter(column.eq(true))
+            .exec(db)
+            .await
+            .to_local_error(RecordType::User)?;
+    }
+    Ok(())
+}
+
+async fn find_address<C: ConnectionTrait>(
+    db: &C,
+    user_id: i32,
+    address_id: i32,
+) -> Result<address::Model, LocalError> {
+    address::Entity::find_by_id(address_id)
+        .filter(address::Column::UserId.eq(user_id))
+        .one(db)
+        .await
+        .to_local_error(RecordType::User)?
+        .ok_or(LocalError::IdNotFound)
+}
+
+impl PostgresDB {
+    // Oldest first.
+    pub async fn get_addresses(&self, user_id: i32) -> Result<Vec<address::Model>, LocalError> {
+        address::Entity::find()
+            .filter(address::Column::UserId.eq(user_id))
+            .order_by_asc(address::Column::AddressId)
+            .all(&self.db)
+            .await
+            .to_local_error(RecordType::User)
+    }
+
+    // The user's first address is their default for shipping and billing.
+    pub async fn add_address(
+        &self,
+        user_id: i32,
+        fields: Value,
+    ) -> Result<address::Model, LocalError> {
+        let fields = fields.as_object().ok_or(LocalError::WrongParameters)?;
+        if REQUIRED_FIELDS.iter().any(|f| !fields.contains_key(*f)) {
+            return Err(LocalError::InvalidAddress);
+        }
+
+        let txn = self.db.begin().await.to_local_error(RecordType::User)?;
+
+        let first = self.get_addresses(user_id).await?.is_empty();
+        let mut address = address::ActiveModel {
+            address_id: NotSet,
+            user_id: Set(user_id),
+            label: Set(None),
+            line2: Set(None),
+            region: Set(None),
+            phone: Set(None),
+            default_shipping: Set(first),
+            default_billing: Set(first),
+            created_at: Set(Utc::now().naive_utc()),
+            ..Default::default()
+        };
+        set_fields(&mut address, fields)?;
+        clear_defaults(&txn, user_id, &address).await?;
+
+        let address = address
+            .insert(&txn)
+            .await
+            .to_local_error(RecordType::User)?;
+        txn.commit().await.to_local_error(RecordType::User)?;
+        Ok(address)
+    }
+
+    pub async fn update_address(
+        &self,
+        user_id: i32,
+        address_id: i32,
+        fields: Value,
+    ) -> Result<address::Model, LocalError> {
+        let fields = fields.as_object().ok_or(LocalError::WrongParameters)?;
+
+        let txn = self.db.begin().await.to_local_error(RecordType::User)?;
+
+        let mut address: address::ActiveModel =
+            find_address(&txn, user_id, address_id).await?.into();
+        set_fields(&mut address, fields)?;
+        clear_defaults(&txn, user_id, &address).await?;
+
+        let address = address
+            .update(&txn)
+            .await
+            .to_local_error(RecordType::User)?;
+        txn.commit().await.to_local_error(RecordType::User)?;
+        Ok(address)
+    }
+
+    // A default that is deleted goes to the user's oldest address left.
+    pub async fn delete_address(&self, user_id: i32, address_id: i32) -> Result<(), LocalError> {
+        let txn = self.db.begin().await.to_local_error(RecordType::User)?;
+
+        let address = find_address(&txn, user_id, address_id).await?;
+        address::Entity::delete_by_id(address_id)
+            .exec(&txn)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        let oldest = address::Entity::find()
+            .filter(address::Column::UserId.eq(user_id))
+            .order_by_asc(address::Column::AddressId)
+            .one(&txn)
+            .await
+            .to_local_error(RecordType::User)?;
+        let promote = address.default_shipping || address.default_billing;
+        if let Some(oldest) = oldest.filter(|_| promote) {
+            let mut oldest: address::ActiveModel = oldest.into();
+            if address.default_shipping {
+                oldest.default_shipping = Set(true);
+            }
+            if address.default_billing {
+                oldest.default_billing = Set(true);
+            }
+            oldest.update(&txn).await.to_local_error(RecordType::User)?;
+        }
+
+        txn.commit().await.to_local_error(RecordType::User)
+    }
+}
@@ -0,0 +1,13 @@
+ALTER TABLE account ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+-- accounts created before verification existed keep working
+UPDATE account SET email_verified = TRUE;
+
+CREATE TABLE account_token (
+     token_id serial PRIMARY KEY,
+     user_id INT NOT NULL REFERENCES account ( user_id ) ON DELETE CASCADE,
+     purpose VARCHAR ( 50 ) NOT NULL,
+     token_hash VARCHAR ( 100 ) UNIQUE NOT NULL,
+     created_at TIMESTAMP NOT NULL,
+     expires_at TIMESTAMP NOT NULL,
+     used_at TIMESTAMP
+);
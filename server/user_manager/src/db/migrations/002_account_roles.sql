@@ -0,0 +1,2 @@
+ALTER TABLE account ADD COLUMN role VARCHAR ( 20 ) NOT NULL DEFAULT 'customer';
+ALTER TABLE account ADD COLUMN permissions VARCHAR ( 1000 ) NOT NULL DEFAULT '';
@@ -3,6 +3,7 @@ use crate::db::postgres::PostgresDB;
 use mongodb::{error::Result as MongoResult, Client, Database as MongoDatabase};
 use sea_orm::{Database, DatabaseConnection, DbErr};
 
+pub mod addresses;
 pub mod mongo;
 pub mod postgres;
 
@@ -1,73 +1,338 @@
-use chrono::Utc;
-use mongodb::bson::{doc, Document};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime as BsonDateTime, Document};
+use mongodb::options::UpdateOptions;
 use mongodb::{error::Result as MongoResult, Database as MongoDatabase};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+const USER_STATS: &str = "user_stats";
+const MAX_SAMPLES: i32 = 200;
 
 pub struct MongoDB {
     pub db: MongoDatabase,
 }
 
+#[derive(Clone, Copy)]
+pub enum UserEvent {
+    AccountCreated,
+    LoggedIn,
+    ProductViewed,
+    ProductPurchased,
+}
+
+impl UserEvent {
+    fn counter(&self) -> &'static str {
+        match self {
+            UserEvent::AccountCreated => "created",
+            UserEvent::LoggedIn => "logins",
+            UserEvent::ProductViewed => "views",
+            UserEvent::ProductPurchased => "purchases",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            UserEvent::AccountCreated => "account_created",
+            UserEvent::LoggedIn => "logged_in",
+            UserEvent::ProductViewed => "viewed",
+            UserEvent::ProductPurchased => "purchased",
+        }
+    }
+}
+
+// Stats are stored as one document per user per day:
+// { user_id, bucket, created, logins, views, purchases, samples: [{ event, product_id, at }] }
+// with `samples` capped to the last MAX_SAMPLES events of the day.
+// Buckets written by `migrate_stats` also list in `migrated_from` the old documents whose
+// events they took.
+fn day_bucket(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.duration_trunc(Duration::days(1)).unwrap_or(at)
+}
+
+fn to_bson_date(at: DateTime<Utc>) -> BsonDateTime {
+    BsonDateTime::from_millis(at.timestamp_millis())
+}
+
 impl MongoDB {
     pub async fn add_user(&self, user_id: i32) -> MongoResult<()> {
-        let collection = self.db.collection::<Document>("user_stats");
-
-        let record = doc! {
-            "user_id": user_id,
-            "account_created": Utc::now().to_string(),
-            "last_logged_in": Utc::now().to_string(),
-            "products_viewed": {},
-            "products_purchased": {},
-        };
+        self.record_events(user_id, UserEvent::AccountCreated, &[(None, Utc::now())])
+            .await
+    }
 
-        collection
-            .insert_one(record, None)
+    pub async fn record_logged_in(&self, user_id: i32) -> MongoResult<()> {
+        self.record_events(user_id, UserEvent::LoggedIn, &[(None, Utc::now())])
             .await
-            .and_then(|_| Ok(()))
     }
 
-    pub async fn record_logged_in(&self, user_id: i32) -> Result<(), mongodb::error::Error> {
-        let update = doc! {"$set": {"last_logged_in": Utc::now().to_string()}};
+    pub async fn record_product_viewed(&self, user_id: i32, product_id: i32) -> MongoResult<()> {
+        self.record_events(
+            user_id,
+            UserEvent::ProductViewed,
+            &[(Some(product_id), Utc::now())],
+        )
+        .await
+    }
 
-        self.update_user_record(user_id, update).await
+    pub async fn record_product_purchased(&self, user_id: i32, product_id: i32) -> MongoResult<()> {
+        self.record_events(
+            user_id,
+            UserEvent::ProductPurchased,
+            &[(Some(product_id), Utc::now())],
+        )
+        .await
     }
 
-    pub async fn record_product_viewed(
+    async fn record_events(
         &self,
         user_id: i32,
-        product_id: i32,
-    ) -> Result<(), mongodb::error::Error> {
-        let update =
-            doc! {"$set": {format!("products_viewed.{}", product_id): Utc::now().to_string()}};
+        event: UserEvent,
+        events: &[(Option<i32>, DateTime<Utc>)],
+    ) -> MongoResult<()> {
+        self.add_to_buckets(user_id, event, events, None).await
+    }
+
+    // With `migrated_from`, the `_id` of an old layout document, a bucket takes that
+    // document's events only once, so that a migration stopped halfway can be run again.
+    async fn add_to_buckets(
+        &self,
+        user_id: i32,
+        event: UserEvent,
+        events: &[(Option<i32>, DateTime<Utc>)],
+        migrated_from: Option<&Bson>,
+    ) -> MongoResult<()> {
+        let mut buckets: BTreeMap<DateTime<Utc>, Vec<Document>> = BTreeMap::new();
+        for (product_id, at) in events {
+            let mut sample = doc! { "event": event.name(), "at": to_bson_date(*at) };
+            if let Some(product_id) = product_id {
+                sample.insert("product_id", product_id);
+            }
+            buckets.entry(day_bucket(*at)).or_default().push(sample);
+        }
+
+        let collection = self.db.collection::<Document>(USER_STATS);
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        for (bucket, samples) in buckets {
+            let mut filter = doc! { "user_id": user_id, "bucket": to_bson_date(bucket) };
+            let mut counters =
+                doc! { "created": 0i64, "logins": 0i64, "views": 0i64, "purchases": 0i64 };
+            counters.insert(event.counter(), samples.len() as i64);
+            let mut update = doc! {
+                "$inc": counters,
+                "$push": { "samples": { "$each": samples, "$slice": -MAX_SAMPLES } },
+            };
+
+            match migrated_from {
+                None => {
+                    collection
+                        .update_one(filter, update, options.clone())
+                        .await?;
+                }
+                Some(id) => {
+                    // an upsert can't tell a missing bucket from one that already has the
+                    // events, so the bucket is created first and updated only without them
+                    let empty = doc! { "$setOnInsert": {
+                        "created": 0i64, "logins": 0i64, "views": 0i64, "purchases": 0i64,
+                    } };
+                    collection
+                        .update_one(filter.clone(), empty, options.clone())
+                        .await?;
 
-        self.update_user_record(user_id, update).await
+                    let migration = doc! { "id": id.clone(), "event": event.name() };
+                    filter.insert("migrated_from", doc! { "$ne": migration.clone() });
+                    update.insert("$addToSet", doc! { "migrated_from": migration });
+                    collection.update_one(filter, update, None).await?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
-    pub async fn record_product_purchased(
+    pub async fn delete_user(&self, user_id: i32) -> MongoResult<()> {
+        self.db
+            .collection::<Document>(USER_STATS)
+            .delete_many(doc! { "user_id": user_id }, None)
+            .await
+            .map(|_| ())
+    }
+
+    // Products the user interacted with through `event`, most recent first.
+    pub async fn get_products_history(
         &self,
         user_id: i32,
-        product_id: i32,
-    ) -> Result<(), mongodb::error::Error> {
-        let update =
-            doc! {"$set": {format!("products_purchased.{}", product_id): Utc::now().to_string()}};
+        event: UserEvent,
+        skip: u64,
+        limit: u64,
+    ) -> MongoResult<Vec<Value>> {
+        let pipeline = vec![
+            doc! { "$match": { "user_id": user_id } },
+            doc! { "$unwind": "$samples" },
+            doc! { "$match": { "samples.event": event.name() } },
+            doc! { "$group": {
+                "_id": "$samples.product_id",
+                "last_at": { "$max": "$samples.at" },
+                "count": { "$sum": 1 },
+            } },
+            doc! { "$sort": { "last_at": -1, "_id": 1 } },
+            doc! { "$skip": skip as i64 },
+            doc! { "$limit": limit as i64 },
+        ];
 
-        self.update_user_record(user_id, update).await
+        let docs: Vec<Document> = self
+            .db
+            .collection::<Document>(USER_STATS)
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(docs
+            .iter()
+            .filter_map(|d| {
+                Some(json!({
+                    "product_id": d.get_i32("_id").ok()?,
+                    "last_at": d.get_datetime("last_at").ok()?.try_to_rfc3339_string().ok(),
+                    "count": d.get_i32("count").unwrap_or_default(),
+                }))
+            })
+            .collect())
     }
 
-    async fn update_user_record(
+    pub async fn get_login_history(
         &self,
         user_id: i32,
-        updates: Document,
-    ) -> Result<(), mongodb::error::Error> {
-        let filter = doc! { "user_id": user_id };
-        let collection = self.db.collection::<Document>("user_stats");
+        skip: u64,
+        limit: u64,
+    ) -> MongoResult<Vec<Value>> {
+        let pipeline = vec![
+            doc! { "$match": { "user_id": user_id } },
+            doc! { "$unwind": "$samples" },
+            doc! { "$match": { "samples.event": UserEvent::LoggedIn.name() } },
+            doc! { "$sort": { "samples.at": -1 } },
+            doc! { "$skip": skip as i64 },
+            doc! { "$limit": limit as i64 },
+            doc! { "$project": { "_id": 0, "at": "$samples.at" } },
+        ];
+
+        let docs: Vec<Document> = self
+            .db
+            .collection::<Document>(USER_STATS)
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(docs
+            .iter()
+            .filter_map(|d| d.get_datetime("at").ok()?.try_to_rfc3339_string().ok())
+            .map(|at| json!({ "at": at }))
+            .collect())
+    }
+
+    // Distinct products viewed and purchased by every user since `since`.
+    pub async fn get_interactions(&self, since: DateTime<Utc>) -> MongoResult<Vec<Value>> {
+        let pipeline = vec![
+            doc! { "$match": { "bucket": { "$gte": to_bson_date(day_bucket(since)) } } },
+            doc! { "$unwind": "$samples" },
+            doc! { "$match": { "samples.product_id": { "$exists": true } } },
+            doc! { "$group": {
+                "_id": "$user_id",
+                "viewed": { "$addToSet": { "$cond": [
+                    { "$eq": ["$samples.event", UserEvent::ProductViewed.name()] },
+                    "$samples.product_id",
+                    "$$REMOVE",
+                ] } },
+                "purchased": { "$addToSet": { "$cond": [
+                    { "$eq": ["$samples.event", UserEvent::ProductPurchased.name()] },
+                    "$samples.product_id",
+                    "$$REMOVE",
+                ] } },
+            } },
+        ];
 
-        let res = collection
-            .update_one(filter.clone(), updates.clone(), None)
+        let docs: Vec<Document> = self
+            .db
+            .collection::<Document>(USER_STATS)
+            .aggregate(pipeline, None)
+            .await?
+            .try_collect()
             .await?;
-        if res.matched_count == 0 {
-            let _ = self.add_user(user_id);
-            let _ = collection.update_one(filter, updates, None).await?;
+
+        let ids = |d: &Document, field: &str| -> Vec<i32> {
+            d.get_array(field)
+                .map(|ids| ids.iter().filter_map(|id| id.as_i32()).collect())
+                .unwrap_or_default()
+        };
+
+        Ok(docs
+            .iter()
+            .filter_map(|d| {
+                Some(json!({
+                    "user_id": d.get_i32("_id").ok()?,
+                    "viewed": ids(d, "viewed"),
+                    "purchased": ids(d, "purchased"),
+                }))
+            })
+            .collect())
+    }
+
+    // Converts documents of the old `{ user_id, account_created, last_logged_in,
+    // products_viewed: { id: time }, products_purchased: { id: time } }` layout
+    // into daily buckets and removes them. Safe to run again after a failure.
+    pub async fn migrate_stats(&self) -> MongoResult<u64> {
+        let collection = self.db.collection::<Document>(USER_STATS);
+        let filter = doc! { "bucket": { "$exists": false } };
+        let old_docs: Vec<Document> = collection.find(filter, None).await?.try_collect().await?;
+
+        let parse = |s: &str| s.parse::<DateTime<Utc>>().ok();
+        let mut migrated = 0;
+
+        for old in old_docs {
+            let user_id = match old.get_i32("user_id") {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            for (field, event) in [
+                ("account_created", UserEvent::AccountCreated),
+                ("last_logged_in", UserEvent::LoggedIn),
+            ] {
+                if let Some(at) = old.get_str(field).ok().and_then(parse) {
+                    self.add_to_buckets(user_id, event, &[(None, at)], old.get("_id"))
+                        .await?;
+                }
+            }
+
+            for (field, event) in [
+                ("products_viewed", UserEvent::ProductViewed),
+                ("products_purchased", UserEvent::ProductPurchased),
+            ] {
+                let events: Vec<(Option<i32>, DateTime<Utc>)> = old
+                    .get_document(field)
+                    .map(|products| {
+                        products
+                            .iter()
+                            .filter_map(|(id, at)| {
+                                Some((Some(id.parse().ok()?), parse(at.as_str()?)?))
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                if !events.is_empty() {
+                    self.add_to_buckets(user_id, event, &events, old.get("_id"))
+                        .await?;
+                }
+            }
+
+            if let Some(id) = old.get("_id") {
+                collection.delete_one(doc! { "_id": id }, None).await?;
+            }
+            migrated += 1;
         }
 
-        Ok(())
+        Ok(migrated)
     }
 }
@@ -1,28 +1,63 @@
-use sea_orm::{ActiveModelTrait, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
-use serde_json::Value;
+use chrono::{Duration, Utc};
+use sea_orm::sea_query::Expr;
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, NotSet, PaginatorTrait,
+    QueryFilter, Set,
+};
+use serde_json::{json, Map, Value};
 
-use crate::entities::account;
+use crate::entities::{account, account_token};
+use crate::validation::{validate_email, validate_phone};
+use common::auth::{hash_token, random_token, Role, ALL_PERMISSIONS};
 use common::utils::LocalError;
 
-use common::db_utils::{RecordType, ToError};
+use common::db_utils::{unique_violation, RecordType, ToError};
+
+pub const VERIFY_EMAIL: &str = "verify_email";
+pub const RESET_PASSWORD: &str = "reset_password";
 
 pub struct PostgresDB {
     pub db: DatabaseConnection,
 }
 
+// Surfaces violations of the `#[sea_orm(unique)]` columns of `account`.
+fn to_account_error<T>(res: Result<T, DbErr>) -> Result<T, LocalError> {
+    if let Err(ref e) = res {
+        match unique_violation(e) {
+            Some("account_username_key") => return Err(LocalError::UsernameTaken),
+            Some("account_email_key") => return Err(LocalError::EmailTaken),
+            Some("account_phone_key") => return Err(LocalError::PhoneTaken),
+            _ => {}
+        }
+    }
+
+    res.to_local_error(RecordType::User)
+}
+
 impl PostgresDB {
-    pub async fn add_user(&self, user_json: Value) -> Result<i32, LocalError> {
-        let new_account =
+    pub async fn add_user(&self, mut user_json: Value) -> Result<i32, LocalError> {
+        // `from_json` deserializes a whole `Model`, so it needs a placeholder primary key
+        if let Some(json_map) = user_json.as_object_mut() {
+            json_map.insert("user_id".to_string(), json!(0));
+        }
+        let mut new_account =
             account::ActiveModel::from_json(user_json).to_local_error(RecordType::User)?;
 
-        let res = account::Entity::insert(new_account)
-            .exec(&self.db)
-            .await
-            .to_local_error(RecordType::User)?;
+        // these are never taken from the request
+        new_account.user_id = NotSet;
+        new_account.email_verified = Set(false);
+        new_account.role = Set(Role::Customer.as_str().to_string());
+        new_account.permissions = Set(String::new());
+
+        let res = to_account_error(account::Entity::insert(new_account).exec(&self.db).await)?;
         Ok(res.last_insert_id)
     }
 
-    pub async fn login(&self, user_json: Value) -> Result<i32, LocalError> {
+    pub async fn login(
+        &self,
+        user_json: Value,
+        require_verified_email: bool,
+    ) -> Result<account::Model, LocalError> {
         let username = user_json
             .get("username")
             .unwrap()
@@ -41,11 +76,263 @@ impl PostgresDB {
             .to_local_error(RecordType::User)?;
 
         if let Some(user) = user {
-            if &user.password == password {
-                return Ok(user.user_id);
+            if user.password == password {
+                if require_verified_email && !user.email_verified {
+                    return Err(LocalError::EmailNotVerified);
+                }
+                return Ok(user);
             }
         }
 
         Err(LocalError::WrongUserOrPassword)
     }
+
+    async fn find_user(&self, user_id: i32) -> Result<account::Model, LocalError> {
+        let user: Option<account::Model> = account::Entity::find_by_id(user_id)
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        user.ok_or(LocalError::IdNotFound)
+    }
+
+    pub async fn get_user(&self, user_id: i32) -> Result<Value, LocalError> {
+        Ok(json!(self.find_user(user_id).await?))
+    }
+
+    // A new email has to be verified again.
+    pub async fn update_user(&self, user_id: i32, updates: Value) -> Result<Value, LocalError> {
+        let current = self.find_user(user_id).await?;
+        let current_email = current.email.clone();
+        let mut user: account::ActiveModel = current.into();
+
+        let updates: Map<String, Value> = updates.as_object().unwrap().clone();
+        for (key, val) in updates.iter() {
+            let val = val.as_str();
+            match key.as_str() {
+                "full_name" => {
+                    let full_name = val.map(|v| v.trim()).unwrap_or_default();
+                    if full_name.is_empty() {
+                        return Err(LocalError::WrongParameters);
+                    }
+                    user.full_name = Set(full_name.to_string());
+                }
+                "email" => {
+                    let email = val.ok_or(LocalError::WrongParameters)?.trim();
+                    validate_email(email)?;
+                    if email != current_email {
+                        user.email = Set(email.to_string());
+                        user.email_verified = Set(false);
+                    }
+                }
+                "phone" => {
+                    let phone = val.ok_or(LocalError::WrongParameters)?.trim();
+                    validate_phone(phone)?;
+                    user.phone = Set(phone.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        let user: account::Model = to_account_error(user.update(&self.db).await)?;
+
+        Ok(json!(user))
+    }
+
+    pub async fn change_password(
+        &self,
+        user_id: i32,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<(), LocalError> {
+        let user = self.find_user(user_id).await?;
+        if user.password != current_password {
+            return Err(LocalError::WrongUserOrPassword);
+        }
+
+        self.set_password(user_id, new_password).await
+    }
+
+    pub async fn set_password(&self, user_id: i32, password: &str) -> Result<(), LocalError> {
+        let mut user: account::ActiveModel = self.find_user(user_id).await?.into();
+        user.password = Set(password.to_string());
+        user.update(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        Ok(())
+    }
+
+    pub async fn find_user_by_email(&self, email: &str) -> Result<account::Model, LocalError> {
+        let user: Option<account::Model> = account::Entity::find()
+            .filter(account::Column::Email.eq(email))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        user.ok_or(LocalError::IdNotFound)
+    }
+
+    pub async fn set_email_verified(&self, user_id: i32) -> Result<(), LocalError> {
+        let mut user: account::ActiveModel = self.find_user(user_id).await?.into();
+        user.email_verified = Set(true);
+        user.update(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        Ok(())
+    }
+
+    // Issues a new single-use token for `purpose`, invalidating the user's previous ones.
+    // Only the hash is stored; the returned token is what gets mailed to the user.
+    pub async fn create_token(
+        &self,
+        user_id: i32,
+        purpose: &str,
+        ttl: Duration,
+    ) -> Result<String, LocalError> {
+        let now = Utc::now().naive_utc();
+
+        account_token::Entity::update_many()
+            .col_expr(account_token::Column::UsedAt, Expr::value(now))
+            .filter(account_token::Column::UserId.eq(user_id))
+            .filter(account_token::Column::Purpose.eq(purpose))
+            .filter(account_token::Column::UsedAt.is_null())
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        let token = random_token();
+        let record = account_token::ActiveModel {
+            user_id: Set(user_id),
+            purpose: Set(purpose.to_string()),
+            token_hash: Set(hash_token(&token)),
+            created_at: Set(now),
+            expires_at: Set(now + ttl),
+            ..Default::default()
+        };
+
+        account_token::Entity::insert(record)
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        Ok(token)
+    }
+
+    // Marks the token as used and returns its user, if it is valid for `purpose`.
+    pub async fn consume_token(&self, token: &str, purpose: &str) -> Result<i32, LocalError> {
+        let now = Utc::now().naive_utc();
+
+        let record: Option<account_token::Model> = account_token::Entity::find()
+            .filter(account_token::Column::TokenHash.eq(hash_token(token)))
+            .filter(account_token::Column::Purpose.eq(purpose))
+            .filter(account_token::Column::UsedAt.is_null())
+            .filter(account_token::Column::ExpiresAt.gt(now))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        let record = record.ok_or(LocalError::InvalidToken)?;
+
+        // the `used_at IS NULL` filter makes concurrent uses of the same token fail
+        let res = account_token::Entity::update_many()
+            .col_expr(account_token::Column::UsedAt, Expr::value(now))
+            .filter(account_token::Column::TokenId.eq(record.token_id))
+            .filter(account_token::Column::UsedAt.is_null())
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        if res.rows_affected == 0 {
+            return Err(LocalError::InvalidToken);
+        }
+
+        Ok(record.user_id)
+    }
+
+    pub async fn delete_user(&self, user_id: i32) -> Result<(), LocalError> {
+        let res = account::Entity::delete_by_id(user_id)
+            .exec(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        if res.rows_affected == 0 {
+            return Err(LocalError::IdNotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_role(
+        &self,
+        user_id: i32,
+        role: Role,
+        permissions: Option<Vec<String>>,
+    ) -> Result<Value, LocalError> {
+        let mut user: account::ActiveModel = self.find_user(user_id).await?.into();
+        user.role = Set(role.as_str().to_string());
+
+        if let Some(permissions) = permissions {
+            if permissions
+                .iter()
+                .any(|p| !ALL_PERMISSIONS.contains(&p.as_str()))
+            {
+                return Err(LocalError::WrongParameters);
+            }
+            user.permissions = Set(permissions.join(","));
+        }
+
+        let user: account::Model = user
+            .update(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        Ok(json!(user))
+    }
+
+    // Creates the first admin account, or promotes an existing account with the same username
+    // and password. Refuses to run once any admin exists.
+    pub async fn bootstrap_admin(&self, admin_json: Value) -> Result<i32, LocalError> {
+        let admins = account::Entity::find()
+            .filter(account::Column::Role.eq(Role::Admin.as_str()))
+            .count(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+        if admins > 0 {
+            return Err(LocalError::OperationFailed);
+        }
+
+        let username = admin_json
+            .get("username")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let existing: Option<account::Model> = account::Entity::find()
+            .filter(account::Column::Username.eq(username))
+            .one(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        let password = admin_json.get("password").and_then(|v| v.as_str());
+        let (user_id, created) = match existing {
+            // whoever runs it has to own the account, not just know its username
+            Some(user) if Some(user.password.as_str()) != password => {
+                return Err(LocalError::WrongUserOrPassword);
+            }
+            Some(user) => (user.user_id, false),
+            None => (self.add_user(admin_json).await?, true),
+        };
+
+        let mut user: account::ActiveModel = self.find_user(user_id).await?.into();
+        user.role = Set(Role::Admin.as_str().to_string());
+        // the email of an existing account stays as verified as it was
+        if created {
+            user.email_verified = Set(true);
+        }
+        user.update(&self.db)
+            .await
+            .to_local_error(RecordType::User)?;
+
+        Ok(user_id)
+    }
 }
@@ -6,5 +6,39 @@ CREATE TABLE account (
      full_name VARCHAR ( 500 ) NOT NULL,
      password VARCHAR ( 500 ) NOT NULL,
      email VARCHAR ( 500 ) UNIQUE NOT NULL,
-     phone VARCHAR ( 100 ) UNIQUE NOT NULL
+     phone VARCHAR ( 100 ) UNIQUE NOT NULL,
+     email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+     role VARCHAR ( 20 ) NOT NULL DEFAULT 'customer',
+     permissions VARCHAR ( 1000 ) NOT NULL DEFAULT ''
 );
+
+CREATE TABLE account_token (
+     token_id serial PRIMARY KEY,
+     user_id INT NOT NULL REFERENCES account ( user_id ) ON DELETE CASCADE,
+     purpose VARCHAR ( 50 ) NOT NULL,
+     token_hash VARCHAR ( 100 ) UNIQUE NOT NULL,
+     created_at TIMESTAMP NOT NULL,
+     expires_at TIMESTAMP NOT NULL,
+     used_at TIMESTAMP
+);
+
+CREATE TABLE address (
+     address_id serial PRIMARY KEY,
+     user_id INT NOT NULL REFERENCES account ( user_id ) ON DELETE CASCADE,
+     label VARCHAR ( 100 ),
+     full_name VARCHAR ( 500 ) NOT NULL,
+     line1 VARCHAR ( 500 ) NOT NULL,
+     line2 VARCHAR ( 500 ),
+     city VARCHAR ( 200 ) NOT NULL,
+     postal_code VARCHAR ( 20 ) NOT NULL,
+     region VARCHAR ( 200 ),
+     country CHAR ( 2 ) NOT NULL,
+     phone VARCHAR ( 100 ),
+     default_shipping BOOLEAN NOT NULL DEFAULT FALSE,
+     default_billing BOOLEAN NOT NULL DEFAULT FALSE,
+     created_at TIMESTAMP NOT NULL
+);
+CREATE INDEX address_user_id ON address ( user_id );
+-- at most one default of each kind per user
+CREATE UNIQUE INDEX address_default_shipping ON address ( user_id ) WHERE default_shipping;
+CREATE UNIQUE INDEX address_default_billing ON address ( user_id ) WHERE default_billing;
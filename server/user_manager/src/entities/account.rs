@@ -1,5 +1,6 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.0
 
+use common::auth::{AuthContext, Role};
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -11,14 +12,42 @@ pub struct Model {
     #[sea_orm(unique)]
     pub username: String,
     pub full_name: String,
+    #[serde(skip_serializing)]
     pub password: String,
     #[sea_orm(unique)]
     pub email: String,
     #[sea_orm(unique)]
     pub phone: String,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default = "default_role")]
+    pub role: String,
+    // comma separated permissions granted on top of the role's ones
+    #[serde(default)]
+    pub permissions: String,
+}
+
+fn default_role() -> String {
+    Role::Customer.as_str().to_string()
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
+
+impl Model {
+    pub fn extra_permissions(&self) -> Vec<String> {
+        self.permissions
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    pub fn auth_context(&self) -> AuthContext {
+        let role = Role::parse(&self.role).unwrap_or(Role::Customer);
+        AuthContext::new(self.user_id, role, &self.extra_permissions())
+    }
+}
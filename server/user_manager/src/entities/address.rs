@@ -0,0 +1,47 @@
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// An entry of the user's address book.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[sea_orm(table_name = "address")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub address_id: i32,
+    pub user_id: i32,
+    // e.g. "Home" or "Office"
+    pub label: Option<String>,
+    // of the recipient
+    pub full_name: String,
+    pub line1: String,
+    pub line2: Option<String>,
+    pub city: String,
+    pub postal_code: String,
+    // state or province, where it is part of the address
+    pub region: Option<String>,
+    // ISO 3166-1 alpha-2
+    pub country: String,
+    pub phone: Option<String>,
+    pub default_shipping: bool,
+    pub default_billing: bool,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::account::Entity",
+        from = "Column::UserId",
+        to = "super::account::Column::UserId",
+        on_update = "NoAction",
+        on_delete = "Cascade"
+    )]
+    Account,
+}
+
+impl Related<super::account::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Account.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
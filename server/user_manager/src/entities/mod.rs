@@ -3,3 +3,5 @@
 pub mod prelude;
 
 pub mod account;
+pub mod account_token;
+pub mod address;
@@ -1,3 +1,5 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.11.0
 
 pub use super::account::Entity as Account;
+pub use super::account_token::Entity as AccountToken;
+pub use super::address::Entity as Address;
@@ -1,11 +1,80 @@
 use crate::context::Context;
-use common::request_response_utils::{create_response, get_id_from_uri};
+use crate::db::mongo::UserEvent;
+use crate::db::postgres::{RESET_PASSWORD, VERIFY_EMAIL};
+use crate::login_guard::{ip_key, user_key};
+use crate::validation::{validate_email, validate_phone};
+use chrono::{Duration, Utc};
+use common::auth::{
+    authenticate, authorize, issue_token, token_ttl_secs, Role, ACCOUNTS_MANAGE, ORDERS_READ,
+    ROLES_MANAGE,
+};
+use common::mailer::Mail;
+use common::request_response_utils::{
+    create_response, get_id_from_uri, get_params, response_too_many_requests,
+};
 use common::utils::LocalError;
 use http::request::Parts;
 use http::StatusCode;
-use hyper::{Body, Response};
-use serde_json::Value;
+use hyper::{Body, Client, Response};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
+use url::Url;
+
+const DEFAULT_PAGE_SIZE: u64 = 20;
+const MAX_PAGE_SIZE: u64 = 100;
+const DEFAULT_INTERACTION_DAYS: i64 = 90;
+const MAX_INTERACTION_DAYS: i64 = 3650;
+
+// Mailers block, so mails are sent from the blocking pool without holding up the response.
+fn send_mail(context: Arc<Context>, mail: Mail) {
+    tokio::task::spawn_blocking(move || {
+        if context.mailer.send(&mail).is_err() {
+            println!("Error when sending mail to {}", mail.to);
+        }
+    });
+}
+
+async fn send_verification_mail(context: Arc<Context>, user_id: i32, email: String) {
+    let token = context
+        .db
+        .postgres_db
+        .create_token(
+            user_id,
+            VERIFY_EMAIL,
+            context.account.verification_token_ttl,
+        )
+        .await;
+
+    match token {
+        Err(e) => println!("Error when creating verification token: {}", e.to_string()),
+        Ok(token) => {
+            let mail = Mail {
+                to: email,
+                subject: "Verify your email".to_string(),
+                body: format!(
+                    "Open the link below to verify your email:\n{}/account/verify?token={}",
+                    context.account.base_url, token
+                ),
+            };
+            send_mail(context, mail);
+        }
+    }
+}
+
+fn error_status(error: &LocalError) -> StatusCode {
+    match error {
+        LocalError::UnauthenticatedUser => StatusCode::UNAUTHORIZED,
+        LocalError::EmailNotVerified | LocalError::Forbidden => StatusCode::FORBIDDEN,
+        LocalError::IdNotFound => StatusCode::NOT_FOUND,
+        LocalError::UsernameTaken | LocalError::EmailTaken | LocalError::PhoneTaken => {
+            StatusCode::CONFLICT
+        }
+        LocalError::OperationFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
 
 // /account/add
 pub async fn add_account(
@@ -42,14 +111,29 @@ pub async fn add_account(
         );
     }
 
+    let email = json_map
+        .get("email")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let phone = json_map
+        .get("phone")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    if let Err(e) = validate_email(&email).and_then(|_| validate_phone(phone)) {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
     match context.db.postgres_db.add_user(body.unwrap()).await {
-        Err(e) => create_response(StatusCode::BAD_REQUEST, e.to_string()),
+        Err(e) => create_response(error_status(&e), e.to_string()),
         Ok(id) => {
             let res = context.db.mongo_db.add_user(id).await;
             if res.is_err() {
                 let _ = context.db.mongo_db.add_user(id).await;
             }
 
+            send_verification_mail(context.clone(), id, email).await;
+
             create_response(StatusCode::OK, id.to_string())
         }
     }
@@ -58,6 +142,7 @@ pub async fn add_account(
 // /account/login
 pub async fn login(
     body: Option<Value>,
+    client_ip: IpAddr,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
     if body.is_none() {
@@ -85,15 +170,60 @@ pub async fn login(
         );
     }
 
-    match context.db.postgres_db.login(body.unwrap()).await {
+    let username = json_map
+        .get("username")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    let login_guard = &context.login_guard;
+    if let Some(locked_for) = login_guard.locked_for(&username, &client_ip) {
+        return response_too_many_requests(LocalError::AccountLocked, locked_for.as_secs() + 1);
+    }
+
+    match context
+        .db
+        .postgres_db
+        .login(body.unwrap(), context.account.require_verified_email)
+        .await
+    {
+        Err(LocalError::EmailNotVerified) => create_response(
+            StatusCode::FORBIDDEN,
+            LocalError::EmailNotVerified.to_string(),
+        ),
+        Err(LocalError::WrongUserOrPassword) => {
+            let delay = login_guard.record_failure(&username, &client_ip);
+            tokio::time::sleep(delay).await;
+
+            create_response(
+                StatusCode::BAD_REQUEST,
+                LocalError::WrongUserOrPassword.to_string(),
+            )
+        }
         Err(e) => create_response(StatusCode::BAD_REQUEST, e.to_string()),
-        Ok(id) => {
+        Ok(user) => {
+            login_guard.record_success(&username);
+
+            let id = user.user_id;
             let res = context.db.mongo_db.record_logged_in(id).await;
             if res.is_err() {
                 let _ = context.db.mongo_db.record_logged_in(id).await;
             }
 
-            create_response(StatusCode::OK, id.to_string())
+            let auth = user.auth_context();
+            let token = issue_token(&auth, &context.auth_secret);
+
+            create_response(
+                StatusCode::OK,
+                json!({
+                    "user_id": id,
+                    "role": auth.role.as_str(),
+                    "permissions": auth.permissions,
+                    "token": token,
+                    "expires_in": token_ttl_secs(&auth),
+                })
+                .to_string(),
+            )
         }
     }
 }
@@ -181,3 +311,641 @@ pub async fn add_product_purchase(
         create_response(StatusCode::OK, String::new())
     }
 }
+
+async fn get_products(context: &Context, product_ids: Vec<i32>) -> HashMap<i32, Value> {
+    let mut products = HashMap::new();
+    if product_ids.is_empty() {
+        return products;
+    }
+
+    let url = Url::parse(&format!(
+        "{}{}",
+        context.product_manager.uri, context.product_manager.get_products_endpoint
+    ));
+    if let Ok(mut url) = url {
+        let ids: Vec<String> = product_ids.iter().map(|id| id.to_string()).collect();
+        url.query_pairs_mut().append_pair("ids", &ids.join(","));
+
+        let request = hyper::Request::get(url.as_str()).body(Body::empty());
+        if let Ok(request) = request {
+            let response = Client::new().request(request).await;
+            if let Ok(response) = response {
+                let body = hyper::body::to_bytes(response.into_body()).await;
+                let items: Option<Vec<Value>> =
+                    body.ok().and_then(|b| serde_json::from_slice(&b).ok());
+
+                for item in items.unwrap_or_default() {
+                    if let Some(id) = item.get("product_id").and_then(|id| id.as_i64()) {
+                        products.insert(id as i32, item);
+                    }
+                }
+            }
+        }
+    }
+
+    products
+}
+
+// /account/history
+pub async fn history(parts: &Parts, context: Arc<Context>) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    let params = get_params(&parts.uri);
+    let page: Option<u64> = match params.get("page") {
+        Some(p) => p.parse().ok().filter(|p| *p > 0),
+        None => Some(1),
+    };
+    let page_size: Option<u64> = match params.get("page_size") {
+        Some(p) => p
+            .parse()
+            .ok()
+            .filter(|p| *p > 0)
+            .map(|p: u64| p.min(MAX_PAGE_SIZE)),
+        None => Some(DEFAULT_PAGE_SIZE),
+    };
+    let skip = page
+        .zip(page_size)
+        .and_then(|(page, page_size)| page.checked_sub(1).and_then(|p| p.checked_mul(page_size)));
+    if skip.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+    let (page, page_size, skip) = (page.unwrap(), page_size.unwrap(), skip.unwrap());
+
+    let mongo_db = &context.db.mongo_db;
+    let viewed = mongo_db
+        .get_products_history(user_id, UserEvent::ProductViewed, skip, page_size)
+        .await;
+    let purchased = mongo_db
+        .get_products_history(user_id, UserEvent::ProductPurchased, skip, page_size)
+        .await;
+    let logins = mongo_db.get_login_history(user_id, skip, page_size).await;
+
+    if viewed.is_err() || purchased.is_err() || logins.is_err() {
+        return create_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            LocalError::OperationFailed.to_string(),
+        );
+    }
+
+    let mut viewed = viewed.unwrap();
+    let mut purchased = purchased.unwrap();
+
+    let product_ids: Vec<i32> = viewed
+        .iter()
+        .chain(purchased.iter())
+        .filter_map(|entry| entry.get("product_id").and_then(|id| id.as_i64()))
+        .map(|id| id as i32)
+        .collect();
+    let products = get_products(&context, product_ids).await;
+
+    for entry in viewed.iter_mut().chain(purchased.iter_mut()) {
+        let product = entry
+            .get("product_id")
+            .and_then(|id| id.as_i64())
+            .and_then(|id| products.get(&(id as i32)))
+            .cloned()
+            .unwrap_or(Value::Null);
+        entry["product"] = product;
+    }
+
+    create_response(
+        StatusCode::OK,
+        json!({
+            "page": page,
+            "page_size": page_size,
+            "viewed": viewed,
+            "purchased": purchased,
+            "logins": logins.unwrap(),
+        })
+        .to_string(),
+    )
+}
+
+// /account/interactions
+// Everyone's history, for the recommendations of product_manager.
+pub async fn interactions(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, ACCOUNTS_MANAGE) {
+        return create_response(error_status(&e), e.to_string());
+    }
+
+    let params = get_params(&parts.uri);
+    let days: i64 = params
+        .get("days")
+        .and_then(|d| d.parse().ok())
+        .filter(|d| *d > 0)
+        .unwrap_or(DEFAULT_INTERACTION_DAYS)
+        .min(MAX_INTERACTION_DAYS);
+
+    let since = Utc::now() - Duration::days(days);
+    match context.db.mongo_db.get_interactions(since).await {
+        Err(e) => {
+            println!("{}", e);
+            create_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                LocalError::OperationFailed.to_string(),
+            )
+        }
+        Ok(interactions) => create_response(StatusCode::OK, json!(interactions).to_string()),
+    }
+}
+
+// /account/profile
+// The caller's profile, or with `user_id` the profile of the buyer of an order, for staff
+// and services reading orders.
+pub async fn get_profile(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let auth = auth.ok().unwrap();
+
+    let user_id = match get_id_from_uri(&parts.uri, "user_id") {
+        Ok(user_id) if user_id != auth.user_id => {
+            if !auth.has_permission(ORDERS_READ) {
+                let e = LocalError::Forbidden;
+                return create_response(error_status(&e), e.to_string());
+            }
+            user_id
+        }
+        _ => auth.user_id,
+    };
+
+    match context.db.postgres_db.get_user(user_id).await {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(user) => create_response(StatusCode::OK, user.to_string()),
+    }
+}
+
+// /account/profile
+pub async fn update_profile(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    if body.as_ref().and_then(|json| json.as_object()).is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let body = body.unwrap();
+    let email_given = body.get("email").is_some();
+    match context.db.postgres_db.update_user(user_id, body).await {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(user) => {
+            // a changed email comes back unverified
+            if email_given && user.get("email_verified") == Some(&Value::Bool(false)) {
+                if let Some(email) = user.get("email").and_then(|e| e.as_str()) {
+                    send_verification_mail(context.clone(), user_id, email.to_string()).await;
+                }
+            }
+            create_response(StatusCode::OK, user.to_string())
+        }
+    }
+}
+
+// /account/password
+pub async fn change_password(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    let current_password = body
+        .as_ref()
+        .and_then(|json| json.get("current_password"))
+        .and_then(|v| v.as_str());
+    let new_password = body
+        .as_ref()
+        .and_then(|json| json.get("new_password"))
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty());
+
+    if current_password.is_none() || new_password.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context
+        .db
+        .postgres_db
+        .change_password(user_id, current_password.unwrap(), new_password.unwrap())
+        .await
+    {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(_) => create_response(StatusCode::OK, String::new()),
+    }
+}
+
+// /account
+pub async fn delete_account(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    // the history (and the interactions made from it) goes first: if it can't be deleted the
+    // account stays, so that deleting it again can be retried
+    let mut res = context.db.mongo_db.delete_user(user_id).await;
+    if res.is_err() {
+        res = context.db.mongo_db.delete_user(user_id).await;
+    }
+    if let Err(e) = res {
+        println!("Error when deleting the history of user {}: {}", user_id, e);
+        return create_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            LocalError::OperationFailed.to_string(),
+        );
+    }
+
+    match context.db.postgres_db.delete_user(user_id).await {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(_) => create_response(StatusCode::OK, String::new()),
+    }
+}
+
+// /account/verify
+pub async fn verify_email(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let params = get_params(&parts.uri);
+    let token = params.get("token");
+    if token.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let postgres_db = &context.db.postgres_db;
+    let res = match postgres_db
+        .consume_token(token.unwrap(), VERIFY_EMAIL)
+        .await
+    {
+        Ok(user_id) => postgres_db.set_email_verified(user_id).await,
+        Err(e) => Err(e),
+    };
+
+    match res {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(_) => create_response(StatusCode::OK, String::new()),
+    }
+}
+
+// /account/verify/resend
+pub async fn resend_verification(
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let email = body
+        .as_ref()
+        .and_then(|json| json.get("email"))
+        .and_then(|v| v.as_str());
+    if email.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    // the response doesn't tell whether an account with this email exists
+    let user = context
+        .db
+        .postgres_db
+        .find_user_by_email(email.unwrap().trim())
+        .await;
+    if let Ok(user) = user {
+        if !user.email_verified {
+            send_verification_mail(context.clone(), user.user_id, user.email).await;
+        }
+    }
+
+    create_response(StatusCode::OK, String::new())
+}
+
+// /account/forgot_password
+pub async fn forgot_password(
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let email = body
+        .as_ref()
+        .and_then(|json| json.get("email"))
+        .and_then(|v| v.as_str());
+    if email.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let postgres_db = &context.db.postgres_db;
+
+    // the response doesn't tell whether an account with this email exists
+    if let Ok(user) = postgres_db.find_user_by_email(email.unwrap().trim()).await {
+        let token = postgres_db
+            .create_token(
+                user.user_id,
+                RESET_PASSWORD,
+                context.account.reset_token_ttl,
+            )
+            .await;
+
+        match token {
+            Err(e) => println!("Error when creating reset token: {}", e.to_string()),
+            Ok(token) => {
+                let mail = Mail {
+                    to: user.email,
+                    subject: "Reset your password".to_string(),
+                    body: format!(
+                        "Use the token below to reset your password. \
+                         If you didn't ask for it, ignore this mail.\n{}",
+                        token
+                    ),
+                };
+                send_mail(context.clone(), mail);
+            }
+        }
+    }
+
+    create_response(StatusCode::OK, String::new())
+}
+
+// /account/reset_password
+pub async fn reset_password(
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let token = body
+        .as_ref()
+        .and_then(|json| json.get("token"))
+        .and_then(|v| v.as_str());
+    let new_password = body
+        .as_ref()
+        .and_then(|json| json.get("new_password"))
+        .and_then(|v| v.as_str())
+        .filter(|v| !v.is_empty());
+
+    if token.is_none() || new_password.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let postgres_db = &context.db.postgres_db;
+    let res = match postgres_db
+        .consume_token(token.unwrap(), RESET_PASSWORD)
+        .await
+    {
+        Ok(user_id) => {
+            postgres_db
+                .set_password(user_id, new_password.unwrap())
+                .await
+        }
+        Err(e) => Err(e),
+    };
+
+    match res {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(_) => create_response(StatusCode::OK, String::new()),
+    }
+}
+
+// /account/role
+pub async fn set_role(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    if let Err(e) = authorize(parts, &context.auth_secret, ROLES_MANAGE) {
+        return create_response(error_status(&e), e.to_string());
+    }
+
+    let user_id = get_id_from_uri(&parts.uri, "user_id");
+    if let Err(e) = user_id {
+        return create_response(StatusCode::BAD_REQUEST, e.to_string());
+    }
+
+    let role = body
+        .as_ref()
+        .and_then(|json| json.get("role"))
+        .and_then(|v| v.as_str())
+        .and_then(Role::parse);
+    if role.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let permissions: Option<Vec<String>> = body
+        .as_ref()
+        .and_then(|json| json.get("permissions"))
+        .and_then(|v| v.as_array())
+        .map(|permissions| {
+            permissions
+                .iter()
+                .filter_map(|p| p.as_str())
+                .map(|p| p.to_string())
+                .collect()
+        });
+
+    match context
+        .db
+        .postgres_db
+        .set_role(user_id.ok().unwrap(), role.unwrap(), permissions)
+        .await
+    {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(user) => create_response(StatusCode::OK, user.to_string()),
+    }
+}
+
+// /account/unlock
+pub async fn unlock(parts: &Parts, context: Arc<Context>) -> Result<Response<Body>, hyper::Error> {
+    let auth = authorize(parts, &context.auth_secret, ACCOUNTS_MANAGE);
+    if let Err(e) = auth {
+        return create_response(error_status(&e), e.to_string());
+    }
+    let admin_id = auth.ok().unwrap().user_id;
+
+    let params = get_params(&parts.uri);
+    let key = if let Some(username) = params.get("username") {
+        Some(user_key(username))
+    } else {
+        params
+            .get("ip")
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .map(|ip| ip_key(&ip))
+    };
+
+    if key.is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    let key = key.unwrap();
+    match context.login_guard.unlock(&key) {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(_) => {
+            println!("SECURITY: {} unlocked by user {}", key, admin_id);
+            create_response(StatusCode::OK, String::new())
+        }
+    }
+}
+
+// /account/addresses
+// The caller's address book, or with `user_id` the one of the buyer of an order, for staff
+// and services reading orders.
+pub async fn get_addresses(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let auth = auth.ok().unwrap();
+
+    let user_id = match get_id_from_uri(&parts.uri, "user_id") {
+        Ok(user_id) if user_id != auth.user_id => {
+            if !auth.has_permission(ORDERS_READ) {
+                let e = LocalError::Forbidden;
+                return create_response(error_status(&e), e.to_string());
+            }
+            user_id
+        }
+        _ => auth.user_id,
+    };
+
+    match context.db.postgres_db.get_addresses(user_id).await {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(addresses) => create_response(StatusCode::OK, json!(addresses).to_string()),
+    }
+}
+
+// /account/address
+pub async fn add_address(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    if body.as_ref().and_then(|json| json.as_object()).is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context
+        .db
+        .postgres_db
+        .add_address(user_id, body.unwrap())
+        .await
+    {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(address) => create_response(StatusCode::OK, json!(address).to_string()),
+    }
+}
+
+// /account/address
+pub async fn update_address(
+    parts: &Parts,
+    body: Option<Value>,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    let address_id = get_id_from_uri(&parts.uri, "id");
+    if address_id.is_err() || body.as_ref().and_then(|json| json.as_object()).is_none() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context
+        .db
+        .postgres_db
+        .update_address(user_id, address_id.ok().unwrap(), body.unwrap())
+        .await
+    {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(address) => create_response(StatusCode::OK, json!(address).to_string()),
+    }
+}
+
+// /account/address
+pub async fn delete_address(
+    parts: &Parts,
+    context: Arc<Context>,
+) -> Result<Response<Body>, hyper::Error> {
+    let auth = authenticate(parts, &context.auth_secret);
+    if let Err(e) = auth {
+        return create_response(StatusCode::UNAUTHORIZED, e.to_string());
+    }
+    let user_id = auth.ok().unwrap().user_id;
+
+    let address_id = get_id_from_uri(&parts.uri, "id");
+    if address_id.is_err() {
+        return create_response(
+            StatusCode::BAD_REQUEST,
+            LocalError::WrongParameters.to_string(),
+        );
+    }
+
+    match context
+        .db
+        .postgres_db
+        .delete_address(user_id, address_id.ok().unwrap())
+        .await
+    {
+        Err(e) => create_response(error_status(&e), e.to_string()),
+        Ok(_) => create_response(StatusCode::OK, String::new()),
+    }
+}
@@ -0,0 +1,5 @@
+// The database layer and the login guard, shared with the `shopctl` admin tool.
+pub mod db;
+pub mod entities;
+pub mod login_guard;
+pub mod validation;
@@ -0,0 +1,96 @@
+use crate::login_guard::AttemptStore;
+use common::utils::LocalError;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct Attempts {
+    failures: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+}
+
+impl Attempts {
+    // Whether a failure is still in the window or a lock still runs.
+    fn is_live(&self, now: Instant, window: Duration) -> bool {
+        let failing = self
+            .failures
+            .back()
+            .is_some_and(|last| now.duration_since(*last) <= window);
+        let locked = self.locked_until.is_some_and(|until| until > now);
+        failing || locked
+    }
+}
+
+// Counters local to this process.
+#[derive(Default)]
+pub struct MemoryAttemptStore {
+    attempts: Mutex<HashMap<String, Attempts>>,
+    // when expired keys were last dropped
+    swept_at: Mutex<Option<Instant>>,
+}
+
+impl AttemptStore for MemoryAttemptStore {
+    fn record_failure(&self, key: &str, window: Duration) -> Result<u64, LocalError> {
+        let now = Instant::now();
+        let mut attempts = self
+            .attempts
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        // keys of one-off failures would otherwise pile up; swept at most once per window
+        let mut swept_at = self
+            .swept_at
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+        if swept_at.is_none_or(|at| now.duration_since(at) >= window) {
+            attempts.retain(|_, entry| entry.is_live(now, window));
+            *swept_at = Some(now);
+        }
+
+        let entry = attempts.entry(key.to_string()).or_default();
+        while let Some(first) = entry.failures.front() {
+            if now.duration_since(*first) <= window {
+                break;
+            }
+            entry.failures.pop_front();
+        }
+        entry.failures.push_back(now);
+
+        Ok(entry.failures.len() as u64)
+    }
+
+    fn lock(&self, key: &str, duration: Duration) -> Result<(), LocalError> {
+        let mut attempts = self
+            .attempts
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        attempts.entry(key.to_string()).or_default().locked_until = Some(Instant::now() + duration);
+
+        Ok(())
+    }
+
+    fn locked_for(&self, key: &str) -> Result<Option<Duration>, LocalError> {
+        let attempts = self
+            .attempts
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        Ok(attempts
+            .get(key)
+            .and_then(|entry| entry.locked_until)
+            .and_then(|until| until.checked_duration_since(Instant::now())))
+    }
+
+    fn clear(&self, key: &str) -> Result<(), LocalError> {
+        let mut attempts = self
+            .attempts
+            .lock()
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        attempts.remove(key);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use crate::login_guard::memory_store::MemoryAttemptStore;
+use crate::login_guard::redis_store::RedisAttemptStore;
+use common::settings::Settings;
+use common::utils::LocalError;
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub mod memory_store;
+pub mod redis_store;
+
+// Failed attempts are kept per key in a sliding window; a key can also be locked for a while.
+pub trait AttemptStore: Send + Sync {
+    // Records a failed attempt and returns the number of failures within `window`.
+    fn record_failure(&self, key: &str, window: Duration) -> Result<u64, LocalError>;
+    fn lock(&self, key: &str, duration: Duration) -> Result<(), LocalError>;
+    // Remaining lock time, if the key is locked.
+    fn locked_for(&self, key: &str) -> Result<Option<Duration>, LocalError>;
+    // Drops both the failures and the lock of the key.
+    fn clear(&self, key: &str) -> Result<(), LocalError>;
+}
+
+pub struct LoginGuard {
+    store: Box<dyn AttemptStore>,
+    window: Duration,
+    lockout: Duration,
+    max_failures_per_user: u64,
+    max_failures_per_ip: u64,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+pub fn user_key(username: &str) -> String {
+    format!("login:user:{}", username.to_lowercase())
+}
+
+pub fn ip_key(ip: &IpAddr) -> String {
+    format!("login:ip:{}", ip)
+}
+
+impl LoginGuard {
+    // `login_guard.store` selects the store: "memory" for a single replica, "redis" to share
+    // the counters between replicas.
+    pub fn init(settings: &Settings) -> Result<LoginGuard, LocalError> {
+        let get = |key: &str| -> Result<u64, LocalError> {
+            settings
+                .get("login_guard", key)
+                .parse()
+                .map_err(|_| LocalError::WrongParameters)
+        };
+
+        let store: Box<dyn AttemptStore> = match settings.get("login_guard", "store").as_str() {
+            "memory" => Box::new(MemoryAttemptStore::default()),
+            "redis" => Box::new(RedisAttemptStore::init(
+                &settings.get("login_guard", "redis_uri"),
+            )?),
+            _ => return Err(LocalError::WrongParameters),
+        };
+
+        Ok(LoginGuard {
+            store,
+            window: Duration::from_secs(get("window_secs")?),
+            lockout: Duration::from_secs(get("lockout_secs")?),
+            max_failures_per_user: get("max_failures_per_user")?,
+            max_failures_per_ip: get("max_failures_per_ip")?,
+            base_delay: Duration::from_millis(get("base_delay_ms")?),
+            max_delay: Duration::from_millis(get("max_delay_ms")?),
+        })
+    }
+
+    // Remaining lockout of the username or the address, whichever is longer.
+    // Store errors don't block logins.
+    pub fn locked_for(&self, username: &str, ip: &IpAddr) -> Option<Duration> {
+        [user_key(username), ip_key(ip)]
+            .iter()
+            .filter_map(|key| self.store.locked_for(key).ok().flatten())
+            .max()
+    }
+
+    // Records the failure for both the username and the address, locks whichever reached
+    // its limit and returns how long the failed response should be delayed.
+    pub fn record_failure(&self, username: &str, ip: &IpAddr) -> Duration {
+        let mut failures = 0;
+
+        for (key, max_failures) in [
+            (user_key(username), self.max_failures_per_user),
+            (ip_key(ip), self.max_failures_per_ip),
+        ] {
+            let count = match self.store.record_failure(&key, self.window) {
+                Ok(count) => count,
+                Err(_) => {
+                    println!("Error when recording failed login for {}", key);
+                    continue;
+                }
+            };
+
+            if count >= max_failures {
+                let _ = self.store.lock(&key, self.lockout);
+                println!(
+                    "SECURITY: {} locked for {}s after {} failed logins (username: {}, ip: {})",
+                    key,
+                    self.lockout.as_secs(),
+                    count,
+                    username,
+                    ip
+                );
+            }
+
+            // per-address counts are scaled to the per-username limit so both grow the delay alike
+            failures = failures.max(count * self.max_failures_per_user / max_failures.max(1));
+        }
+
+        self.delay(failures)
+    }
+
+    pub fn record_success(&self, username: &str) {
+        let _ = self.store.clear(&user_key(username));
+    }
+
+    pub fn unlock(&self, key: &str) -> Result<(), LocalError> {
+        self.store.clear(key)
+    }
+
+    // base_delay, doubled with each further failure, up to max_delay
+    fn delay(&self, failures: u64) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+
+        let factor = 1u32 << (failures - 1).min(16);
+        self.base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+}
@@ -0,0 +1,86 @@
+use crate::login_guard::AttemptStore;
+use common::utils::LocalError;
+use rand::Rng;
+use redis::{Client, Commands, Connection};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Counters shared between replicas: failures are a sorted set scored by time in ms,
+// the lock is a key with a TTL.
+pub struct RedisAttemptStore {
+    redis_client: Client,
+}
+
+fn failures_key(key: &str) -> String {
+    format!("{}:failures", key)
+}
+
+fn lock_key(key: &str) -> String {
+    format!("{}:lock", key)
+}
+
+impl RedisAttemptStore {
+    pub fn init(redis_uri: &str) -> Result<RedisAttemptStore, LocalError> {
+        let client = Client::open(redis_uri).map_err(|_| LocalError::OperationFailed)?;
+
+        Ok(RedisAttemptStore {
+            redis_client: client,
+        })
+    }
+
+    fn connection(&self) -> Result<Connection, LocalError> {
+        self.redis_client
+            .get_connection()
+            .map_err(|_| LocalError::OperationFailed)
+    }
+}
+
+impl AttemptStore for RedisAttemptStore {
+    fn record_failure(&self, key: &str, window: Duration) -> Result<u64, LocalError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| LocalError::OperationFailed)?
+            .as_millis() as u64;
+        let window_ms = window.as_millis() as u64;
+        let member = format!("{}-{}", now, rand::thread_rng().gen::<u32>());
+        let key = failures_key(key);
+
+        let (count,): (u64,) = redis::pipe()
+            .atomic()
+            .zrembyscore(&key, 0, now.saturating_sub(window_ms))
+            .ignore()
+            .zadd(&key, member, now)
+            .ignore()
+            .zcard(&key)
+            .pexpire(&key, window_ms as usize)
+            .ignore()
+            .query(&mut self.connection()?)
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        Ok(count)
+    }
+
+    fn lock(&self, key: &str, duration: Duration) -> Result<(), LocalError> {
+        self.connection()?
+            .pset_ex(lock_key(key), 1, duration.as_millis() as usize)
+            .map_err(|_| LocalError::OperationFailed)
+    }
+
+    fn locked_for(&self, key: &str) -> Result<Option<Duration>, LocalError> {
+        let ttl: i64 = self
+            .connection()?
+            .pttl(lock_key(key))
+            .map_err(|_| LocalError::OperationFailed)?;
+
+        Ok(if ttl > 0 {
+            Some(Duration::from_millis(ttl as u64))
+        } else {
+            None
+        })
+    }
+
+    fn clear(&self, key: &str) -> Result<(), LocalError> {
+        self.connection()?
+            .del(&[failures_key(key), lock_key(key)])
+            .map_err(|_| LocalError::OperationFailed)
+    }
+}
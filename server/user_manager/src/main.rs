@@ -1,34 +1,96 @@
 extern crate core;
 
-use crate::context::Context;
-use common::request_response_utils::{get_json_from_body, response_ok};
+use crate::context::{AccountContext, Context, ProductManagerContext};
+use crate::login_guard::LoginGuard;
+use chrono::Duration;
+use common::request_response_utils::{
+    get_client_ip, get_json_from_body, response_ok, trusted_proxies,
+};
+use common::auth::{check_secrets, MIN_SECRET_LEN};
+use common::idempotency::{Begin, Idempotency};
+use common::rate_limit::RateLimiter;
 use common::settings::Settings;
 use http::{Method, StatusCode};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{http, Body, Request, Response, Server};
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 mod context;
-mod db;
-mod entities;
 mod handlers;
 
+use user_manager::{db, login_guard, validation};
+
 async fn route_service(
     req: Request<Body>,
     _addr: String,
+    remote_addr: SocketAddr,
     context: Arc<Context>,
 ) -> Result<Response<Body>, hyper::Error> {
     let (parts, body) = req.into_parts();
 
+    let rate_limit = context.rate_limiter.check(&parts, remote_addr);
+    if !rate_limit.allowed {
+        return rate_limit.response();
+    }
+    let client_ip = get_client_ip(&parts, remote_addr, &context.trusted_proxies);
+
     println!("method: {}, uri: {}", &parts.method, parts.uri.path());
 
     let body_json = get_json_from_body(body).await;
 
-    match (&parts.method, parts.uri.path()) {
+    // handlers take the context by value
+    let route_context = context.clone();
+    let claim = match context.idempotency.begin(&parts, &body_json) {
+        Begin::Handle(claim) => claim,
+        Begin::Respond(response) => return response,
+    };
+
+    let response = match (&parts.method, parts.uri.path()) {
         (&Method::POST, "/account/add") => handlers::add_account(body_json, context).await,
         (&Method::OPTIONS, "/account/add") => response_ok(),
-        (&Method::PUT, "/account/login") => handlers::login(body_json, context).await,
+        (&Method::PUT, "/account/login") => handlers::login(body_json, client_ip, context).await,
+        (&Method::PUT, "/account/unlock") => handlers::unlock(&parts, context).await,
+        (&Method::OPTIONS, "/account/unlock") => response_ok(),
+        (&Method::GET, "/account/verify") => handlers::verify_email(&parts, context).await,
+        (&Method::POST, "/account/verify/resend") => {
+            handlers::resend_verification(body_json, context).await
+        }
+        (&Method::OPTIONS, "/account/verify/resend") => response_ok(),
+        (&Method::POST, "/account/forgot_password") => {
+            handlers::forgot_password(body_json, context).await
+        }
+        (&Method::OPTIONS, "/account/forgot_password") => response_ok(),
+        (&Method::POST, "/account/reset_password") => {
+            handlers::reset_password(body_json, context).await
+        }
+        (&Method::OPTIONS, "/account/reset_password") => response_ok(),
+        (&Method::GET, "/account/profile") => handlers::get_profile(&parts, context).await,
+        (&Method::PUT, "/account/profile") => {
+            handlers::update_profile(&parts, body_json, context).await
+        }
+        (&Method::OPTIONS, "/account/profile") => response_ok(),
+        (&Method::GET, "/account/addresses") => handlers::get_addresses(&parts, context).await,
+        (&Method::POST, "/account/address") => {
+            handlers::add_address(&parts, body_json, context).await
+        }
+        (&Method::PUT, "/account/address") => {
+            handlers::update_address(&parts, body_json, context).await
+        }
+        (&Method::DELETE, "/account/address") => handlers::delete_address(&parts, context).await,
+        (&Method::OPTIONS, "/account/addresses") => response_ok(),
+        (&Method::OPTIONS, "/account/address") => response_ok(),
+        (&Method::PUT, "/account/password") => {
+            handlers::change_password(&parts, body_json, context).await
+        }
+        (&Method::OPTIONS, "/account/password") => response_ok(),
+        (&Method::DELETE, "/account") => handlers::delete_account(&parts, context).await,
+        (&Method::OPTIONS, "/account") => response_ok(),
+        (&Method::PUT, "/account/role") => handlers::set_role(&parts, body_json, context).await,
+        (&Method::OPTIONS, "/account/role") => response_ok(),
+        (&Method::GET, "/account/history") => handlers::history(&parts, context).await,
+        (&Method::GET, "/account/interactions") => handlers::interactions(&parts, context).await,
         // (&Method::PUT, "/account/logout") => handlers::logout(body, context).await,
         (&Method::PUT, "/account/add_product_view") => handlers::add_product_view(&parts, context).await,
         (&Method::PUT, "/account/add_product_purchase") => {
@@ -39,7 +101,13 @@ async fn route_service(
             .status(StatusCode::NOT_FOUND)
             .body(Body::empty())
             .unwrap()),
-    }
+    };
+    let response = route_context.idempotency.finish(claim, response).await;
+
+    response.map(|mut response| {
+        rate_limit.add_headers(&mut response);
+        response
+    })
 }
 
 #[tokio::main]
@@ -48,12 +116,13 @@ pub async fn run_server(settings: &Settings, context: Arc<Context>) {
     let in_addr: SocketAddr = addr.parse().unwrap();
     println!("{}", settings.get("network", "listen_on"));
 
-    let service = make_service_fn(move |_| {
+    let service = make_service_fn(move |conn: &AddrStream| {
         let addr = addr.clone();
+        let remote_addr = conn.remote_addr();
         let context = context.clone();
         async move {
             Ok::<_, hyper::Error>(service_fn(move |req| {
-                route_service(req, addr.clone(), context.clone())
+                route_service(req, addr.clone(), remote_addr, context.clone())
             }))
         }
     });
@@ -67,6 +136,36 @@ pub async fn run_server(settings: &Settings, context: Arc<Context>) {
     }
 }
 
+#[tokio::main]
+async fn migrate_stats(context: Arc<Context>) {
+    match context.db.mongo_db.migrate_stats().await {
+        Ok(count) => println!("Migrated {} stats documents", count),
+        Err(e) => println!("Error when migrating stats: {}", e),
+    }
+}
+
+// Usage: user_manager --bootstrap-admin <username> <password> <email> <phone> [full name]
+#[tokio::main]
+async fn bootstrap_admin(context: Arc<Context>, args: &[String]) {
+    if args.len() < 4 {
+        println!("Usage: --bootstrap-admin <username> <password> <email> <phone> [full name]");
+        return;
+    }
+
+    let admin = serde_json::json!({
+        "username": args[0],
+        "password": args[1],
+        "email": args[2],
+        "phone": args[3],
+        "full_name": args.get(4).unwrap_or(&args[0]),
+    });
+
+    match context.db.postgres_db.bootstrap_admin(admin).await {
+        Ok(id) => println!("Admin account {} created", id),
+        Err(e) => println!("Error when creating admin: {}", e.to_string()),
+    }
+}
+
 fn main() {
     let settings = Settings::new("./config/config.yml");
     let postgres_url = settings.get("postgres", "uri");
@@ -74,6 +173,14 @@ fn main() {
     let mongodb_uri = settings.get("mongodb", "uri");
     let mongodb_name = settings.get("mongodb", "name");
 
+    if let Err(setting) = check_secrets(&settings) {
+        println!(
+            "Error: {} is a placeholder or shorter than {} bytes",
+            setting, MIN_SECRET_LEN
+        );
+        return;
+    }
+
     let context;
     match db::DB::init(&postgres_url, &postgres_name, &mongodb_uri, &mongodb_name) {
         None => {
@@ -83,9 +190,83 @@ fn main() {
         Some(db) => {
             println!("Database initialized: {}", postgres_name);
 
-            context = Arc::new(Context { db });
+            let product_manager = ProductManagerContext {
+                uri: settings.get("product_manager", "uri"),
+                get_products_endpoint: "/product/product/batch".to_string(),
+            };
+
+            let mailer = common::mailer::init(&settings);
+            if mailer.is_err() {
+                println!("Error when initializing mailer");
+                return;
+            }
+
+            let account = AccountContext {
+                base_url: settings.get("account", "base_url"),
+                require_verified_email: settings.get("account", "require_verified_email")
+                    == "true",
+                verification_token_ttl: Duration::seconds(
+                    settings
+                        .get("account", "verification_token_ttl_secs")
+                        .parse()
+                        .unwrap(),
+                ),
+                reset_token_ttl: Duration::seconds(
+                    settings
+                        .get("account", "reset_token_ttl_secs")
+                        .parse()
+                        .unwrap(),
+                ),
+            };
+
+            let login_guard = LoginGuard::init(&settings);
+            if login_guard.is_err() {
+                println!("Error when initializing login guard");
+                return;
+            }
+
+            let idempotency = Idempotency::init(&settings);
+            if idempotency.is_err() {
+                println!("Error when initializing idempotency store");
+                return;
+            }
+
+            let rate_limiter = RateLimiter::init(&settings);
+            if rate_limiter.is_err() {
+                println!("Error when initializing rate limiter");
+                return;
+            }
+
+            let trusted_proxies = trusted_proxies(&settings);
+            if trusted_proxies.is_err() {
+                println!("Error when reading trusted proxies");
+                return;
+            }
+
+            context = Arc::new(Context {
+                db,
+                auth_secret: settings.get("auth", "secret"),
+                mailer: mailer.ok().unwrap(),
+                account,
+                login_guard: login_guard.ok().unwrap(),
+                rate_limiter: rate_limiter.ok().unwrap(),
+                trusted_proxies: trusted_proxies.ok().unwrap(),
+                idempotency: idempotency.ok().unwrap(),
+                product_manager,
+            });
         }
     };
 
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(pos) = args.iter().position(|arg| arg == "--bootstrap-admin") {
+        bootstrap_admin(context, &args[pos + 1..]);
+        return;
+    }
+
+    if std::env::args().any(|arg| arg == "--migrate-stats") {
+        migrate_stats(context);
+        return;
+    }
+
     run_server(&settings, context.clone());
 }
@@ -0,0 +1,57 @@
+use common::utils::LocalError;
+
+pub fn validate_email(email: &str) -> Result<(), LocalError> {
+    let (local, domain) = email.split_once('@').ok_or(LocalError::InvalidEmail)?;
+
+    let valid = !local.is_empty()
+        && !domain.contains('@')
+        && !email.chars().any(char::is_whitespace)
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty());
+
+    if valid {
+        Ok(())
+    } else {
+        Err(LocalError::InvalidEmail)
+    }
+}
+
+// Digits with optional leading `+` and the usual separators, e.g. `+374 (10) 12-34-56`.
+pub fn validate_phone(phone: &str) -> Result<(), LocalError> {
+    let digits = phone.chars().filter(char::is_ascii_digit).count();
+    let valid = phone
+        .trim_start_matches('+')
+        .chars()
+        .all(|c| c.is_ascii_digit() || " -()".contains(c))
+        && (7..=15).contains(&digits);
+
+    if valid {
+        Ok(())
+    } else {
+        Err(LocalError::InvalidPhone)
+    }
+}
+
+// ISO 3166-1 alpha-2, e.g. `DE`.
+pub fn validate_country(country: &str) -> Result<(), LocalError> {
+    if country.len() == 2 && country.chars().all(|c| c.is_ascii_uppercase()) {
+        Ok(())
+    } else {
+        Err(LocalError::InvalidAddress)
+    }
+}
+
+// Letters and digits with spaces or dashes, e.g. `10115`, `SW1A 1AA` or `1234-567`.
+pub fn validate_postal_code(postal_code: &str) -> Result<(), LocalError> {
+    let chars = postal_code.chars().filter(char::is_ascii_alphanumeric).count();
+    let valid = postal_code
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || " -".contains(c))
+        && (2..=10).contains(&chars);
+
+    if valid {
+        Ok(())
+    } else {
+        Err(LocalError::InvalidAddress)
+    }
+}